@@ -6,6 +6,8 @@ use std::panic::{AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
 use thiserror::Error;
 use tracing;
 
+use crate::telemetry;
+
 #[derive(Error, Debug)]
 pub enum ActorError {
     #[error("Pre-start initialization failed: {0}")]
@@ -30,6 +32,19 @@ pub enum ActorStatus {
     ShutDown,       // Successfully completed all stages it attempted
 }
 
+impl ActorStatus {
+    /// The actor didn't stop on its own terms, a supervisor may restart it.
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            ActorStatus::PreStartFailed
+                | ActorStatus::ExitedWithError
+                | ActorStatus::Panicked
+                | ActorStatus::PostStopFailed
+        )
+    }
+}
+
 impl Display for ActorStatus {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
@@ -74,14 +89,24 @@ pub trait Actor: Send + Sized {
 
 // --- Actor Runner ---
 
-pub async fn run<A>(actor: A)
+/// Runs `actor` to completion and returns its final status.
+pub async fn run<A>(mut actor: A) -> ActorStatus
+where
+    A: Actor + Send + 'static,
+{
+    run_until(&mut actor, std::future::pending()).await
+}
+
+/// Runs `actor` until it exits or `stop` resolves, `post_stop` is called either way.
+/// The actor is only borrowed, a supervisor can run it again after a failure.
+pub async fn run_until<A>(actor: &mut A, stop: impl Future<Output = ()>) -> ActorStatus
 where
     A: Actor + Send + 'static,
 {
     let actor_kind = actor.kind();
     let actor_id = actor.id();
 
-    run_instrumented(actor, actor_kind, actor_id).await;
+    run_instrumented(actor, actor_kind, actor_id, stop).await
 }
 
 #[tracing::instrument(
@@ -93,7 +118,12 @@ where
         status = tracing::field::Empty,
     )
 )]
-async fn run_instrumented<A>(mut actor: A, actor_kind: &'static str, actor_id: A::ID)
+async fn run_instrumented<A>(
+    actor: &mut A,
+    actor_kind: &'static str,
+    actor_id: A::ID,
+    stop: impl Future<Output = ()>,
+) -> ActorStatus
 where
     A: Actor + Send + 'static,
 {
@@ -123,7 +153,13 @@ where
         // AssertUnwindSafe is used because the future produced by actor.run_actor_logic()
         // might not be UnwindSafe by default if `A` (the actor type) isn't UnwindSafe.
         // We are catching the panic from this specific unit of work.
-        let run_result = AssertUnwindSafe(future_to_run).catch_unwind().await;
+        let run_result = tokio::select! {
+            res = AssertUnwindSafe(future_to_run).catch_unwind() => res,
+            _ = stop => {
+                tracing::info!("stop is requested, interrupting run_actor_logic.");
+                Ok(Ok(()))
+            }
+        };
 
         match run_result {
             Ok(Ok(())) => {
@@ -187,6 +223,13 @@ where
 
     span.record("status", &tracing::field::display(current_status));
     tracing::info!("Fully shut down with final status: {}", current_status);
+    metrics::counter!(
+        telemetry::ACTOR_EXITS,
+        "kind" => actor_kind,
+        "status" => current_status.to_string(),
+    )
+    .increment(1);
+    current_status
 }
 
 fn extract_panic_message(payload: &Box<dyn Any + Send>) -> String {
@@ -0,0 +1,431 @@
+use std::{path::PathBuf, sync::Arc};
+
+use crate::{
+    auth::{AuthError, Authenticator, Claims},
+    capture::CaptureRequest,
+    controller::ControllerHandle,
+    entity::{EntityId, ExternalParticipantId, ExternalRoomId},
+    room::RoomInfo,
+    shutdown::Drain,
+    signaling::{SignalingError, SignalingState, verify_token},
+};
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, get, post},
+};
+
+#[derive(Debug, serde::Serialize)]
+pub struct RoomSummary {
+    room_id: ExternalRoomId,
+    participants: usize,
+    tracks: usize,
+    recording: bool,
+}
+
+impl From<RoomInfo> for RoomSummary {
+    fn from(info: RoomInfo) -> Self {
+        Self {
+            participants: info.participants.len(),
+            tracks: info.participants.iter().map(|p| p.tracks.len()).sum(),
+            room_id: info.room_id,
+            recording: info.recording,
+        }
+    }
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn list_rooms(
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RoomSummary>>, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), None)?;
+
+    let rooms = controller.list_rooms().await?;
+    Ok(Json(rooms.into_iter().map(RoomSummary::from).collect()))
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn get_room(
+    Path(room_id): Path<ExternalRoomId>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<Json<RoomInfo>, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    Ok(Json(controller.room_info(room_id).await?))
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn close_room(
+    Path(room_id): Path<ExternalRoomId>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    controller.close_room(room_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn kick_participant(
+    Path((room_id, participant_id)): Path<(ExternalRoomId, ExternalParticipantId)>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    controller.kick_participant(room_id, participant_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CaptureStarted {
+    files: Vec<PathBuf>,
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn capture_participant(
+    Path((room_id, participant_id)): Path<(ExternalRoomId, ExternalParticipantId)>,
+    Query(req): Query<CaptureRequest>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<Json<CaptureStarted>, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    let files = controller
+        .capture_participant(room_id, participant_id, req)
+        .await?;
+    Ok(Json(CaptureStarted { files }))
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn start_recording(
+    Path(room_id): Path<ExternalRoomId>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    controller.start_recording(room_id, None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn stop_recording(
+    Path(room_id): Path<ExternalRoomId>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    controller.stop_recording(room_id, None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn start_track_recording(
+    Path((room_id, track_id)): Path<(ExternalRoomId, EntityId)>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    controller.start_recording(room_id, Some(track_id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn stop_track_recording(
+    Path((room_id, track_id)): Path<(ExternalRoomId, EntityId)>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    authorize(claims.as_ref(), Some(&room_id))?;
+
+    controller.stop_recording(room_id, Some(track_id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Admin tokens need the `roomAdmin` grant. A token with a room can only manage that
+/// room, server-wide endpoints require a token without a room. Without API keys there
+/// are no tokens, and the admin API is refused altogether.
+fn authorize(claims: Option<&Claims>, room: Option<&ExternalRoomId>) -> Result<(), AuthError> {
+    let Some(claims) = claims else {
+        return Err(AuthError::Forbidden("admin"));
+    };
+
+    if !claims.grants.room_admin {
+        return Err(AuthError::Forbidden("admin"));
+    }
+
+    match (&claims.room, room) {
+        (None, _) => Ok(()),
+        (Some(allowed), Some(room)) if allowed == room => Ok(()),
+        _ => Err(AuthError::Forbidden("room")),
+    }
+}
+
+pub fn router(controller: ControllerHandle, auth: Arc<Authenticator>) -> Router {
+    Router::new()
+        .route("/admin/rooms", get(list_rooms))
+        .route("/admin/rooms/{room_id}", get(get_room).delete(close_room))
+        .route(
+            "/admin/rooms/{room_id}/participants/{participant_id}",
+            delete(kick_participant),
+        )
+        .route(
+            "/admin/rooms/{room_id}/participants/{participant_id}/capture",
+            post(capture_participant),
+        )
+        .route(
+            "/admin/rooms/{room_id}/recording",
+            post(start_recording).delete(stop_recording),
+        )
+        .route(
+            "/admin/rooms/{room_id}/tracks/{track_id}/recording",
+            post(start_track_recording).delete(stop_track_recording),
+        )
+        .with_state(SignalingState {
+            controller,
+            auth,
+            turn: None,
+            drain: Drain::default(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{ApiKey, ApiKeyMaterial, Grants, issue_token},
+        controller::testing,
+        participant::ParticipantConfig,
+    };
+    use axum::{
+        body::{self, Body},
+        http::{Request, header},
+    };
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tower::ServiceExt;
+
+    const KEY_ID: &str = "kid_3mJr7AoUXx2Wqd";
+    const SECRET: &str = "sk_2NEpo7TZRRrLZSi2U";
+    const PROJECT_ID: &str = "p_6aBfUS7iYUcWJ";
+
+    fn claims(room: Option<&str>, room_admin: bool) -> Claims {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        Claims {
+            iss: KEY_ID.to_string(),
+            sub: ExternalParticipantId::new("admin".to_string()).unwrap(),
+            exp,
+            project: PROJECT_ID.to_string(),
+            room: room.map(|room| ExternalRoomId::new(room.to_string()).unwrap()),
+            grants: Grants {
+                room_admin,
+                ..Grants::default()
+            },
+            metadata: None,
+        }
+    }
+
+    fn token(room: Option<&str>, room_admin: bool) -> String {
+        issue_token(KEY_ID, SECRET, &claims(room, room_admin)).unwrap()
+    }
+
+    async fn app() -> (Router, ControllerHandle) {
+        let auth = Authenticator::new(vec![ApiKey {
+            id: KEY_ID.to_string(),
+            project_id: PROJECT_ID.to_string(),
+            material: ApiKeyMaterial::Secret(SECRET.to_string()),
+        }])
+        .unwrap();
+        let controller = testing::spawn_controller().await;
+        (router(controller.clone(), Arc::new(auth)), controller)
+    }
+
+    async fn send(app: &Router, method: &str, uri: &str, token: Option<&str>) -> StatusCode {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = builder.body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[test]
+    fn test_authorize() {
+        let room = ExternalRoomId::new("room".to_string()).unwrap();
+        let other = ExternalRoomId::new("other".to_string()).unwrap();
+
+        // Authentication is disabled
+        assert!(matches!(
+            authorize(None, None),
+            Err(AuthError::Forbidden("admin"))
+        ));
+
+        let admin = claims(None, true);
+        assert!(authorize(Some(&admin), None).is_ok());
+        assert!(authorize(Some(&admin), Some(&room)).is_ok());
+
+        let room_admin = claims(Some("room"), true);
+        assert!(authorize(Some(&room_admin), Some(&room)).is_ok());
+        assert!(matches!(
+            authorize(Some(&room_admin), Some(&other)),
+            Err(AuthError::Forbidden("room"))
+        ));
+        assert!(matches!(
+            authorize(Some(&room_admin), None),
+            Err(AuthError::Forbidden("room"))
+        ));
+
+        let participant = claims(Some("room"), false);
+        assert!(matches!(
+            authorize(Some(&participant), Some(&room)),
+            Err(AuthError::Forbidden("admin"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_admin_is_refused_without_auth() {
+        let auth = Authenticator::new(vec![]).unwrap();
+        let app = router(testing::spawn_controller().await, Arc::new(auth));
+
+        assert_eq!(
+            send(&app, "GET", "/admin/rooms", None).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(&app, "DELETE", "/admin/rooms/room", None).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let (app, _) = app().await;
+
+        assert_eq!(
+            send(&app, "GET", "/admin/rooms", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send(&app, "GET", "/admin/rooms", Some("not-a-token")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let participant = token(None, false);
+        assert_eq!(
+            send(&app, "GET", "/admin/rooms", Some(&participant)).await,
+            StatusCode::FORBIDDEN
+        );
+        let room_admin = token(Some("other"), true);
+        assert_eq!(
+            send(&app, "GET", "/admin/rooms", Some(&room_admin)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(&app, "DELETE", "/admin/rooms/room", Some(&room_admin)).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let admin = token(None, true);
+        assert_eq!(
+            send(&app, "GET", "/admin/rooms", Some(&admin)).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_not_found() {
+        let (app, _) = app().await;
+        let admin = token(None, true);
+
+        for (method, uri) in [
+            ("GET", "/admin/rooms/missing"),
+            ("DELETE", "/admin/rooms/missing"),
+            ("DELETE", "/admin/rooms/missing/participants/alice"),
+            ("POST", "/admin/rooms/missing/recording"),
+            ("GET", "/admin/unknown"),
+        ] {
+            assert_eq!(
+                send(&app, method, uri, Some(&admin)).await,
+                StatusCode::NOT_FOUND,
+                "{method} {uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_room_info_and_kick() {
+        let (app, controller) = app().await;
+        controller
+            .allocate(
+                ExternalRoomId::new("room".to_string()).unwrap(),
+                Some(ExternalParticipantId::new("alice".to_string()).unwrap()),
+                testing::offer(),
+                ParticipantConfig::default(),
+            )
+            .await
+            .unwrap();
+        let admin = token(Some("room"), true);
+
+        let request = Request::get("/admin/rooms/room")
+            .header(header::AUTHORIZATION, format!("Bearer {admin}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let participant = &info["participants"][0];
+        assert_eq!(participant["participant_id"], "alice");
+        assert!(participant.get("session_id").is_none());
+
+        assert_eq!(
+            send(
+                &app,
+                "DELETE",
+                "/admin/rooms/room/participants/bob",
+                Some(&admin)
+            )
+            .await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(
+                &app,
+                "DELETE",
+                "/admin/rooms/room/participants/alice",
+                Some(&admin)
+            )
+            .await,
+            StatusCode::NO_CONTENT
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+
+use crate::entity::{self, EntityId, ExternalParticipantId, ExternalRoomId, prefix};
+
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("access token is missing")]
+    MissingToken,
+
+    #[error("access token is invalid: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+
+    #[error("access token is signed by an unknown api key")]
+    UnknownKey,
+
+    #[error("access token is not valid for this {0}")]
+    Forbidden(&'static str),
+
+    #[error("api key is invalid: {0}")]
+    InvalidKey(String),
+}
+
+/// Permissions of a participant, enforced by the participant actor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Grants {
+    #[serde(default)]
+    pub can_publish: bool,
+    #[serde(default)]
+    pub can_subscribe: bool,
+    #[serde(default)]
+    pub can_publish_data: bool,
+    /// Allows the admin API, scoped to the token's room if there is one.
+    #[serde(default)]
+    pub room_admin: bool,
+}
+
+impl Grants {
+    pub fn all() -> Self {
+        Self {
+            can_publish: true,
+            can_subscribe: true,
+            can_publish_data: true,
+            room_admin: true,
+        }
+    }
+}
+
+/// Join token claims. `iss` is the API key ID and `sub` is the participant identity.
+/// Admin tokens may omit the room to manage every room.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Claims {
+    pub iss: EntityId,
+    pub sub: ExternalParticipantId,
+    pub exp: u64,
+    pub project: EntityId,
+    #[serde(default)]
+    pub room: Option<ExternalRoomId>,
+    #[serde(default)]
+    pub grants: Grants,
+    /// App-specific metadata shared with the other participants in the room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
+#[derive(Clone)]
+pub enum ApiKeyMaterial {
+    /// Shared secret, "sk_" prefixed, verifies HS256 tokens.
+    Secret(String),
+    /// Ed25519 public key, "pk_" prefixed, verifies EdDSA tokens.
+    PublicKey(String),
+}
+
+#[derive(Clone)]
+pub struct ApiKey {
+    pub id: EntityId,
+    pub project_id: EntityId,
+    pub material: ApiKeyMaterial,
+}
+
+struct VerifyingKey {
+    project_id: EntityId,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Verifies join tokens against the configured API keys. Without any key, every
+/// request is let through with all grants.
+#[derive(Default)]
+pub struct Authenticator {
+    keys: HashMap<EntityId, VerifyingKey>,
+}
+
+impl Authenticator {
+    pub fn new(api_keys: Vec<ApiKey>) -> Result<Self, AuthError> {
+        let mut keys = HashMap::with_capacity(api_keys.len());
+        for api_key in api_keys {
+            expect_prefix(&api_key.id, prefix::API_KEY_ID)?;
+            expect_prefix(&api_key.project_id, prefix::PROJECT_ID)?;
+
+            let (algorithm, key) = match &api_key.material {
+                ApiKeyMaterial::Secret(secret) => {
+                    expect_prefix(secret, prefix::API_SECRET)?;
+                    (
+                        Algorithm::HS256,
+                        DecodingKey::from_secret(secret.as_bytes()),
+                    )
+                }
+                ApiKeyMaterial::PublicKey(public_key) => {
+                    expect_prefix(public_key, prefix::API_PUBLIC_KEY)?;
+                    let raw = entity::decode_id(public_key)
+                        .filter(|raw| raw.len() == ED25519_PUBLIC_KEY_LEN)
+                        .ok_or_else(|| {
+                            AuthError::InvalidKey(format!(
+                                "{} is not a base58 encoded Ed25519 public key",
+                                api_key.id
+                            ))
+                        })?;
+                    // The raw key is the "x" parameter of an OKP JWK, not DER
+                    let key = DecodingKey::from_ed_components(&URL_SAFE_NO_PAD.encode(&raw))
+                        .map_err(|err| AuthError::InvalidKey(err.to_string()))?;
+                    (Algorithm::EdDSA, key)
+                }
+            };
+
+            keys.insert(
+                api_key.id,
+                VerifyingKey {
+                    project_id: api_key.project_id,
+                    algorithm,
+                    key,
+                },
+            );
+        }
+
+        Ok(Self { keys })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let Some(verifying_key) = header.kid.as_ref().and_then(|kid| self.keys.get(kid)) else {
+            return Err(AuthError::UnknownKey);
+        };
+
+        let mut validation = Validation::new(verifying_key.algorithm);
+        validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+        validation.set_issuer(&[header.kid.as_deref().unwrap_or_default()]);
+        let data = jsonwebtoken::decode::<Claims>(token, &verifying_key.key, &validation)?;
+
+        if data.claims.project != verifying_key.project_id {
+            return Err(AuthError::Forbidden("project"));
+        }
+
+        Ok(data.claims)
+    }
+}
+
+/// Issues an HS256 join token, the counterpart of [`Authenticator::verify`].
+pub fn issue_token(key_id: &str, secret: &str, claims: &Claims) -> Result<String, AuthError> {
+    let header = Header {
+        kid: Some(key_id.to_string()),
+        ..Header::new(Algorithm::HS256)
+    };
+    Ok(jsonwebtoken::encode(
+        &header,
+        claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )?)
+}
+
+fn expect_prefix(id: &str, expected: &str) -> Result<(), AuthError> {
+    if entity::get_prefix(id) == Some(expected) {
+        Ok(())
+    } else {
+        Err(AuthError::InvalidKey(format!(
+            "expected a \"{expected}_\" prefix"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const KEY_ID: &str = "kid_3mJr7AoUXx2Wqd";
+    const SECRET: &str = "sk_2NEpo7TZRRrLZSi2U";
+    const PROJECT_ID: &str = "p_6aBfUS7iYUcWJ";
+    const ED_KEY_ID: &str = "kid_8Wq3dNpRt5YcVz";
+    const ED_PUBLIC_KEY: &str = "pk_2BKZiX9bWdEN9gUW1FKujJxJmEzdTrJ38tTYt8nFhfs7";
+    // PKCS#8 DER of the matching private key, from `openssl genpkey -algorithm ed25519`
+    const ED_PRIVATE_KEY: &str = "302e020100300506032b6570042204202af1cf237ec031858b416d8c0bc053d73b6fe2cc23cb14aca8a5653b5157f306";
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(vec![ApiKey {
+            id: KEY_ID.to_string(),
+            project_id: PROJECT_ID.to_string(),
+            material: ApiKeyMaterial::Secret(SECRET.to_string()),
+        }])
+        .unwrap()
+    }
+
+    fn claims(exp_offset: i64) -> Claims {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Claims {
+            iss: KEY_ID.to_string(),
+            sub: ExternalParticipantId::new("alice".to_string()).unwrap(),
+            exp: now.saturating_add_signed(exp_offset),
+            project: PROJECT_ID.to_string(),
+            room: Some(ExternalRoomId::new("room".to_string()).unwrap()),
+            grants: Grants {
+                can_publish: true,
+                ..Default::default()
+            },
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_verify_issued_token() {
+        let token = issue_token(KEY_ID, SECRET, &claims(60)).unwrap();
+        let verified = authenticator().verify(&token).unwrap();
+
+        assert_eq!(verified.sub.as_str(), "alice");
+        assert_eq!(verified.room.unwrap().as_str(), "room");
+        assert!(verified.grants.can_publish);
+        assert!(!verified.grants.can_subscribe);
+    }
+
+    #[test]
+    fn test_reject_expired_token() {
+        let token = issue_token(KEY_ID, SECRET, &claims(-3600)).unwrap();
+        assert!(matches!(
+            authenticator().verify(&token),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn test_reject_wrong_secret() {
+        let token = issue_token(KEY_ID, "sk_wrong", &claims(60)).unwrap();
+        assert!(matches!(
+            authenticator().verify(&token),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn test_reject_unknown_key() {
+        let token = issue_token("kid_unknown", SECRET, &claims(60)).unwrap();
+        assert!(matches!(
+            authenticator().verify(&token),
+            Err(AuthError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn test_reject_other_project() {
+        let mut claims = claims(60);
+        claims.project = "p_other".to_string();
+        let token = issue_token(KEY_ID, SECRET, &claims).unwrap();
+        assert!(matches!(
+            authenticator().verify(&token),
+            Err(AuthError::Forbidden("project"))
+        ));
+    }
+
+    fn eddsa_token(claims: &Claims) -> String {
+        let header = Header {
+            kid: Some(ED_KEY_ID.to_string()),
+            ..Header::new(Algorithm::EdDSA)
+        };
+        let key = EncodingKey::from_ed_der(&hex::decode(ED_PRIVATE_KEY).unwrap());
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    #[test]
+    fn test_verify_eddsa_token() {
+        let auth = Authenticator::new(vec![ApiKey {
+            id: ED_KEY_ID.to_string(),
+            project_id: PROJECT_ID.to_string(),
+            material: ApiKeyMaterial::PublicKey(ED_PUBLIC_KEY.to_string()),
+        }])
+        .unwrap();
+        let mut claims = claims(60);
+        claims.iss = ED_KEY_ID.to_string();
+
+        let token = eddsa_token(&claims);
+        let verified = auth.verify(&token).unwrap();
+        assert_eq!(verified.sub.as_str(), "alice");
+
+        // Flip a character of the signature
+        let mut tampered = token.into_bytes();
+        let last = tampered.len() - 2;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(matches!(
+            auth.verify(&tampered),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn test_reject_invalid_public_key() {
+        let res = Authenticator::new(vec![ApiKey {
+            id: ED_KEY_ID.to_string(),
+            project_id: PROJECT_ID.to_string(),
+            material: ApiKeyMaterial::PublicKey("pk_3mJr7AoUXx2Wqd".to_string()),
+        }]);
+        assert!(matches!(res, Err(AuthError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn test_reject_invalid_key_prefix() {
+        let res = Authenticator::new(vec![ApiKey {
+            id: KEY_ID.to_string(),
+            project_id: PROJECT_ID.to_string(),
+            material: ApiKeyMaterial::Secret("secret".to_string()),
+        }]);
+        assert!(matches!(res, Err(AuthError::InvalidKey(_))));
+    }
+}
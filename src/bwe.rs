@@ -0,0 +1,125 @@
+use str0m::media::Rid;
+
+/// Starting point of the downlink estimate, low enough to not congest a weak link
+/// before the first feedback arrives.
+pub const INITIAL_EGRESS_BITRATE_KBPS: u64 = 300;
+
+/// Estimates are noisy, leave some room so that a small overshoot doesn't build up a queue.
+pub const BWE_HEADROOM_PERCENT: u64 = 90;
+
+/// Measured bitrate of each layer of a track in bps, ordered from the lowest to the
+/// highest quality. A track without simulcast has a single `None` layer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayerBitrates(pub Vec<(Option<Rid>, u64)>);
+
+impl LayerBitrates {
+    pub fn is_measured(&self) -> bool {
+        self.0.iter().any(|(_, bitrate)| *bitrate > 0)
+    }
+
+    pub fn get(&self, rid: Option<Rid>) -> Option<u64> {
+        self.0.iter().find(|(r, _)| *r == rid).map(|(_, b)| *b)
+    }
+
+    /// Layers that are currently being sent, up to and including `max`.
+    pub fn up_to(&self, max: Option<Rid>) -> Vec<(Option<Rid>, u64)> {
+        let mut layers = Vec::with_capacity(self.0.len());
+        for (rid, bitrate) in &self.0 {
+            // a publisher stops sending higher layers when its uplink is constrained
+            if *bitrate > 0 {
+                layers.push((*rid, *bitrate));
+            }
+
+            if *rid == max {
+                break;
+            }
+        }
+        layers
+    }
+}
+
+/// Splits `available` bps across tracks. Each track is described by the bitrates of its
+/// eligible layers, from the lowest to the highest quality.
+///
+/// Every track gets its lowest layer before any track is upgraded, then tracks are
+/// upgraded one layer at a time in a round-robin fashion. Returns the chosen layer index
+/// of each track, `None` pauses the track.
+pub fn allocate(available: u64, tracks: &[Vec<u64>]) -> Vec<Option<usize>> {
+    let mut remaining = available;
+    let mut chosen = vec![None; tracks.len()];
+
+    for (layers, chosen) in tracks.iter().zip(chosen.iter_mut()) {
+        let Some(&lowest) = layers.first() else {
+            continue;
+        };
+
+        if lowest <= remaining {
+            remaining -= lowest;
+            *chosen = Some(0);
+        }
+    }
+
+    loop {
+        let mut upgraded = false;
+        for (layers, chosen) in tracks.iter().zip(chosen.iter_mut()) {
+            let Some(current) = *chosen else {
+                continue;
+            };
+
+            let Some(&next) = layers.get(current + 1) else {
+                continue;
+            };
+
+            let cost = next.saturating_sub(layers[current]);
+            if cost <= remaining {
+                remaining -= cost;
+                *chosen = Some(current + 1);
+                upgraded = true;
+            }
+        }
+
+        if !upgraded {
+            break;
+        }
+    }
+
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_all_layers() {
+        let tracks = vec![vec![100, 500, 1500], vec![100, 500, 1500]];
+        assert_eq!(allocate(10_000, &tracks), vec![Some(2), Some(2)]);
+    }
+
+    #[test]
+    fn test_allocate_base_layers_first() {
+        let tracks = vec![vec![100, 500, 1500], vec![100, 500, 1500]];
+        assert_eq!(allocate(700, &tracks), vec![Some(1), Some(0)]);
+        assert_eq!(allocate(250, &tracks), vec![Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn test_allocate_pauses_when_starved() {
+        let tracks = vec![vec![100, 500], vec![100, 500]];
+        assert_eq!(allocate(150, &tracks), vec![Some(0), None]);
+        assert_eq!(allocate(0, &tracks), vec![None, None]);
+    }
+
+    #[test]
+    fn test_layers_up_to_max() {
+        let q = Some(Rid::from("q"));
+        let h = Some(Rid::from("h"));
+        let f = Some(Rid::from("f"));
+        let bitrates = LayerBitrates(vec![(q, 100), (h, 0), (f, 1500)]);
+
+        assert_eq!(bitrates.up_to(q), vec![(q, 100)]);
+        assert_eq!(bitrates.up_to(f), vec![(q, 100), (f, 1500)]);
+        assert!(bitrates.is_measured());
+        assert!(!LayerBitrates(vec![(None, 0)]).is_measured());
+    }
+}
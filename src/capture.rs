@@ -0,0 +1,399 @@
+//! On-demand packet capture of a single participant for debugging frozen media. The
+//! datagrams are written as they are on the wire, SRTP is not decrypted, to a pcapng file.
+//! Each one gets a synthesized IP and UDP header, Wireshark decodes them with "Decode As
+//! RTP". ICE-TCP frames are written as UDP too.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+
+use crate::{
+    actor::{Actor, ActorError},
+    entity::ParticipantId,
+    telemetry,
+};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+// Packets start with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u16 = 101;
+const OPTION_EPB_FLAGS: u16 = 2;
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+/// Limits asked for through the admin API, capped by the configured ones.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+pub struct CaptureRequest {
+    pub max_bytes: Option<u64>,
+    pub duration_secs: Option<u64>,
+}
+
+/// A capture stops at whichever limit is reached first.
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureLimits {
+    pub max_bytes: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug)]
+pub struct CapturedPacket {
+    direction: CaptureDirection,
+    src: SocketAddr,
+    dst: SocketAddr,
+    timestamp: SystemTime,
+    raw: Bytes,
+}
+
+pub struct CaptureActor {
+    participant_id: Arc<ParticipantId>,
+    path: PathBuf,
+    limits: CaptureLimits,
+    receiver: mpsc::Receiver<CapturedPacket>,
+}
+
+impl Actor for CaptureActor {
+    type ID = Arc<ParticipantId>;
+
+    fn kind(&self) -> &'static str {
+        "capture"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.participant_id.clone()
+    }
+
+    async fn run(&mut self) -> Result<(), ActorError> {
+        let res = self.capture().await;
+        // The participant stops sending once closed
+        self.receiver.close();
+        res.map_err(|err| ActorError::LogicError(format!("capture has failed: {err}")))
+    }
+}
+
+impl CaptureActor {
+    async fn capture(&mut self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut writer = BufWriter::new(File::create(&self.path).await?);
+        let mut written = 0;
+        for block in [section_header_block(), interface_description_block()] {
+            writer.write_all(&block).await?;
+            written += block.len() as u64;
+        }
+
+        tracing::info!(path = %self.path.display(), limits = ?self.limits, "capture has started");
+        let deadline = Instant::now() + self.limits.duration;
+        let mut packets = 0u64;
+        loop {
+            let packet = match tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                Ok(Some(packet)) => packet,
+                // The participant has left or started another capture
+                Ok(None) => break,
+                Err(_) => {
+                    tracing::info!("capture duration is reached");
+                    break;
+                }
+            };
+
+            let block = enhanced_packet_block(&packet);
+            if written + block.len() as u64 > self.limits.max_bytes {
+                tracing::info!("capture size limit is reached");
+                break;
+            }
+            writer.write_all(&block).await?;
+            written += block.len() as u64;
+            packets += 1;
+        }
+
+        writer.flush().await?;
+        tracing::info!(path = %self.path.display(), packets, bytes = written, "capture has finished");
+        Ok(())
+    }
+}
+
+fn section_header_block() -> Vec<u8> {
+    let mut block = Vec::with_capacity(28);
+    block.extend_from_slice(&BLOCK_SECTION_HEADER.to_le_bytes());
+    block.extend_from_slice(&28u32.to_le_bytes());
+    block.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    block.extend_from_slice(&1u16.to_le_bytes());
+    block.extend_from_slice(&0u16.to_le_bytes());
+    // Section length is unknown
+    block.extend_from_slice(&(-1i64).to_le_bytes());
+    block.extend_from_slice(&28u32.to_le_bytes());
+    block
+}
+
+/// Single interface with the default microsecond timestamps and no snap length.
+fn interface_description_block() -> Vec<u8> {
+    let mut block = Vec::with_capacity(20);
+    block.extend_from_slice(&BLOCK_INTERFACE_DESCRIPTION.to_le_bytes());
+    block.extend_from_slice(&20u32.to_le_bytes());
+    block.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    block.extend_from_slice(&0u16.to_le_bytes());
+    block.extend_from_slice(&0u32.to_le_bytes());
+    block.extend_from_slice(&20u32.to_le_bytes());
+    block
+}
+
+fn enhanced_packet_block(packet: &CapturedPacket) -> Vec<u8> {
+    let data = ip_udp_packet(packet.src, packet.dst, &packet.raw);
+    let padded = data.len().next_multiple_of(4);
+    // Fixed fields, the packet data and the epb_flags option with its end marker
+    let total = (32 + padded + 12) as u32;
+    let micros = packet
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let flags: u32 = match packet.direction {
+        CaptureDirection::Inbound => 0b01,
+        CaptureDirection::Outbound => 0b10,
+    };
+
+    let mut block = Vec::with_capacity(total as usize);
+    block.extend_from_slice(&BLOCK_ENHANCED_PACKET.to_le_bytes());
+    block.extend_from_slice(&total.to_le_bytes());
+    block.extend_from_slice(&0u32.to_le_bytes());
+    block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    block.extend_from_slice(&(micros as u32).to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    block.extend_from_slice(&data);
+    block.resize(block.len() + padded - data.len(), 0);
+    block.extend_from_slice(&OPTION_EPB_FLAGS.to_le_bytes());
+    block.extend_from_slice(&4u16.to_le_bytes());
+    block.extend_from_slice(&flags.to_le_bytes());
+    block.extend_from_slice(&[0; 4]);
+    block.extend_from_slice(&total.to_le_bytes());
+    block
+}
+
+/// Wraps `payload` in an IP and UDP header. Mixed families are written as IPv6 with
+/// IPv4-mapped addresses.
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0; 2]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = Vec::with_capacity(20 + udp.len());
+            header.extend_from_slice(&[0x45, 0]);
+            header.extend_from_slice(&udp_len.saturating_add(20).to_be_bytes());
+            // No fragmentation
+            header.extend_from_slice(&[0, 0, 0x40, 0, TTL, IPPROTO_UDP, 0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let checksum = internet_checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let pseudo = [
+                &src.octets()[..],
+                &dst.octets()[..],
+                &[0, IPPROTO_UDP],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat();
+            set_udp_checksum(&mut udp, &pseudo);
+            header
+        }
+        (src, dst) => {
+            let src = to_ipv6(src).octets();
+            let dst = to_ipv6(dst).octets();
+            let mut header = Vec::with_capacity(40 + udp.len());
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            header.extend_from_slice(&[IPPROTO_UDP, TTL]);
+            header.extend_from_slice(&src);
+            header.extend_from_slice(&dst);
+
+            let pseudo = [
+                &src[..],
+                &dst[..],
+                &(udp_len as u32).to_be_bytes(),
+                &[0, 0, 0, IPPROTO_UDP],
+            ]
+            .concat();
+            set_udp_checksum(&mut udp, &pseudo);
+            header
+        }
+    };
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let checksum = match internet_checksum(&[pseudo_header, udp]) {
+        // Zero means no checksum in UDP
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// RFC 1071 checksum over the concatenation of `parts`, each part must have an even
+/// length except the last.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            let word = match chunk {
+                [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+                [hi] => u16::from_be_bytes([*hi, 0]),
+                _ => unreachable!(),
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureHandle {
+    sender: mpsc::Sender<CapturedPacket>,
+}
+
+impl CaptureHandle {
+    pub fn new(
+        participant_id: Arc<ParticipantId>,
+        path: PathBuf,
+        limits: CaptureLimits,
+        capacity: usize,
+    ) -> (Self, CaptureActor) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let actor = CaptureActor {
+            participant_id,
+            path,
+            limits,
+            receiver,
+        };
+        (Self { sender }, actor)
+    }
+
+    /// Returns `false` once the capture has finished. Packets are dropped rather than
+    /// slowing down the participant when the disk can't keep up.
+    pub fn record(
+        &self,
+        direction: CaptureDirection,
+        src: SocketAddr,
+        dst: SocketAddr,
+        raw: Bytes,
+    ) -> bool {
+        let packet = CapturedPacket {
+            direction,
+            src,
+            dst,
+            timestamp: SystemTime::now(),
+            raw,
+        };
+        match self.sender.try_send(packet) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                telemetry::record_dropped("participant_capture");
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enhanced_packet_block_is_padded() {
+        let packet = CapturedPacket {
+            direction: CaptureDirection::Outbound,
+            src: "10.0.0.1:3478".parse().unwrap(),
+            dst: "192.168.1.2:50000".parse().unwrap(),
+            timestamp: UNIX_EPOCH + Duration::from_micros(0x1_0000_0002),
+            raw: Bytes::from_static(&[0x80, 0x60, 0x00, 0x01, 0xaa]),
+        };
+        let block = enhanced_packet_block(&packet);
+
+        // 20 bytes of IPv4, 8 of UDP and 5 of payload, padded to 36
+        assert_eq!(block.len(), 32 + 36 + 12);
+        assert_eq!(block.len() % 4, 0);
+        let total = u32::from_le_bytes(block[4..8].try_into().unwrap());
+        assert_eq!(total as usize, block.len());
+        assert_eq!(block[block.len() - 4..], block[4..8]);
+        assert_eq!(u32::from_le_bytes(block[12..16].try_into().unwrap()), 1);
+        assert_eq!(u32::from_le_bytes(block[16..20].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(block[20..24].try_into().unwrap()), 33);
+        // Outbound flag
+        assert_eq!(block[28 + 36 + 4], 0b10);
+    }
+
+    #[test]
+    fn test_ip_udp_checksums() {
+        let payload = [0x80, 0x60, 0x12, 0x34, 0x56];
+        let packet = ip_udp_packet(
+            "10.0.0.1:3478".parse().unwrap(),
+            "192.168.1.2:50000".parse().unwrap(),
+            &payload,
+        );
+        assert_eq!(packet.len(), 20 + 8 + payload.len());
+        assert_eq!(internet_checksum(&[&packet[..20]]), 0);
+        let pseudo = [&packet[12..20], &[0, IPPROTO_UDP], &packet[24..26]].concat();
+        assert_eq!(internet_checksum(&[&pseudo, &packet[20..]]), 0);
+        assert_eq!(&packet[28..], &payload);
+
+        let packet = ip_udp_packet(
+            "[2001:db8::1]:3478".parse().unwrap(),
+            "10.0.0.2:50000".parse().unwrap(),
+            &payload,
+        );
+        assert_eq!(packet.len(), 40 + 8 + payload.len());
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(
+            &packet[24..40],
+            &"::ffff:10.0.0.2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+        let pseudo = [
+            &packet[8..40],
+            &(8 + payload.len() as u32).to_be_bytes(),
+            &[0, 0, 0, IPPROTO_UDP],
+        ]
+        .concat();
+        assert_eq!(internet_checksum(&[&pseudo, &packet[40..]]), 0);
+    }
+}
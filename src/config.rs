@@ -0,0 +1,1002 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Serializer;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    auth::{ApiKey, ApiKeyMaterial, Authenticator},
+    bwe::INITIAL_EGRESS_BITRATE_KBPS,
+    capture::{CaptureLimits, CaptureRequest},
+    entity::EntityId,
+    net,
+};
+
+/// Nested settings are read from `PULSEBEAM__<SECTION>__<KEY>`, e.g.
+/// `PULSEBEAM__MEDIA__ENABLE_H264=true`. The double underscore keeps them apart from the
+/// `PULSEBEAM_*` variables of the command line flags.
+const ENV_PREFIX: &str = "PULSEBEAM";
+const ENV_SEPARATOR: &str = "__";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to load config: {0}")]
+    Load(#[from] config::ConfigError),
+
+    #[error("config is invalid: {0}")]
+    Invalid(String),
+
+    #[error("failed to bind {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// Server configuration. Defaults are overridden by the TOML file, then by the
+/// environment and last by the command line.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub network: NetworkConfig,
+    pub turn: TurnConfig,
+    pub signaling: SignalingConfig,
+    pub metrics: MetricsConfig,
+    pub shutdown: ShutdownConfig,
+    pub recording: RecordingConfig,
+    pub capture: CaptureConfig,
+    pub media: MediaConfig,
+    pub rtx: RtxConfig,
+    pub limits: RoomLimits,
+    pub channels: ChannelCapacities,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Local address of the UDP media sockets. An unspecified IP listens on every interface.
+    pub udp_bind: SocketAddr,
+    /// Also listen for media over IPv6 on the same port, for dual-stack host candidates.
+    pub enable_ipv6: bool,
+    /// Announced in the host candidates instead of the interface addresses, e.g. the
+    /// external IP of a 1:1 NAT.
+    pub public_ips: Vec<IpAddr>,
+    /// Number of UDP sockets sharing the media port with SO_REUSEPORT. Defaults to the
+    /// number of cores.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp_shards: Option<usize>,
+    /// Accept ICE-TCP for clients behind firewalls that block UDP.
+    pub enable_tcp: bool,
+    /// Local address of the ICE-TCP listener, ports like 443 pass most firewalls.
+    pub tcp_bind: SocketAddr,
+    /// Open ICE-TCP connections per listener, further connections are refused.
+    pub max_tcp_connections: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            udp_bind: SocketAddr::from(([0, 0, 0, 0], 3478)),
+            enable_ipv6: false,
+            public_ips: Vec::new(),
+            udp_shards: None,
+            enable_tcp: true,
+            tcp_bind: SocketAddr::from(([0, 0, 0, 0], 3478)),
+            max_tcp_connections: 4096,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn udp_binds(&self) -> Vec<SocketAddr> {
+        self.dual_stack(self.udp_bind)
+    }
+
+    pub fn tcp_binds(&self) -> Vec<SocketAddr> {
+        if self.enable_tcp {
+            self.dual_stack(self.tcp_bind)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// `bind`, plus its IPv6 counterpart when IPv6 is enabled.
+    pub fn dual_stack(&self, bind: SocketAddr) -> Vec<SocketAddr> {
+        let mut binds = vec![bind];
+        if self.enable_ipv6 && bind.is_ipv4() {
+            let ip = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+            binds.push(SocketAddr::new(ip, bind.port()));
+        }
+        binds
+    }
+
+    /// Addresses to announce for a socket bound to `bind`, empty when no usable
+    /// interface is found.
+    pub fn advertised_addrs(&self, bind: SocketAddr) -> Vec<SocketAddr> {
+        let public: Vec<IpAddr> = self
+            .public_ips
+            .iter()
+            .filter(|ip| ip.is_ipv6() == bind.is_ipv6())
+            .copied()
+            .collect();
+
+        let ips = if !public.is_empty() {
+            public
+        } else if !bind.ip().is_unspecified() {
+            vec![bind.ip()]
+        } else {
+            net::select_host_addresses(bind.is_ipv6())
+        };
+
+        ips.into_iter()
+            .map(|ip| SocketAddr::new(ip, bind.port()))
+            .collect()
+    }
+
+    pub fn shards(&self) -> usize {
+        if cfg!(unix) {
+            self.udp_shards
+                .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+                .unwrap_or(1)
+        } else {
+            // SO_REUSEPORT is unavailable, a second bind would fail
+            1
+        }
+    }
+}
+
+/// Embedded TURN server for clients that can't reach the media port directly. It relays
+/// to the SFU only, credentials are derived from the API secrets.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TurnConfig {
+    pub enabled: bool,
+    /// Local address of the TURN UDP socket, announced with the network.public_ips.
+    pub udp_bind: SocketAddr,
+    pub enable_tcp: bool,
+    pub tcp_bind: SocketAddr,
+    /// Local address of TURN over TLS, only served with a certificate and key.
+    pub tls_bind: SocketAddr,
+    /// PEM certificate chain for TURN over TLS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for TURN over TLS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    /// Domain name of the certificate, announced in the turns: URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    pub realm: String,
+    /// Validity of the credentials handed out with a session.
+    pub credential_ttl_secs: u64,
+    pub max_allocations: usize,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            udp_bind: SocketAddr::from(([0, 0, 0, 0], 3479)),
+            enable_tcp: true,
+            tcp_bind: SocketAddr::from(([0, 0, 0, 0], 3479)),
+            tls_bind: SocketAddr::from(([0, 0, 0, 0], 5349)),
+            tls_cert: None,
+            tls_key: None,
+            domain: None,
+            realm: "pulsebeam".to_string(),
+            credential_ttl_secs: 3600,
+            max_allocations: 4096,
+        }
+    }
+}
+
+impl TurnConfig {
+    pub fn is_tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
+    fn validate(&self, signaling: &SignalingConfig) -> Result<(), ConfigError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        if !signaling.api_keys.iter().any(|key| key.secret.is_some()) {
+            return Err(ConfigError::Invalid(
+                "turn requires an api key with a secret to derive credentials".to_string(),
+            ));
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(ConfigError::Invalid(
+                "turn.tls_cert and turn.tls_key must be set together".to_string(),
+            ));
+        }
+        if self.is_tls_enabled() && self.domain.is_none() {
+            return Err(ConfigError::Invalid(
+                "turn over tls requires turn.domain".to_string(),
+            ));
+        }
+        if self.credential_ttl_secs == 0 || self.max_allocations == 0 {
+            return Err(ConfigError::Invalid(
+                "turn.credential_ttl_secs and turn.max_allocations must be greater than 0"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalingConfig {
+    /// Address of the HTTP signaling and admin API.
+    pub http_addr: SocketAddr,
+    /// Keys that join tokens are verified against. Authentication is disabled without any.
+    pub api_keys: Vec<ApiKeyConfig>,
+}
+
+impl Default for SignalingConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            api_keys: Vec::new(),
+        }
+    }
+}
+
+impl SignalingConfig {
+    pub fn api_keys(&self) -> Result<Vec<ApiKey>, ConfigError> {
+        self.api_keys.iter().map(ApiKeyConfig::to_api_key).collect()
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Address of the Prometheus scrape endpoint, served on `/metrics`.
+    pub http_addr: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            http_addr: SocketAddr::from(([0, 0, 0, 0], 9464)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// After SIGTERM or SIGINT, rooms get this long to empty out before the remaining
+    /// participants are disconnected.
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Recordings are written to `{dir}/{room_id}/`, started from the admin API.
+    pub dir: PathBuf,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("recordings"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Packet captures are written to `{dir}/{room_id}/`, started from the admin API.
+    pub dir: PathBuf,
+    /// Upper bound of a single capture file, requests can only lower it.
+    pub max_bytes: u64,
+    /// Upper bound of a single capture, requests can only lower it.
+    pub max_duration_secs: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("captures"),
+            max_bytes: 50 * 1024 * 1024,
+            max_duration_secs: 300,
+        }
+    }
+}
+
+impl CaptureConfig {
+    /// Requested limits, capped by the configured ones.
+    pub fn limits(&self, req: &CaptureRequest) -> CaptureLimits {
+        let max_bytes = req
+            .max_bytes
+            .map_or(self.max_bytes, |max| max.min(self.max_bytes));
+        let duration_secs = req.duration_secs.map_or(self.max_duration_secs, |secs| {
+            secs.min(self.max_duration_secs)
+        });
+        CaptureLimits {
+            max_bytes,
+            duration: Duration::from_secs(duration_secs),
+        }
+    }
+}
+
+/// Either `secret` or `public_key` must be set.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    pub id: EntityId,
+    pub project_id: EntityId,
+    /// Shared secret (sk_...) for HS256 signed join tokens.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "redact"
+    )]
+    pub secret: Option<String>,
+    /// Ed25519 public key (pk_...) for EdDSA signed join tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+impl ApiKeyConfig {
+    fn to_api_key(&self) -> Result<ApiKey, ConfigError> {
+        let material = match (&self.secret, &self.public_key) {
+            (Some(secret), None) => ApiKeyMaterial::Secret(secret.clone()),
+            (None, Some(public_key)) => ApiKeyMaterial::PublicKey(public_key.clone()),
+            _ => {
+                return Err(ConfigError::Invalid(format!(
+                    "api key {} needs either a secret or a public key",
+                    self.id
+                )));
+            }
+        };
+
+        Ok(ApiKey {
+            id: self.id.clone(),
+            project_id: self.project_id.clone(),
+            material,
+        })
+    }
+}
+
+fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    pub enable_vp8: bool,
+    pub enable_vp9: bool,
+    pub enable_h264: bool,
+    /// Starting point of the downlink estimate of every subscriber.
+    pub initial_egress_bitrate_kbps: u64,
+    /// Frames kept per simulcast layer since its latest keyframe to prime new
+    /// subscribers, 0 disables the cache. The publisher's keyframe interval has to fit,
+    /// longer groups aren't cached and new subscribers wait for the next keyframe. The
+    /// default covers 5 seconds at 30 fps, `channels.participant_data` must hold as many.
+    pub keyframe_cache_frames: usize,
+    /// Bytes kept per simulcast layer, a group that grows larger isn't cached either.
+    pub keyframe_cache_bytes: usize,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            enable_vp8: true,
+            enable_vp9: false,
+            enable_h264: false,
+            initial_egress_bitrate_kbps: INITIAL_EGRESS_BITRATE_KBPS,
+            keyframe_cache_frames: 150,
+            keyframe_cache_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Loss recovery on both legs of the SFU. Packets lost towards a subscriber are resent
+/// from the send buffer of its stream when it NACKs them. Packets lost from a publisher
+/// are NACKed while the reorder window holds back the frames behind the gap.
+///
+/// Both legs are handled by each session on its own, the SFU keeps no packet history per
+/// track and doesn't forward subscriber NACKs to the publisher. Only the buffer sizes and
+/// the loss counters are exposed.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtxConfig {
+    /// Packets kept per outgoing video stream to answer NACKs.
+    pub send_buffer_video: usize,
+    /// Packets kept per outgoing audio stream to answer NACKs.
+    pub send_buffer_audio: usize,
+    /// Packets an incoming video stream waits for a retransmission before skipping
+    /// the loss. Larger windows recover more but add latency.
+    pub reorder_window_video: usize,
+    /// Packets an incoming audio stream waits for a retransmission.
+    pub reorder_window_audio: usize,
+    /// How often the stream statistics of every session are refreshed.
+    pub stats_interval_secs: u64,
+}
+
+impl Default for RtxConfig {
+    fn default() -> Self {
+        Self {
+            send_buffer_video: 1000,
+            send_buffer_audio: 50,
+            reorder_window_video: 30,
+            reorder_window_audio: 15,
+            stats_interval_secs: 5,
+        }
+    }
+}
+
+impl RtxConfig {
+    pub fn stats_interval(&self) -> Duration {
+        Duration::from_secs(self.stats_interval_secs)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let values = [
+            ("send_buffer_video", self.send_buffer_video as u64),
+            ("send_buffer_audio", self.send_buffer_audio as u64),
+            ("reorder_window_video", self.reorder_window_video as u64),
+            ("reorder_window_audio", self.reorder_window_audio as u64),
+            ("stats_interval_secs", self.stats_interval_secs),
+        ];
+        for (name, value) in values {
+            if value == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "rtx.{name} must be greater than 0"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Joins beyond a limit are rejected, unset means unlimited.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rooms: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_participants_per_room: Option<usize>,
+    /// Larger data channel messages are dropped instead of forwarded.
+    pub max_data_message_size: usize,
+    /// SFU -> client transceivers per media kind that renegotiation may add to a session.
+    pub max_downstream_slots_per_kind: usize,
+}
+
+impl Default for RoomLimits {
+    fn default() -> Self {
+        Self {
+            max_rooms: None,
+            max_participants_per_room: None,
+            max_data_message_size: 64 * 1024,
+            max_downstream_slots_per_kind: 16,
+        }
+    }
+}
+
+/// Capacities of the actor mailboxes. Data channels absorb media bursts, packets are
+/// dropped once they are full. Control channels apply back pressure to the sender,
+/// except for the room, which drops what a participant can't take and resyncs it later.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelCapacities {
+    pub controller: usize,
+    pub room: usize,
+    pub participant_data: usize,
+    pub participant_control: usize,
+    pub track_data: usize,
+    pub track_control: usize,
+    pub udp_source: usize,
+    pub udp_sink: usize,
+    pub tcp: usize,
+    /// Egress queue of a single ICE-TCP connection.
+    pub tcp_connection: usize,
+    pub turn: usize,
+}
+
+impl Default for ChannelCapacities {
+    fn default() -> Self {
+        Self {
+            controller: 1,
+            room: 8,
+            participant_data: 256,
+            participant_control: 8,
+            track_data: 64,
+            track_control: 8,
+            udp_source: 1,
+            udp_sink: 2048,
+            tcp: 1024,
+            tcp_connection: 256,
+            turn: 1024,
+        }
+    }
+}
+
+impl ChannelCapacities {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let capacities = [
+            ("controller", self.controller),
+            ("room", self.room),
+            ("participant_data", self.participant_data),
+            ("participant_control", self.participant_control),
+            ("track_data", self.track_data),
+            ("track_control", self.track_control),
+            ("udp_source", self.udp_source),
+            ("udp_sink", self.udp_sink),
+            ("tcp", self.tcp),
+            ("tcp_connection", self.tcp_connection),
+            ("turn", self.turn),
+        ];
+        for (name, capacity) in capacities {
+            if capacity == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "channels.{name} must be greater than 0"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Compact,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Filter directives, e.g. "info,pulsebeam=debug". RUST_LOG takes precedence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+}
+
+impl LogConfig {
+    pub fn env_filter(&self) -> EnvFilter {
+        match (&self.filter, std::env::var(EnvFilter::DEFAULT_ENV)) {
+            (Some(filter), Err(_)) => EnvFilter::new(filter),
+            _ => EnvFilter::from_default_env(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads `path` if given, then applies the environment and `overrides`.
+    pub fn load(path: Option<&Path>, overrides: ConfigOverrides) -> Result<Self, ConfigError> {
+        let mut builder = config::Config::builder();
+        if let Some(path) = path {
+            builder = builder.add_source(config::File::from(path).format(config::FileFormat::Toml));
+        }
+
+        let env = config::Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator(ENV_SEPARATOR)
+            .separator(ENV_SEPARATOR)
+            .list_separator(",")
+            .with_list_parse_key("network.public_ips")
+            .try_parsing(true);
+        let mut config: ServerConfig = builder.add_source(env).build()?.try_deserialize()?;
+
+        overrides.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let network = &self.network;
+        if network.udp_shards == Some(0) {
+            return Err(ConfigError::Invalid(
+                "network.udp_shards must be greater than 0".to_string(),
+            ));
+        }
+        if network.max_tcp_connections == 0 {
+            return Err(ConfigError::Invalid(
+                "network.max_tcp_connections must be greater than 0".to_string(),
+            ));
+        }
+        let mut binds = vec![("network.udp_bind", network.udp_bind)];
+        if network.enable_tcp {
+            binds.push(("network.tcp_bind", network.tcp_bind));
+        }
+        if self.turn.enabled {
+            binds.push(("turn.udp_bind", self.turn.udp_bind));
+            if self.turn.enable_tcp {
+                binds.push(("turn.tcp_bind", self.turn.tcp_bind));
+            }
+            if self.turn.is_tls_enabled() {
+                binds.push(("turn.tls_bind", self.turn.tls_bind));
+            }
+        }
+        for (name, bind) in binds {
+            if network.enable_ipv6 && bind.is_ipv4() && !bind.ip().is_unspecified() {
+                return Err(ConfigError::Invalid(format!(
+                    "network.enable_ipv6 requires an unspecified {name} address"
+                )));
+            }
+        }
+
+        if self.metrics.enabled && self.metrics.http_addr == self.signaling.http_addr {
+            return Err(ConfigError::Invalid(
+                "metrics.http_addr must differ from signaling.http_addr".to_string(),
+            ));
+        }
+
+        if self.media.initial_egress_bitrate_kbps == 0 {
+            return Err(ConfigError::Invalid(
+                "media.initial_egress_bitrate_kbps must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.media.keyframe_cache_frames > self.channels.participant_data {
+            return Err(ConfigError::Invalid(
+                "media.keyframe_cache_frames must not exceed channels.participant_data, a \
+                 cached group is replayed into the subscriber's mailbox at once"
+                    .to_string(),
+            ));
+        }
+
+        self.rtx.validate()?;
+
+        let limits = &self.limits;
+        if limits.max_rooms == Some(0) || limits.max_participants_per_room == Some(0) {
+            return Err(ConfigError::Invalid(
+                "limits must be greater than 0, leave them unset for no limit".to_string(),
+            ));
+        }
+        if limits.max_data_message_size == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.max_data_message_size must be greater than 0".to_string(),
+            ));
+        }
+        if limits.max_downstream_slots_per_kind == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.max_downstream_slots_per_kind must be greater than 0".to_string(),
+            ));
+        }
+
+        self.channels.validate()?;
+
+        if self.capture.max_bytes == 0 || self.capture.max_duration_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "capture.max_bytes and capture.max_duration_secs must be greater than 0"
+                    .to_string(),
+            ));
+        }
+
+        if let Some(filter) = &self.log.filter {
+            EnvFilter::try_new(filter)
+                .map_err(|err| ConfigError::Invalid(format!("log.filter: {err}")))?;
+        }
+
+        Authenticator::new(self.signaling.api_keys()?)
+            .map_err(|err| ConfigError::Invalid(err.to_string()))?;
+        self.turn.validate(&self.signaling)?;
+        Ok(())
+    }
+
+    /// Effective configuration with secrets redacted.
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string_pretty(self)
+    }
+}
+
+/// Command line flags, each can also be set through its environment variable. They take
+/// precedence over the config file.
+#[derive(clap::Args, Debug, Default)]
+pub struct ConfigOverrides {
+    /// API key ID used to verify join tokens, added to the keys of the config file.
+    #[arg(
+        long,
+        env = "PULSEBEAM_API_KEY_ID",
+        requires = "project_id",
+        global = true
+    )]
+    pub api_key_id: Option<String>,
+
+    /// API secret (sk_...) for HS256 signed join tokens.
+    #[arg(
+        long,
+        env = "PULSEBEAM_API_SECRET",
+        hide_env_values = true,
+        global = true
+    )]
+    pub api_secret: Option<String>,
+
+    /// API public key (pk_...) for EdDSA signed join tokens.
+    #[arg(
+        long,
+        env = "PULSEBEAM_API_PUBLIC_KEY",
+        conflicts_with = "api_secret",
+        global = true
+    )]
+    pub api_public_key: Option<String>,
+
+    /// Project ID that join tokens must be issued for.
+    #[arg(long, env = "PULSEBEAM_PROJECT_ID", global = true)]
+    pub project_id: Option<String>,
+
+    /// Number of UDP sockets sharing the media port with SO_REUSEPORT, each served by its
+    /// own source and sink. Defaults to the number of cores.
+    #[arg(long, env = "PULSEBEAM_UDP_SHARDS", global = true)]
+    pub udp_shards: Option<usize>,
+
+    /// Local address of the UDP media sockets [default: 0.0.0.0:3478]
+    #[arg(long, env = "PULSEBEAM_UDP_BIND", global = true)]
+    pub udp_bind: Option<SocketAddr>,
+
+    /// Also listen for media over IPv6 on the same port, for dual-stack host candidates.
+    #[arg(long, env = "PULSEBEAM_ENABLE_IPV6", global = true)]
+    pub enable_ipv6: bool,
+
+    /// IP announced in the host candidates instead of the interface addresses, e.g. the
+    /// external IP of a 1:1 NAT. Accepts one or more addresses per address family.
+    #[arg(
+        long = "public-ip",
+        env = "PULSEBEAM_PUBLIC_IPS",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub public_ips: Vec<IpAddr>,
+
+    /// Local address of the ICE-TCP listener [default: 0.0.0.0:3478]
+    #[arg(long, env = "PULSEBEAM_TCP_BIND", global = true)]
+    pub tcp_bind: Option<SocketAddr>,
+
+    /// Run the embedded TURN server, requires an API secret.
+    #[arg(long, env = "PULSEBEAM_ENABLE_TURN", global = true)]
+    pub enable_turn: bool,
+
+    /// Address of the HTTP signaling and admin API [default: 0.0.0.0:3000]
+    #[arg(long, env = "PULSEBEAM_HTTP_ADDR", global = true)]
+    pub http_addr: Option<SocketAddr>,
+
+    /// Address of the Prometheus metrics endpoint [default: 0.0.0.0:9464]
+    #[arg(long, env = "PULSEBEAM_METRICS_ADDR", global = true)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Log output format [default: pretty]
+    #[arg(long, env = "PULSEBEAM_LOG_FORMAT", global = true)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl ConfigOverrides {
+    pub fn apply(self, config: &mut ServerConfig) {
+        if let (Some(id), Some(project_id)) = (self.api_key_id, self.project_id) {
+            config.signaling.api_keys.push(ApiKeyConfig {
+                id,
+                project_id,
+                secret: self.api_secret,
+                public_key: self.api_public_key,
+            });
+        }
+
+        let network = &mut config.network;
+        if let Some(udp_shards) = self.udp_shards {
+            network.udp_shards = Some(udp_shards);
+        }
+        if let Some(udp_bind) = self.udp_bind {
+            network.udp_bind = udp_bind;
+        }
+        if self.enable_ipv6 {
+            network.enable_ipv6 = true;
+        }
+        if !self.public_ips.is_empty() {
+            network.public_ips = self.public_ips;
+        }
+        if let Some(tcp_bind) = self.tcp_bind {
+            network.tcp_bind = tcp_bind;
+        }
+
+        if self.enable_turn {
+            config.turn.enabled = true;
+        }
+
+        if let Some(http_addr) = self.http_addr {
+            config.signaling.http_addr = http_addr;
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            config.metrics.http_addr = metrics_addr;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log.format = log_format;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_for_missing_sections() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [network]
+            udp_bind = "10.0.0.1:5000"
+
+            [media]
+            enable_h264 = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.network.udp_bind, "10.0.0.1:5000".parse().unwrap());
+        assert!(config.media.enable_vp8);
+        assert!(config.media.enable_h264);
+        assert_eq!(config.channels.udp_sink, 2048);
+        assert_eq!(config.log.format, LogFormat::Pretty);
+        assert_eq!(config.shutdown.drain_timeout(), Duration::from_secs(30));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_reject_unknown_fields() {
+        let res = toml::from_str::<ServerConfig>("[media]\nenable_h265 = true\n");
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_capture_limits_are_capped() {
+        let config = CaptureConfig::default();
+        let limits = config.limits(&CaptureRequest {
+            max_bytes: None,
+            duration_secs: Some(10),
+        });
+        assert_eq!(limits.max_bytes, config.max_bytes);
+        assert_eq!(limits.duration, Duration::from_secs(10));
+
+        let limits = config.limits(&CaptureRequest {
+            max_bytes: Some(u64::MAX),
+            duration_secs: Some(u64::MAX),
+        });
+        assert_eq!(limits.max_bytes, config.max_bytes);
+        assert_eq!(
+            limits.duration,
+            Duration::from_secs(config.max_duration_secs)
+        );
+    }
+
+    #[test]
+    fn test_reject_invalid_values() {
+        let mut config = ServerConfig::default();
+        config.channels.track_data = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = ServerConfig::default();
+        config.limits.max_data_message_size = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = ServerConfig::default();
+        config.limits.max_downstream_slots_per_kind = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = ServerConfig::default();
+        config.network.max_tcp_connections = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = ServerConfig::default();
+        config.capture.max_duration_secs = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = ServerConfig::default();
+        config.media.keyframe_cache_frames = config.channels.participant_data + 1;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = ServerConfig::default();
+        config.rtx.send_buffer_video = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = ServerConfig::default();
+        config.network.udp_bind = "10.0.0.1:3478".parse().unwrap();
+        config.network.enable_ipv6 = true;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.network.udp_bind = "0.0.0.0:3478".parse().unwrap();
+        config.network.tcp_bind = "10.0.0.1:443".parse().unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.network.enable_tcp = false;
+        config.validate().unwrap();
+
+        let mut config = ServerConfig::default();
+        config.turn.enabled = true;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.signaling.api_keys.push(ApiKeyConfig {
+            id: "kid_3mJr7AoUXx2Wqd".to_string(),
+            project_id: "p_6aBfUS7iYUcWJ".to_string(),
+            secret: Some("sk_2NEpo7TZRRrLZSi2U".to_string()),
+            public_key: None,
+        });
+        config.validate().unwrap();
+        config.turn.tls_cert = Some("cert.pem".into());
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.turn.tls_key = Some("key.pem".into());
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.turn.domain = Some("turn.example.com".to_string());
+        config.validate().unwrap();
+
+        let mut config = ServerConfig::default();
+        config.signaling.api_keys.push(ApiKeyConfig {
+            id: "kid_3mJr7AoUXx2Wqd".to_string(),
+            project_id: "p_6aBfUS7iYUcWJ".to_string(),
+            secret: None,
+            public_key: None,
+        });
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_overrides_take_precedence() {
+        let mut config = ServerConfig::default();
+        config.network.public_ips = vec!["1.1.1.1".parse().unwrap()];
+
+        ConfigOverrides {
+            udp_bind: Some("0.0.0.0:4000".parse().unwrap()),
+            log_format: Some(LogFormat::Json),
+            ..Default::default()
+        }
+        .apply(&mut config);
+
+        assert_eq!(config.network.udp_bind.port(), 4000);
+        assert_eq!(config.network.public_ips.len(), 1);
+        assert_eq!(config.log.format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_print_redacts_secrets() {
+        let mut config = ServerConfig::default();
+        config.signaling.api_keys.push(ApiKeyConfig {
+            id: "kid_3mJr7AoUXx2Wqd".to_string(),
+            project_id: "p_6aBfUS7iYUcWJ".to_string(),
+            secret: Some("sk_2NEpo7TZRRrLZSi2U".to_string()),
+            public_key: None,
+        });
+
+        let printed = config.to_toml().unwrap();
+        assert!(printed.contains("<redacted>"));
+        assert!(!printed.contains("sk_2NEpo7TZRRrLZSi2U"));
+    }
+}
@@ -1,20 +1,28 @@
-use std::{collections::HashMap, io, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, io, path::PathBuf, sync::Arc, time::Duration};
 
 use crate::{
-    actor::{self, Actor, ActorError},
-    entity::{ExternalParticipantId, ExternalRoomId, ParticipantId, RoomId},
-    participant::ParticipantHandle,
+    actor::{Actor, ActorError, ActorStatus},
+    capture::CaptureRequest,
+    config::{CaptureConfig, ChannelCapacities, MediaConfig, RoomLimits, RtxConfig, ServerConfig},
+    entity::{
+        EntityId, ExternalParticipantId, ExternalRoomId, ParticipantId, RoomId, new_random_id,
+        prefix,
+    },
+    participant::{
+        IceSession, IceUpdate, ParticipantConfig, ParticipantError, ParticipantHandle, Transport,
+    },
     rng::Rng,
-    room::RoomHandle,
-    sink::UdpSinkHandle,
-    source::UdpSourceHandle,
+    room::{RoomError, RoomHandle, RoomInfo},
+    supervisor::ChildSet,
 };
-use str0m::{Candidate, Rtc, RtcError, change::SdpOffer, error::SdpError};
-use tokio::{
-    sync::{mpsc, oneshot},
-    task::JoinSet,
-};
-use tracing::Instrument;
+use rand::RngCore;
+use str0m::{Candidate, Rtc, RtcError, bwe::Bitrate, change::SdpOffer, error::SdpError};
+use tokio::sync::{mpsc, oneshot};
+
+/// Prefix of the generated IDs of viewers that don't name themselves.
+const ANONYMOUS_PARTICIPANT: &str = "viewer";
+/// Session IDs are bearer secrets when authentication is disabled, 128 bits.
+const SESSION_ID_BYTES: usize = 16;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ControllerError {
@@ -27,6 +35,36 @@ pub enum ControllerError {
     #[error("server is busy, please try again later.")]
     ServiceUnavailable,
 
+    #[error("room is full")]
+    RoomFull,
+
+    #[error("server has reached its room limit")]
+    TooManyRooms,
+
+    #[error("session is not found")]
+    SessionNotFound,
+
+    #[error("session belongs to another participant")]
+    Forbidden,
+
+    #[error("room is not found")]
+    RoomNotFound,
+
+    #[error("participant is not found")]
+    ParticipantNotFound,
+
+    #[error("track is not found")]
+    TrackNotFound,
+
+    #[error("recording is not found")]
+    RecordingNotFound,
+
+    #[error("track codec can't be recorded")]
+    UnsupportedCodec,
+
+    #[error("session update is rejected: {0}")]
+    UpdateRejected(#[from] ParticipantError),
+
     #[error("IO error: {0}")]
     IOError(#[from] io::Error),
 
@@ -36,23 +74,98 @@ pub enum ControllerError {
 
 pub enum ControllerMessage {
     Allocate(
-        ExternalRoomId,
-        ExternalParticipantId,
-        String,
-        oneshot::Sender<Result<String, ControllerError>>,
+        AllocateRequest,
+        oneshot::Sender<Result<Allocation, ControllerError>>,
+    ),
+    UpdateIce(
+        EntityId,
+        Option<SessionOwner>,
+        IceUpdate,
+        oneshot::Sender<Result<IceSession, ControllerError>>,
+    ),
+    DeleteSession(
+        EntityId,
+        Option<SessionOwner>,
+        oneshot::Sender<Result<(), ControllerError>>,
     ),
+    ListRooms(oneshot::Sender<Vec<RoomHandle>>),
+    GetRoom(ExternalRoomId, oneshot::Sender<Option<RoomHandle>>),
+    CloseRoom(ExternalRoomId, oneshot::Sender<Result<(), ControllerError>>),
+    /// Answered once every room has stopped.
+    Drain(Duration, oneshot::Sender<()>),
+}
+
+#[derive(Debug)]
+pub struct AllocateRequest {
+    pub room_id: ExternalRoomId,
+    /// `None` for an anonymous viewer, the controller names it.
+    pub participant_id: Option<ExternalParticipantId>,
+    pub offer: String,
+    pub config: ParticipantConfig,
+}
+
+/// A successfully negotiated participant session.
+#[derive(Debug)]
+pub struct Allocation {
+    /// Identifies the session resource for follow-up requests, e.g. WHIP DELETE. It's
+    /// 128 random bits from the controller's RNG, when authentication is disabled it's
+    /// the only secret needed to update or delete the session.
+    pub session_id: EntityId,
+    /// Changes whenever the ICE session of the participant changes.
+    pub etag: String,
+    pub answer: String,
+}
+
+/// The room and participant a session was created for. When authentication is enabled,
+/// only a token for the same owner may manage the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionOwner {
+    pub room_id: ExternalRoomId,
+    pub participant_id: ExternalParticipantId,
+}
+
+struct Session {
+    owner: SessionOwner,
+    participant: ParticipantHandle,
+}
+
+/// Settings shared by every room and participant.
+#[derive(Debug, Clone, Default)]
+pub struct ControllerConfig {
+    pub media: MediaConfig,
+    pub rtx: RtxConfig,
+    pub limits: RoomLimits,
+    pub channels: ChannelCapacities,
+    pub recording_dir: PathBuf,
+    pub capture: CaptureConfig,
+}
+
+impl From<&ServerConfig> for ControllerConfig {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            media: config.media,
+            rtx: config.rtx,
+            limits: config.limits,
+            channels: config.channels,
+            recording_dir: config.recording.dir.clone(),
+            capture: config.capture.clone(),
+        }
+    }
 }
 
 pub struct ControllerActor {
     rng: Rng,
     id: Arc<String>,
-    source: UdpSourceHandle,
-    sink: UdpSinkHandle,
+    transport: Transport,
     receiver: mpsc::Receiver<ControllerMessage>,
-    local_addrs: Vec<SocketAddr>,
+    candidates: Vec<Candidate>,
+    config: ControllerConfig,
 
     rooms: HashMap<Arc<RoomId>, RoomHandle>,
-    room_tasks: JoinSet<Arc<RoomId>>,
+    room_tasks: ChildSet<Arc<RoomId>>,
+    sessions: HashMap<EntityId, Session>,
+    draining: bool,
+    drained: Vec<oneshot::Sender<()>>,
 }
 
 impl Actor for ControllerActor {
@@ -71,90 +184,280 @@ impl Actor for ControllerActor {
             tokio::select! {
                 Some(msg) = self.receiver.recv() => {
                     match msg {
-                        ControllerMessage::Allocate(room_id, participant_id, offer, resp) => {
+                        ControllerMessage::Allocate(req, resp) => {
+                            let room_id = RoomId::new(req.room_id);
+                            let res = match self.participant_id(req.participant_id) {
+                                Ok(participant_id) => self.allocate(room_id, participant_id, req.offer, req.config).await,
+                                Err(err) => Err(err),
+                            };
+                            let _ = resp.send(res);
+                        }
+                        ControllerMessage::UpdateIce(session_id, owner, update, resp) => {
+                            let _ = resp.send(self.update_ice(&session_id, owner.as_ref(), update).await);
+                        }
+                        ControllerMessage::DeleteSession(session_id, owner, resp) => {
+                            let _ = resp.send(self.delete_session(&session_id, owner.as_ref()).await);
+                        }
+                        ControllerMessage::ListRooms(resp) => {
+                            let _ = resp.send(self.rooms.values().cloned().collect());
+                        }
+                        ControllerMessage::GetRoom(room_id, resp) => {
                             let room_id = RoomId::new(room_id);
-                            let participant_id = ParticipantId::new(&mut self.rng, participant_id);
-                            let _ = resp.send(self.allocate(room_id, participant_id, offer).await);
+                            let _ = resp.send(self.rooms.get(&room_id).cloned());
+                        }
+                        ControllerMessage::CloseRoom(room_id, resp) => {
+                            let _ = resp.send(self.close_room(room_id).await);
+                        }
+                        ControllerMessage::Drain(deadline, resp) => {
+                            self.drain(deadline).await;
+                            self.drained.push(resp);
                         }
                     }
                 }
 
-                Some(Ok(participant_id)) = self.room_tasks.join_next() => {
-                    // TODO: notify participant leaving
-                    self.rooms.remove(&participant_id);
+                Some((room_id, status)) = self.room_tasks.join_next() => {
+                    self.handle_room_exited(room_id, status);
                 }
 
                 else => break,
             }
+
+            if self.draining && self.room_tasks.is_empty() {
+                for resp in self.drained.drain(..) {
+                    let _ = resp.send(());
+                }
+            }
         }
         Ok(())
     }
 }
 
 impl ControllerActor {
+    /// Every anonymous viewer gets its own ID, so that they can be told apart in the room
+    /// and addressed by the admin API.
+    fn participant_id(
+        &mut self,
+        external: Option<ExternalParticipantId>,
+    ) -> Result<ParticipantId, ControllerError> {
+        let external = match external {
+            Some(external) => external,
+            None => {
+                // 48 random bits keep the ID within the length limit
+                let suffix = self.rng.next_u64() >> 16;
+                ExternalParticipantId::new(format!("{ANONYMOUS_PARTICIPANT}-{suffix:012x}"))
+                    .map_err(|err| ControllerError::Unknown(err.to_string()))?
+            }
+        };
+        Ok(ParticipantId::new(&mut self.rng, external))
+    }
+
     pub async fn allocate(
         &mut self,
         room_id: RoomId,
         participant_id: ParticipantId,
         offer: String,
-    ) -> Result<String, ControllerError> {
+        mut config: ParticipantConfig,
+    ) -> Result<Allocation, ControllerError> {
+        if self.draining {
+            return Err(ControllerError::ServiceUnavailable);
+        }
+
         let offer = SdpOffer::from_sdp_string(&offer)?;
+        let media = &self.config.media;
+        let rtx = self.config.rtx;
+        config.rtx = rtx;
+        config.media = *media;
+        config.limits = self.config.limits;
         let mut rtc = Rtc::builder()
-            // Uncomment this to see statistics
-            // .set_stats_interval(Some(Duration::from_secs(1)))
+            .set_stats_interval(Some(rtx.stats_interval()))
+            .set_send_buffer_video(rtx.send_buffer_video)
+            .set_send_buffer_audio(rtx.send_buffer_audio)
+            .set_reordering_size_video(rtx.reorder_window_video)
+            .set_reordering_size_audio(rtx.reorder_window_audio)
             .set_ice_lite(true)
-            .enable_vp9(false)
-            .enable_h264(false)
+            // TWCC feedback from subscribers drives the simulcast layer allocation
+            .enable_bwe(Some(Bitrate::kbps(media.initial_egress_bitrate_kbps)))
+            .enable_vp8(media.enable_vp8)
+            .enable_vp9(media.enable_vp9)
+            .enable_h264(media.enable_h264)
             .build();
 
-        for addr in self.local_addrs.iter() {
-            // TODO: add tcp and ssltcp later
-            let candidate = Candidate::host(*addr, "udp").expect("a host candidate");
-            rtc.add_local_candidate(candidate);
+        for candidate in self.candidates.iter() {
+            rtc.add_local_candidate(candidate.clone());
         }
 
         let answer = rtc
             .sdp_api()
             .accept_offer(offer)
             .map_err(ControllerError::OfferRejected)?;
+        let etag = rtc.direct_api().local_ice_credentials().ufrag;
 
+        let owner = SessionOwner {
+            room_id: room_id.external.clone(),
+            participant_id: participant_id.external.clone(),
+        };
         let room_id = Arc::new(room_id);
-        let room_handle = self.get_or_create_room(room_id);
+        let room_handle = self.get_or_create_room(room_id)?;
         let participant = ParticipantHandle::new(
             self.rng.clone(),
-            self.source.clone(),
-            self.sink.clone(),
+            self.transport.clone(),
             room_handle.clone(),
             Arc::new(participant_id),
             rtc,
+            config,
+            self.config.channels,
         );
 
+        // Not the internal participant ID, that one ends up in logs and recordings
+        let session_id = new_random_id(&mut self.rng, prefix::SESSION_ID, SESSION_ID_BYTES);
+        let session = Session {
+            owner,
+            participant: participant.0.clone(),
+        };
+
         // TODO: probably retry? Or, let the client to retry instead?
         // Each room will always have a graceful timeout before closing.
         // But, a data race can still occur nonetheless
         room_handle
             .add_participant(participant.0, participant.1)
             .await
-            .map_err(|_| ControllerError::ServiceUnavailable)?;
+            .map_err(|err| match err {
+                RoomError::Full => ControllerError::RoomFull,
+                RoomError::Closed => ControllerError::ServiceUnavailable,
+                err => ControllerError::Unknown(err.to_string()),
+            })?;
+
+        // Sessions are only pruned lazily, participants can leave without going through
+        // the controller.
+        self.sessions
+            .retain(|_, session| !session.participant.control_sender.is_closed());
+        self.sessions.insert(session_id.clone(), session);
+
+        Ok(Allocation {
+            session_id,
+            etag,
+            answer: answer.to_sdp_string(),
+        })
+    }
+
+    fn handle_room_exited(&mut self, room_id: Arc<RoomId>, status: ActorStatus) {
+        // A closed room may have been replaced by a new room with the same id
+        if self
+            .rooms
+            .get(&room_id)
+            .is_some_and(|room| room.sender.is_closed())
+        {
+            self.rooms.remove(&room_id);
+        }
+
+        // A failed room takes its participants down without them leaving on their own
+        if status.is_failure() {
+            self.sessions
+                .retain(|_, session| !session.participant.control_sender.is_closed());
+        }
+    }
+
+    pub async fn update_ice(
+        &mut self,
+        session_id: &EntityId,
+        owner: Option<&SessionOwner>,
+        update: IceUpdate,
+    ) -> Result<IceSession, ControllerError> {
+        let session = self.session(session_id, owner)?;
+        let res = session.participant.update_ice(update).await;
+
+        match res {
+            Err(ParticipantError::Gone) => {
+                self.sessions.remove(session_id);
+                Err(ControllerError::SessionNotFound)
+            }
+            res => Ok(res?),
+        }
+    }
+
+    pub async fn delete_session(
+        &mut self,
+        session_id: &EntityId,
+        owner: Option<&SessionOwner>,
+    ) -> Result<(), ControllerError> {
+        self.session(session_id, owner)?;
+        let Some(session) = self.sessions.remove(session_id) else {
+            return Err(ControllerError::SessionNotFound);
+        };
 
-        Ok(answer.to_sdp_string())
+        session
+            .participant
+            .disconnect()
+            .await
+            .map_err(|_| ControllerError::SessionNotFound)
     }
 
-    fn get_or_create_room(&mut self, room_id: Arc<RoomId>) -> RoomHandle {
+    /// Looks up a session, `owner` is `None` when authentication is disabled. Session IDs
+    /// can't be guessed, see [`Allocation::session_id`], so knowing one is enough then.
+    fn session(
+        &self,
+        session_id: &EntityId,
+        owner: Option<&SessionOwner>,
+    ) -> Result<&Session, ControllerError> {
+        let Some(session) = self.sessions.get(session_id) else {
+            return Err(ControllerError::SessionNotFound);
+        };
+
+        if owner.is_some_and(|owner| *owner != session.owner) {
+            return Err(ControllerError::Forbidden);
+        }
+        Ok(session)
+    }
+
+    /// Disconnects everyone in the room. The room is forgotten right away, so joining
+    /// the same room afterwards starts a new one.
+    pub async fn close_room(&mut self, room_id: ExternalRoomId) -> Result<(), ControllerError> {
+        let room_id = RoomId::new(room_id);
+        let Some(room) = self.rooms.remove(&room_id) else {
+            return Err(ControllerError::RoomNotFound);
+        };
+
+        room.close()
+            .await
+            .map_err(|_| ControllerError::RoomNotFound)
+    }
+
+    /// Stops accepting participants and drains every room, see [`RoomHandle::drain`].
+    async fn drain(&mut self, deadline: Duration) {
+        if !self.draining {
+            tracing::info!(rooms = self.rooms.len(), ?deadline, "draining");
+        }
+        self.draining = true;
+        for room in self.rooms.values() {
+            let _ = room.drain(deadline).await;
+        }
+    }
+
+    fn get_or_create_room(&mut self, room_id: Arc<RoomId>) -> Result<RoomHandle, ControllerError> {
         if let Some(handle) = self.rooms.get(&room_id) {
-            handle.clone()
+            Ok(handle.clone())
         } else {
-            let (room_handle, room_actor) = RoomHandle::new(self.rng.clone(), room_id.clone());
-            self.rooms.insert(room_id.clone(), room_handle.clone());
-            self.room_tasks.spawn(
-                async move {
-                    actor::run(room_actor).await;
-                    room_id
-                }
-                .in_current_span(),
+            if self
+                .config
+                .limits
+                .max_rooms
+                .is_some_and(|max| self.rooms.len() >= max)
+            {
+                return Err(ControllerError::TooManyRooms);
+            }
+
+            let (room_handle, room_actor) = RoomHandle::new(
+                self.rng.clone(),
+                room_id.clone(),
+                self.config.limits,
+                self.config.channels,
+                self.config.recording_dir.clone(),
+                self.config.capture.clone(),
             );
+            self.rooms.insert(room_id.clone(), room_handle.clone());
+            self.room_tasks.spawn(room_id, room_actor);
 
-            room_handle
+            Ok(room_handle)
         }
     }
 }
@@ -167,23 +470,26 @@ pub struct ControllerHandle {
 impl ControllerHandle {
     pub fn new(
         rng: Rng,
-        source: UdpSourceHandle,
-        sink: UdpSinkHandle,
-        local_addrs: Vec<SocketAddr>,
+        transport: Transport,
+        candidates: Vec<Candidate>,
+        config: ControllerConfig,
         id: Arc<String>,
     ) -> (Self, ControllerActor) {
-        let (sender, receiver) = mpsc::channel(1);
+        let (sender, receiver) = mpsc::channel(config.channels.controller);
         let handle = ControllerHandle { sender };
 
         let actor = ControllerActor {
             id,
             rng,
             receiver,
-            source,
-            sink,
-            local_addrs,
+            transport,
+            candidates,
+            config,
             rooms: HashMap::new(),
-            room_tasks: JoinSet::new(),
+            room_tasks: ChildSet::default(),
+            sessions: HashMap::new(),
+            draining: false,
+            drained: Vec::new(),
         };
         (handle, actor)
     }
@@ -191,19 +497,218 @@ impl ControllerHandle {
     pub async fn allocate(
         &self,
         room_id: ExternalRoomId,
-        participant_id: ExternalParticipantId,
+        participant_id: Option<ExternalParticipantId>,
         offer: String,
-    ) -> Result<String, ControllerError> {
+        config: ParticipantConfig,
+    ) -> Result<Allocation, ControllerError> {
         let (tx, rx) = oneshot::channel();
         self.sender
             .send(ControllerMessage::Allocate(
-                room_id,
-                participant_id,
-                offer,
+                AllocateRequest {
+                    room_id,
+                    participant_id,
+                    offer,
+                    config,
+                },
                 tx,
             ))
             .await
             .map_err(|_| ControllerError::ServiceUnavailable)?;
         rx.await.map_err(|_| ControllerError::ServiceUnavailable)?
     }
+
+    pub async fn update_ice(
+        &self,
+        session_id: EntityId,
+        owner: Option<SessionOwner>,
+        update: IceUpdate,
+    ) -> Result<IceSession, ControllerError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ControllerMessage::UpdateIce(session_id, owner, update, tx))
+            .await
+            .map_err(|_| ControllerError::ServiceUnavailable)?;
+        rx.await.map_err(|_| ControllerError::ServiceUnavailable)?
+    }
+
+    pub async fn delete_session(
+        &self,
+        session_id: EntityId,
+        owner: Option<SessionOwner>,
+    ) -> Result<(), ControllerError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ControllerMessage::DeleteSession(session_id, owner, tx))
+            .await
+            .map_err(|_| ControllerError::ServiceUnavailable)?;
+        rx.await.map_err(|_| ControllerError::ServiceUnavailable)?
+    }
+
+    /// Rooms are queried directly, a slow room doesn't hold up the controller.
+    pub async fn list_rooms(&self) -> Result<Vec<RoomInfo>, ControllerError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ControllerMessage::ListRooms(tx))
+            .await
+            .map_err(|_| ControllerError::ServiceUnavailable)?;
+        let rooms = rx.await.map_err(|_| ControllerError::ServiceUnavailable)?;
+
+        let infos = futures::future::join_all(rooms.iter().map(|room| room.info())).await;
+        // Rooms that closed in the meantime are skipped
+        Ok(infos.into_iter().flatten().collect())
+    }
+
+    pub async fn room_info(&self, room_id: ExternalRoomId) -> Result<RoomInfo, ControllerError> {
+        let room = self.get_room(room_id).await?;
+        room.info().await.ok_or(ControllerError::RoomNotFound)
+    }
+
+    pub async fn kick_participant(
+        &self,
+        room_id: ExternalRoomId,
+        participant_id: ExternalParticipantId,
+    ) -> Result<(), ControllerError> {
+        let room = self.get_room(room_id).await?;
+        match room.kick(participant_id).await {
+            None => Err(ControllerError::RoomNotFound),
+            Some(0) => Err(ControllerError::ParticipantNotFound),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Writes the datagrams of every session of `participant_id` to pcapng files, see
+    /// [`crate::capture`].
+    pub async fn capture_participant(
+        &self,
+        room_id: ExternalRoomId,
+        participant_id: ExternalParticipantId,
+        req: CaptureRequest,
+    ) -> Result<Vec<PathBuf>, ControllerError> {
+        let room = self.get_room(room_id).await?;
+        match room.capture(participant_id, req).await {
+            None => Err(ControllerError::RoomNotFound),
+            Some(files) if files.is_empty() => Err(ControllerError::ParticipantNotFound),
+            Some(files) => Ok(files),
+        }
+    }
+
+    /// Records every track in the room, or only `track_id`. Tracks published later are
+    /// recorded too unless a single track was selected.
+    pub async fn start_recording(
+        &self,
+        room_id: ExternalRoomId,
+        track_id: Option<EntityId>,
+    ) -> Result<(), ControllerError> {
+        let room = self.get_room(room_id).await?;
+        room.start_recording(track_id.map(Arc::new))
+            .await
+            .map_err(recording_error)
+    }
+
+    /// Stops recording the room, or only `track_id`.
+    pub async fn stop_recording(
+        &self,
+        room_id: ExternalRoomId,
+        track_id: Option<EntityId>,
+    ) -> Result<(), ControllerError> {
+        let room = self.get_room(room_id).await?;
+        room.stop_recording(track_id.map(Arc::new))
+            .await
+            .map_err(recording_error)
+    }
+
+    pub async fn close_room(&self, room_id: ExternalRoomId) -> Result<(), ControllerError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ControllerMessage::CloseRoom(room_id, tx))
+            .await
+            .map_err(|_| ControllerError::ServiceUnavailable)?;
+        rx.await.map_err(|_| ControllerError::ServiceUnavailable)?
+    }
+
+    /// Resolves once every room has emptied out or was closed at `deadline`. New
+    /// sessions are refused from here on.
+    pub async fn drain(&self, deadline: Duration) -> Result<(), ControllerError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ControllerMessage::Drain(deadline, tx))
+            .await
+            .map_err(|_| ControllerError::ServiceUnavailable)?;
+        rx.await.map_err(|_| ControllerError::ServiceUnavailable)
+    }
+
+    async fn get_room(&self, room_id: ExternalRoomId) -> Result<RoomHandle, ControllerError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(ControllerMessage::GetRoom(room_id, tx))
+            .await
+            .map_err(|_| ControllerError::ServiceUnavailable)?;
+        rx.await
+            .map_err(|_| ControllerError::ServiceUnavailable)?
+            .ok_or(ControllerError::RoomNotFound)
+    }
+}
+
+fn recording_error(err: RoomError) -> ControllerError {
+    match err {
+        RoomError::TrackNotFound => ControllerError::TrackNotFound,
+        RoomError::NotRecording => ControllerError::RecordingNotFound,
+        RoomError::UnsupportedCodec => ControllerError::UnsupportedCodec,
+        RoomError::Full | RoomError::Closed => ControllerError::RoomNotFound,
+    }
+}
+
+/// A controller on a loopback socket for the HTTP handler tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::{
+        actor, net::UdpSocket, sink::UdpSinkHandle, source::UdpSourceHandle, tcp::TcpHandle,
+        turn::TurnHandle,
+    };
+    use rand::SeedableRng;
+    use std::net::SocketAddr;
+    use str0m::media::{Direction, MediaKind};
+
+    /// UDP source and sink on a loopback socket, returns the transport and its address.
+    pub async fn spawn_transport() -> (Transport, SocketAddr) {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket = UdpSocket::new(socket).unwrap();
+        let channels = ChannelCapacities::default();
+        let (source, source_actor) =
+            UdpSourceHandle::new(vec![addr], socket.clone(), channels.udp_source, 0);
+        let (sink, sink_actor) = UdpSinkHandle::new(socket, channels.udp_sink, 0);
+        tokio::spawn(actor::run(source_actor));
+        tokio::spawn(actor::run(sink_actor));
+        let transport = Transport {
+            source,
+            sink,
+            tcp: TcpHandle::default(),
+            turn: TurnHandle::default(),
+        };
+        (transport, addr)
+    }
+
+    pub async fn spawn_controller() -> ControllerHandle {
+        let (transport, addr) = spawn_transport().await;
+        let (controller, controller_actor) = ControllerHandle::new(
+            Rng::seed_from_u64(1),
+            transport,
+            vec![Candidate::host(addr, "udp").unwrap()],
+            ControllerConfig::default(),
+            Arc::new("test".to_string()),
+        );
+        tokio::spawn(actor::run(controller_actor));
+        controller
+    }
+
+    /// An offer from a client publishing a single audio track.
+    pub fn offer() -> String {
+        let mut rtc = Rtc::new();
+        let mut change = rtc.sdp_api();
+        change.add_media(MediaKind::Audio, Direction::SendOnly, None, None, None);
+        let (offer, _) = change.apply().unwrap();
+        offer.to_sdp_string()
+    }
 }
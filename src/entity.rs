@@ -16,6 +16,7 @@ pub mod prefix {
     pub const PARTICIPANT_ID: &str = "pa";
     pub const USER_ID: &str = "u";
     pub const TRACK_ID: &str = "tr";
+    pub const SESSION_ID: &str = "se";
 }
 
 const HASH_OUTPUT_BYTES: usize = 16;
@@ -182,6 +182,48 @@ fn first_token(input: &[u8], delimiter: u8) -> Option<&[u8]> {
     None
 }
 
+/// ICE attributes carried by an `application/trickle-ice-sdpfrag` body.
+///
+/// https://www.rfc-editor.org/rfc/rfc8840.html#section-9
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IceFragment {
+    pub ufrag: Option<String>,
+    pub pwd: Option<String>,
+    /// Candidate attribute values, e.g. "candidate:1 1 udp 2130706431 10.0.0.1 9 typ host"
+    pub candidates: Vec<String>,
+    pub end_of_candidates: bool,
+}
+
+/// Parses the ICE related attributes of an SDP fragment. Media and session level
+/// attributes are not distinguished since all media are bundled on a single transport.
+/// Unknown lines are ignored.
+pub fn parse_ice_fragment(input: &str) -> IceFragment {
+    let mut fragment = IceFragment::default();
+
+    for line in input.lines() {
+        let Some(attr) = line.trim().strip_prefix("a=") else {
+            continue;
+        };
+
+        if let Some(ufrag) = attr.strip_prefix("ice-ufrag:") {
+            fragment.ufrag = Some(ufrag.to_string());
+        } else if let Some(pwd) = attr.strip_prefix("ice-pwd:") {
+            fragment.pwd = Some(pwd.to_string());
+        } else if attr.starts_with("candidate:") {
+            fragment.candidates.push(attr.to_string());
+        } else if attr == "end-of-candidates" {
+            fragment.end_of_candidates = true;
+        }
+    }
+
+    fragment
+}
+
+/// Formats the local ICE credentials as an SDP fragment, used to answer an ICE restart.
+pub fn format_ice_credentials(ufrag: &str, pwd: &str) -> String {
+    format!("a=ice-ufrag:{ufrag}\r\na=ice-pwd:{pwd}\r\n")
+}
+
 // --- Robust Test Suite ---
 #[cfg(test)]
 mod tests {
@@ -667,4 +709,37 @@ mod tests {
         assert_eq!(first_token(b"asd:", b':'), Some(b"asd".as_slice()));
         assert_eq!(first_token(b"asd:bcd", b':'), Some(b"asd".as_slice()));
     }
+
+    #[test]
+    fn test_parse_ice_fragment_trickle() {
+        let input = "a=ice-ufrag:EsAw\r\n\
+                     a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+                     m=audio 9 RTP/AVP 0\r\n\
+                     a=mid:0\r\n\
+                     a=candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host generation 0 ufrag EsAw network-id 1\r\n\
+                     a=end-of-candidates\r\n";
+        let fragment = parse_ice_fragment(input);
+
+        assert_eq!(fragment.ufrag.as_deref(), Some("EsAw"));
+        assert_eq!(fragment.pwd.as_deref(), Some("P2uYro0UCOQ4zxjKXaWCBui1"));
+        assert_eq!(
+            fragment.candidates,
+            vec!["candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host generation 0 ufrag EsAw network-id 1".to_string()]
+        );
+        assert!(fragment.end_of_candidates);
+    }
+
+    #[test]
+    fn test_parse_ice_fragment_ignores_unknown_lines() {
+        let fragment = parse_ice_fragment("m=video 9 RTP/AVP 0\na=mid:1\na=sendrecv\n");
+        assert_eq!(fragment, IceFragment::default());
+    }
+
+    #[test]
+    fn test_format_ice_credentials_roundtrip() {
+        let fragment = parse_ice_fragment(&format_ice_credentials("abcd", "secret"));
+        assert_eq!(fragment.ufrag.as_deref(), Some("abcd"));
+        assert_eq!(fragment.pwd.as_deref(), Some("secret"));
+        assert!(fragment.candidates.is_empty());
+    }
 }
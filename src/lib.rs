@@ -1,4 +1,9 @@
 pub mod actor;
+pub mod admin;
+pub mod auth;
+pub mod bwe;
+pub mod capture;
+pub mod config;
 pub mod controller;
 pub mod entity;
 pub mod ice;
@@ -6,9 +11,17 @@ pub mod message;
 pub mod net;
 pub mod participant;
 pub mod proto;
+pub mod recorder;
 pub mod rng;
 pub mod room;
+pub mod shutdown;
 pub mod signaling;
 pub mod sink;
 pub mod source;
+pub mod speaker;
+pub mod stun;
+pub mod supervisor;
+pub mod tcp;
+pub mod telemetry;
 pub mod track;
+pub mod turn;
@@ -3,89 +3,437 @@ use mimalloc::MiMalloc;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-use std::{
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
-    time::Duration,
-};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
+use clap::{Parser, Subcommand};
 use pulsebeam::{
-    actor, controller::ControllerHandle, net::UdpSocket, rng::Rng, signaling, sink::UdpSinkHandle,
+    actor, admin,
+    auth::Authenticator,
+    config::{ConfigError, ConfigOverrides, LogFormat, ServerConfig},
+    controller::{ControllerConfig, ControllerHandle},
+    net::{self, UdpSocket},
+    participant::Transport,
+    rng::Rng,
+    shutdown::{self, Drain},
+    signaling,
+    sink::UdpSinkHandle,
     source::UdpSourceHandle,
+    supervisor::{Intensity, Restart, Restartable, Strategy, Supervisor},
+    tcp::TcpHandle,
+    telemetry,
+    turn::{self, TurnCredentials, TurnHandle, TurnListeners, TurnSettings},
 };
 use rand::SeedableRng;
-use systemstat::{Platform, System};
-use tokio::task::JoinSet;
+use str0m::Candidate;
+use tokio::sync::oneshot;
 use tower_http::cors::{AllowOrigin, CorsLayer};
-use tracing_subscriber::EnvFilter;
+
+const METRICS_UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+/// On top of the drain timeout, for the last participants to be disconnected.
+const DRAIN_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// TOML config file. Settings can also be set with PULSEBEAM__<SECTION>__<KEY>
+    /// environment variables, e.g. PULSEBEAM__MEDIA__ENABLE_H264=true.
+    #[arg(long, short, env = "PULSEBEAM_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, Default)]
+enum Command {
+    /// Run the server, the default.
+    #[default]
+    Serve,
+    /// Validate the configuration and print the effective configuration.
+    CheckConfig,
+}
 
 fn main() {
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
+    let cli = Cli::parse();
+    let config = match ServerConfig::load(cli.config.as_deref(), cli.overrides) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    match cli.command.unwrap_or_default() {
+        Command::Serve => {
+            let rt = match tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(err) => {
+                    eprintln!("failed to start the runtime: {err}");
+                    std::process::exit(1);
+                }
+            };
 
-    rt.block_on(run());
+            if let Err(err) = rt.block_on(run(config)) {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+        Command::CheckConfig => match config.to_toml() {
+            Ok(toml) => print!("{toml}"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        },
+    }
 }
 
-async fn run() {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_target(true)
-        .pretty()
-        .init();
+/// Binding and loading errors are returned before anything is served, so a bad
+/// setup exits non-zero like an unparsable config.
+async fn run(config: ServerConfig) -> Result<(), ConfigError> {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(config.log.env_filter())
+        .with_target(true);
+    match config.log.format {
+        LogFormat::Pretty => subscriber.pretty().init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    // Before any actor is created, they register their metrics on construction
+    if config.metrics.enabled {
+        let handle = telemetry::install().map_err(|err| {
+            ConfigError::Invalid(format!("failed to install the metrics recorder: {err}"))
+        })?;
+        let listener = tokio::net::TcpListener::bind(config.metrics.http_addr)
+            .await
+            .map_err(|source| ConfigError::Bind {
+                addr: config.metrics.http_addr,
+                source,
+            })?;
+        tracing::info!(addr = %config.metrics.http_addr, "serving metrics");
+
+        let upkeep = handle.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(METRICS_UPKEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                upkeep.run_upkeep();
+            }
+        });
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, telemetry::router(handle)).await;
+        });
+    }
 
     let cors = CorsLayer::very_permissive()
         // https://github.com/tower-rs/tower-http/issues/194
         .allow_origin(AllowOrigin::mirror_request())
         .max_age(Duration::from_secs(86400));
 
-    let ip = select_host_address();
-    let local_addr: SocketAddr = format!("{ip}:3478").parse().expect("valid bind addr");
-    let socket = tokio::net::UdpSocket::bind(local_addr)
-        .await
-        .expect("bind to udp socket");
-    let socket: UdpSocket = Arc::new(socket).into();
+    let shards = config.network.shards();
+    let channels = config.channels;
+    // Long-lived actors are restarted in place after a panic, their handles stay valid
+    let mut supervisor = Supervisor::new("root", Strategy::OneForOne, Intensity::default());
+    let mut source_handles = Vec::new();
+    let mut sink_handles = Vec::new();
+    let mut candidates = Vec::new();
+    let mut media_addrs = Vec::new();
+    for bind in config.network.udp_binds() {
+        let local_addrs = config.network.advertised_addrs(bind);
+        if local_addrs.is_empty() {
+            return Err(no_usable_interface(bind));
+        }
+
+        let sockets = net::bind_shards(bind, shards)
+            .map_err(|source| ConfigError::Bind { addr: bind, source })?;
+        for (shard, socket) in sockets.into_iter().enumerate() {
+            let (source_handle, source_actor) = UdpSourceHandle::new(
+                local_addrs.clone(),
+                socket.clone(),
+                channels.udp_source,
+                shard,
+            );
+            let (sink_handle, sink_actor) = UdpSinkHandle::new(socket, channels.udp_sink, shard);
+            let source_actor = Restartable::new(source_actor);
+            supervisor.add_child(
+                format!("udp_source:{bind}/{shard}"),
+                Restart::Transient,
+                move |stop| source_actor.clone().run(stop),
+            );
+            let sink_actor = Restartable::new(sink_actor);
+            supervisor.add_child(
+                format!("udp_sink:{bind}/{shard}"),
+                Restart::Transient,
+                move |stop| sink_actor.clone().run(stop),
+            );
+            source_handles.push(source_handle);
+            sink_handles.push(sink_handle);
+        }
+
+        tracing::info!(%bind, ?local_addrs, shards, "listening for media");
+        media_addrs.extend(local_addrs.iter().copied());
+        for addr in local_addrs {
+            candidates.push(host_candidate(addr, "udp")?);
+        }
+    }
+
+    let mut tcp_handles = Vec::new();
+    for bind in config.network.tcp_binds() {
+        let local_addrs = config.network.advertised_addrs(bind);
+        if local_addrs.is_empty() {
+            return Err(no_usable_interface(bind));
+        }
+
+        let listener =
+            net::bind_tcp(bind).map_err(|source| ConfigError::Bind { addr: bind, source })?;
+        let (tcp_handle, tcp_actor) = TcpHandle::new(
+            local_addrs.clone(),
+            listener,
+            channels.tcp,
+            channels.tcp_connection,
+            config.network.max_tcp_connections,
+        );
+        let tcp_actor = Restartable::new(tcp_actor);
+        supervisor.add_child(format!("tcp:{bind}"), Restart::Transient, move |stop| {
+            tcp_actor.clone().run(stop)
+        });
+        tcp_handles.push(tcp_handle);
+
+        tracing::info!(%bind, ?local_addrs, "listening for ice-tcp");
+        for addr in local_addrs {
+            candidates.push(host_candidate(addr, "tcp")?);
+        }
+    }
+
+    let source = UdpSourceHandle::sharded(source_handles);
+    let (turn_handle, turn_credentials) = if config.turn.enabled {
+        let (turn_handle, turn_actor, credentials) =
+            spawn_turn(&config, media_addrs, source.clone())?;
+        let turn_actor = Restartable::new(turn_actor);
+        supervisor.add_child("turn", Restart::Transient, move |stop| {
+            turn_actor.clone().run(stop)
+        });
+        (turn_handle, Some(Arc::new(credentials)))
+    } else {
+        (TurnHandle::default(), None)
+    };
+
+    let transport = Transport {
+        source,
+        sink: UdpSinkHandle::sharded(sink_handles),
+        tcp: TcpHandle::combined(tcp_handles),
+        turn: turn_handle,
+    };
 
     let rng = Rng::from_os_rng();
-    let (source_handle, source_actor) = UdpSourceHandle::new(local_addr, socket.clone());
-    let (sink_handle, sink_actor) = UdpSinkHandle::new(socket.clone());
     let (controller_handle, controller_actor) = ControllerHandle::new(
         rng,
-        source_handle,
-        sink_handle,
-        vec![local_addr],
+        transport,
+        candidates,
+        ControllerConfig::from(&config),
         Arc::new("root".to_string()),
     );
 
-    let router = signaling::router(controller_handle).layer(cors);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    let signaling = async move {
-        let _ = axum::serve(listener, router).await;
-    };
+    let api_keys = config.signaling.api_keys()?;
+    let auth = Authenticator::new(api_keys)
+        .map_err(|err| ConfigError::Invalid(format!("signaling.api_keys: {err}")))?;
+    if !auth.is_enabled() {
+        tracing::warn!(
+            "no api key is configured, anyone can join any room and the admin api is refused"
+        );
+    }
+    let auth = Arc::new(auth);
+    let drain = Drain::default();
+    let router = signaling::router(
+        controller_handle.clone(),
+        auth.clone(),
+        turn_credentials,
+        drain.clone(),
+    )
+    .merge(admin::router(controller_handle.clone(), auth))
+    .layer(cors);
+    let listener = tokio::net::TcpListener::bind(config.signaling.http_addr)
+        .await
+        .map_err(|source| ConfigError::Bind {
+            addr: config.signaling.http_addr,
+            source,
+        })?;
+    let (http_stop, http_stopped) = oneshot::channel::<()>();
+    let signaling = tokio::spawn(async move {
+        let _ = axum::serve(listener, router)
+            .with_graceful_shutdown(async {
+                let _ = http_stopped.await;
+            })
+            .await;
+    });
 
-    let mut join_set = JoinSet::new();
-    join_set.spawn(actor::run(source_actor));
-    join_set.spawn(actor::run(sink_actor));
-    join_set.spawn(actor::run(controller_actor));
-    join_set.spawn(signaling);
+    // Added last, so it is stopped first and the sockets outlive the rooms
+    let controller_actor = Restartable::new(controller_actor);
+    supervisor.add_child("controller", Restart::Transient, move |stop| {
+        controller_actor.clone().run(stop)
+    });
 
-    while let Some(_) = join_set.join_next().await {}
-}
+    let (supervisor_stop, supervisor_stopped) = oneshot::channel::<()>();
+    let mut supervisor_task = tokio::spawn(async move {
+        actor::run_until(&mut supervisor, async {
+            let _ = supervisor_stopped.await;
+        })
+        .await
+    });
 
-pub fn select_host_address() -> IpAddr {
-    let system = System::new();
-    let networks = system.networks().unwrap();
+    tokio::select! {
+        _ = shutdown::signal() => {}
+        status = &mut supervisor_task => {
+            tracing::error!(?status, "supervisor has stopped unexpectedly");
+            std::process::exit(1);
+        }
+    }
 
-    for net in networks.values() {
-        for n in &net.addrs {
-            if let systemstat::IpAddr::V4(v) = n.addr {
-                if !v.is_loopback() && !v.is_link_local() && !v.is_broadcast() {
-                    return IpAddr::V4(v);
-                }
+    // New sessions are refused from here on, the load balancer moves them elsewhere
+    drain.start();
+    let deadline = config.shutdown.drain_timeout();
+    tracing::info!(
+        ?deadline,
+        "draining, send the signal again to exit right away"
+    );
+    tokio::select! {
+        res = tokio::time::timeout(deadline + DRAIN_GRACE, controller_handle.drain(deadline)) => {
+            match res {
+                Ok(Ok(())) => tracing::info!("all rooms are closed"),
+                Ok(Err(err)) => tracing::warn!("failed to drain rooms: {err}"),
+                Err(_) => tracing::warn!("rooms are still open after the drain timeout"),
             }
         }
+        _ = shutdown::signal() => {
+            tracing::warn!("exiting without draining");
+            std::process::exit(1);
+        }
+    }
+
+    let _ = supervisor_stop.send(());
+    let _ = supervisor_task.await;
+    let _ = http_stop.send(());
+    let _ = signaling.await;
+    tracing::info!("shut down");
+    Ok(())
+}
+
+fn no_usable_interface(bind: SocketAddr) -> ConfigError {
+    ConfigError::Invalid(format!(
+        "found no usable network interface for {bind}, configure network.public_ips"
+    ))
+}
+
+fn host_candidate(addr: SocketAddr, proto: &str) -> Result<Candidate, ConfigError> {
+    Candidate::host(addr, proto).map_err(|err| {
+        ConfigError::Invalid(format!("{addr} is not a usable {proto} candidate: {err}"))
+    })
+}
+
+/// Binds the TURN sockets. Clients may only relay to `media_addrs`.
+fn spawn_turn(
+    config: &ServerConfig,
+    media_addrs: Vec<SocketAddr>,
+    source: UdpSourceHandle,
+) -> Result<(TurnHandle, turn::TurnActor, TurnCredentials), ConfigError> {
+    let turn = &config.turn;
+    let mut listeners = TurnListeners::default();
+    let mut relay_ips = Vec::new();
+    let mut urls = Vec::new();
+
+    for bind in config.network.dual_stack(turn.udp_bind) {
+        let local_addrs = config.network.advertised_addrs(bind);
+        let Some(relay) = local_addrs.first() else {
+            return Err(no_usable_interface(bind));
+        };
+
+        let socket = net::bind_reuseport(bind)
+            .and_then(UdpSocket::new)
+            .map_err(|source| ConfigError::Bind { addr: bind, source })?;
+        listeners.udp.push(socket);
+        relay_ips.push(relay.ip());
+        urls.extend(
+            local_addrs
+                .iter()
+                .map(|addr| format!("turn:{addr}?transport=udp")),
+        );
+        tracing::info!(%bind, ?local_addrs, "listening for turn over udp");
+    }
+
+    if turn.enable_tcp {
+        for bind in config.network.dual_stack(turn.tcp_bind) {
+            let local_addrs = config.network.advertised_addrs(bind);
+            let listener =
+                net::bind_tcp(bind).map_err(|source| ConfigError::Bind { addr: bind, source })?;
+            listeners.tcp.push(listener);
+            urls.extend(
+                local_addrs
+                    .iter()
+                    .map(|addr| format!("turn:{addr}?transport=tcp")),
+            );
+            tracing::info!(%bind, ?local_addrs, "listening for turn over tcp");
+        }
     }
 
-    panic!("Found no usable network interface");
+    // Already validated while loading
+    if let (Some(cert), Some(key), Some(domain)) = (&turn.tls_cert, &turn.tls_key, &turn.domain) {
+        let acceptor = turn::tls_acceptor(cert, key).map_err(|err| {
+            ConfigError::Invalid(format!(
+                "failed to load turn.tls_cert and turn.tls_key: {err}"
+            ))
+        })?;
+        for bind in config.network.dual_stack(turn.tls_bind) {
+            let listener =
+                net::bind_tcp(bind).map_err(|source| ConfigError::Bind { addr: bind, source })?;
+            listeners.tls.push(listener);
+            tracing::info!(%bind, domain, "listening for turn over tls");
+        }
+        listeners.tls_acceptor = Some(acceptor);
+        urls.push(format!(
+            "turns:{domain}:{}?transport=tcp",
+            turn.tls_bind.port()
+        ));
+    }
+
+    let keys: Vec<(String, String)> = config
+        .signaling
+        .api_keys
+        .iter()
+        .filter_map(|key| Some((key.id.clone(), key.secret.clone()?)))
+        .collect();
+    let Some((key_id, secret)) = keys.first().cloned() else {
+        return Err(ConfigError::Invalid(
+            "turn needs an api key with a secret in signaling.api_keys".to_string(),
+        ));
+    };
+    let credentials = TurnCredentials::new(
+        key_id,
+        secret,
+        urls,
+        Duration::from_secs(turn.credential_ttl_secs),
+    );
+
+    let settings = TurnSettings {
+        realm: turn.realm.clone(),
+        keys,
+        relay_ips,
+        peers: media_addrs,
+        max_allocations: turn.max_allocations,
+    };
+    let (handle, actor) = TurnHandle::new(
+        settings,
+        listeners,
+        source,
+        Rng::from_os_rng(),
+        config.channels.turn,
+        config.channels.tcp_connection,
+    );
+    Ok((handle, actor, credentials))
 }
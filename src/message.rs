@@ -3,21 +3,26 @@ use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use str0m::media::{KeyframeRequestKind, MediaKind, Rid, Simulcast};
+use str0m::net::Protocol;
 
 pub use str0m::change::{SdpAnswer, SdpOffer};
 pub use str0m::error::SdpError;
 pub use str0m::{Rtc, RtcError};
 
-use crate::entity::TrackId;
+use crate::entity::{ExternalParticipantId, ParticipantId, TrackId};
 
 #[derive(Debug)]
 pub struct UDPPacket {
     pub raw: Bytes,
     pub src: SocketAddr,
     pub dst: SocketAddr,
+    /// TCP packets are ICE-TCP frames without the length prefix.
+    pub proto: Protocol,
+    /// Arrived through the embedded TURN server, `src` is then a relayed address.
+    pub relayed: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EgressUDPPacket {
     pub raw: Bytes,
     pub dst: SocketAddr,
@@ -30,6 +35,43 @@ pub struct TrackIn {
     pub simulcast: Option<Simulcast>,
 }
 
+impl TrackIn {
+    /// RIDs of the simulcast layers sent by the publisher, empty without simulcast.
+    pub fn rids(&self) -> impl Iterator<Item = Rid> + '_ {
+        self.simulcast
+            .iter()
+            .flat_map(|simulcast| simulcast.recv.iter().map(|layer| layer.rid))
+    }
+
+    /// The layer new subscribers start with, the first announced layer. Publishers
+    /// conventionally announce their layers from the lowest quality.
+    pub fn default_rid(&self) -> Option<Rid> {
+        self.rids().next()
+    }
+}
+
+/// A data channel published to the room, subscribers receive its messages on a channel
+/// with the same label.
+#[derive(Debug)]
+pub struct DataChannel {
+    pub origin: Arc<ParticipantId>,
+    pub label: Arc<str>,
+    /// Reliable messages go through the control mailboxes, unreliable ones through the
+    /// data mailboxes. Both are dropped when a mailbox is full, the publisher is told
+    /// about dropped reliable messages. Subscriber channels are opened with the same
+    /// reliability.
+    pub reliable: bool,
+}
+
+#[derive(Debug)]
+pub struct DataPacket {
+    pub channel: Arc<DataChannel>,
+    pub payload: Bytes,
+    pub binary: bool,
+    /// Only delivered to these participants, to everyone else in the room when `None`.
+    pub destinations: Option<Vec<ExternalParticipantId>>,
+}
+
 #[derive(Debug)]
 pub struct KeyframeRequest {
     pub rid: Option<Rid>,
@@ -1,32 +1,275 @@
-use std::io;
-use std::net::SocketAddr;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
+use quinn_udp::{UdpSockRef, UdpSocketState};
+use socket2::{Domain, Protocol, Socket, Type};
+use systemstat::{Platform, System};
+use tokio::io::Interest;
+
+/// Max number of datagrams per batched syscall.
+pub const BATCH_SIZE: usize = quinn_udp::BATCH_SIZE;
+
+/// A received buffer. With GRO, the buffer holds several datagrams from the same source,
+/// each `stride` bytes long except the last one.
+#[derive(Debug, Clone, Copy)]
+pub struct RecvMeta {
+    pub addr: SocketAddr,
+    pub len: usize,
+    pub stride: usize,
+    /// The local IP the datagram was sent to, if the socket can tell.
+    pub dst_ip: Option<IpAddr>,
+}
+
+/// Datagrams to a single destination. With `segment_size`, `contents` is split into
+/// datagrams of that size by GSO, only the last one may be shorter.
+#[derive(Debug)]
+pub struct Transmit<'a> {
+    pub dst: SocketAddr,
+    pub contents: &'a [u8],
+    pub segment_size: Option<usize>,
+}
+
+/// The batch methods fall back to a datagram per syscall, sockets override them when
+/// the platform can do better.
 pub trait PacketSocket: Send + Sync + Clone + 'static {
     fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>>;
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> impl Future<Output = io::Result<usize>>;
     fn local_addr(&self) -> Result<SocketAddr, io::Error>;
+
+    /// Receives into `bufs`, returns the number of filled `metas`.
+    fn recv_batch(
+        &self,
+        bufs: &mut [Vec<u8>],
+        metas: &mut [RecvMeta],
+    ) -> impl Future<Output = io::Result<usize>> {
+        async move {
+            let (len, addr) = self.recv_from(&mut bufs[0]).await?;
+            metas[0] = RecvMeta {
+                addr,
+                len,
+                stride: len,
+                dst_ip: None,
+            };
+            Ok(1)
+        }
+    }
+
+    fn send_batch(&self, transmit: &Transmit<'_>) -> impl Future<Output = io::Result<()>> {
+        async move {
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for datagram in transmit.contents.chunks(segment_size.max(1)) {
+                self.send_to(datagram, transmit.dst).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Max number of datagrams `send_batch` can send in a single syscall.
+    fn max_gso_segments(&self) -> usize {
+        1
+    }
+
+    /// Max number of datagrams `recv_batch` can coalesce into a single buffer.
+    fn max_gro_segments(&self) -> usize {
+        1
+    }
+}
+
+/// Binds a UDP socket that can share `addr` with other sockets of this process. The
+/// kernel spreads remote flows across the sockets by hashing the 4-tuple, so a flow
+/// always lands on the same socket.
+pub fn bind_reuseport(addr: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    if addr.is_ipv6() {
+        // IPv4 gets its own socket, mapped addresses would confuse ICE
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+/// Binds `shards` sockets sharing `addr`. With port 0, the shards share the port the
+/// first one was given.
+pub fn bind_shards(addr: SocketAddr, shards: usize) -> io::Result<Vec<UdpSocket>> {
+    let mut addr = addr;
+    let mut sockets = Vec::with_capacity(shards);
+    for _ in 0..shards {
+        let socket = bind_reuseport(addr)?;
+        addr = socket.local_addr()?;
+        sockets.push(UdpSocket::new(socket)?);
+    }
+    Ok(sockets)
+}
+
+/// Binds a listener for ICE-TCP. Like UDP, IPv6 gets its own listener.
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
 }
 
+/// Usable addresses of the local interfaces for host candidates.
+pub fn select_host_addresses(ipv6: bool) -> Vec<IpAddr> {
+    let system = System::new();
+    let Ok(networks) = system.networks() else {
+        return Vec::new();
+    };
+
+    let mut addrs = Vec::new();
+    for net in networks.values() {
+        for n in &net.addrs {
+            match n.addr {
+                systemstat::IpAddr::V4(v) if !ipv6 => {
+                    if !v.is_loopback() && !v.is_link_local() && !v.is_broadcast() {
+                        addrs.push(IpAddr::V4(v));
+                    }
+                }
+                systemstat::IpAddr::V6(v) if ipv6 => {
+                    // fe80::/10 needs a scope id, it's useless for remote peers
+                    let link_local = (v.segments()[0] & 0xffc0) == 0xfe80;
+                    if !v.is_loopback() && !v.is_unspecified() && !link_local {
+                        addrs.push(IpAddr::V6(v));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    addrs
+}
+
+/// Uses recvmmsg with GRO and sendmsg with GSO on Linux through quinn-udp, other
+/// platforms get whatever quinn-udp supports there.
 #[derive(Clone)]
-pub struct UdpSocket(Arc<tokio::net::UdpSocket>);
+pub struct UdpSocket {
+    io: Arc<tokio::net::UdpSocket>,
+    state: Arc<UdpSocketState>,
+}
 
-impl Into<UdpSocket> for Arc<tokio::net::UdpSocket> {
-    fn into(self) -> UdpSocket {
-        UdpSocket(self)
+impl UdpSocket {
+    pub fn new(socket: tokio::net::UdpSocket) -> io::Result<Self> {
+        let state = UdpSocketState::new(UdpSockRef::from(&socket))?;
+        Ok(Self {
+            io: Arc::new(socket),
+            state: Arc::new(state),
+        })
     }
 }
 
 impl PacketSocket for UdpSocket {
     fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> {
-        self.0.recv_from(buf)
+        self.io.recv_from(buf)
     }
 
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> impl Future<Output = io::Result<usize>> {
-        self.0.send_to(buf, addr)
+        self.io.send_to(buf, addr)
     }
 
     fn local_addr(&self) -> Result<SocketAddr, io::Error> {
-        self.0.local_addr()
+        self.io.local_addr()
+    }
+
+    async fn recv_batch(&self, bufs: &mut [Vec<u8>], metas: &mut [RecvMeta]) -> io::Result<usize> {
+        let mut quinn_metas = [quinn_udp::RecvMeta::default(); BATCH_SIZE];
+        let mut iovs: Vec<IoSliceMut<'_>> = bufs
+            .iter_mut()
+            .take(BATCH_SIZE)
+            .map(|buf| IoSliceMut::new(buf))
+            .collect();
+        let count = iovs.len().min(metas.len());
+
+        let received = self
+            .io
+            .async_io(Interest::READABLE, || {
+                self.state.recv(
+                    UdpSockRef::from(&*self.io),
+                    &mut iovs[..count],
+                    &mut quinn_metas[..count],
+                )
+            })
+            .await?;
+
+        for (meta, quinn_meta) in metas.iter_mut().zip(&quinn_metas[..received]) {
+            *meta = RecvMeta {
+                addr: quinn_meta.addr,
+                len: quinn_meta.len,
+                stride: quinn_meta.stride,
+                dst_ip: quinn_meta.dst_ip,
+            };
+        }
+        Ok(received)
+    }
+
+    async fn send_batch(&self, transmit: &Transmit<'_>) -> io::Result<()> {
+        let transmit = quinn_udp::Transmit {
+            destination: transmit.dst,
+            ecn: None,
+            contents: transmit.contents,
+            segment_size: transmit.segment_size,
+            src_ip: None,
+        };
+        self.io
+            .async_io(Interest::WRITABLE, || {
+                self.state.send(UdpSockRef::from(&*self.io), &transmit)
+            })
+            .await
+    }
+
+    fn max_gso_segments(&self) -> usize {
+        self.state.max_gso_segments()
+    }
+
+    fn max_gro_segments(&self) -> usize {
+        self.state.gro_segments()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shards_share_the_address() {
+        let shards = bind_shards("127.0.0.1:0".parse().unwrap(), 4).unwrap();
+        assert_eq!(shards.len(), 4);
+        let addr = shards[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        for shard in &shards {
+            assert_eq!(shard.local_addr().unwrap(), addr);
+        }
+
+        // Every datagram lands on one of the shards
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", addr).await.unwrap();
+        let recv = shards.iter().map(|shard| {
+            Box::pin(async move {
+                let mut buf = [0; 16];
+                shard
+                    .recv_from(&mut buf)
+                    .await
+                    .map(|(len, _)| buf[..len].to_vec())
+            })
+        });
+        let (received, _, _) = futures::future::select_all(recv).await;
+        assert_eq!(received.unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_shards_fail_on_a_taken_address() {
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        // The socket without SO_REUSEPORT keeps the address to itself
+        assert!(bind_shards(addr, 2).is_err());
     }
 }
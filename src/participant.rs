@@ -1,43 +1,64 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Display},
+    net::SocketAddr,
     ops::Deref,
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 
 use bytes::Bytes;
 use prost::{DecodeError, Message};
+use rand::Rng as _;
 use str0m::{
-    Event, Input, Output, Rtc, RtcError,
-    channel::{ChannelData, ChannelId},
+    Candidate, Event, IceCreds, Input, Output, Rtc, RtcError,
+    bwe::{Bitrate, BweKind},
+    change::{SdpAnswer, SdpOffer, SdpPendingOffer},
+    channel::{ChannelConfig, ChannelData, ChannelId, Reliability},
     error::SdpError,
-    media::{Direction, KeyframeRequest, MediaAdded, MediaData, MediaKind, Mid, Simulcast},
+    media::{
+        Direction, KeyframeRequest, MediaAdded, MediaChanged, MediaData, MediaKind, Mid, Rid,
+        Simulcast,
+    },
     net::{self, Transmit},
 };
 use tokio::{
-    sync::mpsc::{
-        self,
-        error::{SendError, TrySendError},
+    sync::{
+        mpsc::{
+            self,
+            error::{SendError, TrySendError},
+        },
+        oneshot, watch,
     },
-    task::JoinSet,
     time::Instant,
 };
-use tracing::Instrument;
 
 use crate::{
-    actor::{self, Actor, ActorError},
-    entity::{EntityId, ParticipantId, TrackId},
-    message::{self, EgressUDPPacket, TrackIn},
+    actor::{Actor, ActorError, ActorStatus},
+    auth::Grants,
+    bwe,
+    capture::{CaptureDirection, CaptureHandle, CaptureLimits},
+    config::{ChannelCapacities, MediaConfig, RoomLimits, RtxConfig},
+    entity::{EntityId, ExternalParticipantId, ParticipantId, TrackId},
+    ice::IceFragment,
+    message::{self, DataChannel, DataPacket, EgressUDPPacket, TrackIn},
     proto::sfu,
     rng::Rng,
     room::RoomHandle,
     sink::UdpSinkHandle,
     source::UdpSourceHandle,
+    speaker::ActiveSpeakers,
+    supervisor::ChildSet,
+    tcp::TcpHandle,
+    telemetry,
     track::TrackHandle,
+    turn::TurnHandle,
 };
 
 const DATA_CHANNEL_LABEL: &str = "pulsebeam::rpc";
+/// A renegotiation offer that isn't answered in time is rolled back.
+const RENEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(thiserror::Error, Debug)]
 pub enum ParticipantError {
@@ -49,12 +70,83 @@ pub enum ParticipantError {
 
     #[error("invalid rpc format: {0}")]
     InvalidRPCFormat(#[from] DecodeError),
+
+    #[error("invalid ice fragment: {0}")]
+    InvalidIceFragment(String),
+
+    #[error("ice session does not match")]
+    IceSessionMismatch,
+
+    #[error("participant has left")]
+    Gone,
 }
 
 #[derive(Debug)]
 pub enum ParticipantControlMessage {
     TracksAdded(Arc<Vec<TrackHandle>>),
     TracksRemoved(Arc<Vec<Arc<TrackId>>>),
+    ParticipantsJoined(Arc<Vec<RemoteParticipant>>),
+    ParticipantLeft(Arc<ParticipantId>),
+    UpdateIce(
+        IceUpdate,
+        oneshot::Sender<Result<IceSession, ParticipantError>>,
+    ),
+    DataChannelsAdded(Arc<Vec<Arc<DataChannel>>>),
+    /// Closed by their publisher or the publisher has left.
+    DataChannelsRemoved(Arc<Vec<Arc<DataChannel>>>),
+    /// Reliable data, see [`ParticipantDataMessage::ForwardData`] for unreliable data.
+    ForwardData(Arc<DataPacket>),
+    /// The server is draining, the session is disconnected after the deadline.
+    GoingAway(Duration),
+    /// Writes the datagrams of this session to a file, replacing a running capture.
+    StartCapture(PathBuf, CaptureLimits),
+    ActiveSpeakersChanged(Arc<ActiveSpeakers<Arc<ParticipantId>>>),
+    /// Reliable data of this participant that didn't fit the mailboxes of these receivers.
+    DataDropped(Arc<DataChannel>, Vec<ExternalParticipantId>),
+    /// Replaces everything known about the room, sent on joining and after the room
+    /// dropped updates on a full mailbox.
+    RoomSynced(Arc<RoomSnapshot>),
+    Disconnect,
+}
+
+/// Another participant in the same room.
+#[derive(Debug, Clone)]
+pub struct RemoteParticipant {
+    pub participant_id: Arc<ParticipantId>,
+    pub metadata: Option<String>,
+}
+
+impl RemoteParticipant {
+    fn to_info(&self) -> sfu::ParticipantInfo {
+        sfu::ParticipantInfo {
+            participant_id: self.participant_id.external.to_string(),
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+/// The room as seen by one participant: everyone else, every track including its own,
+/// and everyone else's data channels.
+#[derive(Debug, Default)]
+pub struct RoomSnapshot {
+    pub participants: Vec<RemoteParticipant>,
+    pub tracks: Vec<TrackHandle>,
+    pub data_channels: Vec<Arc<DataChannel>>,
+}
+
+/// Trickled candidates or an ICE restart from the client.
+#[derive(Debug)]
+pub struct IceUpdate {
+    /// The ETag the client expects the ICE session to be in, "*" requests an ICE restart.
+    pub if_match: Option<String>,
+    pub fragment: IceFragment,
+}
+
+#[derive(Debug)]
+pub struct IceSession {
+    pub etag: String,
+    /// New local credentials, only set after an ICE restart.
+    pub restarted: Option<IceCreds>,
 }
 
 #[derive(Debug)]
@@ -62,12 +154,179 @@ pub enum ParticipantDataMessage {
     UdpPacket(message::UDPPacket),
     ForwardMedia(Arc<TrackIn>, Arc<MediaData>),
     KeyframeRequest(Arc<TrackId>, message::KeyframeRequest),
+    ForwardData(Arc<DataPacket>),
+}
+
+/// Restricts the remote tracks that a participant gets to see and subscribe to.
+/// An empty filter matches every track in the room.
+#[derive(Debug, Clone, Default)]
+pub struct TrackFilter {
+    pub participant: Option<ExternalParticipantId>,
+    pub track: Option<EntityId>,
+}
+
+impl TrackFilter {
+    pub fn matches(&self, track_id: &TrackId) -> bool {
+        if let Some(participant) = &self.participant {
+            if track_id.origin_participant.external != *participant {
+                return false;
+            }
+        }
+
+        if let Some(track) = &self.track {
+            if *track_id.internal != *track {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParticipantConfig {
+    pub grants: Grants,
+    pub track_filter: TrackFilter,
+    /// App-specific metadata shared with the other participants.
+    pub metadata: Option<String>,
+    /// Set by the controller, the session's `Rtc` is built with it.
+    pub rtx: RtxConfig,
+    /// Set by the controller.
+    pub media: MediaConfig,
+    /// Set by the controller.
+    pub limits: RoomLimits,
+}
+
+impl Default for ParticipantConfig {
+    fn default() -> Self {
+        Self {
+            grants: Grants::all(),
+            track_filter: TrackFilter::default(),
+            metadata: None,
+            rtx: RtxConfig::default(),
+            media: MediaConfig::default(),
+            limits: RoomLimits::default(),
+        }
+    }
+}
+
+impl ParticipantConfig {
+    /// A participant that only receives media, e.g. a WHEP viewer.
+    pub fn subscribe_only(grants: Grants, track_filter: TrackFilter) -> Self {
+        Self {
+            grants: Grants {
+                can_publish: false,
+                can_publish_data: false,
+                ..grants
+            },
+            track_filter,
+            metadata: None,
+            rtx: RtxConfig::default(),
+            media: MediaConfig::default(),
+            limits: RoomLimits::default(),
+        }
+    }
+
+    /// A participant that only sends media, e.g. a WHIP encoder.
+    pub fn publish_only(grants: Grants) -> Self {
+        Self {
+            grants: Grants {
+                can_subscribe: false,
+                can_publish_data: false,
+                ..grants
+            },
+            track_filter: TrackFilter::default(),
+            metadata: None,
+            rtx: RtxConfig::default(),
+            media: MediaConfig::default(),
+            limits: RoomLimits::default(),
+        }
+    }
+}
+
+/// Loss recovery of a session, summed over its streams since it started.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ParticipantStats {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// NACKs from the client, answered from the send buffers.
+    pub nacks_received: u64,
+    /// NACKs to the client for packets lost on the way in.
+    pub nacks_sent: u64,
+    /// NACKs received per packet sent. Not a retransmission rate, a NACK may ask for
+    /// several packets and packets that left the send buffer aren't resent.
+    pub egress_nacks_per_packet: f64,
+    /// NACKs sent per packet received.
+    pub ingress_nacks_per_packet: f64,
+    pub send_buffer_video: usize,
+    pub send_buffer_audio: usize,
+    pub reorder_window_video: usize,
+    pub reorder_window_audio: usize,
+}
+
+impl ParticipantStats {
+    fn new(rtx: &RtxConfig) -> Self {
+        Self {
+            send_buffer_video: rtx.send_buffer_video,
+            send_buffer_audio: rtx.send_buffer_audio,
+            reorder_window_video: rtx.reorder_window_video,
+            reorder_window_audio: rtx.reorder_window_audio,
+            ..Default::default()
+        }
+    }
+}
+
+/// Cumulative counters of a single RTP stream as reported by str0m.
+#[derive(Debug, Clone, Copy, Default)]
+struct StreamCounters {
+    packets: u64,
+    nacks: u64,
+}
+
+impl StreamCounters {
+    fn sum<'a>(streams: impl Iterator<Item = &'a StreamCounters>) -> Self {
+        streams.fold(Self::default(), |acc, stream| Self {
+            packets: acc.packets + stream.packets,
+            nacks: acc.nacks + stream.nacks,
+        })
+    }
+
+    fn nacks_per_packet(&self) -> f64 {
+        if self.packets == 0 {
+            return 0.0;
+        }
+        self.nacks as f64 / self.packets as f64
+    }
+}
+
+/// Stores the latest counters of a stream, returns the NACKs since its last report.
+fn record_stream(
+    streams: &mut HashMap<(Mid, Option<Rid>), StreamCounters>,
+    key: (Mid, Option<Rid>),
+    counters: StreamCounters,
+) -> u64 {
+    let previous = streams.insert(key, counters).unwrap_or_default();
+    counters.nacks.saturating_sub(previous.nacks)
 }
 
 #[derive(Debug)]
 struct TrackOut {
     handle: TrackHandle,
     mid: Option<Mid>,
+    // Desired simulcast layer, None when the track isn't simulcast
+    rid: Option<Rid>,
+    // Layer that is actually forwarded, can be lower than `rid` to fit the downlink
+    allocated: Option<Rid>,
+    paused: bool,
+}
+
+/// Network actors that move packets between the sockets and the participants.
+#[derive(Clone, Debug)]
+pub struct Transport {
+    pub source: UdpSourceHandle,
+    pub sink: UdpSinkHandle,
+    pub tcp: TcpHandle,
+    pub turn: TurnHandle,
 }
 
 struct MidOutSlot {
@@ -91,20 +350,39 @@ struct MidOutSlot {
 pub struct ParticipantActor {
     rng: Rng,
     handle: ParticipantHandle,
-    source: UdpSourceHandle,
+    transport: Transport,
     data_receiver: mpsc::Receiver<ParticipantDataMessage>,
     control_receiver: mpsc::Receiver<ParticipantControlMessage>,
-    sink: UdpSinkHandle,
     room: RoomHandle,
     participant_id: Arc<ParticipantId>,
     rtc: str0m::Rtc,
+    config: ParticipantConfig,
+    channels: ChannelCapacities,
     cid: Option<ChannelId>,
-    track_tasks: JoinSet<Arc<TrackId>>,
-
+    pending_offer: Option<SdpPendingOffer>,
+    pending_offer_deadline: Option<Instant>,
+    // Latest downlink estimate in bps, from TWCC or REMB feedback
+    egress_estimate: Option<u64>,
+    // Remote candidates behind the embedded TURN server, replies go through it
+    relayed_addrs: HashSet<SocketAddr>,
+    track_tasks: ChildSet<Arc<TrackId>>,
+    capture: Option<CaptureHandle>,
+    capture_tasks: ChildSet<PathBuf>,
+
+    remote_participants: HashMap<Arc<ParticipantId>, RemoteParticipant>,
     published_tracks: HashMap<Mid, TrackHandle>,
     // InternalTrackId -> TrackOut
     available_tracks: HashMap<Arc<EntityId>, TrackOut>,
     mid_out_slots: HashMap<Mid, MidOutSlot>,
+    // Data channels published by this participant
+    data_in: HashMap<ChannelId, Arc<DataChannel>>,
+    // Channels that receive data from the room, by label
+    data_out: HashMap<Arc<str>, ChannelId>,
+    remote_data_channels: Vec<Arc<DataChannel>>,
+    // Outgoing and incoming streams by (mid, rid)
+    egress_streams: HashMap<(Mid, Option<Rid>), StreamCounters>,
+    ingress_streams: HashMap<(Mid, Option<Rid>), StreamCounters>,
+    stats: watch::Sender<ParticipantStats>,
 }
 
 impl fmt::Debug for ParticipantActor {
@@ -127,9 +405,9 @@ impl Actor for ParticipantActor {
     }
 
     async fn pre_start(&mut self) -> Result<(), crate::actor::ActorError> {
+        metrics::gauge!(telemetry::PARTICIPANTS).increment(1.0);
         let ufrag = self.rtc.direct_api().local_ice_credentials().ufrag;
-        self.source
-            .add_participant(ufrag, self.handle.clone())
+        self.add_ufrag(ufrag)
             .await
             .map_err(|_| ActorError::PreStartFailed("source is closed".to_string()))
     }
@@ -140,12 +418,16 @@ impl Actor for ParticipantActor {
         // We should yield back to the scheduler based on some heuristic here.
 
         loop {
-            let delay = if let Some(delay) = self.poll().await {
+            self.expire_pending_offer(Instant::now());
+            let mut delay = if let Some(delay) = self.poll().await {
                 delay
             } else {
                 // Rtc timeout
                 break;
             };
+            if let Some(deadline) = self.pending_offer_deadline {
+                delay = delay.min(deadline.saturating_duration_since(Instant::now()));
+            }
 
             tokio::select! {
                 Some(msg) = self.data_receiver.recv() => {
@@ -164,10 +446,12 @@ impl Actor for ParticipantActor {
                     // tracing::warn!("woke up from sleep: {}us", delay.as_micros());
                 }
 
-                Some(Ok(key)) = self.track_tasks.join_next() => {
-                    // TODO: clean up track
+                Some((track_id, status)) = self.track_tasks.join_next() => {
+                    self.handle_track_exited(track_id, status).await;
                 }
 
+                Some(_) = self.capture_tasks.join_next() => {}
+
                 else => break,
             }
         }
@@ -176,24 +460,71 @@ impl Actor for ParticipantActor {
     }
 
     async fn post_stop(&mut self) -> Result<(), ActorError> {
+        metrics::gauge!(telemetry::PARTICIPANTS).decrement(1.0);
+        // Lets a running capture flush its file
+        self.capture = None;
+        while self.capture_tasks.join_next().await.is_some() {}
         let ufrag = self.rtc.direct_api().local_ice_credentials().ufrag;
-        self.source
-            .remove_participant(ufrag)
+        self.remove_ufrag(ufrag)
             .await
             .map_err(|_| ActorError::PostStopFailed("source is closed".to_string()))
     }
 }
 
 impl ParticipantActor {
+    pub fn metadata(&self) -> Option<&str> {
+        self.config.metadata.as_deref()
+    }
+
+    pub fn stats(&self) -> watch::Receiver<ParticipantStats> {
+        self.stats.subscribe()
+    }
+
+    /// Routes packets with a STUN binding for `ufrag` to this participant, over UDP and TCP.
+    async fn add_ufrag(&self, ufrag: String) -> Result<(), ParticipantError> {
+        self.transport
+            .source
+            .add_participant(ufrag.clone(), self.handle.clone())
+            .await
+            .map_err(|_| ParticipantError::Gone)?;
+        self.transport
+            .tcp
+            .add_participant(ufrag, self.handle.clone())
+            .await
+            .map_err(|_| ParticipantError::Gone)
+    }
+
+    async fn remove_ufrag(&self, ufrag: String) -> Result<(), ParticipantError> {
+        self.transport
+            .source
+            .remove_participant(ufrag.clone())
+            .await
+            .map_err(|_| ParticipantError::Gone)?;
+        self.transport
+            .tcp
+            .remove_participant(ufrag)
+            .await
+            .map_err(|_| ParticipantError::Gone)
+    }
+
     #[inline]
     async fn handle_data_message(&mut self, msg: ParticipantDataMessage) {
         match msg {
             ParticipantDataMessage::UdpPacket(packet) => {
+                if packet.relayed {
+                    self.relayed_addrs.insert(packet.src);
+                }
+                self.capture_packet(
+                    CaptureDirection::Inbound,
+                    packet.src,
+                    packet.dst,
+                    &packet.raw,
+                );
                 let now = Instant::now();
                 let res = self.rtc.handle_input(Input::Receive(
                     now.into_std(),
                     net::Receive {
-                        proto: net::Protocol::Udp,
+                        proto: packet.proto,
                         source: packet.src,
                         destination: packet.dst,
                         contents: (&*packet.raw).try_into().unwrap(),
@@ -201,7 +532,7 @@ impl ParticipantActor {
                 ));
 
                 if let Err(err) = res {
-                    tracing::warn!("dropped a UDP packet: {err}");
+                    tracing::warn!("dropped a {:?} packet: {err}", packet.proto);
                 }
             }
             ParticipantDataMessage::ForwardMedia(track, data) => {
@@ -218,6 +549,7 @@ impl ParticipantActor {
                     tracing::warn!("failed to request a keyframe from the publisher: {err}");
                 }
             }
+            ParticipantDataMessage::ForwardData(packet) => self.handle_forward_data(&packet),
         }
     }
 
@@ -226,68 +558,192 @@ impl ParticipantActor {
 
         match msg {
             ParticipantControlMessage::TracksAdded(tracks) => {
-                let mut should_reconfigure: bool = false;
-                let mut new_tracks = Vec::new();
-
-                for track in tracks.iter() {
-                    if track.meta.id.origin_participant == self.participant_id {
-                        // successfully publish a track
-                        tracing::info!(track_id = ?track.meta.id, origin = ?track.meta.id.origin_participant, "published track");
-                        self.published_tracks
-                            .insert(track.meta.id.origin_mid, track.clone());
-                    } else {
-                        // new tracks from other participants
-                        tracing::info!(track_id = ?track.meta.id, origin = ?track.meta.id.origin_participant, "subscribed track");
-                        let track_id = track.meta.id.clone();
-
-                        self.available_tracks.insert(
-                            track_id.internal.clone(),
-                            TrackOut {
-                                handle: track.clone(),
-                                mid: None,
-                            },
-                        );
-                        let kind = if track.meta.kind.is_video() {
-                            sfu::TrackKind::Video
-                        } else {
-                            sfu::TrackKind::Audio
-                        };
-
-                        new_tracks.push(sfu::TrackInfo {
-                            track_id: track.meta.id.to_string(),
-                            kind: kind as i32,
-                            participant_id: track.meta.id.origin_participant.to_string(),
-                        });
-                        should_reconfigure = true;
-                    }
-                }
+                let new_tracks: Vec<_> = tracks
+                    .iter()
+                    .filter(|track| self.add_track(track))
+                    .map(track_info)
+                    .collect();
 
-                if should_reconfigure {
+                if !new_tracks.is_empty() {
                     self.send_server_event(Payload::TrackPublished(sfu::TrackPublishedPayload {
                         remote_tracks: new_tracks,
                     }));
                     self.reconfigure_downstreams().await;
+                    self.renegotiate_downstreams();
                 }
             }
             ParticipantControlMessage::TracksRemoved(track_ids) => {
                 for track_id in track_ids.iter() {
-                    let Some(track) = self.available_tracks.remove(&track_id.internal) else {
-                        return;
-                    };
-
-                    let Some(mid) = track.mid else {
-                        return;
-                    };
-
-                    // We don't reconfigure downstreams here because the client will
-                    // likely rearrange their layout and subscribe for new streams.
-                    self.mid_out_slots.remove(&mid);
+                    self.remove_track(&track_id.internal);
                 }
                 self.send_server_event(Payload::TrackUnpublished(sfu::TrackUnpublishedPayload {
                     remote_track_ids: track_ids.iter().map(|t| t.to_string()).collect(),
                 }));
             }
+            ParticipantControlMessage::ParticipantsJoined(participants) => {
+                for participant in participants.iter() {
+                    self.remote_participants
+                        .insert(participant.participant_id.clone(), participant.clone());
+                }
+                self.send_server_event(Payload::ParticipantJoined(sfu::ParticipantJoinedPayload {
+                    participants: participants.iter().map(|p| p.to_info()).collect(),
+                }));
+            }
+            ParticipantControlMessage::ParticipantLeft(participant_id) => {
+                if self.remote_participants.remove(&participant_id).is_none() {
+                    return;
+                }
+                // Channels stay open, someone else may publish on the same label
+                self.remote_data_channels
+                    .retain(|channel| channel.origin != participant_id);
+                self.send_server_event(Payload::ParticipantLeft(sfu::ParticipantLeftPayload {
+                    participant_ids: vec![participant_id.external.to_string()],
+                }));
+            }
+            ParticipantControlMessage::UpdateIce(update, resp) => {
+                let _ = resp.send(self.handle_ice_update(update).await);
+            }
+            ParticipantControlMessage::DataChannelsAdded(channels) => {
+                if !self.config.grants.can_subscribe {
+                    return;
+                }
+
+                let channels: Vec<_> = channels
+                    .iter()
+                    .filter(|channel| channel.origin != self.participant_id)
+                    .cloned()
+                    .collect();
+                self.remote_data_channels.extend(channels.iter().cloned());
+                self.open_data_channels();
+                self.send_server_event(Payload::DataChannelPublished(
+                    sfu::DataChannelPublishedPayload {
+                        data_channels: channels.iter().map(|c| data_channel_info(c)).collect(),
+                    },
+                ));
+            }
+            ParticipantControlMessage::DataChannelsRemoved(channels) => {
+                if !self.config.grants.can_subscribe {
+                    return;
+                }
+
+                // Channels stay open, someone else may publish on the same label
+                self.remote_data_channels.retain(|channel| {
+                    !channels.iter().any(|removed| Arc::ptr_eq(channel, removed))
+                });
+                self.send_server_event(Payload::DataChannelUnpublished(
+                    sfu::DataChannelUnpublishedPayload {
+                        data_channels: channels.iter().map(|c| data_channel_info(c)).collect(),
+                    },
+                ));
+            }
+            ParticipantControlMessage::ForwardData(packet) => self.handle_forward_data(&packet),
+            ParticipantControlMessage::GoingAway(deadline) => {
+                let deadline_ms = deadline.as_millis().min(u32::MAX as u128) as u32;
+                // Spread out the reconnects so the remaining instances aren't hit at once
+                let reconnect_after_ms = self.rng.random_range(0..=deadline_ms / 2);
+                self.send_server_event(Payload::GoingAway(sfu::ServerGoingAwayPayload {
+                    reconnect_after_ms,
+                    deadline_ms,
+                }));
+            }
+            ParticipantControlMessage::StartCapture(path, limits) => {
+                let (capture, capture_actor) = CaptureHandle::new(
+                    self.participant_id.clone(),
+                    path.clone(),
+                    limits,
+                    self.channels.participant_data,
+                );
+                // A running capture finishes once its handle is dropped
+                self.capture = Some(capture);
+                self.capture_tasks.spawn(path, capture_actor);
+            }
+            ParticipantControlMessage::ActiveSpeakersChanged(speakers) => {
+                self.send_server_event(Payload::ActiveSpeakersChanged(
+                    sfu::ActiveSpeakersChangedPayload {
+                        dominant_participant_id: speakers
+                            .dominant
+                            .as_ref()
+                            .map(|id| id.external.to_string()),
+                        participant_ids: speakers
+                            .active
+                            .iter()
+                            .map(|id| id.external.to_string())
+                            .collect(),
+                    },
+                ));
+            }
+            ParticipantControlMessage::DataDropped(channel, receivers) => {
+                let receivers: Vec<&str> = receivers.iter().map(|id| id.as_str()).collect();
+                self.send_server_event(Payload::Error(sfu::ErrorPayload {
+                    description: format!(
+                        "data on {} is dropped for busy participants: {}",
+                        channel.label,
+                        receivers.join(", ")
+                    ),
+                }));
+            }
+            ParticipantControlMessage::RoomSynced(snapshot) => {
+                self.handle_room_synced(&snapshot).await;
+            }
+            ParticipantControlMessage::Disconnect => {
+                tracing::info!("disconnect is requested");
+                self.rtc.disconnect();
+            }
+        }
+    }
+
+    async fn handle_ice_update(
+        &mut self,
+        update: IceUpdate,
+    ) -> Result<IceSession, ParticipantError> {
+        let local = self.rtc.direct_api().local_ice_credentials();
+        let restart = update.if_match.as_deref() == Some("*");
+        if !restart {
+            if let Some(etag) = &update.if_match {
+                if *etag != local.ufrag {
+                    return Err(ParticipantError::IceSessionMismatch);
+                }
+            }
         }
+
+        // Validate everything first so that a bad fragment doesn't leave a half-applied update
+        let mut candidates = Vec::with_capacity(update.fragment.candidates.len());
+        for candidate in &update.fragment.candidates {
+            let candidate = Candidate::from_sdp_string(candidate)
+                .map_err(|err| ParticipantError::InvalidIceFragment(err.to_string()))?;
+            candidates.push(candidate);
+        }
+
+        let mut restarted = None;
+        if restart {
+            let (Some(ufrag), Some(pass)) = (update.fragment.ufrag, update.fragment.pwd) else {
+                return Err(ParticipantError::InvalidIceFragment(
+                    "ice restart requires ice-ufrag and ice-pwd".to_string(),
+                ));
+            };
+
+            let creds = self.rtc.sdp_api().ice_restart(true);
+            self.rtc
+                .direct_api()
+                .set_remote_ice_credentials(IceCreds { ufrag, pass });
+
+            // Packets from the new network path are only routed with the new ufrag.
+            // Removing the old ufrag also drops the stale address mappings.
+            self.add_ufrag(creds.ufrag.clone()).await?;
+            self.remove_ufrag(local.ufrag).await?;
+
+            tracing::info!(ufrag = creds.ufrag, "ice restarted");
+            restarted = Some(creds);
+        }
+
+        for candidate in candidates {
+            self.rtc.add_remote_candidate(candidate);
+        }
+
+        Ok(IceSession {
+            etag: self.rtc.direct_api().local_ice_credentials().ufrag,
+            restarted,
+        })
     }
 
     async fn poll(&mut self) -> Option<Duration> {
@@ -328,6 +784,126 @@ impl ParticipantActor {
         None
     }
 
+    /// Returns true when `track` is another participant's track that can be subscribed
+    /// to, the own tracks are only remembered as published.
+    fn add_track(&mut self, track: &TrackHandle) -> bool {
+        if track.meta.id.origin_participant == self.participant_id {
+            // successfully publish a track
+            tracing::info!(track_id = ?track.meta.id, origin = ?track.meta.id.origin_participant, "published track");
+            self.published_tracks
+                .insert(track.meta.id.origin_mid, track.clone());
+            return false;
+        }
+
+        if !self.config.grants.can_subscribe || !self.config.track_filter.matches(&track.meta.id) {
+            return false;
+        }
+
+        // new tracks from other participants
+        tracing::info!(track_id = ?track.meta.id, origin = ?track.meta.id.origin_participant, "subscribed track");
+        self.available_tracks.insert(
+            track.meta.id.internal.clone(),
+            TrackOut {
+                handle: track.clone(),
+                mid: None,
+                rid: track.meta.default_rid(),
+                allocated: track.meta.default_rid(),
+                paused: false,
+            },
+        );
+        true
+    }
+
+    fn remove_track(&mut self, track_id: &EntityId) {
+        let Some(track) = self.available_tracks.remove(track_id) else {
+            return;
+        };
+
+        let Some(mid) = track.mid else {
+            return;
+        };
+
+        // The transceiver stays negotiated, only free up the slot.
+        // We don't reconfigure downstreams here because the client will
+        // likely rearrange their layout and subscribe for new streams.
+        if let Some(slot) = self.mid_out_slots.get_mut(&mid) {
+            slot.track_id = None;
+        }
+    }
+
+    /// Catches up with the room, the client gets the whole state again instead of the
+    /// individual changes.
+    async fn handle_room_synced(&mut self, snapshot: &RoomSnapshot) {
+        self.remote_participants = snapshot
+            .participants
+            .iter()
+            .map(|participant| (participant.participant_id.clone(), participant.clone()))
+            .collect();
+
+        let current: HashSet<_> = snapshot
+            .tracks
+            .iter()
+            .map(|track| track.meta.id.internal.clone())
+            .collect();
+        let removed: Vec<_> = self
+            .available_tracks
+            .keys()
+            .filter(|track_id| !current.contains(*track_id))
+            .cloned()
+            .collect();
+        for track_id in removed {
+            self.remove_track(&track_id);
+        }
+
+        let mut added = false;
+        for track in &snapshot.tracks {
+            if !self.available_tracks.contains_key(&track.meta.id.internal) {
+                added |= self.add_track(track);
+            }
+        }
+        if added {
+            self.reconfigure_downstreams().await;
+            self.renegotiate_downstreams();
+        }
+
+        if self.config.grants.can_subscribe {
+            self.remote_data_channels = snapshot
+                .data_channels
+                .iter()
+                .filter(|channel| channel.origin != self.participant_id)
+                .cloned()
+                .collect();
+            self.open_data_channels();
+        }
+
+        self.send_room_snapshot();
+    }
+
+    fn send_room_snapshot(&mut self) {
+        use sfu::server_message::Payload;
+
+        let participants = self
+            .remote_participants
+            .values()
+            .map(|p| p.to_info())
+            .collect();
+        let remote_tracks = self
+            .available_tracks
+            .values()
+            .map(|t| track_info(&t.handle))
+            .collect();
+        let data_channels = self
+            .remote_data_channels
+            .iter()
+            .map(|c| data_channel_info(c))
+            .collect();
+        self.send_server_event(Payload::RoomSnapshot(sfu::RoomSnapshotPayload {
+            participants,
+            remote_tracks,
+            data_channels,
+        }));
+    }
+
     fn send_server_event(&mut self, msg: sfu::server_message::Payload) {
         // TODO: handle when data channel is closed
 
@@ -350,114 +926,585 @@ impl ParticipantActor {
         match payload {
             sfu::client_message::Payload::Subscribe(subscribe) => {
                 let mid = Mid::from(subscribe.mid.as_str());
-                if let Some(track) = self.available_tracks.get_mut(&subscribe.remote_track_id) {
-                    if let Some(slot) = self.mid_out_slots.get_mut(&mid) {
-                        track.mid.replace(mid);
-                        if let Some(last_track) =
-                            slot.track_id.replace(track.handle.meta.id.clone())
-                        {
-                            self.handle_unsubscribe(last_track).await;
-                        }
-                    }
-                }
+                self.handle_subscribe(mid, &subscribe.remote_track_id).await;
             }
             sfu::client_message::Payload::Unsubscribe(unsubscribe) => {
                 let mid = Mid::from(unsubscribe.mid.as_str());
-                if let Some(slot) = self.mid_out_slots.get_mut(&mid) {
-                    if let Some(last_track) = slot.track_id.take() {
-                        self.handle_unsubscribe(last_track).await;
-                    }
+                if let Some(confirmation) = self.handle_unsubscribe_mid(mid).await {
+                    self.send_server_event(confirmation);
                 }
             }
+            sfu::client_message::Payload::SelectLayer(select) => {
+                let mid = Mid::from(select.mid.as_str());
+                self.handle_select_layer(mid, &select.rid).await;
+            }
+            sfu::client_message::Payload::Offer(offer) => {
+                self.handle_remote_offer(offer)?;
+            }
+            sfu::client_message::Payload::Answer(answer) => {
+                self.handle_remote_answer(answer)?;
+            }
+            sfu::client_message::Payload::PublishData(publish) => {
+                self.handle_publish_data(publish).await;
+            }
+            sfu::client_message::Payload::SendData(send) => {
+                self.handle_send_data(send);
+            }
         };
 
         Ok(())
     }
 
-    async fn handle_unsubscribe(&mut self, track_id: Arc<TrackId>) {
-        // TODO: handle unsubscribe
-        // let Some(track) = self.available_tracks.get(&track_id.internal) else {
-        //     return;
-        // };
-        // track.handle.subscribe(participant)
+    fn handle_remote_offer(&mut self, offer: sfu::SdpOfferPayload) -> Result<(), ParticipantError> {
+        use sfu::server_message::Payload;
+
+        if self.pending_offer.is_some() {
+            // Glare, the SFU is the impolite peer. The client is expected to roll back
+            // and answer the pending offer first.
+            self.send_server_event(Payload::Error(sfu::ErrorPayload {
+                description: "offer collision, answer the pending offer first".to_string(),
+            }));
+            return Ok(());
+        }
+
+        let offer = SdpOffer::from_sdp_string(&offer.sdp)?;
+        let answer = self
+            .rtc
+            .sdp_api()
+            .accept_offer(offer)
+            .map_err(ParticipantError::OfferRejected)?;
+        self.send_server_event(Payload::Answer(sfu::SdpAnswerPayload {
+            sdp: answer.to_sdp_string(),
+        }));
+        Ok(())
     }
 
-    async fn handle_output_transmit(&mut self, t: Transmit) {
-        let packet = Bytes::copy_from_slice(&t.contents);
-        let _ = self
-            .sink
-            .send(EgressUDPPacket {
-                raw: packet,
-                dst: t.destination,
-            })
-            .await;
+    fn handle_remote_answer(
+        &mut self,
+        answer: sfu::SdpAnswerPayload,
+    ) -> Result<(), ParticipantError> {
+        let Some(pending) = self.pending_offer.take() else {
+            tracing::warn!("received an answer without a pending offer, ignoring");
+            return Ok(());
+        };
+        self.pending_offer_deadline = None;
+
+        let answer = SdpAnswer::from_sdp_string(&answer.sdp)?;
+        // New transceivers are announced through MediaAdded once negotiated
+        self.rtc
+            .sdp_api()
+            .accept_answer(pending, answer)
+            .map_err(ParticipantError::OfferRejected)?;
+        Ok(())
     }
 
-    async fn handle_output_event(&mut self, event: Event) {
-        match event {
-            // Abort if we disconnect.
-            Event::IceConnectionStateChange(ice_state) => match ice_state {
-                str0m::IceConnectionState::Disconnected => self.rtc.disconnect(),
-                state => tracing::trace!("ice state: {:?}", state),
-            },
-            Event::MediaAdded(e) => {
-                self.handle_new_media(e).await;
-            }
-            Event::ChannelOpen(cid, label) => {
-                if label == DATA_CHANNEL_LABEL {
-                    self.cid = Some(cid);
-                    tracing::warn!(label, "data channel is open");
-                }
-            }
-            Event::ChannelData(data) => {
-                if Some(data.id) == self.cid {
-                    if let Err(err) = self.handle_rpc(data).await {
-                        tracing::warn!("data channel dropped due to an error: {err}");
-                    }
-                } else {
-                    todo!("forward data channel");
-                }
-            }
-            Event::ChannelClose(cid) => {
-                if Some(cid) == self.cid {
-                    self.rtc.disconnect();
-                } else {
-                    tracing::warn!("channel closed: {:?}", cid);
-                }
-            }
-            Event::MediaData(e) => {
-                if let Some(track) = self.published_tracks.get(&e.mid) {
-                    let _ = track.forward_media(Arc::new(e)).await;
-                }
-            }
-            Event::KeyframeRequest(req) => self.handle_keyframe_request(req),
-            Event::Connected => {
-                tracing::info!("connected");
+    /// Adds SendOnly transceivers when there are more available tracks than free
+    /// mid slots. Renegotiation happens over the rpc data channel, so it's skipped for
+    /// clients without one, e.g. WHEP viewers.
+    fn renegotiate_downstreams(&mut self) {
+        use sfu::server_message::Payload;
+
+        if self.cid.is_none() || self.pending_offer.is_some() {
+            return;
+        }
+
+        let max_slots = self.config.limits.max_downstream_slots_per_kind;
+        let mut change = self.rtc.sdp_api();
+        for kind in [MediaKind::Audio, MediaKind::Video] {
+            let unassigned = self
+                .available_tracks
+                .values()
+                .filter(|t| t.mid.is_none() && t.handle.meta.kind == kind)
+                .count();
+            let (total, free) = self
+                .mid_out_slots
+                .values()
+                .filter(|s| s.kind == kind)
+                .fold((0, 0), |(total, free), s| {
+                    (total + 1, free + s.track_id.is_none() as usize)
+                });
+
+            let missing = unassigned
+                .saturating_sub(free)
+                .min(max_slots.saturating_sub(total));
+            for _ in 0..missing {
+                change.add_media(kind, Direction::SendOnly, None, None, None);
             }
-            event => tracing::warn!("unhandled output event: {:?}", event),
         }
-    }
 
-    fn handle_keyframe_request(&mut self, req: KeyframeRequest) {
-        let Some(MidOutSlot {
-            track_id: Some(track_id),
-            ..
-        }) = self.mid_out_slots.get(&req.mid)
-        else {
+        let Some((offer, pending)) = change.apply() else {
             return;
         };
 
-        let Some(track) = self.available_tracks.get(&track_id.internal) else {
+        tracing::info!("renegotiating to add downstream transceivers");
+        self.pending_offer = Some(pending);
+        self.pending_offer_deadline = Some(Instant::now() + RENEGOTIATION_TIMEOUT);
+        self.send_server_event(Payload::Offer(sfu::SdpOfferPayload {
+            sdp: offer.to_sdp_string(),
+        }));
+    }
+
+    /// Rolls back an offer the client hasn't answered in time. The transceivers are only
+    /// added once an answer is accepted, so dropping the pending offer is enough. The
+    /// next track change renegotiates again.
+    fn expire_pending_offer(&mut self, now: Instant) {
+        use sfu::server_message::Payload;
+
+        if self
+            .pending_offer_deadline
+            .is_none_or(|deadline| now < deadline)
+        {
             return;
-        };
+        }
 
-        track.handle.request_keyframe(req.into());
+        tracing::warn!("renegotiation offer was not answered in time, rolling it back");
+        self.pending_offer = None;
+        self.pending_offer_deadline = None;
+        self.send_server_event(Payload::Error(sfu::ErrorPayload {
+            description: "renegotiation offer timed out".to_string(),
+        }));
     }
 
-    async fn handle_new_media(&mut self, media: MediaAdded) {
-        match media.direction {
-            // client -> SFU
-            Direction::RecvOnly => {
+    /// Tracks stop on their own only after a failure. Subscribers would wait on the dead
+    /// track forever, so it's unpublished as if the client stopped sending it.
+    async fn handle_track_exited(&mut self, track_id: Arc<TrackId>, status: ActorStatus) {
+        if !status.is_failure() {
+            return;
+        }
+
+        let mid = track_id.origin_mid;
+        if self
+            .published_tracks
+            .get(&mid)
+            .is_some_and(|track| track.meta.id == track_id)
+        {
+            self.published_tracks.remove(&mid);
+        }
+        tracing::warn!(?track_id, "unpublished a failed track");
+        if let Err(err) = self.room.unpublish(track_id).await {
+            tracing::warn!("failed to unpublish track from room: {err}");
+        }
+    }
+
+    async fn handle_media_changed(&mut self, media: MediaChanged) {
+        if media.direction.is_receiving() {
+            return;
+        }
+
+        // client stopped publishing on this mid
+        if let Some(track) = self.published_tracks.remove(&media.mid) {
+            tracing::info!(track_id = ?track.meta.id, "unpublished track");
+            if let Err(err) = self.room.unpublish(track.meta.id.clone()).await {
+                tracing::warn!("failed to unpublish track from room: {err}");
+            }
+        }
+    }
+
+    /// Switches `mid` to `track_id`. The track is moved if it's already assigned to
+    /// another mid, and the track previously on `mid` is unsubscribed.
+    async fn handle_subscribe(&mut self, mid: Mid, track_id: &EntityId) {
+        let Some(slot) = self.mid_out_slots.get(&mid) else {
+            tracing::warn!(%mid, "mid slot is not found, ignoring subscribe");
+            return;
+        };
+        let Some(track) = self.available_tracks.get(track_id) else {
+            tracing::warn!(track_id, "track is not available, ignoring subscribe");
+            return;
+        };
+
+        if track.handle.meta.kind != slot.kind {
+            tracing::warn!(%mid, track_id, "track kind doesn't match the mid slot");
+            return;
+        }
+
+        let handle = track.handle.clone();
+        let previous_mid = track.mid;
+        let rid = track.rid;
+        if previous_mid == Some(mid) {
+            return;
+        }
+
+        // Still subscribed to the track actor when the track is only moved between mids
+        if previous_mid.is_none() {
+            if let Err(err) = handle.subscribe(self.handle.clone(), rid).await {
+                tracing::warn!("failed to subscribe to track: {err}");
+                return;
+            }
+        }
+
+        let mut switches = Vec::with_capacity(2);
+        if let Some(previous_mid) = previous_mid {
+            if let Some(previous_slot) = self.mid_out_slots.get_mut(&previous_mid) {
+                previous_slot.track_id = None;
+            }
+            switches.push((previous_mid, None));
+        }
+
+        if let Some(track) = self.available_tracks.get_mut(track_id) {
+            track.mid = Some(mid);
+            track.allocated = rid;
+            track.paused = false;
+        }
+        let last_track = self
+            .mid_out_slots
+            .get_mut(&mid)
+            .and_then(|slot| slot.track_id.replace(handle.meta.id.clone()));
+        if let Some(last_track) = last_track {
+            self.handle_unsubscribe(last_track).await;
+        }
+
+        switches.push((mid, Some(handle)));
+        self.send_track_switched(switches);
+    }
+
+    /// Selects the simulcast layer of the track on `mid`. An empty `rid` falls back to
+    /// the track's default layer.
+    async fn handle_select_layer(&mut self, mid: Mid, rid: &str) {
+        let Some(MidOutSlot {
+            track_id: Some(track_id),
+            ..
+        }) = self.mid_out_slots.get(&mid)
+        else {
+            tracing::warn!(%mid, "no track is assigned to the mid, ignoring layer selection");
+            return;
+        };
+
+        let Some(track) = self.available_tracks.get_mut(&track_id.internal) else {
+            return;
+        };
+
+        let rid = if rid.is_empty() {
+            track.handle.meta.default_rid()
+        } else {
+            let rid = Rid::from(rid);
+            if !track.handle.meta.rids().any(|r| r == rid) {
+                tracing::warn!(%mid, %rid, "track doesn't have the simulcast layer");
+                return;
+            }
+            Some(rid)
+        };
+
+        if track.rid == rid {
+            return;
+        }
+
+        track.rid = rid;
+        if self.egress_estimate.is_some() {
+            // the selection caps the layer, the downlink may not afford it
+            self.allocate_bitrate().await;
+        } else {
+            let track_id = track_id.internal.clone();
+            self.select_layer(&track_id, rid).await;
+        }
+    }
+
+    /// Splits the downlink estimate across the subscribed tracks. Audio is always
+    /// forwarded, video tracks are downgraded or paused when the estimate drops.
+    async fn allocate_bitrate(&mut self) {
+        let Some(estimate) = self.egress_estimate else {
+            return;
+        };
+
+        let mut available = estimate * bwe::BWE_HEADROOM_PERCENT / 100;
+        let mut desired = 0;
+        let mut candidates = Vec::new();
+        for (track_id, track) in &self.available_tracks {
+            if track.mid.is_none() {
+                continue;
+            }
+
+            let bitrates = track.handle.bitrates.borrow().clone();
+            if !bitrates.is_measured() {
+                // nothing to decide on until the track actor has measured its layers
+                continue;
+            }
+
+            if !track.handle.meta.kind.is_video() {
+                let bitrate = bitrates.get(None).unwrap_or_default();
+                available = available.saturating_sub(bitrate);
+                desired += bitrate;
+                continue;
+            }
+
+            let layers = bitrates.up_to(track.rid);
+            if let Some((_, bitrate)) = layers.last() {
+                desired += bitrate;
+                candidates.push((track_id.clone(), layers));
+            }
+        }
+
+        // Allows the estimator to probe for more when the desired layers don't fit
+        self.rtc.bwe().set_desired_bitrate(Bitrate::bps(desired));
+
+        let budgets: Vec<Vec<u64>> = candidates
+            .iter()
+            .map(|(_, layers)| layers.iter().map(|(_, bitrate)| *bitrate).collect())
+            .collect();
+        let chosen = bwe::allocate(available, &budgets);
+
+        let mut current = 0;
+        for ((track_id, layers), chosen) in candidates.into_iter().zip(chosen) {
+            match chosen {
+                Some(index) => {
+                    let (rid, bitrate) = layers[index];
+                    current += bitrate;
+                    self.select_layer(&track_id, rid).await;
+                }
+                None => self.pause_layer(&track_id).await,
+            }
+        }
+        self.rtc.bwe().set_current_bitrate(Bitrate::bps(current));
+    }
+
+    /// Forwards the `rid` layer of a subscribed track, resuming it if it was paused.
+    async fn select_layer(&mut self, track_id: &EntityId, rid: Option<Rid>) {
+        let Some(track) = self.available_tracks.get_mut(track_id) else {
+            return;
+        };
+
+        if !track.paused && track.allocated == rid {
+            return;
+        }
+
+        tracing::debug!(track_id, ?rid, "switching layer");
+        track.allocated = rid;
+        track.paused = false;
+        if let Err(err) = track
+            .handle
+            .select_layer(self.participant_id.clone(), rid)
+            .await
+        {
+            tracing::warn!("failed to select layer: {err}");
+        }
+    }
+
+    async fn pause_layer(&mut self, track_id: &EntityId) {
+        let Some(track) = self.available_tracks.get_mut(track_id) else {
+            return;
+        };
+
+        if track.paused {
+            return;
+        }
+
+        tracing::debug!(track_id, "pausing track, downlink is congested");
+        track.paused = true;
+        if let Err(err) = track.handle.pause(self.participant_id.clone()).await {
+            tracing::warn!("failed to pause track: {err}");
+        }
+    }
+
+    /// Frees the mid slot, returns the switch that confirms it to the client. There is
+    /// nothing to confirm when no track is assigned to the mid.
+    async fn handle_unsubscribe_mid(&mut self, mid: Mid) -> Option<sfu::server_message::Payload> {
+        let last_track = self.mid_out_slots.get_mut(&mid)?.track_id.take()?;
+        self.handle_unsubscribe(last_track).await;
+        Some(track_switched(vec![(mid, None)]))
+    }
+
+    /// Stops forwarding of `track_id` to this participant. The caller is responsible for
+    /// clearing the mid slot.
+    async fn handle_unsubscribe(&mut self, track_id: Arc<TrackId>) {
+        let Some(track) = self.available_tracks.get_mut(&track_id.internal) else {
+            return;
+        };
+
+        track.mid = None;
+        if let Err(err) = track.handle.unsubscribe(self.participant_id.clone()).await {
+            tracing::warn!("failed to unsubscribe from track: {err}");
+        }
+    }
+
+    fn send_track_switched(&mut self, switches: Vec<(Mid, Option<TrackHandle>)>) {
+        self.send_server_event(track_switched(switches));
+    }
+
+    fn capture_packet(
+        &mut self,
+        direction: CaptureDirection,
+        src: SocketAddr,
+        dst: SocketAddr,
+        raw: &Bytes,
+    ) {
+        let Some(capture) = &self.capture else {
+            return;
+        };
+
+        if !capture.record(direction, src, dst, raw.clone()) {
+            self.capture = None;
+        }
+    }
+
+    async fn handle_output_transmit(&mut self, t: Transmit) {
+        let packet = EgressUDPPacket {
+            raw: Bytes::copy_from_slice(&t.contents),
+            dst: t.destination,
+        };
+        self.capture_packet(
+            CaptureDirection::Outbound,
+            t.source,
+            t.destination,
+            &packet.raw,
+        );
+        match t.proto {
+            net::Protocol::Udp if self.relayed_addrs.contains(&t.destination) => {
+                let _ = self.transport.turn.send(t.source, packet).await;
+            }
+            net::Protocol::Udp => {
+                let _ = self.transport.sink.send(packet).await;
+            }
+            net::Protocol::Tcp => {
+                let _ = self.transport.tcp.send(packet).await;
+            }
+            proto => tracing::warn!("dropped a packet with unsupported protocol: {proto:?}"),
+        }
+    }
+
+    async fn handle_output_event(&mut self, event: Event) {
+        match event {
+            // Abort if we disconnect.
+            Event::IceConnectionStateChange(ice_state) => match ice_state {
+                str0m::IceConnectionState::Disconnected => self.rtc.disconnect(),
+                state => tracing::trace!("ice state: {:?}", state),
+            },
+            Event::MediaAdded(e) => {
+                self.handle_new_media(e).await;
+            }
+            Event::MediaChanged(e) => {
+                self.handle_media_changed(e).await;
+            }
+            Event::ChannelOpen(cid, label) => {
+                if label == DATA_CHANNEL_LABEL {
+                    self.cid = Some(cid);
+                    tracing::warn!(label, "data channel is open");
+                    // Events before the channel is open are lost, catch the client up
+                    self.send_room_snapshot();
+                    self.open_data_channels();
+                } else if self.data_out.get(label.as_str()) == Some(&cid) {
+                    tracing::debug!(label, "data channel opened by the sfu is open");
+                } else {
+                    // Opened by the client, publishes the label as it was opened
+                    tracing::info!(label, "data channel is open");
+                    let label: Arc<str> = Arc::from(label);
+                    self.data_out.entry(label.clone()).or_insert(cid);
+                    if self.config.grants.can_publish_data {
+                        let reliable = self.is_reliable(cid);
+                        self.publish_data(cid, label, reliable).await;
+                    }
+                }
+            }
+            Event::ChannelData(data) => {
+                if Some(data.id) == self.cid {
+                    if let Err(err) = self.handle_rpc(data).await {
+                        tracing::warn!("data channel dropped due to an error: {err}");
+                    }
+                } else if !self.config.grants.can_publish_data {
+                    tracing::warn!(channel = ?data.id, "participant is not allowed to publish data, dropping");
+                } else {
+                    self.handle_channel_data(data).await;
+                }
+            }
+            Event::ChannelClose(cid) => {
+                if Some(cid) == self.cid {
+                    self.rtc.disconnect();
+                } else {
+                    tracing::info!("channel closed: {:?}", cid);
+                    if let Some(channel) = self.data_in.remove(&cid) {
+                        if let Err(err) = self.room.unpublish_data(channel).await {
+                            tracing::warn!("failed to unpublish data channel from room: {err}");
+                        }
+                    }
+                    self.data_out.retain(|_, id| *id != cid);
+                }
+            }
+            Event::MediaData(e) => {
+                if let Some(track) = self.published_tracks.get(&e.mid) {
+                    let _ = track.forward_media(Arc::new(e)).await;
+                }
+            }
+            Event::KeyframeRequest(req) => self.handle_keyframe_request(req),
+            Event::EgressBitrateEstimate(estimate) => {
+                let bitrate = match estimate {
+                    BweKind::Twcc(bitrate) => bitrate,
+                    BweKind::Remb(_, bitrate) => bitrate,
+                };
+                tracing::trace!(%bitrate, "egress bitrate estimate");
+                self.egress_estimate = Some(bitrate.as_u64());
+                self.allocate_bitrate().await;
+            }
+            Event::Connected => {
+                tracing::info!("connected");
+            }
+            Event::MediaEgressStats(stats) => {
+                let counters = StreamCounters {
+                    packets: stats.packets,
+                    nacks: stats.nacks,
+                };
+                let nacks =
+                    record_stream(&mut self.egress_streams, (stats.mid, stats.rid), counters);
+                metrics::counter!(telemetry::RTP_NACKS, "direction" => "received").increment(nacks);
+                self.publish_stats();
+            }
+            Event::MediaIngressStats(stats) => {
+                let counters = StreamCounters {
+                    packets: stats.packets,
+                    nacks: stats.nacks,
+                };
+                let nacks =
+                    record_stream(&mut self.ingress_streams, (stats.mid, stats.rid), counters);
+                metrics::counter!(telemetry::RTP_NACKS, "direction" => "sent").increment(nacks);
+                self.publish_stats();
+            }
+            Event::PeerStats(_) => {}
+            event => tracing::warn!("unhandled output event: {:?}", event),
+        }
+    }
+
+    fn publish_stats(&self) {
+        let egress = StreamCounters::sum(self.egress_streams.values());
+        let ingress = StreamCounters::sum(self.ingress_streams.values());
+        self.stats.send_replace(ParticipantStats {
+            packets_sent: egress.packets,
+            packets_received: ingress.packets,
+            nacks_received: egress.nacks,
+            nacks_sent: ingress.nacks,
+            egress_nacks_per_packet: egress.nacks_per_packet(),
+            ingress_nacks_per_packet: ingress.nacks_per_packet(),
+            ..ParticipantStats::new(&self.config.rtx)
+        });
+    }
+
+    fn handle_keyframe_request(&mut self, req: KeyframeRequest) {
+        let Some(MidOutSlot {
+            track_id: Some(track_id),
+            ..
+        }) = self.mid_out_slots.get(&req.mid)
+        else {
+            return;
+        };
+
+        let Some(track) = self.available_tracks.get(&track_id.internal) else {
+            return;
+        };
+
+        // The subscriber only sees a single layer, request it from the same layer
+        let mut req: message::KeyframeRequest = req.into();
+        req.rid = track.allocated;
+        track.handle.request_keyframe(req);
+    }
+
+    async fn handle_new_media(&mut self, media: MediaAdded) {
+        match media.direction {
+            // client -> SFU
+            Direction::RecvOnly => {
+                if !self.config.grants.can_publish {
+                    tracing::warn!(
+                        ?media,
+                        "participant is not allowed to publish, ignoring media"
+                    );
+                    return;
+                }
+
                 tracing::info!(?media, "handle_new_media from client");
                 // TODO: handle back pressure by buffering temporarily
                 let track_id = TrackId::new(&mut self.rng, self.participant_id.clone(), media.mid);
@@ -468,14 +1515,13 @@ impl ParticipantActor {
                     simulcast: media.simulcast,
                 };
 
-                let (handle, actor) = TrackHandle::new(self.handle.clone(), Arc::new(track));
-                self.track_tasks.spawn(
-                    async move {
-                        actor::run(actor).await;
-                        track_id
-                    }
-                    .in_current_span(),
+                let (handle, actor) = TrackHandle::new(
+                    self.handle.clone(),
+                    Arc::new(track),
+                    self.channels,
+                    &self.config.media,
                 );
+                self.track_tasks.spawn(track_id, actor);
                 if let Err(err) = self.room.publish(handle).await {
                     // this participant should get cleaned up by the supervisor
                     tracing::warn!("failed to publish track to room: {err}");
@@ -484,16 +1530,16 @@ impl ParticipantActor {
             // SFU -> client
             Direction::SendOnly => {
                 tracing::info!(?media, "handle_new_media from other participant");
-                self.mid_out_slots.insert(
-                    media.mid,
-                    MidOutSlot {
+                self.mid_out_slots
+                    .entry(media.mid)
+                    .or_insert_with(|| MidOutSlot {
                         kind: media.kind,
                         simulcast: media.simulcast,
                         track_id: None,
-                    },
-                );
+                    });
 
                 self.reconfigure_downstreams().await;
+                self.renegotiate_downstreams();
             }
             dir => {
                 tracing::warn!("{dir} transceiver is unsupported, shutdown misbehaving client");
@@ -527,7 +1573,202 @@ impl ParticipantActor {
         }
     }
 
+    /// Opens a channel for every remote label that this participant can't receive on
+    /// yet. The SCTP association is only known to be up once the rpc channel is open.
+    fn open_data_channels(&mut self) {
+        if self.cid.is_none() {
+            return;
+        }
+
+        let labels: Vec<_> = self
+            .remote_data_channels
+            .iter()
+            .map(|channel| (channel.label.clone(), channel.reliable))
+            .collect();
+        for (label, reliable) in labels {
+            if !self.data_out.contains_key(&label) {
+                self.open_data_channel(label, reliable);
+            }
+        }
+    }
+
+    fn open_data_channel(&mut self, label: Arc<str>, reliable: bool) -> ChannelId {
+        let reliability = if reliable {
+            Reliability::Reliable
+        } else {
+            Reliability::MaxRetransmits { retransmits: 0 }
+        };
+        let cid = self.rtc.direct_api().create_data_channel(ChannelConfig {
+            label: label.to_string(),
+            ordered: reliable,
+            reliability,
+            negotiated: None,
+            protocol: String::new(),
+        });
+        tracing::debug!(%label, reliable, "opening data channel");
+        self.data_out.insert(label, cid);
+        cid
+    }
+
+    /// Reads the reliability a channel was negotiated with, reliable unless it says otherwise.
+    fn is_reliable(&mut self, cid: ChannelId) -> bool {
+        self.rtc
+            .channel(cid)
+            .and_then(|channel| {
+                channel
+                    .config()
+                    .map(|config| matches!(config.reliability, Reliability::Reliable))
+            })
+            .unwrap_or(true)
+    }
+
+    async fn publish_data(
+        &mut self,
+        cid: ChannelId,
+        label: Arc<str>,
+        reliable: bool,
+    ) -> Arc<DataChannel> {
+        let channel = Arc::new(DataChannel {
+            origin: self.participant_id.clone(),
+            label,
+            reliable,
+        });
+        tracing::info!(label = %channel.label, reliable, "published data channel");
+        self.data_in.insert(cid, channel.clone());
+        if let Err(err) = self.room.publish_data(channel.clone()).await {
+            tracing::warn!("failed to publish data channel to room: {err}");
+        }
+        channel
+    }
+
+    async fn handle_publish_data(&mut self, publish: sfu::ClientPublishDataPayload) {
+        use sfu::server_message::Payload;
+
+        if !self.config.grants.can_publish_data || publish.label == DATA_CHANNEL_LABEL {
+            self.send_server_event(Payload::Error(sfu::ErrorPayload {
+                description: format!("not allowed to publish data on {}", publish.label),
+            }));
+            return;
+        }
+
+        let label: Arc<str> = Arc::from(publish.label);
+        let reliable = !publish.unreliable;
+        let cid = match self.data_out.get(&label) {
+            Some(cid) => *cid,
+            None => self.open_data_channel(label.clone(), reliable),
+        };
+        self.publish_data(cid, label, reliable).await;
+    }
+
+    /// Broadcasts a message written by the client to everyone else in the room. Writing
+    /// on a channel opened by the SFU publishes its label.
+    async fn handle_channel_data(&mut self, data: ChannelData) {
+        let channel = match self.data_in.get(&data.id) {
+            Some(channel) => channel.clone(),
+            None => {
+                let Some(label) = self
+                    .data_out
+                    .iter()
+                    .find(|(_, cid)| **cid == data.id)
+                    .map(|(label, _)| label.clone())
+                else {
+                    tracing::warn!(channel = ?data.id, "data on an unknown channel, dropping");
+                    return;
+                };
+                let reliable = self.is_reliable(data.id);
+                self.publish_data(data.id, label, reliable).await
+            }
+        };
+
+        self.forward_data_to_room(DataPacket {
+            channel,
+            payload: Bytes::from(data.data),
+            binary: data.binary,
+            destinations: None,
+        });
+    }
+
+    fn handle_send_data(&mut self, send: sfu::ClientSendDataPayload) {
+        use sfu::server_message::Payload;
+
+        let channel = self
+            .data_in
+            .values()
+            .find(|channel| *channel.label == send.label)
+            .cloned();
+        let Some(channel) = channel.filter(|_| self.config.grants.can_publish_data) else {
+            self.send_server_event(Payload::Error(sfu::ErrorPayload {
+                description: format!("data channel {} is not published", send.label),
+            }));
+            return;
+        };
+
+        if send.broadcast != send.destination_participant_ids.is_empty() {
+            self.send_server_event(Payload::Error(sfu::ErrorPayload {
+                description: format!(
+                    "data on {} needs either broadcast or destinations",
+                    send.label
+                ),
+            }));
+            return;
+        }
+
+        let mut destinations = Vec::with_capacity(send.destination_participant_ids.len());
+        for id in &send.destination_participant_ids {
+            match id.parse::<ExternalParticipantId>() {
+                Ok(id) => destinations.push(id),
+                Err(err) => {
+                    self.send_server_event(Payload::Error(sfu::ErrorPayload {
+                        description: format!("invalid destination participant id {id}: {err}"),
+                    }));
+                    return;
+                }
+            }
+        }
+
+        self.forward_data_to_room(DataPacket {
+            channel,
+            payload: Bytes::from(send.payload),
+            binary: send.binary,
+            destinations: (!send.broadcast).then_some(destinations),
+        });
+    }
+
+    /// Never waits on the room, the client is told when reliable data is dropped.
+    fn forward_data_to_room(&mut self, packet: DataPacket) {
+        use sfu::server_message::Payload;
+
+        let channel = packet.channel.clone();
+        match self.room.forward_data(Arc::new(packet)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if channel.reliable {
+                    self.send_server_event(Payload::Error(sfu::ErrorPayload {
+                        description: format!("room is busy, data on {} is dropped", channel.label),
+                    }));
+                }
+            }
+            Err(err) => tracing::warn!("failed to forward data to room: {err}"),
+        }
+    }
+
+    fn handle_forward_data(&mut self, packet: &DataPacket) {
+        let Some(cid) = self.data_out.get(&packet.channel.label) else {
+            return;
+        };
+
+        // Not open yet, the data is lost like any other message before the channel opens
+        let Some(mut channel) = self.rtc.channel(*cid) else {
+            return;
+        };
+
+        if let Err(err) = channel.write(packet.binary, &packet.payload) {
+            tracing::warn!(label = %packet.channel.label, "failed to write data: {err}");
+        }
+    }
+
     async fn reconfigure_downstreams(&mut self) {
+        let mut switches = Vec::new();
         for (mid, mid_slot) in &mut self.mid_out_slots {
             if mid_slot.track_id.is_some() {
                 continue;
@@ -539,18 +1780,59 @@ impl ParticipantActor {
                 }
 
                 if track.handle.meta.kind == mid_slot.kind {
-                    let Ok(_) = track.handle.subscribe(self.handle.clone()).await else {
+                    let Ok(_) = track.handle.subscribe(self.handle.clone(), track.rid).await else {
                         continue;
                     };
                     track.mid = Some(*mid);
+                    track.allocated = track.rid;
+                    track.paused = false;
                     mid_slot.track_id = Some(track.handle.meta.id.clone());
+                    switches.push((*mid, Some(track.handle.clone())));
                     break;
                 }
             }
         }
+
+        if !switches.is_empty() {
+            self.send_track_switched(switches);
+        }
+    }
+}
+
+fn data_channel_info(channel: &DataChannel) -> sfu::DataChannelInfo {
+    sfu::DataChannelInfo {
+        label: channel.label.to_string(),
+        participant_id: channel.origin.external.to_string(),
+        reliable: channel.reliable,
+    }
+}
+
+fn track_info(track: &TrackHandle) -> sfu::TrackInfo {
+    let kind = if track.meta.kind.is_video() {
+        sfu::TrackKind::Video
+    } else {
+        sfu::TrackKind::Audio
+    };
+
+    sfu::TrackInfo {
+        track_id: track.meta.id.to_string(),
+        kind: kind as i32,
+        participant_id: track.meta.id.origin_participant.to_string(),
+        rids: track.meta.rids().map(|rid| rid.to_string()).collect(),
     }
 }
 
+fn track_switched(switches: Vec<(Mid, Option<TrackHandle>)>) -> sfu::server_message::Payload {
+    let switches = switches
+        .into_iter()
+        .map(|(mid, track)| sfu::TrackSwitchInfo {
+            mid: mid.to_string(),
+            remote_track: track.as_ref().map(track_info),
+        })
+        .collect();
+    sfu::server_message::Payload::TrackSwitched(sfu::TrackSwitchedPayload { switches })
+}
+
 #[derive(Clone, Debug)]
 pub struct ParticipantHandle {
     pub data_sender: mpsc::Sender<ParticipantDataMessage>,
@@ -561,34 +1843,50 @@ pub struct ParticipantHandle {
 impl ParticipantHandle {
     pub fn new(
         rng: Rng,
-        source: UdpSourceHandle,
-        sink: UdpSinkHandle,
+        transport: Transport,
         room: RoomHandle,
         participant_id: Arc<ParticipantId>,
         rtc: Rtc,
+        config: ParticipantConfig,
+        channels: ChannelCapacities,
     ) -> (Self, ParticipantActor) {
-        let (data_sender, data_receiver) = mpsc::channel(128);
-        let (control_sender, control_receiver) = mpsc::channel(8);
+        let (data_sender, data_receiver) = mpsc::channel(channels.participant_data);
+        let (control_sender, control_receiver) = mpsc::channel(channels.participant_control);
         let handle = Self {
             data_sender,
             control_sender,
             participant_id: participant_id.clone(),
         };
+        let (stats, _) = watch::channel(ParticipantStats::new(&config.rtx));
         let actor = ParticipantActor {
             rng,
-            source,
+            transport,
             handle: handle.clone(),
             data_receiver,
             control_receiver,
-            sink,
             room,
             participant_id,
             rtc,
-            track_tasks: JoinSet::new(),
+            config,
+            channels,
+            track_tasks: ChildSet::default(),
+            capture: None,
+            capture_tasks: ChildSet::default(),
+            remote_participants: HashMap::new(),
             published_tracks: HashMap::new(),
             available_tracks: HashMap::new(),
             mid_out_slots: HashMap::new(),
+            data_in: HashMap::new(),
+            data_out: HashMap::new(),
+            remote_data_channels: Vec::new(),
+            egress_streams: HashMap::new(),
+            ingress_streams: HashMap::new(),
+            stats,
             cid: None,
+            pending_offer: None,
+            pending_offer_deadline: None,
+            egress_estimate: None,
+            relayed_addrs: HashSet::new(),
         };
         (handle, actor)
     }
@@ -602,29 +1900,253 @@ impl ParticipantHandle {
             .try_send(ParticipantDataMessage::UdpPacket(msg));
 
         if let Err(err) = &res {
+            telemetry::record_dropped("participant_forward");
             tracing::warn!("raw packet is dropped: {err}");
         }
         res
     }
 
-    pub async fn add_tracks(
+    /// Dropped when the mailbox is full, like [`Self::add_participants`].
+    pub fn add_tracks(
         &self,
         tracks: Arc<Vec<TrackHandle>>,
-    ) -> Result<(), SendError<ParticipantControlMessage>> {
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::TracksAdded(tracks));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_tracks_added");
+            tracing::warn!("tracks added event is dropped: {err}");
+        }
+        res
+    }
+
+    /// Dropped when the mailbox is full, like [`Self::add_participants`].
+    pub fn remove_tracks(
+        &self,
+        track_ids: Arc<Vec<Arc<TrackId>>>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::TracksRemoved(track_ids));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_tracks_removed");
+            tracing::warn!("tracks removed event is dropped: {err}");
+        }
+        res
+    }
+
+    /// Dropped when the mailbox is full, the room doesn't wait on a busy participant. It
+    /// sends a [`RoomSnapshot`] once there's room again, see [`Self::sync_room`].
+    pub fn add_participants(
+        &self,
+        participants: Arc<Vec<RemoteParticipant>>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::ParticipantsJoined(participants));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_joined");
+            tracing::warn!("participant joined event is dropped: {err}");
+        }
+        res
+    }
+
+    /// Dropped when the mailbox is full, like [`Self::add_participants`].
+    pub fn remove_participant(
+        &self,
+        participant_id: Arc<ParticipantId>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::ParticipantLeft(participant_id));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_left");
+            tracing::warn!("participant left event is dropped: {err}");
+        }
+        res
+    }
+
+    pub async fn update_ice(&self, update: IceUpdate) -> Result<IceSession, ParticipantError> {
+        let (tx, rx) = oneshot::channel();
         self.control_sender
-            .send(ParticipantControlMessage::TracksAdded(tracks))
+            .send(ParticipantControlMessage::UpdateIce(update, tx))
             .await
+            .map_err(|_| ParticipantError::Gone)?;
+        rx.await.map_err(|_| ParticipantError::Gone)?
     }
 
-    pub async fn remove_tracks(
+    /// Dropped when the mailbox is full, like [`Self::add_participants`].
+    pub fn add_data_channels(
         &self,
-        track_ids: Arc<Vec<Arc<TrackId>>>,
-    ) -> Result<(), SendError<ParticipantControlMessage>> {
+        channels: Arc<Vec<Arc<DataChannel>>>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::DataChannelsAdded(channels));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_data_channels_added");
+            tracing::warn!("data channels added event is dropped: {err}");
+        }
+        res
+    }
+
+    /// Closed data channels, see [`Self::add_data_channels`].
+    pub fn remove_data_channels(
+        &self,
+        channels: Arc<Vec<Arc<DataChannel>>>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::DataChannelsRemoved(channels));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_data_channels_removed");
+            tracing::warn!("data channels removed event is dropped: {err}");
+        }
+        res
+    }
+
+    /// Reliable data, dropped when the mailbox is full so the room never waits on a busy
+    /// participant.
+    pub fn send_data(
+        &self,
+        packet: Arc<DataPacket>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::ForwardData(packet));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_send_data");
+            tracing::warn!("reliable data is dropped: {err}");
+        }
+        res
+    }
+
+    /// Tells the publisher that reliable data was dropped, dropped itself when the
+    /// publisher's mailbox is full too.
+    pub fn data_dropped(
+        &self,
+        channel: Arc<DataChannel>,
+        receivers: Vec<ExternalParticipantId>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::DataDropped(channel, receivers));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_data_dropped");
+            tracing::warn!("data dropped event is dropped: {err}");
+        }
+        res
+    }
+
+    /// Unreliable data, dropped when the mailbox is full.
+    pub fn forward_data(
+        &self,
+        packet: Arc<DataPacket>,
+    ) -> Result<(), TrySendError<ParticipantDataMessage>> {
+        let res = self
+            .data_sender
+            .try_send(ParticipantDataMessage::ForwardData(packet));
+
+        if res.is_err() {
+            telemetry::record_dropped("participant_forward_data");
+        }
+        res
+    }
+
+    /// Dropped when the mailbox is full, the room resends it with the time that's left.
+    pub fn going_away(
+        &self,
+        deadline: Duration,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::GoingAway(deadline));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_going_away");
+            tracing::warn!("going away event is dropped: {err}");
+        }
+        res
+    }
+
+    /// Dropped when the mailbox is full, the capture isn't started then.
+    pub fn start_capture(
+        &self,
+        path: PathBuf,
+        limits: CaptureLimits,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::StartCapture(path, limits));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_start_capture");
+            tracing::warn!("capture request is dropped: {err}");
+        }
+        res
+    }
+
+    /// Dropped when the mailbox is full, like [`Self::add_participants`].
+    pub fn sync_room(
+        &self,
+        snapshot: Arc<RoomSnapshot>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::RoomSynced(snapshot));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_room_synced");
+            tracing::warn!("room snapshot is dropped: {err}");
+        }
+        res
+    }
+
+    /// Dropped when the mailbox is full, like [`Self::add_participants`]. The room sends the
+    /// latest speakers again once the mailbox has room.
+    pub fn active_speakers_changed(
+        &self,
+        speakers: Arc<ActiveSpeakers<Arc<ParticipantId>>>,
+    ) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::ActiveSpeakersChanged(speakers));
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_active_speakers");
+            tracing::warn!("active speakers event is dropped: {err}");
+        }
+        res
+    }
+
+    pub async fn disconnect(&self) -> Result<(), SendError<ParticipantControlMessage>> {
         self.control_sender
-            .send(ParticipantControlMessage::TracksRemoved(track_ids))
+            .send(ParticipantControlMessage::Disconnect)
             .await
     }
 
+    /// Like [`Self::disconnect`] for the room, which retries when the mailbox is full.
+    pub fn try_disconnect(&self) -> Result<(), TrySendError<ParticipantControlMessage>> {
+        let res = self
+            .control_sender
+            .try_send(ParticipantControlMessage::Disconnect);
+
+        if let Err(err) = &res {
+            telemetry::record_dropped("participant_disconnect");
+            tracing::warn!("disconnect is dropped: {err}");
+        }
+        res
+    }
+
     pub fn forward_media(
         &self,
         track: Arc<TrackIn>,
@@ -635,6 +2157,7 @@ impl ParticipantHandle {
             .try_send(ParticipantDataMessage::ForwardMedia(track, data));
 
         if let Err(err) = &res {
+            telemetry::record_dropped("participant_forward_media");
             tracing::warn!("media packet is dropped: {err}");
         }
 
@@ -646,6 +2169,7 @@ impl ParticipantHandle {
             .data_sender
             .try_send(ParticipantDataMessage::KeyframeRequest(track_id, req))
         {
+            telemetry::record_dropped("participant_request_keyframe");
             tracing::warn!("keyframe request is dropped by the participant actor: {err}");
         }
     }
@@ -656,3 +2180,330 @@ impl Display for ParticipantHandle {
         f.write_str(self.participant_id.deref().as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bwe::LayerBitrates,
+        config::CaptureConfig,
+        controller::testing,
+        entity::{ExternalRoomId, RoomId},
+        room::RoomMessage,
+        track::TrackControlMessage,
+    };
+    use rand::SeedableRng;
+
+    async fn participant() -> ParticipantActor {
+        let mut rng = Rng::seed_from_u64(1);
+        let (transport, _) = testing::spawn_transport().await;
+        let room_id = ExternalRoomId::new("room".to_string()).unwrap();
+        let (room, _) = RoomHandle::new(
+            rng.clone(),
+            Arc::new(RoomId::new(room_id)),
+            RoomLimits::default(),
+            ChannelCapacities::default(),
+            PathBuf::new(),
+            CaptureConfig::default(),
+        );
+        let external = ExternalParticipantId::new("alice".to_string()).unwrap();
+        let participant_id = Arc::new(ParticipantId::new(&mut rng, external));
+        let (_, actor) = ParticipantHandle::new(
+            rng,
+            transport,
+            room,
+            participant_id,
+            Rtc::new(),
+            ParticipantConfig::default(),
+            ChannelCapacities::default(),
+        );
+        actor
+    }
+
+    /// Points the participant at a room with a single slot mailbox, the messages it sends
+    /// the room end up in the receiver.
+    fn probe_room(participant: &mut ParticipantActor) -> mpsc::Receiver<RoomMessage> {
+        let (sender, receiver) = mpsc::channel(1);
+        participant.room = RoomHandle {
+            sender,
+            room_id: participant.room.room_id.clone(),
+        };
+        receiver
+    }
+
+    /// A track of another participant, its control messages end up in the receiver.
+    fn remote_track(kind: MediaKind) -> (TrackHandle, mpsc::Receiver<TrackControlMessage>) {
+        let mut rng = Rng::seed_from_u64(2);
+        let external = ExternalParticipantId::new("bob".to_string()).unwrap();
+        let origin = Arc::new(ParticipantId::new(&mut rng, external));
+        let meta = Arc::new(TrackIn {
+            id: Arc::new(TrackId::new(&mut rng, origin, Mid::from("0"))),
+            kind,
+            simulcast: None,
+        });
+        let (data_sender, _) = mpsc::channel(1);
+        let (control_sender, control_receiver) = mpsc::channel(8);
+        let (_, bitrates) = watch::channel(LayerBitrates::default());
+        let (_, codec) = watch::channel(None);
+        let (_, audio_level) = watch::channel(None);
+        let track = TrackHandle {
+            data_sender,
+            control_sender,
+            meta,
+            bitrates,
+            codec,
+            audio_level,
+        };
+        (track, control_receiver)
+    }
+
+    /// Leaves an SFU offer for a new downstream transceiver pending, like a renegotiation.
+    fn renegotiate(participant: &mut ParticipantActor) -> String {
+        let mut change = participant.rtc.sdp_api();
+        change.add_media(MediaKind::Video, Direction::SendOnly, None, None, None);
+        let (offer, pending) = change.apply().unwrap();
+        participant.pending_offer = Some(pending);
+        participant.pending_offer_deadline = Some(Instant::now() + RENEGOTIATION_TIMEOUT);
+        offer.to_sdp_string()
+    }
+
+    fn answer(offer: &str) -> sfu::SdpAnswerPayload {
+        let offer = SdpOffer::from_sdp_string(offer).unwrap();
+        let answer = Rtc::new().sdp_api().accept_offer(offer).unwrap();
+        sfu::SdpAnswerPayload {
+            sdp: answer.to_sdp_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offer_collision_keeps_pending_offer() {
+        let mut participant = participant().await;
+        let offer = renegotiate(&mut participant);
+
+        // The client's offer loses, the SFU keeps waiting for its answer
+        participant
+            .handle_remote_offer(sfu::SdpOfferPayload {
+                sdp: testing::offer(),
+            })
+            .unwrap();
+        assert!(participant.pending_offer.is_some());
+
+        participant.handle_remote_answer(answer(&offer)).unwrap();
+        assert!(participant.pending_offer.is_none());
+        assert!(participant.pending_offer_deadline.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_answer_without_offer_is_ignored() {
+        let mut participant = participant().await;
+        let offer = renegotiate(&mut participant);
+        participant.pending_offer = None;
+        participant.pending_offer_deadline = None;
+
+        participant.handle_remote_answer(answer(&offer)).unwrap();
+        assert!(participant.pending_offer.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_offer_is_rolled_back() {
+        let mut participant = participant().await;
+        let offer = renegotiate(&mut participant);
+        let deadline = participant.pending_offer_deadline.unwrap();
+
+        participant.expire_pending_offer(deadline - Duration::from_millis(1));
+        assert!(participant.pending_offer.is_some());
+        participant.expire_pending_offer(deadline);
+        assert!(participant.pending_offer.is_none());
+        assert!(participant.pending_offer_deadline.is_none());
+
+        // A late answer no longer applies
+        participant.handle_remote_answer(answer(&offer)).unwrap();
+        assert!(participant.pending_offer.is_none());
+
+        // The next renegotiation starts over
+        renegotiate(&mut participant);
+        assert!(participant.pending_offer.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_frees_mid_slot() {
+        let mut participant = participant().await;
+        let (track, mut track_control) = remote_track(MediaKind::Audio);
+        let track_id = track.meta.id.clone();
+        participant.available_tracks.insert(
+            track_id.internal.clone(),
+            TrackOut {
+                handle: track,
+                mid: None,
+                rid: None,
+                allocated: None,
+                paused: false,
+            },
+        );
+        let mid = Mid::from("1");
+        participant.mid_out_slots.insert(
+            mid,
+            MidOutSlot {
+                kind: MediaKind::Audio,
+                simulcast: None,
+                track_id: None,
+            },
+        );
+
+        participant.handle_subscribe(mid, &track_id.internal).await;
+        assert!(matches!(
+            track_control.try_recv(),
+            Ok(TrackControlMessage::Subscribe(_, None))
+        ));
+        assert_eq!(
+            participant.mid_out_slots[&mid].track_id,
+            Some(track_id.clone())
+        );
+
+        let Some(sfu::server_message::Payload::TrackSwitched(switched)) =
+            participant.handle_unsubscribe_mid(mid).await
+        else {
+            panic!("unsubscribe is not confirmed");
+        };
+        assert_eq!(
+            switched.switches,
+            vec![sfu::TrackSwitchInfo {
+                mid: mid.to_string(),
+                remote_track: None,
+            }]
+        );
+        assert!(participant.mid_out_slots[&mid].track_id.is_none());
+        assert!(
+            participant.available_tracks[&track_id.internal]
+                .mid
+                .is_none()
+        );
+        assert!(matches!(
+            track_control.try_recv(),
+            Ok(TrackControlMessage::Unsubscribe(_))
+        ));
+
+        // Nothing left to confirm, and the freed slot takes a track again
+        assert!(participant.handle_unsubscribe_mid(mid).await.is_none());
+        participant.handle_subscribe(mid, &track_id.internal).await;
+        assert_eq!(participant.mid_out_slots[&mid].track_id, Some(track_id));
+    }
+
+    #[tokio::test]
+    async fn test_failed_track_is_unpublished() {
+        let mut participant = participant().await;
+        let (track, _control) = remote_track(MediaKind::Audio);
+        let track_id = track.meta.id.clone();
+        let mid = track_id.origin_mid;
+        participant.published_tracks.insert(mid, track);
+
+        participant
+            .handle_track_exited(track_id.clone(), ActorStatus::ShutDown)
+            .await;
+        assert!(participant.published_tracks.contains_key(&mid));
+
+        participant
+            .handle_track_exited(track_id, ActorStatus::Panicked)
+            .await;
+        assert!(!participant.published_tracks.contains_key(&mid));
+    }
+
+    #[tokio::test]
+    async fn test_room_synced_replaces_state() {
+        let mut participant = participant().await;
+        let (track, _control) = remote_track(MediaKind::Audio);
+        let bob = track.meta.id.origin_participant.clone();
+        let snapshot = RoomSnapshot {
+            participants: vec![RemoteParticipant {
+                participant_id: bob.clone(),
+                metadata: None,
+            }],
+            tracks: vec![track.clone()],
+            data_channels: Vec::new(),
+        };
+
+        participant.handle_room_synced(&snapshot).await;
+        assert!(participant.remote_participants.contains_key(&bob));
+        assert!(
+            participant
+                .available_tracks
+                .contains_key(&track.meta.id.internal)
+        );
+
+        // Whatever the next snapshot leaves out is gone
+        participant
+            .handle_room_synced(&RoomSnapshot::default())
+            .await;
+        assert!(participant.remote_participants.is_empty());
+        assert!(participant.available_tracks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_data_destinations() {
+        let mut participant = participant().await;
+        let mut room = probe_room(&mut participant);
+        let label: Arc<str> = Arc::from("chat");
+        let cid = participant.open_data_channel(label.clone(), true);
+        let channel = Arc::new(DataChannel {
+            origin: participant.participant_id.clone(),
+            label,
+            reliable: true,
+        });
+        participant.data_in.insert(cid, channel);
+        let send = |ids: &[&str], broadcast| sfu::ClientSendDataPayload {
+            label: "chat".to_string(),
+            payload: b"hello".to_vec(),
+            destination_participant_ids: ids.iter().map(|id| id.to_string()).collect(),
+            binary: false,
+            broadcast,
+        };
+
+        // Rejected as a whole, nothing reaches the room
+        participant.handle_send_data(send(&["bob", "not valid!"], false));
+        participant.handle_send_data(send(&["bob"], true));
+        participant.handle_send_data(send(&[], false));
+        assert!(room.try_recv().is_err());
+
+        participant.handle_send_data(send(&["bob"], false));
+        let Ok(RoomMessage::ForwardData(packet)) = room.try_recv() else {
+            panic!("data is not forwarded");
+        };
+        assert_eq!(
+            packet.destinations,
+            Some(vec![ExternalParticipantId::new("bob".to_string()).unwrap()])
+        );
+
+        participant.handle_send_data(send(&[], true));
+        let Ok(RoomMessage::ForwardData(packet)) = room.try_recv() else {
+            panic!("data is not forwarded");
+        };
+        assert!(packet.destinations.is_none());
+
+        // The room is busy, the second message is dropped instead of waited on
+        participant.handle_send_data(send(&[], true));
+        participant.handle_send_data(send(&[], true));
+        assert!(room.try_recv().is_ok());
+        assert!(room.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_stream_stats_folding() {
+        let mut streams = HashMap::new();
+        let video = (Mid::from("0"), Some(Rid::from("h")));
+        let audio = (Mid::from("1"), None);
+        let counters = |packets, nacks| StreamCounters { packets, nacks };
+
+        // Reports are cumulative, only the NACKs since the last one are new
+        assert_eq!(record_stream(&mut streams, video, counters(100, 4)), 4);
+        assert_eq!(record_stream(&mut streams, video, counters(300, 10)), 6);
+        assert_eq!(record_stream(&mut streams, audio, counters(100, 0)), 0);
+        // A stream that starts over doesn't count backwards
+        assert_eq!(record_stream(&mut streams, audio, counters(10, 0)), 0);
+
+        let total = StreamCounters::sum(streams.values());
+        assert_eq!(total.packets, 310);
+        assert_eq!(total.nacks, 10);
+        assert_eq!(total.nacks_per_packet(), 10.0 / 310.0);
+        assert_eq!(StreamCounters::default().nacks_per_packet(), 0.0);
+    }
+}
@@ -1,4 +1,16 @@
 // This file is @generated by prost-build.
+/// An SDP offer, either side may send one to renegotiate the session.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SdpOfferPayload {
+    #[prost(string, tag = "1")]
+    pub sdp: ::prost::alloc::string::String,
+}
+/// The SDP answer to the last received offer.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SdpAnswerPayload {
+    #[prost(string, tag = "1")]
+    pub sdp: ::prost::alloc::string::String,
+}
 /// Subscribe a remote_track_id to the mid slot
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ClientSubscribePayload {
@@ -15,10 +27,48 @@ pub struct ClientUnsubscribePayload {
     #[prost(string, tag = "1")]
     pub mid: ::prost::alloc::string::String,
 }
+/// Select the simulcast layer forwarded to the mid slot
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientSelectLayerPayload {
+    /// The client's MID that receives the remote track.
+    #[prost(string, tag = "1")]
+    pub mid: ::prost::alloc::string::String,
+    /// The RID of the simulcast layer, empty for the default layer.
+    #[prost(string, tag = "2")]
+    pub rid: ::prost::alloc::string::String,
+}
+/// Publish a data channel to the room, the SFU opens the channel with this label on
+/// the client and on everyone else. Opening a channel on the client publishes it with the
+/// reliability it was opened with.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientPublishDataPayload {
+    #[prost(string, tag = "1")]
+    pub label: ::prost::alloc::string::String,
+    /// Unordered without retransmits, messages may be dropped.
+    #[prost(bool, tag = "2")]
+    pub unreliable: bool,
+}
+/// Send a message on a published data channel to everyone else or to some participants only.
+/// The message is rejected with an error when a destination is not a valid participant ID.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientSendDataPayload {
+    #[prost(string, tag = "1")]
+    pub label: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: ::prost::alloc::vec::Vec<u8>,
+    /// The external IDs of the receivers, empty when broadcasting.
+    #[prost(string, repeated, tag = "3")]
+    pub destination_participant_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bool, tag = "4")]
+    pub binary: bool,
+    /// Send to everyone else in the room instead of the destinations.
+    #[prost(bool, tag = "5")]
+    pub broadcast: bool,
+}
 /// ClientMessage encapsulates all possible messages from client to SFU.
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ClientMessage {
-    #[prost(oneof = "client_message::Payload", tags = "1, 2")]
+    #[prost(oneof = "client_message::Payload", tags = "1, 2, 3, 4, 5, 6, 7")]
     pub payload: ::core::option::Option<client_message::Payload>,
 }
 /// Nested message and enum types in `ClientMessage`.
@@ -29,6 +79,18 @@ pub mod client_message {
         Subscribe(super::ClientSubscribePayload),
         #[prost(message, tag = "2")]
         Unsubscribe(super::ClientUnsubscribePayload),
+        /// Client adds or removes published media.
+        #[prost(message, tag = "3")]
+        Offer(super::SdpOfferPayload),
+        /// Client answers an offer from the SFU.
+        #[prost(message, tag = "4")]
+        Answer(super::SdpAnswerPayload),
+        #[prost(message, tag = "5")]
+        SelectLayer(super::ClientSelectLayerPayload),
+        #[prost(message, tag = "6")]
+        PublishData(super::ClientPublishDataPayload),
+        #[prost(message, tag = "7")]
+        SendData(super::ClientSendDataPayload),
     }
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -42,6 +104,9 @@ pub struct TrackInfo {
     /// The ID of the participant who published this track.
     #[prost(string, tag = "3")]
     pub participant_id: ::prost::alloc::string::String,
+    /// The simulcast layers of the track, empty if it's not simulcast.
+    #[prost(string, repeated, tag = "5")]
+    pub rids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TrackSwitchInfo {
@@ -69,6 +134,80 @@ pub struct TrackSwitchedPayload {
     pub switches: ::prost::alloc::vec::Vec<TrackSwitchInfo>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ParticipantInfo {
+    /// The external ID of the participant.
+    #[prost(string, tag = "1")]
+    pub participant_id: ::prost::alloc::string::String,
+    /// App-specific metadata from the participant's join token.
+    #[prost(string, optional, tag = "2")]
+    pub metadata: ::core::option::Option<::prost::alloc::string::String>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ParticipantJoinedPayload {
+    #[prost(message, repeated, tag = "1")]
+    pub participants: ::prost::alloc::vec::Vec<ParticipantInfo>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ParticipantLeftPayload {
+    /// The external IDs of the participants that left.
+    #[prost(string, repeated, tag = "1")]
+    pub participant_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DataChannelInfo {
+    #[prost(string, tag = "1")]
+    pub label: ::prost::alloc::string::String,
+    /// The ID of the participant who published this channel.
+    #[prost(string, tag = "2")]
+    pub participant_id: ::prost::alloc::string::String,
+    #[prost(bool, tag = "3")]
+    pub reliable: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DataChannelPublishedPayload {
+    #[prost(message, repeated, tag = "1")]
+    pub data_channels: ::prost::alloc::vec::Vec<DataChannelInfo>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DataChannelUnpublishedPayload {
+    #[prost(message, repeated, tag = "1")]
+    pub data_channels: ::prost::alloc::vec::Vec<DataChannelInfo>,
+}
+/// Current state of the room, sent once the data channel is open and again whenever the
+/// client may have missed updates. It replaces everything the client knew about the room.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RoomSnapshotPayload {
+    /// Other participants in the room.
+    #[prost(message, repeated, tag = "1")]
+    pub participants: ::prost::alloc::vec::Vec<ParticipantInfo>,
+    /// Tracks available to subscribe.
+    #[prost(message, repeated, tag = "2")]
+    pub remote_tracks: ::prost::alloc::vec::Vec<TrackInfo>,
+    /// Data channels published by the others.
+    #[prost(message, repeated, tag = "3")]
+    pub data_channels: ::prost::alloc::vec::Vec<DataChannelInfo>,
+}
+/// The server is shutting down, reconnect to get moved to another instance
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ServerGoingAwayPayload {
+    /// Suggested wait before reconnecting, jittered per client.
+    #[prost(uint32, tag = "1")]
+    pub reconnect_after_ms: u32,
+    /// The session is disconnected after this.
+    #[prost(uint32, tag = "2")]
+    pub deadline_ms: u32,
+}
+/// Who is speaking, sent when the speakers change but not more often than every 500ms
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ActiveSpeakersChangedPayload {
+    /// Stays set through silence, until someone else takes over.
+    #[prost(string, optional, tag = "1")]
+    pub dominant_participant_id: ::core::option::Option<::prost::alloc::string::String>,
+    /// The external IDs of the current speakers, loudest first.
+    #[prost(string, repeated, tag = "2")]
+    pub participant_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ErrorPayload {
     /// General error message from the SFU.
     #[prost(string, tag = "1")]
@@ -77,7 +216,10 @@ pub struct ErrorPayload {
 /// ServerMessage encapsulates all possible messages from SFU to client.
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ServerMessage {
-    #[prost(oneof = "server_message::Payload", tags = "1, 2, 3, 4")]
+    #[prost(
+        oneof = "server_message::Payload",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13"
+    )]
     pub payload: ::core::option::Option<server_message::Payload>,
 }
 /// Nested message and enum types in `ServerMessage`.
@@ -96,6 +238,33 @@ pub mod server_message {
         /// SFU confirms track switching for a mid
         #[prost(message, tag = "4")]
         TrackSwitched(super::TrackSwitchedPayload),
+        /// SFU adds transceivers, e.g. to receive more tracks.
+        #[prost(message, tag = "5")]
+        Offer(super::SdpOfferPayload),
+        /// SFU answers an offer from the client.
+        #[prost(message, tag = "6")]
+        Answer(super::SdpAnswerPayload),
+        /// SFU informs client other participants joined the room.
+        #[prost(message, tag = "7")]
+        ParticipantJoined(super::ParticipantJoinedPayload),
+        /// SFU informs client other participants left the room.
+        #[prost(message, tag = "8")]
+        ParticipantLeft(super::ParticipantLeftPayload),
+        /// SFU informs client who and what is in the room.
+        #[prost(message, tag = "9")]
+        RoomSnapshot(super::RoomSnapshotPayload),
+        /// SFU is shutting down, client should reconnect.
+        #[prost(message, tag = "10")]
+        GoingAway(super::ServerGoingAwayPayload),
+        /// SFU informs client a data channel is available.
+        #[prost(message, tag = "11")]
+        DataChannelPublished(super::DataChannelPublishedPayload),
+        /// SFU informs client who is speaking.
+        #[prost(message, tag = "12")]
+        ActiveSpeakersChanged(super::ActiveSpeakersChangedPayload),
+        /// SFU informs client a data channel was closed by its publisher.
+        #[prost(message, tag = "13")]
+        DataChannelUnpublished(super::DataChannelUnpublishedPayload),
     }
 }
 /// Represents the kind of media track.
@@ -0,0 +1,743 @@
+//! Server-side recording. A recorder subscribes to tracks like a participant, but its
+//! [`ParticipantHandle`] is drained by a [`RecorderActor`] that writes the frames to disk
+//! instead of an `Rtc`: VP8 to IVF and Opus to Ogg, each file with a JSON sidecar.
+//!
+//! Files are created on the first decodable frame, `{dir}/{room}/{track}_{unix_ms}.ivf`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use str0m::{
+    format::Codec,
+    media::{MediaData, MediaKind},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, error::SendError},
+};
+
+use crate::{
+    actor::{Actor, ActorError},
+    config::ChannelCapacities,
+    entity::{EntityId, ParticipantId, RoomId},
+    message::TrackIn,
+    participant::{ParticipantControlMessage, ParticipantDataMessage, ParticipantHandle},
+    track::{self, TrackHandle},
+};
+
+const IVF_HEADER_SIZE: usize = 32;
+const IVF_FRAME_COUNT_OFFSET: u64 = 24;
+// Milliseconds
+const IVF_TIMEBASE: u32 = 1000;
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+// libopus encoder lookahead at 48kHz
+const OPUS_PRE_SKIP: u16 = 312;
+const OGG_SERIAL: u32 = 0x7075_6c73;
+
+#[derive(Debug)]
+pub enum RecorderMessage {
+    /// Records a single track, or every track in the room when `None`.
+    Record(Option<Arc<EntityId>>),
+    /// Stops recording a single track, or stops the recorder when `None`.
+    Stop(Option<Arc<EntityId>>),
+}
+
+pub struct RecorderActor {
+    room_id: Arc<RoomId>,
+    handle: ParticipantHandle,
+    dir: PathBuf,
+    receiver: mpsc::Receiver<RecorderMessage>,
+    data_receiver: mpsc::Receiver<ParticipantDataMessage>,
+    control_receiver: mpsc::Receiver<ParticipantControlMessage>,
+
+    all_tracks: bool,
+    selected: HashSet<Arc<EntityId>>,
+    available: HashMap<Arc<EntityId>, TrackHandle>,
+    recordings: HashMap<Arc<EntityId>, Recording>,
+}
+
+impl Actor for RecorderActor {
+    type ID = Arc<ParticipantId>;
+
+    fn kind(&self) -> &'static str {
+        "recorder"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.handle.participant_id.clone()
+    }
+
+    async fn run(&mut self) -> Result<(), ActorError> {
+        loop {
+            tokio::select! {
+                msg = self.receiver.recv() => {
+                    match msg {
+                        Some(RecorderMessage::Record(track_id)) => self.record(track_id).await,
+                        Some(RecorderMessage::Stop(Some(track_id))) => self.stop(&track_id).await,
+                        Some(RecorderMessage::Stop(None)) | None => break,
+                    }
+                }
+
+                Some(msg) = self.data_receiver.recv() => {
+                    if let ParticipantDataMessage::ForwardMedia(track, data) = msg {
+                        self.write(&track, &data).await;
+                    }
+                }
+
+                Some(msg) = self.control_receiver.recv() => {
+                    match msg {
+                        ParticipantControlMessage::TracksAdded(tracks) => self.add_tracks(&tracks).await,
+                        ParticipantControlMessage::TracksRemoved(track_ids) => {
+                            for track_id in track_ids.iter() {
+                                self.remove_track(&track_id.internal).await;
+                            }
+                        }
+                        ParticipantControlMessage::RoomSynced(snapshot) => self.sync_tracks(&snapshot.tracks).await,
+                        ParticipantControlMessage::Disconnect => break,
+                        _ => {}
+                    }
+                }
+
+                else => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn post_stop(&mut self) -> Result<(), ActorError> {
+        let track_ids: Vec<_> = self.recordings.keys().cloned().collect();
+        for track_id in track_ids {
+            self.finish(&track_id).await;
+        }
+        Ok(())
+    }
+}
+
+impl RecorderActor {
+    fn is_selected(&self, track_id: &EntityId) -> bool {
+        self.all_tracks || self.selected.contains(track_id)
+    }
+
+    async fn add_tracks(&mut self, tracks: &[TrackHandle]) {
+        for track in tracks {
+            self.available
+                .insert(track.meta.id.internal.clone(), track.clone());
+        }
+        for track in tracks {
+            if self.is_selected(&track.meta.id.internal) {
+                self.start(track.clone()).await;
+            }
+        }
+    }
+
+    async fn remove_track(&mut self, track_id: &EntityId) {
+        self.available.remove(track_id);
+        self.finish(track_id).await;
+    }
+
+    /// Catches up with the room after it dropped track updates on a full mailbox.
+    async fn sync_tracks(&mut self, tracks: &[TrackHandle]) {
+        let current: HashSet<_> = tracks
+            .iter()
+            .map(|track| track.meta.id.internal.clone())
+            .collect();
+        let removed: Vec<_> = self
+            .available
+            .keys()
+            .filter(|track_id| !current.contains(*track_id))
+            .cloned()
+            .collect();
+        for track_id in removed {
+            self.remove_track(&track_id).await;
+        }
+
+        let added: Vec<_> = tracks
+            .iter()
+            .filter(|track| !self.available.contains_key(&track.meta.id.internal))
+            .cloned()
+            .collect();
+        self.add_tracks(&added).await;
+    }
+
+    async fn record(&mut self, track_id: Option<Arc<EntityId>>) {
+        let tracks: Vec<TrackHandle> = match track_id {
+            None => {
+                self.all_tracks = true;
+                self.available.values().cloned().collect()
+            }
+            Some(track_id) => {
+                self.selected.insert(track_id.clone());
+                self.available.get(&track_id).cloned().into_iter().collect()
+            }
+        };
+
+        for track in tracks {
+            self.start(track).await;
+        }
+    }
+
+    async fn stop(&mut self, track_id: &EntityId) {
+        if self.all_tracks {
+            // Keeps recording the others, but no new tracks
+            self.all_tracks = false;
+            self.selected = self.recordings.keys().cloned().collect();
+        }
+        self.selected.remove(track_id);
+        self.finish(track_id).await;
+    }
+
+    async fn start(&mut self, track: TrackHandle) {
+        let track_id = track.meta.id.internal.clone();
+        if self.recordings.contains_key(&track_id) {
+            return;
+        }
+
+        let codec = *track.codec.borrow();
+        if codec.is_some_and(|codec| !can_record(codec)) {
+            tracing::warn!(%track_id, ?codec, "codec can't be recorded, skipping track");
+            return;
+        }
+
+        // Publishers announce their simulcast layers from the lowest quality
+        let rid = track.meta.rids().last();
+        if let Err(err) = track.subscribe(self.handle.clone(), rid).await {
+            tracing::warn!(%track_id, "failed to subscribe to track: {err}");
+            return;
+        }
+
+        tracing::info!(%track_id, ?rid, "recording track");
+        let container = match track.meta.kind {
+            MediaKind::Video => Container::Ivf(IvfWriter::default()),
+            MediaKind::Audio => Container::Ogg(OggWriter::default()),
+        };
+        self.recordings.insert(
+            track_id,
+            Recording {
+                track,
+                container,
+                file: None,
+                path: PathBuf::new(),
+                sidecar: None,
+            },
+        );
+    }
+
+    async fn write(&mut self, track: &TrackIn, data: &MediaData) {
+        let track_id = &track.id.internal;
+        let Some(recording) = self.recordings.get_mut(track_id) else {
+            return;
+        };
+
+        if let Err(err) = recording.write(&self.room_id, &self.dir, data).await {
+            tracing::warn!(%track_id, "recording is stopped: {err}");
+            self.finish(track_id).await;
+        }
+    }
+
+    /// Closes the file and writes the final sidecar.
+    async fn finish(&mut self, track_id: &EntityId) {
+        let Some(mut recording) = self.recordings.remove(track_id) else {
+            return;
+        };
+
+        let _ = recording
+            .track
+            .unsubscribe(self.handle.participant_id.clone())
+            .await;
+        if let Err(err) = recording.finish().await {
+            tracing::warn!(%track_id, "failed to finish recording: {err}");
+        } else {
+            tracing::info!(%track_id, path = %recording.path.display(), "recording is finished");
+        }
+    }
+}
+
+struct Recording {
+    track: TrackHandle,
+    container: Container,
+    file: Option<BufWriter<File>>,
+    path: PathBuf,
+    sidecar: Option<Sidecar>,
+}
+
+impl Recording {
+    async fn write(
+        &mut self,
+        room_id: &RoomId,
+        dir: &Path,
+        data: &MediaData,
+    ) -> std::io::Result<()> {
+        let Some(bytes) = self.container.write(data)? else {
+            return Ok(());
+        };
+
+        if self.file.is_none() {
+            let sidecar = Sidecar::new(room_id, &self.track.meta, self.container.codec());
+            let dir = dir.join(room_id.external.as_str());
+            tokio::fs::create_dir_all(&dir).await?;
+            self.path = dir.join(format!(
+                "{}_{}.{}",
+                sidecar.track_id,
+                sidecar.started_at_ms,
+                self.container.extension()
+            ));
+            let file = File::create(&self.path).await?;
+            write_sidecar(&self.path, &sidecar).await?;
+            self.sidecar = Some(sidecar);
+            self.file = Some(BufWriter::new(file));
+        }
+
+        if let Some(file) = &mut self.file {
+            file.write_all(&bytes).await?;
+        }
+        if let Some(sidecar) = &mut self.sidecar {
+            sidecar.frames += 1;
+            sidecar.duration_ms = self.container.duration_ms();
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> std::io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+
+        file.write_all(&self.container.finish()).await?;
+        if let Container::Ivf(ivf) = &self.container {
+            file.flush().await?;
+            file.seek(SeekFrom::Start(IVF_FRAME_COUNT_OFFSET)).await?;
+            file.write_all(&ivf.frames.to_le_bytes()).await?;
+        }
+        file.flush().await?;
+
+        if let Some(sidecar) = &mut self.sidecar {
+            sidecar.ended_at_ms = Some(unix_ms());
+            write_sidecar(&self.path, sidecar).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Timing and identity of a recording, stored next to it as `.json`.
+#[derive(Debug, serde::Serialize)]
+struct Sidecar {
+    room_id: String,
+    participant_id: String,
+    session_id: String,
+    track_id: String,
+    codec: &'static str,
+    started_at_ms: u64,
+    ended_at_ms: Option<u64>,
+    duration_ms: u64,
+    frames: u64,
+}
+
+impl Sidecar {
+    fn new(room_id: &RoomId, track: &TrackIn, codec: &'static str) -> Self {
+        Self {
+            room_id: room_id.external.to_string(),
+            participant_id: track.id.origin_participant.external.to_string(),
+            session_id: track.id.origin_participant.internal.to_string(),
+            track_id: track.id.internal.to_string(),
+            codec,
+            started_at_ms: unix_ms(),
+            ended_at_ms: None,
+            duration_ms: 0,
+            frames: 0,
+        }
+    }
+}
+
+async fn write_sidecar(path: &Path, sidecar: &Sidecar) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(sidecar)?;
+    tokio::fs::write(path.with_extension("json"), json).await
+}
+
+/// Only VP8 and Opus have a container to be written to.
+pub(crate) fn can_record(codec: Codec) -> bool {
+    matches!(codec, Codec::Vp8 | Codec::Opus)
+}
+
+pub(crate) fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+enum Container {
+    Ivf(IvfWriter),
+    Ogg(OggWriter),
+}
+
+impl Container {
+    fn codec(&self) -> &'static str {
+        match self {
+            Container::Ivf(_) => "vp8",
+            Container::Ogg(_) => "opus",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Container::Ivf(_) => "ivf",
+            Container::Ogg(_) => "ogg",
+        }
+    }
+
+    /// Returns the bytes to append, `None` while waiting for a decodable frame.
+    fn write(&mut self, data: &MediaData) -> std::io::Result<Option<Vec<u8>>> {
+        let codec = data.params.spec().codec;
+        match self {
+            Container::Ivf(ivf) if codec == Codec::Vp8 => {
+                let is_keyframe = track::is_keyframe(data);
+                Ok(ivf.write_frame(&data.data, is_keyframe, data.network_time))
+            }
+            // The RTP clock of Opus is always 48kHz, RFC 7587 section 4.1
+            Container::Ogg(ogg) if codec == Codec::Opus => {
+                Ok(Some(ogg.write_packet(&data.data, data.time.numer())))
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("{codec:?} can't be recorded"),
+            )),
+        }
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        match self {
+            Container::Ivf(_) => Vec::new(),
+            Container::Ogg(ogg) => ogg.finish(),
+        }
+    }
+
+    fn duration_ms(&self) -> u64 {
+        match self {
+            Container::Ivf(ivf) => ivf.last_pts,
+            Container::Ogg(ogg) => ogg.granule * 1000 / OPUS_SAMPLE_RATE as u64,
+        }
+    }
+}
+
+/// VP8 in IVF with a millisecond timebase. Starts on the first keyframe, its size goes
+/// into the header.
+#[derive(Debug, Default)]
+struct IvfWriter {
+    first: Option<Instant>,
+    frames: u32,
+    last_pts: u64,
+}
+
+impl IvfWriter {
+    fn write_frame(&mut self, frame: &[u8], is_keyframe: bool, at: Instant) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(IVF_HEADER_SIZE + 12 + frame.len());
+        let first = match self.first {
+            Some(first) => first,
+            None => {
+                if !is_keyframe {
+                    return None;
+                }
+                let (width, height) = vp8_dimensions(frame)?;
+                out.extend_from_slice(&ivf_header(width, height, 0));
+                *self.first.insert(at)
+            }
+        };
+
+        self.last_pts = at.saturating_duration_since(first).as_millis() as u64;
+        self.frames += 1;
+        out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.last_pts.to_le_bytes());
+        out.extend_from_slice(frame);
+        Some(out)
+    }
+}
+
+fn ivf_header(width: u16, height: u16, frames: u32) -> [u8; IVF_HEADER_SIZE] {
+    let mut header = [0; IVF_HEADER_SIZE];
+    header[0..4].copy_from_slice(b"DKIF");
+    header[4..6].copy_from_slice(&0u16.to_le_bytes());
+    header[6..8].copy_from_slice(&(IVF_HEADER_SIZE as u16).to_le_bytes());
+    header[8..12].copy_from_slice(b"VP80");
+    header[12..14].copy_from_slice(&width.to_le_bytes());
+    header[14..16].copy_from_slice(&height.to_le_bytes());
+    header[16..20].copy_from_slice(&IVF_TIMEBASE.to_le_bytes());
+    header[20..24].copy_from_slice(&1u32.to_le_bytes());
+    header[24..28].copy_from_slice(&frames.to_le_bytes());
+    header
+}
+
+/// Frame size from the header of a VP8 keyframe, RFC 6386 section 9.1.
+fn vp8_dimensions(frame: &[u8]) -> Option<(u16, u16)> {
+    if frame.len() < 10 || frame[0] & 0x01 != 0 || frame[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+
+    let width = u16::from_le_bytes([frame[6], frame[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([frame[8], frame[9]]) & 0x3fff;
+    Some((width, height))
+}
+
+/// Opus in Ogg, RFC 7845. Each packet gets its own page, the last one is held back so
+/// it can be marked as the end of the stream. Granule positions follow the RTP
+/// timestamps, lost packets and silence leave a gap instead of shortening the recording.
+#[derive(Debug, Default)]
+struct OggWriter {
+    sequence: u32,
+    granule: u64,
+    first_rtp_time: Option<u64>,
+    pending: Option<Vec<u8>>,
+}
+
+impl OggWriter {
+    fn write_packet(&mut self, packet: &[u8], rtp_time: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        if self.sequence == 0 {
+            out.extend(self.page(&opus_head(), 0, 0x02));
+            out.extend(self.page(&opus_tags(), 0, 0x00));
+        }
+
+        if let Some(pending) = self.pending.take() {
+            out.extend(self.page(&pending, self.granule, 0x00));
+        }
+        let first = *self.first_rtp_time.get_or_insert(rtp_time);
+        // Granule positions never go back, a late packet ends where the previous one did
+        let end = rtp_time.saturating_sub(first) + opus_samples(packet);
+        self.granule = self.granule.max(end);
+        self.pending = Some(packet.to_vec());
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        match self.pending.take() {
+            Some(pending) => self.page(&pending, self.granule, 0x04),
+            None => Vec::new(),
+        }
+    }
+
+    fn page(&mut self, packet: &[u8], granule: u64, header_type: u8) -> Vec<u8> {
+        let segments = packet.len() / 255 + 1;
+        let mut page = Vec::with_capacity(27 + segments + packet.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0);
+        page.push(header_type);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&OGG_SERIAL.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(segments as u8);
+        page.extend(std::iter::repeat_n(255, segments - 1));
+        page.push((packet.len() % 255) as u8);
+        page.extend_from_slice(packet);
+
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.sequence += 1;
+        page
+    }
+}
+
+fn opus_head() -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    // WebRTC negotiates stereo, mono packets decode to both channels
+    head.push(2);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes());
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+fn opus_tags() -> Vec<u8> {
+    let vendor = b"pulsebeam";
+    let mut tags = Vec::with_capacity(16 + vendor.len());
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    tags
+}
+
+/// Samples at 48kHz in an Opus packet, from its TOC byte, RFC 6716 section 3.1.
+fn opus_samples(packet: &[u8]) -> u64 {
+    let Some(&toc) = packet.first() else {
+        return 0;
+    };
+
+    let config = toc >> 3;
+    let frame_size = match config {
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        12..=15 => [480, 960][config as usize % 2],
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(0, |count| count & 0x3f) as u64,
+    };
+    frame_size * frames
+}
+
+/// CRC-32 with polynomial 0x04c11db7, no reflection and no final xor.
+fn ogg_crc(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[derive(Clone, Debug)]
+pub struct RecorderHandle {
+    sender: mpsc::Sender<RecorderMessage>,
+    /// Subscribes the recorder to tracks, like a participant.
+    pub participant: ParticipantHandle,
+}
+
+impl RecorderHandle {
+    pub fn new(
+        room_id: Arc<RoomId>,
+        participant_id: Arc<ParticipantId>,
+        dir: PathBuf,
+        channels: ChannelCapacities,
+    ) -> (Self, RecorderActor) {
+        let (sender, receiver) = mpsc::channel(channels.participant_control);
+        let (data_sender, data_receiver) = mpsc::channel(channels.participant_data);
+        let (control_sender, control_receiver) = mpsc::channel(channels.participant_control);
+        let participant = ParticipantHandle {
+            data_sender,
+            control_sender,
+            participant_id,
+        };
+        let handle = Self {
+            sender,
+            participant: participant.clone(),
+        };
+        let actor = RecorderActor {
+            room_id,
+            handle: participant,
+            dir,
+            receiver,
+            data_receiver,
+            control_receiver,
+            all_tracks: false,
+            selected: HashSet::new(),
+            available: HashMap::new(),
+            recordings: HashMap::new(),
+        };
+        (handle, actor)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    pub async fn record(
+        &self,
+        track_id: Option<Arc<EntityId>>,
+    ) -> Result<(), SendError<RecorderMessage>> {
+        self.sender.send(RecorderMessage::Record(track_id)).await
+    }
+
+    pub async fn stop(
+        &self,
+        track_id: Option<Arc<EntityId>>,
+    ) -> Result<(), SendError<RecorderMessage>> {
+        self.sender.send(RecorderMessage::Stop(track_id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ivf_starts_on_keyframe() {
+        let mut keyframe = vec![0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a];
+        keyframe.extend_from_slice(&640u16.to_le_bytes());
+        keyframe.extend_from_slice(&360u16.to_le_bytes());
+        keyframe.extend_from_slice(&[0xaa; 4]);
+
+        let mut ivf = IvfWriter::default();
+        let start = Instant::now();
+        assert!(ivf.write_frame(&[0x11, 0x00, 0x00], false, start).is_none());
+
+        let out = ivf.write_frame(&keyframe, true, start).unwrap();
+        assert_eq!(&out[0..4], b"DKIF");
+        assert_eq!(&out[8..12], b"VP80");
+        assert_eq!(u16::from_le_bytes([out[12], out[13]]), 640);
+        assert_eq!(u16::from_le_bytes([out[14], out[15]]), 360);
+        assert_eq!(out.len(), IVF_HEADER_SIZE + 12 + keyframe.len());
+
+        let later = start + std::time::Duration::from_millis(33);
+        let out = ivf.write_frame(&[0x11, 0x00, 0x00], false, later).unwrap();
+        assert_eq!(u64::from_le_bytes(out[4..12].try_into().unwrap()), 33);
+        assert_eq!(ivf.frames, 2);
+    }
+
+    #[test]
+    fn test_opus_samples_from_toc() {
+        // CELT 20ms, one frame
+        assert_eq!(opus_samples(&[0xf8, 0x00]), 960);
+        // SILK 20ms, two frames
+        assert_eq!(opus_samples(&[0x09, 0x00]), 1920);
+        // CELT 10ms, code 3 with three frames
+        assert_eq!(opus_samples(&[0xf3, 0x03]), 1440);
+        assert_eq!(opus_samples(&[]), 0);
+    }
+
+    #[test]
+    fn test_ogg_pages() {
+        let mut ogg = OggWriter::default();
+        let out = ogg.write_packet(&[0xf8, 0xff, 0xfe], 1000);
+        // OpusHead and OpusTags, the packet is held back
+        assert_eq!(ogg.sequence, 2);
+        assert_eq!(&out[0..4], b"OggS");
+        assert_eq!(out[5], 0x02);
+        assert_eq!(&out[28..36], b"OpusHead");
+
+        let out = ogg.write_packet(&[0xf8, 0xff, 0xfe], 1960);
+        assert_eq!(u64::from_le_bytes(out[6..14].try_into().unwrap()), 960);
+
+        let out = ogg.finish();
+        assert_eq!(out[5], 0x04);
+        assert_eq!(u64::from_le_bytes(out[6..14].try_into().unwrap()), 1920);
+
+        // The checksum is computed with its own field zeroed
+        let mut page = out.clone();
+        page[22..26].copy_from_slice(&[0; 4]);
+        assert_eq!(ogg_crc(&page).to_le_bytes(), out[22..26]);
+    }
+
+    #[test]
+    fn test_ogg_granule_follows_rtp_time() {
+        let granule = |page: &[u8]| u64::from_le_bytes(page[6..14].try_into().unwrap());
+        let mut ogg = OggWriter::default();
+        ogg.write_packet(&[0xf8], u64::from(u32::MAX));
+
+        // Four packets are lost, the gap stays in the timeline
+        let out = ogg.write_packet(&[0xf8], u64::from(u32::MAX) + 5 * 960);
+        assert_eq!(granule(&out), 960);
+
+        // A late packet can't move the granule position back
+        let out = ogg.write_packet(&[0xf8], u64::from(u32::MAX) + 960);
+        assert_eq!(granule(&out), 6 * 960);
+        let out = ogg.finish();
+        assert_eq!(granule(&out), 6 * 960);
+        assert_eq!(ogg.granule * 1000 / OPUS_SAMPLE_RATE as u64, 120);
+    }
+}
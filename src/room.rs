@@ -1,28 +1,137 @@
-use std::{collections::HashMap, fmt::Display, ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap, fmt::Display, ops::Deref, path::PathBuf, sync::Arc, time::Duration,
+};
 
 use tokio::{
-    sync::mpsc::{self, error::SendError},
-    task::JoinSet,
+    sync::{
+        mpsc::{
+            self,
+            error::{SendError, TrySendError},
+        },
+        oneshot, watch,
+    },
+    time::Instant,
 };
-use tracing::Instrument;
 
 use crate::{
-    actor::{self, Actor, ActorError},
-    entity::{ParticipantId, RoomId, TrackId},
-    participant::{ParticipantActor, ParticipantHandle},
+    actor::{Actor, ActorError, ActorStatus},
+    capture::CaptureRequest,
+    config::CaptureConfig,
+    config::{ChannelCapacities, RoomLimits},
+    entity::{EntityId, ExternalParticipantId, ExternalRoomId, ParticipantId, RoomId, TrackId},
+    message::{DataChannel, DataPacket},
+    participant::{
+        ParticipantActor, ParticipantHandle, ParticipantStats, RemoteParticipant, RoomSnapshot,
+    },
+    recorder::{self, RecorderHandle},
     rng::Rng,
+    speaker::{self, ActiveSpeakers, SpeakerDetector},
+    supervisor::ChildSet,
+    telemetry,
     track::TrackHandle,
 };
 
+#[derive(thiserror::Error, Debug)]
+pub enum RoomError {
+    #[error("room is full")]
+    Full,
+
+    #[error("room is closed")]
+    Closed,
+
+    #[error("track is not found")]
+    TrackNotFound,
+
+    #[error("room is not being recorded")]
+    NotRecording,
+
+    #[error("track codec can't be recorded")]
+    UnsupportedCodec,
+}
+
 #[derive(Debug)]
 pub enum RoomMessage {
     PublishTrack(TrackHandle),
-    AddParticipant(ParticipantHandle, ParticipantActor),
+    UnpublishTrack(Arc<TrackId>),
+    PublishData(Arc<DataChannel>),
+    UnpublishData(Arc<DataChannel>),
+    ForwardData(Arc<DataPacket>),
+    AddParticipant(
+        ParticipantHandle,
+        ParticipantActor,
+        oneshot::Sender<Result<(), RoomError>>,
+    ),
+    GetInfo(oneshot::Sender<RoomInfo>),
+    KickParticipant(ExternalParticipantId, oneshot::Sender<usize>),
+    /// Records a single track, or every track when `None`.
+    StartRecording(
+        Option<Arc<EntityId>>,
+        oneshot::Sender<Result<(), RoomError>>,
+    ),
+    StopRecording(
+        Option<Arc<EntityId>>,
+        oneshot::Sender<Result<(), RoomError>>,
+    ),
+    /// Answered with the capture files, one per session of the participant.
+    StartCapture(
+        ExternalParticipantId,
+        CaptureRequest,
+        oneshot::Sender<Vec<PathBuf>>,
+    ),
+    Close,
+    Drain(Duration),
+}
+
+/// Point-in-time view of a room for the admin API.
+#[derive(Debug, serde::Serialize)]
+pub struct RoomInfo {
+    pub room_id: ExternalRoomId,
+    pub participants: Vec<ParticipantInfo>,
+    pub recording: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ParticipantInfo {
+    pub participant_id: ExternalParticipantId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    pub tracks: Vec<TrackInfo>,
+    pub stats: ParticipantStats,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TrackInfo {
+    pub track_id: EntityId,
+    pub kind: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rids: Vec<String>,
 }
 
 pub struct ParticipantMeta {
     handle: ParticipantHandle,
+    metadata: Option<String>,
     tracks: HashMap<Arc<TrackId>, TrackHandle>,
+    data_channels: HashMap<Arc<str>, Arc<DataChannel>>,
+    stats: watch::Receiver<ParticipantStats>,
+    missed: Missed,
+}
+
+/// What a participant missed while its mailbox was full. The room never waits on a
+/// participant, it sends these again on the next speaker tick that finds room.
+#[derive(Debug, Default)]
+struct Missed {
+    /// Any change to the room, made up for with a [`RoomSnapshot`].
+    room: bool,
+    /// Made up for with the latest speakers, see `RoomActor::active_speakers`.
+    speakers: bool,
+    going_away: bool,
+    disconnect: bool,
+}
+
+impl Missed {
+    fn any(&self) -> bool {
+        self.room || self.speakers || self.going_away || self.disconnect
+    }
 }
 
 /// Reponsibilities:
@@ -36,9 +145,25 @@ pub struct RoomActor {
     rng: Rng,
     receiver: mpsc::Receiver<RoomMessage>,
     handle: RoomHandle,
+    limits: RoomLimits,
+    channels: ChannelCapacities,
+    recording_dir: PathBuf,
+    capture: CaptureConfig,
 
     participants: HashMap<Arc<ParticipantId>, ParticipantMeta>,
-    participant_tasks: JoinSet<Arc<ParticipantId>>,
+    participant_tasks: ChildSet<Arc<ParticipantId>>,
+    // Runs as a participant task, but isn't listed among the participants
+    recorder: Option<RecorderHandle>,
+    // Track changes the recorder missed, see `Missed::room`
+    recorder_missed: bool,
+    // Stops once the last participant is gone
+    closing: bool,
+    // Everyone left is disconnected at this point while draining
+    drain_deadline: Option<Instant>,
+    speakers: SpeakerDetector<Arc<ParticipantId>>,
+    // Latest speakers, sent again to whoever missed them
+    active_speakers: Option<Arc<ActiveSpeakers<Arc<ParticipantId>>>>,
+    next_speaker_tick: Instant,
 }
 
 impl Actor for RoomActor {
@@ -52,8 +177,29 @@ impl Actor for RoomActor {
         self.handle.room_id.clone()
     }
 
+    async fn pre_start(&mut self) -> Result<(), ActorError> {
+        metrics::gauge!(telemetry::ROOMS).increment(1.0);
+        Ok(())
+    }
+
+    async fn post_stop(&mut self) -> Result<(), ActorError> {
+        metrics::gauge!(telemetry::ROOMS).decrement(1.0);
+        // Recordings are finalized before the room is gone
+        if let Some(recorder) = self.recorder.take() {
+            let _ = recorder.stop(None).await;
+            let recorder_id = recorder.participant.participant_id;
+            while let Some((id, _)) = self.participant_tasks.join_next().await {
+                if id == recorder_id {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn run(&mut self) -> Result<(), ActorError> {
         loop {
+            let drain_deadline = self.drain_deadline;
             tokio::select! {
                 res = self.receiver.recv() => {
                     match res {
@@ -62,12 +208,28 @@ impl Actor for RoomActor {
                     }
                 }
 
-                Some(Ok(participant_id)) = self.participant_tasks.join_next() => {
-                    self.handle_participant_left(participant_id).await;
+                Some((participant_id, status)) = self.participant_tasks.join_next() => {
+                    self.handle_participant_left(participant_id, status);
+                }
+
+                _ = tokio::time::sleep_until(self.next_speaker_tick) => {
+                    self.next_speaker_tick = Instant::now() + speaker::SPEAKER_TICK;
+                    self.resend_missed();
+                    self.detect_speakers();
+                }
+
+                _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(Instant::now)), if drain_deadline.is_some() => {
+                    tracing::info!(remaining = self.participants.len(), "drain deadline has passed");
+                    self.drain_deadline = None;
+                    self.disconnect_all();
                 }
 
                 else => break,
             }
+
+            if self.closing && self.participants.is_empty() {
+                break;
+            }
         }
 
         Ok(())
@@ -77,28 +239,54 @@ impl Actor for RoomActor {
 impl RoomActor {
     async fn handle_message(&mut self, msg: RoomMessage) {
         match msg {
-            RoomMessage::AddParticipant(participant_handle, participant_actor) => {
+            RoomMessage::AddParticipant(participant_handle, participant_actor, resp) => {
+                if self.closing {
+                    let _ = resp.send(Err(RoomError::Closed));
+                    return;
+                }
+                if self
+                    .limits
+                    .max_participants_per_room
+                    .is_some_and(|max| self.participants.len() >= max)
+                {
+                    let _ = resp.send(Err(RoomError::Full));
+                    return;
+                }
+                let _ = resp.send(Ok(()));
+
                 let participant_id = participant_handle.participant_id.clone();
+                let metadata = participant_actor.metadata().map(str::to_string);
+                let stats = participant_actor.stats();
+
+                let joined = Arc::new(vec![RemoteParticipant {
+                    participant_id: participant_id.clone(),
+                    metadata: metadata.clone(),
+                }]);
+                for participant in self.participants.values_mut() {
+                    if participant.handle.add_participants(joined.clone()).is_err() {
+                        participant.missed.room = true;
+                    }
+                }
+
+                // The newcomer starts from a snapshot, like a participant that missed updates
+                let snapshot = self.snapshot(&participant_id);
+                let missed = Missed {
+                    room: participant_handle.sync_room(snapshot).is_err(),
+                    ..Default::default()
+                };
                 self.participants.insert(
-                    participant_handle.participant_id.clone(),
+                    participant_id.clone(),
                     ParticipantMeta {
-                        handle: participant_handle.clone(),
+                        handle: participant_handle,
+                        metadata,
                         tracks: HashMap::new(),
+                        data_channels: HashMap::new(),
+                        stats,
+                        missed,
                     },
                 );
-                self.participant_tasks.spawn(
-                    async move {
-                        actor::run(participant_actor).await;
-                        participant_id
-                    }
-                    .in_current_span(),
-                );
-
-                let mut tracks = Vec::with_capacity(self.participants.len());
-                for (_, meta) in &self.participants {
-                    tracks.extend(meta.tracks.values().cloned());
-                }
-                let _ = participant_handle.add_tracks(Arc::new(tracks)).await;
+                self.participant_tasks
+                    .spawn(participant_id, participant_actor);
             }
             RoomMessage::PublishTrack(track) => {
                 let Some(origin) = self.participants.get_mut(&track.meta.id.origin_participant)
@@ -112,27 +300,444 @@ impl RoomActor {
 
                 origin.tracks.insert(track.meta.id.clone(), track.clone());
                 let new_tracks = Arc::new(vec![track]);
-                for (_, participant) in &self.participants {
-                    let _ = participant.handle.add_tracks(new_tracks.clone()).await;
+                for participant in self.participants.values_mut() {
+                    if participant.handle.add_tracks(new_tracks.clone()).is_err() {
+                        participant.missed.room = true;
+                    }
+                }
+                if let Some(recorder) = &self.recorder {
+                    if recorder.participant.add_tracks(new_tracks).is_err() {
+                        self.recorder_missed = true;
+                    }
+                }
+            }
+            RoomMessage::PublishData(channel) => {
+                let Some(origin) = self.participants.get_mut(&channel.origin) else {
+                    return;
+                };
+
+                origin
+                    .data_channels
+                    .insert(channel.label.clone(), channel.clone());
+                let origin_id = channel.origin.clone();
+                let new_channels = Arc::new(vec![channel]);
+                for (id, participant) in &mut self.participants {
+                    if *id != origin_id
+                        && participant
+                            .handle
+                            .add_data_channels(new_channels.clone())
+                            .is_err()
+                    {
+                        participant.missed.room = true;
+                    }
+                }
+            }
+            RoomMessage::UnpublishData(channel) => {
+                let Some(origin) = self.participants.get_mut(&channel.origin) else {
+                    return;
+                };
+
+                // The label may have been published again in the meantime
+                if !origin
+                    .data_channels
+                    .get(&channel.label)
+                    .is_some_and(|published| Arc::ptr_eq(published, &channel))
+                {
+                    return;
+                }
+                origin.data_channels.remove(&channel.label);
+
+                let origin_id = channel.origin.clone();
+                let removed = Arc::new(vec![channel]);
+                for (id, participant) in &mut self.participants {
+                    if *id != origin_id
+                        && participant
+                            .handle
+                            .remove_data_channels(removed.clone())
+                            .is_err()
+                    {
+                        participant.missed.room = true;
+                    }
+                }
+            }
+            RoomMessage::ForwardData(packet) => self.handle_forward_data(packet),
+            RoomMessage::GetInfo(resp) => {
+                let _ = resp.send(self.info());
+            }
+            RoomMessage::KickParticipant(participant_id, resp) => {
+                let mut kicked = 0;
+                for (id, participant) in &mut self.participants {
+                    if id.external == participant_id {
+                        tracing::info!(participant_id = ?id, "kicking participant");
+                        participant.missed.disconnect =
+                            participant.handle.try_disconnect().is_err();
+                        kicked += 1;
+                    }
+                }
+                let _ = resp.send(kicked);
+            }
+            RoomMessage::StartRecording(track_id, resp) => {
+                let _ = resp.send(self.start_recording(track_id).await);
+            }
+            RoomMessage::StopRecording(track_id, resp) => {
+                let _ = resp.send(self.stop_recording(track_id).await);
+            }
+            RoomMessage::StartCapture(participant_id, req, resp) => {
+                let limits = self.capture.limits(&req);
+                let dir = self.capture.dir.join(self.handle.room_id.external.as_str());
+                let mut files = Vec::new();
+                for (id, participant) in &self.participants {
+                    if id.external != participant_id {
+                        continue;
+                    }
+                    let path = dir.join(format!(
+                        "{}_{}_{}.pcapng",
+                        id.external,
+                        id.internal,
+                        recorder::unix_ms()
+                    ));
+                    tracing::info!(participant_id = ?id, path = %path.display(), "capturing participant");
+                    if participant
+                        .handle
+                        .start_capture(path.clone(), limits)
+                        .is_ok()
+                    {
+                        files.push(path);
+                    }
+                }
+                let _ = resp.send(files);
+            }
+            RoomMessage::Close => {
+                tracing::info!("closing room");
+                self.closing = true;
+                self.disconnect_all();
+            }
+            RoomMessage::Drain(deadline) => {
+                tracing::info!(participants = self.participants.len(), "draining room");
+                self.closing = true;
+                self.drain_deadline = Some(Instant::now() + deadline);
+                for participant in self.participants.values_mut() {
+                    participant.missed.going_away =
+                        participant.handle.going_away(deadline).is_err();
+                }
+            }
+            RoomMessage::UnpublishTrack(track_id) => {
+                let Some(origin) = self.participants.get_mut(&track_id.origin_participant) else {
+                    return;
+                };
+
+                if origin.tracks.remove(&track_id).is_none() {
+                    return;
                 }
+
+                let removed = Arc::new(vec![track_id]);
+                self.remove_tracks(removed);
+            }
+        };
+    }
+
+    async fn start_recording(&mut self, track_id: Option<Arc<EntityId>>) -> Result<(), RoomError> {
+        if self.closing {
+            return Err(RoomError::Closed);
+        }
+        let tracks: Vec<TrackHandle> = self
+            .participants
+            .values()
+            .flat_map(|meta| meta.tracks.values().cloned())
+            .collect();
+        if let Some(track_id) = &track_id {
+            let Some(track) = tracks
+                .iter()
+                .find(|track| track.meta.id.internal == *track_id)
+            else {
+                return Err(RoomError::TrackNotFound);
+            };
+            // Unknown until the publisher sends media, the recorder skips it later then
+            let codec = *track.codec.borrow();
+            if codec.is_some_and(|codec| !recorder::can_record(codec)) {
+                return Err(RoomError::UnsupportedCodec);
             }
+        }
+
+        let recorder = match &self.recorder {
+            Some(recorder) => recorder.clone(),
+            None => {
+                let external = ExternalParticipantId::new("recorder".to_string())
+                    .expect("valid participant id");
+                let participant_id = Arc::new(ParticipantId::new(&mut self.rng, external));
+                let (recorder, recorder_actor) = RecorderHandle::new(
+                    self.handle.room_id.clone(),
+                    participant_id.clone(),
+                    self.recording_dir.clone(),
+                    self.channels,
+                );
+                self.participant_tasks.spawn(participant_id, recorder_actor);
+                self.recorder_missed = recorder.participant.add_tracks(Arc::new(tracks)).is_err();
+                self.recorder = Some(recorder.clone());
+                recorder
+            }
+        };
+
+        recorder
+            .record(track_id)
+            .await
+            .map_err(|_| RoomError::Closed)
+    }
+
+    async fn stop_recording(&mut self, track_id: Option<Arc<EntityId>>) -> Result<(), RoomError> {
+        let Some(recorder) = &self.recorder else {
+            return Err(RoomError::NotRecording);
         };
+
+        let res = recorder.stop(track_id.clone()).await;
+        if track_id.is_none() {
+            self.recorder = None;
+        }
+        res.map_err(|_| RoomError::NotRecording)
     }
 
-    async fn handle_participant_left(&mut self, participant_id: Arc<ParticipantId>) {
-        // TODO: notify participant leaving
+    /// Samples the audio levels of everyone's tracks and tells the participants when
+    /// the speakers change.
+    fn detect_speakers(&mut self) {
+        let now = Instant::now();
+        for (id, participant) in &self.participants {
+            let loudest = participant
+                .tracks
+                .values()
+                .filter_map(|track| *track.audio_level.borrow())
+                .filter(|level| level.is_fresh(now))
+                .map(|level| level.dbov)
+                .min();
+            self.speakers.observe(id.clone(), loudest);
+        }
+
+        let Some(speakers) = self.speakers.update(now) else {
+            return;
+        };
+        tracing::debug!(dominant = ?speakers.dominant, active = speakers.active.len(), "active speakers changed");
+        let speakers = Arc::new(speakers);
+        for participant in self.participants.values_mut() {
+            participant.missed.speakers = participant
+                .handle
+                .active_speakers_changed(speakers.clone())
+                .is_err();
+        }
+        self.active_speakers = Some(speakers);
+    }
+
+    /// Never waits on a participant, a busy participant drops the data like it drops media.
+    /// The publisher is told who missed its reliable data.
+    fn handle_forward_data(&self, packet: Arc<DataPacket>) {
+        if packet.payload.len() > self.limits.max_data_message_size {
+            tracing::warn!(
+                label = %packet.channel.label,
+                size = packet.payload.len(),
+                "data message is too large, dropping"
+            );
+            return;
+        }
+
+        let mut dropped = Vec::new();
+        for (id, participant) in &self.participants {
+            if *id == packet.channel.origin {
+                continue;
+            }
+            if packet
+                .destinations
+                .as_ref()
+                .is_some_and(|destinations| !destinations.contains(&id.external))
+            {
+                continue;
+            }
+
+            if packet.channel.reliable {
+                if let Err(TrySendError::Full(_)) = participant.handle.send_data(packet.clone()) {
+                    dropped.push(id.external.clone());
+                }
+            } else {
+                let _ = participant.handle.forward_data(packet.clone());
+            }
+        }
+
+        if dropped.is_empty() {
+            return;
+        }
+        if let Some(origin) = self.participants.get(&packet.channel.origin) {
+            let _ = origin.handle.data_dropped(packet.channel.clone(), dropped);
+        }
+    }
+
+    fn info(&self) -> RoomInfo {
+        let participants = self
+            .participants
+            .iter()
+            .map(|(id, meta)| ParticipantInfo {
+                participant_id: id.external.clone(),
+                metadata: meta.metadata.clone(),
+                tracks: meta
+                    .tracks
+                    .values()
+                    .map(|track| TrackInfo {
+                        track_id: track.meta.id.internal.to_string(),
+                        kind: if track.meta.kind.is_video() {
+                            "video".to_string()
+                        } else {
+                            "audio".to_string()
+                        },
+                        rids: track.meta.rids().map(|rid| rid.to_string()).collect(),
+                    })
+                    .collect(),
+                stats: meta.stats.borrow().clone(),
+            })
+            .collect();
+
+        RoomInfo {
+            room_id: self.handle.room_id.external.clone(),
+            participants,
+            recording: self.recorder.is_some(),
+        }
+    }
+
+    fn handle_participant_left(&mut self, participant_id: Arc<ParticipantId>, status: ActorStatus) {
+        if self
+            .recorder
+            .as_ref()
+            .is_some_and(|recorder| recorder.participant.participant_id == participant_id)
+        {
+            if status.is_failure() {
+                tracing::warn!(%status, "recorder has failed, the recording may be incomplete");
+            }
+            self.recorder = None;
+            return;
+        }
+
         let Some(participant) = self.participants.remove(&participant_id) else {
             return;
         };
+        tracing::info!(?participant_id, %status, "participant has left");
+        self.speakers.remove(&participant_id);
 
+        for remaining in self.participants.values_mut() {
+            if remaining
+                .handle
+                .remove_participant(participant_id.clone())
+                .is_err()
+            {
+                remaining.missed.room = true;
+            }
+        }
+
+        if !participant.data_channels.is_empty() {
+            let channels: Vec<Arc<DataChannel>> = participant.data_channels.into_values().collect();
+            let channels = Arc::new(channels);
+            for remaining in self.participants.values_mut() {
+                if remaining
+                    .handle
+                    .remove_data_channels(channels.clone())
+                    .is_err()
+                {
+                    remaining.missed.room = true;
+                }
+            }
+        }
+
+        if participant.tracks.is_empty() {
+            return;
+        }
         let tracks: Vec<Arc<TrackId>> = participant
             .tracks
             .into_values()
             .map(|t| t.meta.id.clone())
             .collect();
-        let tracks = Arc::new(tracks);
-        for (_, participant) in &self.participants {
-            let _ = participant.handle.remove_tracks(tracks.clone()).await;
+        self.remove_tracks(Arc::new(tracks));
+    }
+
+    fn remove_tracks(&mut self, track_ids: Arc<Vec<Arc<TrackId>>>) {
+        for participant in self.participants.values_mut() {
+            if participant.handle.remove_tracks(track_ids.clone()).is_err() {
+                participant.missed.room = true;
+            }
+        }
+        if let Some(recorder) = &self.recorder {
+            if recorder.participant.remove_tracks(track_ids).is_err() {
+                self.recorder_missed = true;
+            }
+        }
+    }
+
+    fn disconnect_all(&mut self) {
+        for participant in self.participants.values_mut() {
+            participant.missed.disconnect = participant.handle.try_disconnect().is_err();
+        }
+    }
+
+    /// Everything `participant_id` should know about the room.
+    fn snapshot(&self, participant_id: &Arc<ParticipantId>) -> Arc<RoomSnapshot> {
+        let mut snapshot = RoomSnapshot::default();
+        for (id, meta) in &self.participants {
+            snapshot.tracks.extend(meta.tracks.values().cloned());
+            if id == participant_id {
+                continue;
+            }
+            snapshot.participants.push(RemoteParticipant {
+                participant_id: id.clone(),
+                metadata: meta.metadata.clone(),
+            });
+            snapshot
+                .data_channels
+                .extend(meta.data_channels.values().cloned());
+        }
+        Arc::new(snapshot)
+    }
+
+    /// Sends what participants missed on a full mailbox, until it gets through.
+    fn resend_missed(&mut self) {
+        let now = Instant::now();
+        let missed: Vec<_> = self
+            .participants
+            .iter()
+            .filter(|(_, meta)| meta.missed.any())
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in missed {
+            let snapshot = self
+                .participants
+                .get(&id)
+                .is_some_and(|meta| meta.missed.room)
+                .then(|| self.snapshot(&id));
+            let Some(participant) = self.participants.get_mut(&id) else {
+                continue;
+            };
+            let handle = &participant.handle;
+            let missed = &mut participant.missed;
+
+            // Nothing else matters to a participant that is let go
+            if missed.disconnect {
+                missed.disconnect = handle.try_disconnect().is_err();
+                continue;
+            }
+            if let Some(snapshot) = snapshot {
+                missed.room = handle.sync_room(snapshot).is_err();
+            }
+            if missed.speakers {
+                missed.speakers = self.active_speakers.as_ref().is_some_and(|speakers| {
+                    handle.active_speakers_changed(speakers.clone()).is_err()
+                });
+            }
+            if missed.going_away {
+                missed.going_away = self.drain_deadline.is_some_and(|deadline| {
+                    handle
+                        .going_away(deadline.saturating_duration_since(now))
+                        .is_err()
+                });
+            }
+        }
+
+        if self.recorder_missed {
+            if let Some(recorder) = &self.recorder {
+                let snapshot = self.snapshot(&recorder.participant.participant_id);
+                self.recorder_missed = recorder.participant.sync_room(snapshot).is_err();
+            }
         }
     }
 }
@@ -144,8 +749,15 @@ pub struct RoomHandle {
 }
 
 impl RoomHandle {
-    pub fn new(rng: Rng, room_id: Arc<RoomId>) -> (Self, RoomActor) {
-        let (sender, receiver) = mpsc::channel(8);
+    pub fn new(
+        rng: Rng,
+        room_id: Arc<RoomId>,
+        limits: RoomLimits,
+        channels: ChannelCapacities,
+        recording_dir: PathBuf,
+        capture: CaptureConfig,
+    ) -> (Self, RoomActor) {
+        let (sender, receiver) = mpsc::channel(channels.room);
         let handle = RoomHandle {
             sender,
             room_id: room_id.clone(),
@@ -154,8 +766,19 @@ impl RoomHandle {
             rng,
             receiver,
             handle: handle.clone(),
+            limits,
+            channels,
+            recording_dir,
+            capture,
             participants: HashMap::new(),
-            participant_tasks: JoinSet::new(),
+            participant_tasks: ChildSet::default(),
+            recorder: None,
+            recorder_missed: false,
+            closing: false,
+            drain_deadline: None,
+            speakers: SpeakerDetector::default(),
+            active_speakers: None,
+            next_speaker_tick: Instant::now() + speaker::SPEAKER_TICK,
         };
         (handle, actor)
     }
@@ -164,15 +787,110 @@ impl RoomHandle {
         &self,
         handle: ParticipantHandle,
         actor: ParticipantActor,
-    ) -> Result<(), SendError<RoomMessage>> {
+    ) -> Result<(), RoomError> {
+        let (tx, rx) = oneshot::channel();
         self.sender
-            .send(RoomMessage::AddParticipant(handle, actor))
+            .send(RoomMessage::AddParticipant(handle, actor, tx))
             .await
+            .map_err(|_| RoomError::Closed)?;
+        rx.await.map_err(|_| RoomError::Closed)?
     }
 
     pub async fn publish(&self, track: TrackHandle) -> Result<(), SendError<RoomMessage>> {
         self.sender.send(RoomMessage::PublishTrack(track)).await
     }
+
+    /// Returns `None` when the room is gone.
+    pub async fn info(&self) -> Option<RoomInfo> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(RoomMessage::GetInfo(tx)).await.ok()?;
+        rx.await.ok()
+    }
+
+    /// Disconnects every session of `participant_id`, returns how many were kicked or
+    /// `None` when the room is gone.
+    pub async fn kick(&self, participant_id: ExternalParticipantId) -> Option<usize> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(RoomMessage::KickParticipant(participant_id, tx))
+            .await
+            .ok()?;
+        rx.await.ok()
+    }
+
+    pub async fn start_recording(&self, track_id: Option<Arc<EntityId>>) -> Result<(), RoomError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(RoomMessage::StartRecording(track_id, tx))
+            .await
+            .map_err(|_| RoomError::Closed)?;
+        rx.await.map_err(|_| RoomError::Closed)?
+    }
+
+    pub async fn stop_recording(&self, track_id: Option<Arc<EntityId>>) -> Result<(), RoomError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(RoomMessage::StopRecording(track_id, tx))
+            .await
+            .map_err(|_| RoomError::Closed)?;
+        rx.await.map_err(|_| RoomError::Closed)?
+    }
+
+    /// Captures every session of `participant_id`, returns the files or `None` when the
+    /// room is gone.
+    pub async fn capture(
+        &self,
+        participant_id: ExternalParticipantId,
+        req: CaptureRequest,
+    ) -> Option<Vec<PathBuf>> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(RoomMessage::StartCapture(participant_id, req, tx))
+            .await
+            .ok()?;
+        rx.await.ok()
+    }
+
+    pub async fn close(&self) -> Result<(), SendError<RoomMessage>> {
+        self.sender.send(RoomMessage::Close).await
+    }
+
+    /// Asks everyone to reconnect elsewhere. The room stops once empty, or disconnects
+    /// whoever is left after `deadline`.
+    pub async fn drain(&self, deadline: Duration) -> Result<(), SendError<RoomMessage>> {
+        self.sender.send(RoomMessage::Drain(deadline)).await
+    }
+
+    pub async fn publish_data(
+        &self,
+        channel: Arc<DataChannel>,
+    ) -> Result<(), SendError<RoomMessage>> {
+        self.sender.send(RoomMessage::PublishData(channel)).await
+    }
+
+    pub async fn unpublish_data(
+        &self,
+        channel: Arc<DataChannel>,
+    ) -> Result<(), SendError<RoomMessage>> {
+        self.sender.send(RoomMessage::UnpublishData(channel)).await
+    }
+
+    /// Dropped when the room is busy, reliable or not. The room never waits on a
+    /// participant either, so neither side can block the other.
+    pub fn forward_data(&self, packet: Arc<DataPacket>) -> Result<(), TrySendError<RoomMessage>> {
+        let res = self.sender.try_send(RoomMessage::ForwardData(packet));
+
+        if let Err(TrySendError::Full(_)) = &res {
+            telemetry::record_dropped("room_forward_data");
+        }
+        res
+    }
+
+    pub async fn unpublish(&self, track_id: Arc<TrackId>) -> Result<(), SendError<RoomMessage>> {
+        self.sender
+            .send(RoomMessage::UnpublishTrack(track_id))
+            .await
+    }
 }
 
 impl Display for RoomHandle {
@@ -180,3 +898,427 @@ impl Display for RoomHandle {
         f.write_str(self.room_id.deref().as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bwe::LayerBitrates,
+        controller::testing,
+        message::TrackIn,
+        participant::{ParticipantConfig, ParticipantControlMessage, ParticipantDataMessage},
+    };
+    use bytes::Bytes;
+    use rand::SeedableRng;
+    use str0m::{
+        Rtc,
+        format::Codec,
+        media::{MediaKind, Mid},
+    };
+
+    fn room() -> RoomActor {
+        let room_id = ExternalRoomId::new("room".to_string()).unwrap();
+        let (_, room) = RoomHandle::new(
+            Rng::seed_from_u64(1),
+            Arc::new(RoomId::new(room_id)),
+            RoomLimits::default(),
+            ChannelCapacities::default(),
+            PathBuf::new(),
+            CaptureConfig::default(),
+        );
+        room
+    }
+
+    /// Adds `name` to the room, the messages the room sends it end up in the receivers.
+    /// The data mailbox holds a single message.
+    async fn join(
+        room: &mut RoomActor,
+        name: &str,
+    ) -> (
+        Arc<ParticipantId>,
+        mpsc::Receiver<ParticipantControlMessage>,
+        mpsc::Receiver<ParticipantDataMessage>,
+    ) {
+        let (transport, _) = testing::spawn_transport().await;
+        let external = ExternalParticipantId::new(name.to_string()).unwrap();
+        let participant_id = Arc::new(ParticipantId::new(&mut room.rng, external));
+        let (_, actor) = ParticipantHandle::new(
+            room.rng.clone(),
+            transport,
+            room.handle.clone(),
+            participant_id.clone(),
+            Rtc::new(),
+            ParticipantConfig::default(),
+            room.channels,
+        );
+        let (data_sender, data_receiver) = mpsc::channel(1);
+        let (control_sender, control_receiver) = mpsc::channel(room.channels.participant_control);
+        let handle = ParticipantHandle {
+            data_sender,
+            control_sender,
+            participant_id: participant_id.clone(),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        room.handle_message(RoomMessage::AddParticipant(handle, actor, tx))
+            .await;
+        rx.await.unwrap().unwrap();
+        (participant_id, control_receiver, data_receiver)
+    }
+
+    /// A track of `origin` that has already sent media in `codec`.
+    fn track(
+        room: &mut RoomActor,
+        origin: &Arc<ParticipantId>,
+        kind: MediaKind,
+        codec: Codec,
+    ) -> TrackHandle {
+        let meta = Arc::new(TrackIn {
+            id: Arc::new(TrackId::new(&mut room.rng, origin.clone(), Mid::from("0"))),
+            kind,
+            simulcast: None,
+        });
+        let (data_sender, _) = mpsc::channel(1);
+        let (control_sender, _) = mpsc::channel(1);
+        let (_, bitrates) = watch::channel(LayerBitrates::default());
+        let (_, codec) = watch::channel(Some(codec));
+        let (_, audio_level) = watch::channel(None);
+        TrackHandle {
+            data_sender,
+            control_sender,
+            meta,
+            bitrates,
+            codec,
+            audio_level,
+        }
+    }
+
+    fn data_channel(origin: &Arc<ParticipantId>, label: &str, reliable: bool) -> Arc<DataChannel> {
+        Arc::new(DataChannel {
+            origin: origin.clone(),
+            label: Arc::from(label),
+            reliable,
+        })
+    }
+
+    fn packet(
+        channel: &Arc<DataChannel>,
+        size: usize,
+        destinations: Option<Vec<ExternalParticipantId>>,
+    ) -> Arc<DataPacket> {
+        Arc::new(DataPacket {
+            channel: channel.clone(),
+            payload: Bytes::from(vec![0; size]),
+            binary: true,
+            destinations,
+        })
+    }
+
+    fn drain<T>(receiver: &mut mpsc::Receiver<T>) -> usize {
+        let mut count = 0;
+        while receiver.try_recv().is_ok() {
+            count += 1;
+        }
+        count
+    }
+
+    fn synced(msg: ParticipantControlMessage) -> Arc<RoomSnapshot> {
+        match msg {
+            ParticipantControlMessage::RoomSynced(snapshot) => snapshot,
+            msg => panic!("expected room synced, got {msg:?}"),
+        }
+    }
+
+    fn joined(msg: ParticipantControlMessage) -> Vec<Arc<ParticipantId>> {
+        match msg {
+            ParticipantControlMessage::ParticipantsJoined(participants) => participants
+                .iter()
+                .map(|participant| participant.participant_id.clone())
+                .collect(),
+            msg => panic!("expected participants joined, got {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_on_join() {
+        let mut room = room();
+        let (alice, mut alice_rx, _) = join(&mut room, "alice").await;
+        let snapshot = synced(alice_rx.try_recv().unwrap());
+        assert!(snapshot.participants.is_empty());
+        assert!(snapshot.tracks.is_empty());
+
+        let audio = track(&mut room, &alice, MediaKind::Audio, Codec::Opus);
+        room.handle_message(RoomMessage::PublishTrack(audio)).await;
+        drain(&mut alice_rx);
+
+        let (bob, mut bob_rx, _) = join(&mut room, "bob").await;
+        let snapshot = synced(bob_rx.try_recv().unwrap());
+        assert_eq!(snapshot.participants.len(), 1);
+        assert_eq!(snapshot.participants[0].participant_id, alice);
+        assert_eq!(snapshot.tracks.len(), 1);
+        assert_eq!(joined(alice_rx.try_recv().unwrap()), vec![bob]);
+        assert!(alice_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missed_updates_are_resent() {
+        let mut room = room();
+        let (alice, _alice_rx, _) = join(&mut room, "alice").await;
+        let (bob, mut bob_rx, _) = join(&mut room, "bob").await;
+        drain(&mut bob_rx);
+
+        // Nobody reads bob's mailbox, he misses the track
+        let capacity = room.channels.participant_control;
+        for _ in 0..capacity {
+            room.handle_forward_data(packet(&data_channel(&alice, "chat", true), 16, None));
+        }
+        let audio = track(&mut room, &alice, MediaKind::Audio, Codec::Opus);
+        room.handle_message(RoomMessage::PublishTrack(audio)).await;
+        assert!(room.participants[&bob].missed.room);
+
+        // Still full, it's tried again on the next tick
+        room.resend_missed();
+        assert!(room.participants[&bob].missed.room);
+
+        drain(&mut bob_rx);
+        room.resend_missed();
+        let snapshot = synced(bob_rx.try_recv().unwrap());
+        assert_eq!(snapshot.tracks.len(), 1);
+        assert!(!room.participants[&bob].missed.room);
+
+        // A disconnect isn't lost either
+        for _ in 0..capacity {
+            room.handle_forward_data(packet(&data_channel(&alice, "chat", true), 16, None));
+        }
+        room.handle_message(RoomMessage::Close).await;
+        assert!(room.participants[&bob].missed.disconnect);
+        drain(&mut bob_rx);
+        room.resend_missed();
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ParticipantControlMessage::Disconnect)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_joined_and_left_are_ordered() {
+        let mut room = room();
+        let (_, mut alice_rx, _) = join(&mut room, "alice").await;
+        drain(&mut alice_rx);
+
+        let (bob, _bob_rx, _) = join(&mut room, "bob").await;
+        room.handle_participant_left(bob.clone(), ActorStatus::ShutDown);
+        let (carol, _carol_rx, _) = join(&mut room, "carol").await;
+
+        assert_eq!(joined(alice_rx.try_recv().unwrap()), vec![bob.clone()]);
+        assert!(matches!(
+            alice_rx.try_recv(),
+            Ok(ParticipantControlMessage::ParticipantLeft(left)) if left == bob
+        ));
+        assert_eq!(joined(alice_rx.try_recv().unwrap()), vec![carol]);
+    }
+
+    #[tokio::test]
+    async fn test_full_mailbox_does_not_block_the_room() {
+        let mut room = room();
+        let (_, _alice_rx, _) = join(&mut room, "alice").await;
+
+        // Nobody reads alice's mailbox, the room keeps going once it's full
+        let capacity = room.channels.participant_control;
+        for i in 0..capacity + 2 {
+            let (participant_id, _, _) = join(&mut room, &format!("p{i}")).await;
+            room.handle_participant_left(participant_id, ActorStatus::ShutDown);
+        }
+        assert_eq!(room.participants.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_data_routing() {
+        let mut room = room();
+        let (alice, mut alice_rx, mut alice_data) = join(&mut room, "alice").await;
+        let (_, mut bob_rx, mut bob_data) = join(&mut room, "bob").await;
+        let (carol, mut carol_rx, mut carol_data) = join(&mut room, "carol").await;
+        drain(&mut alice_rx);
+        drain(&mut bob_rx);
+        drain(&mut carol_rx);
+
+        // Reliable data goes through the control mailboxes, never back to its origin
+        let chat = data_channel(&alice, "chat", true);
+        room.handle_forward_data(packet(&chat, 16, None));
+        assert!(alice_rx.try_recv().is_err());
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ParticipantControlMessage::ForwardData(_))
+        ));
+        assert!(matches!(
+            carol_rx.try_recv(),
+            Ok(ParticipantControlMessage::ForwardData(_))
+        ));
+
+        room.handle_forward_data(packet(&chat, 16, Some(vec![carol.external.clone()])));
+        assert!(bob_rx.try_recv().is_err());
+        assert!(matches!(
+            carol_rx.try_recv(),
+            Ok(ParticipantControlMessage::ForwardData(_))
+        ));
+
+        // Unreliable data goes through the data mailboxes
+        let cursor = data_channel(&alice, "cursor", false);
+        room.handle_forward_data(packet(&cursor, 16, None));
+        assert!(alice_data.try_recv().is_err());
+        assert!(bob_rx.try_recv().is_err());
+        assert!(matches!(
+            bob_data.try_recv(),
+            Ok(ParticipantDataMessage::ForwardData(_))
+        ));
+        assert!(matches!(
+            carol_data.try_recv(),
+            Ok(ParticipantDataMessage::ForwardData(_))
+        ));
+
+        let max = room.limits.max_data_message_size;
+        room.handle_forward_data(packet(&chat, max + 1, None));
+        room.handle_forward_data(packet(&cursor, max + 1, None));
+        assert!(bob_rx.try_recv().is_err());
+        assert!(bob_data.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_mailbox_drops_data() {
+        let mut room = room();
+        let (alice, mut alice_rx, _) = join(&mut room, "alice").await;
+        let (bob, mut bob_rx, mut bob_data) = join(&mut room, "bob").await;
+        drain(&mut alice_rx);
+        drain(&mut bob_rx);
+
+        // Nobody reads bob's mailboxes, the room drops what doesn't fit instead of waiting
+        let chat = data_channel(&alice, "chat", true);
+        let capacity = room.channels.participant_control;
+        for _ in 0..capacity + 2 {
+            room.handle_forward_data(packet(&chat, 16, None));
+        }
+        assert_eq!(drain(&mut bob_rx), capacity);
+
+        // Alice hears about each reliable message that bob missed
+        for _ in 0..2 {
+            assert!(matches!(
+                alice_rx.try_recv(),
+                Ok(ParticipantControlMessage::DataDropped(channel, receivers))
+                    if Arc::ptr_eq(&channel, &chat) && receivers == vec![bob.external.clone()]
+            ));
+        }
+        assert!(alice_rx.try_recv().is_err());
+
+        let cursor = data_channel(&alice, "cursor", false);
+        for _ in 0..3 {
+            room.handle_forward_data(packet(&cursor, 16, None));
+        }
+        assert_eq!(drain(&mut bob_data), 1);
+    }
+
+    #[tokio::test]
+    async fn test_data_channel_unpublished() {
+        let mut room = room();
+        let (alice, _alice_rx, _) = join(&mut room, "alice").await;
+        let (_, mut bob_rx, _) = join(&mut room, "bob").await;
+        drain(&mut bob_rx);
+
+        let chat = data_channel(&alice, "chat", true);
+        let cursor = data_channel(&alice, "cursor", false);
+        room.handle_message(RoomMessage::PublishData(chat.clone()))
+            .await;
+        room.handle_message(RoomMessage::PublishData(cursor.clone()))
+            .await;
+        assert_eq!(drain(&mut bob_rx), 2);
+
+        // A channel that was published again isn't removed by the stale one
+        room.handle_message(RoomMessage::UnpublishData(data_channel(
+            &alice, "chat", true,
+        )))
+        .await;
+        assert!(bob_rx.try_recv().is_err());
+
+        room.handle_message(RoomMessage::UnpublishData(chat.clone()))
+            .await;
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ParticipantControlMessage::DataChannelsRemoved(channels))
+                if channels.len() == 1 && Arc::ptr_eq(&channels[0], &chat)
+        ));
+
+        // The rest goes away with the publisher
+        room.handle_participant_left(alice, ActorStatus::ShutDown);
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ParticipantControlMessage::ParticipantLeft(_))
+        ));
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ParticipantControlMessage::DataChannelsRemoved(channels))
+                if channels.len() == 1 && Arc::ptr_eq(&channels[0], &cursor)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_record_unsupported_codec() {
+        let mut room = room();
+        let (alice, _alice_rx, _) = join(&mut room, "alice").await;
+        let h264 = track(&mut room, &alice, MediaKind::Video, Codec::H264);
+        let track_id = h264.meta.id.internal.clone();
+        room.handle_message(RoomMessage::PublishTrack(h264)).await;
+
+        assert!(matches!(
+            room.start_recording(Some(track_id)).await,
+            Err(RoomError::UnsupportedCodec)
+        ));
+        assert!(room.recorder.is_none());
+
+        let vp8 = track(&mut room, &alice, MediaKind::Video, Codec::Vp8);
+        let track_id = vp8.meta.id.internal.clone();
+        room.handle_message(RoomMessage::PublishTrack(vp8)).await;
+        assert!(room.start_recording(Some(track_id)).await.is_ok());
+        assert!(room.recorder.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_full_mailbox_drops_active_speakers() {
+        let mut room = room();
+        let (alice, mut alice_rx, _) = join(&mut room, "alice").await;
+        let (bob, mut bob_rx, _) = join(&mut room, "bob").await;
+        drain(&mut alice_rx);
+        drain(&mut bob_rx);
+
+        let mut audio = track(&mut room, &alice, MediaKind::Audio, Codec::Opus);
+        let level = speaker::AudioLevel {
+            dbov: 0,
+            measured_at: Instant::now(),
+        };
+        audio.audio_level = watch::channel(Some(level)).1;
+        room.handle_message(RoomMessage::PublishTrack(audio)).await;
+        drain(&mut alice_rx);
+
+        // Nobody reads bob's mailbox, the room doesn't wait for it
+        let capacity = room.channels.participant_control;
+        for _ in 0..capacity {
+            room.handle_forward_data(packet(&data_channel(&alice, "chat", true), 16, None));
+        }
+        room.detect_speakers();
+
+        assert!(matches!(
+            alice_rx.try_recv(),
+            Ok(ParticipantControlMessage::ActiveSpeakersChanged(speakers))
+                if speakers.dominant.as_ref() == Some(&alice)
+        ));
+        assert!(room.participants[&bob].missed.speakers);
+        assert_eq!(drain(&mut bob_rx), capacity);
+
+        // Bob gets the latest speakers once his mailbox drains
+        room.resend_missed();
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ParticipantControlMessage::ActiveSpeakersChanged(speakers))
+                if speakers.dominant.as_ref() == Some(&alice)
+        ));
+        assert!(!room.participants[&bob].missed.speakers);
+    }
+}
@@ -0,0 +1,70 @@
+//! Graceful shutdown. Once draining, new sessions are refused while the existing ones
+//! are told to reconnect elsewhere.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Shared flag that flips once when the server starts draining.
+#[derive(Clone, Debug, Default)]
+pub struct Drain {
+    draining: Arc<AtomicBool>,
+}
+
+impl Drain {
+    pub fn start(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("failed to listen for ctrl-c: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("failed to listen for sigterm: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("received sigint"),
+        _ = terminate => tracing::info!("received sigterm"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_is_shared() {
+        let drain = Drain::default();
+        let cloned = drain.clone();
+        assert!(!cloned.is_draining());
+
+        drain.start();
+        assert!(cloned.is_draining());
+    }
+}
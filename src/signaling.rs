@@ -1,21 +1,39 @@
+use std::sync::Arc;
+
 use crate::{
-    controller::{ControllerError, ControllerHandle},
-    entity::{ExternalParticipantId, ExternalRoomId},
+    auth::{AuthError, Authenticator, Claims, Grants},
+    controller::{Allocation, ControllerError, ControllerHandle, SessionOwner},
+    entity::{EntityId, ExternalParticipantId, ExternalRoomId},
+    ice,
+    participant::{IceUpdate, ParticipantConfig, ParticipantError, TrackFilter},
+    shutdown::Drain,
+    turn::{IceServer, TurnCredentials},
 };
 use axum::{
     Router,
-    extract::{Query, State},
-    http::StatusCode,
+    extract::{FromRef, Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{delete, post},
 };
 use axum_extra::{TypedHeader, headers::ContentType};
 
+const SDP_CONTENT_TYPE: &str = "application/sdp";
+const TRICKLE_ICE_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+/// Seconds a client waits before joining a full room again.
+const ROOM_FULL_RETRY_AFTER_SECS: u64 = 5;
+
 #[derive(thiserror::Error, Debug)]
 pub enum SignalingError {
     #[error("join failed: {0}")]
     JoinError(#[from] ControllerError),
 
+    #[error("unauthorized: {0}")]
+    Unauthorized(#[from] AuthError),
+
+    #[error("unsupported content type, expected {0}")]
+    UnsupportedMediaType(&'static str),
+
     #[error("server is busy, please try again later.")]
     ServiceUnavailable,
 
@@ -25,49 +43,690 @@ pub enum SignalingError {
 
 impl IntoResponse for SignalingError {
     fn into_response(self) -> Response {
+        let room_full = matches!(self, SignalingError::JoinError(ControllerError::RoomFull));
         let status = match self {
             SignalingError::JoinError(ControllerError::OfferInvalid(_)) => StatusCode::BAD_REQUEST,
             SignalingError::JoinError(ControllerError::OfferRejected(_)) => StatusCode::BAD_REQUEST,
             SignalingError::JoinError(ControllerError::ServiceUnavailable) => {
                 StatusCode::SERVICE_UNAVAILABLE
             }
+            // Someone may leave, unlike a rejected token
+            SignalingError::JoinError(ControllerError::RoomFull) => StatusCode::SERVICE_UNAVAILABLE,
+            SignalingError::JoinError(ControllerError::TooManyRooms) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            SignalingError::JoinError(ControllerError::SessionNotFound) => StatusCode::NOT_FOUND,
+            SignalingError::JoinError(ControllerError::Forbidden) => StatusCode::FORBIDDEN,
+            SignalingError::JoinError(ControllerError::RoomNotFound) => StatusCode::NOT_FOUND,
+            SignalingError::JoinError(ControllerError::ParticipantNotFound) => {
+                StatusCode::NOT_FOUND
+            }
+            SignalingError::JoinError(ControllerError::TrackNotFound) => StatusCode::NOT_FOUND,
+            SignalingError::JoinError(ControllerError::RecordingNotFound) => StatusCode::NOT_FOUND,
+            SignalingError::JoinError(ControllerError::UnsupportedCodec) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            SignalingError::JoinError(ControllerError::UpdateRejected(
+                ParticipantError::IceSessionMismatch,
+            )) => StatusCode::PRECONDITION_FAILED,
+            SignalingError::JoinError(ControllerError::UpdateRejected(ParticipantError::Gone)) => {
+                StatusCode::NOT_FOUND
+            }
+            SignalingError::JoinError(ControllerError::UpdateRejected(_)) => {
+                StatusCode::BAD_REQUEST
+            }
             SignalingError::JoinError(ControllerError::Unknown(_)) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
             SignalingError::JoinError(ControllerError::IOError(_)) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
+            SignalingError::Unauthorized(AuthError::Forbidden(_)) => StatusCode::FORBIDDEN,
+            SignalingError::Unauthorized(AuthError::InvalidKey(_)) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            SignalingError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            SignalingError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
             SignalingError::Unknown(_) => StatusCode::INTERNAL_SERVER_ERROR,
             SignalingError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         };
-        (status, self.to_string()).into_response()
+
+        let mut response = (status, self.to_string()).into_response();
+        if room_full {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(ROOM_FULL_RETRY_AFTER_SECS),
+            );
+        }
+        response
     }
 }
 
+#[derive(Clone, FromRef)]
+pub struct SignalingState {
+    pub controller: ControllerHandle,
+    pub auth: Arc<Authenticator>,
+    /// Set when the embedded TURN server is enabled.
+    pub turn: Option<Arc<TurnCredentials>>,
+    /// New sessions are refused once set, existing ones can still be updated and deleted.
+    pub drain: Drain,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct ParticipantInfo {
     room: ExternalRoomId,
     participant: ExternalParticipantId,
 }
 
-#[axum::debug_handler]
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct ViewerInfo {
+    room: ExternalRoomId,
+    participant: Option<ExternalParticipantId>,
+    /// Only receive tracks published by this participant.
+    publisher: Option<ExternalParticipantId>,
+    /// Only receive this track.
+    track: Option<EntityId>,
+}
+
+#[axum::debug_handler(state = SignalingState)]
 async fn spawn_participant(
     Query(info): Query<ParticipantInfo>,
-    State(controller): State<ControllerHandle>,
-    TypedHeader(_content_type): TypedHeader<ContentType>,
+    State(state): State<SignalingState>,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    headers: HeaderMap,
     raw_offer: String,
 ) -> Result<String, SignalingError> {
-    // TODO: validate content_type = "application/sdp"
+    let allocation = join(&state, &content_type, &headers, info, raw_offer, |grants| {
+        ParticipantConfig {
+            grants,
+            ..Default::default()
+        }
+    })
+    .await?;
+
+    Ok(allocation.answer)
+}
+
+/// WHIP ingest, https://www.rfc-editor.org/rfc/rfc9725.html
+///
+/// Encoders are publish-only, they neither subscribe nor open data channels.
+#[axum::debug_handler(state = SignalingState)]
+async fn whip(
+    Query(info): Query<ParticipantInfo>,
+    State(state): State<SignalingState>,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    headers: HeaderMap,
+    raw_offer: String,
+) -> Result<Response, SignalingError> {
+    let allocation = join(
+        &state,
+        &content_type,
+        &headers,
+        info,
+        raw_offer,
+        ParticipantConfig::publish_only,
+    )
+    .await?;
+
+    Ok(session_created(allocation, state.turn.as_deref()))
+}
+
+/// Joins a named participant, `config` turns the token's grants into its settings.
+async fn join(
+    state: &SignalingState,
+    content_type: &ContentType,
+    headers: &HeaderMap,
+    info: ParticipantInfo,
+    raw_offer: String,
+    config: impl FnOnce(Grants) -> ParticipantConfig,
+) -> Result<Allocation, SignalingError> {
+    ensure_accepting(&state.drain)?;
+    ensure_content_type(content_type, SDP_CONTENT_TYPE)?;
+    let claims = verify_token(&state.auth, headers)?;
+    let grants = authorize(claims.as_ref(), &info.room, &info.participant)?;
+
+    let allocation = state
+        .controller
+        .allocate(
+            info.room,
+            Some(info.participant),
+            raw_offer,
+            ParticipantConfig {
+                metadata: claims.and_then(|claims| claims.metadata),
+                ..config(grants)
+            },
+        )
+        .await?;
+
+    Ok(allocation)
+}
+
+/// WHEP egress, https://datatracker.ietf.org/doc/draft-ietf-wish-whep/
+///
+/// Viewers are subscribe-only and get auto-subscribed to the room tracks matching
+/// the optional publisher or track, no data channel is required.
+#[axum::debug_handler(state = SignalingState)]
+async fn whep(
+    Query(info): Query<ViewerInfo>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    State(turn): State<Option<Arc<TurnCredentials>>>,
+    State(drain): State<Drain>,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    headers: HeaderMap,
+    raw_offer: String,
+) -> Result<Response, SignalingError> {
+    ensure_accepting(&drain)?;
+    ensure_content_type(&content_type, SDP_CONTENT_TYPE)?;
+    let claims = verify_token(&auth, &headers)?;
+
+    let participant = info
+        .participant
+        .or_else(|| claims.as_ref().map(|claims| claims.sub.clone()));
+    let grants = match &participant {
+        Some(participant) => authorize(claims.as_ref(), &info.room, participant)?,
+        // Anonymous viewers are named by the controller, only without authentication
+        None => Grants::all(),
+    };
+    let filter = TrackFilter {
+        participant: info.publisher,
+        track: info.track,
+    };
+    let allocation = controller
+        .allocate(
+            info.room,
+            participant,
+            raw_offer,
+            ParticipantConfig {
+                metadata: claims.and_then(|claims| claims.metadata),
+                ..ParticipantConfig::subscribe_only(grants, filter)
+            },
+        )
+        .await?;
+
+    Ok(session_created(allocation, turn.as_deref()))
+}
+
+/// Trickle ICE and ICE restarts, https://www.rfc-editor.org/rfc/rfc9725.html#section-4.3
+#[axum::debug_handler(state = SignalingState)]
+async fn patch_session(
+    Path(session_id): Path<EntityId>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, SignalingError> {
+    ensure_content_type(&content_type, TRICKLE_ICE_CONTENT_TYPE)?;
+    let claims = verify_token(&auth, &headers)?;
+
+    let if_match = headers
+        .get(header::IF_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().trim_matches('"').to_string());
+    let update = IceUpdate {
+        if_match,
+        fragment: ice::parse_ice_fragment(&body),
+    };
+    let session = controller
+        .update_ice(session_id, session_owner(claims)?, update)
+        .await?;
+
+    let Some(creds) = session.restarted else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
 
-    let answer = controller
-        .allocate(info.room, info.participant, raw_offer)
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, TRICKLE_ICE_CONTENT_TYPE.to_string()),
+            (header::ETAG, format!("\"{}\"", session.etag)),
+        ],
+        ice::format_ice_credentials(&creds.ufrag, &creds.pass),
+    )
+        .into_response())
+}
+
+#[axum::debug_handler(state = SignalingState)]
+async fn delete_session(
+    Path(session_id): Path<EntityId>,
+    State(controller): State<ControllerHandle>,
+    State(auth): State<Arc<Authenticator>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, SignalingError> {
+    let claims = verify_token(&auth, &headers)?;
+    controller
+        .delete_session(session_id, session_owner(claims)?)
         .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Extracts and verifies the bearer token. Returns `None` when authentication is disabled.
+pub(crate) fn verify_token(
+    auth: &Authenticator,
+    headers: &HeaderMap,
+) -> Result<Option<Claims>, AuthError> {
+    if !auth.is_enabled() {
+        return Ok(None);
+    }
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(AuthError::MissingToken)?;
+    auth.verify(token.trim()).map(Some)
+}
+
+/// Checks that the token is issued for joining `room` as `participant`.
+fn authorize(
+    claims: Option<&Claims>,
+    room: &ExternalRoomId,
+    participant: &ExternalParticipantId,
+) -> Result<Grants, AuthError> {
+    let Some(claims) = claims else {
+        return Ok(Grants::all());
+    };
+
+    if claims.room.as_ref() != Some(room) {
+        return Err(AuthError::Forbidden("room"));
+    }
+
+    if claims.sub != *participant {
+        return Err(AuthError::Forbidden("participant"));
+    }
+
+    Ok(claims.grants)
+}
 
-    Ok(answer)
+/// Sessions are managed with a token for the same room and participant as the one
+/// that created them, see [`authorize`].
+fn session_owner(claims: Option<Claims>) -> Result<Option<SessionOwner>, AuthError> {
+    let Some(claims) = claims else {
+        return Ok(None);
+    };
+
+    let room_id = claims.room.ok_or(AuthError::Forbidden("room"))?;
+    Ok(Some(SessionOwner {
+        room_id,
+        participant_id: claims.sub,
+    }))
+}
+
+fn session_created(allocation: Allocation, turn: Option<&TurnCredentials>) -> Response {
+    let mut response = (
+        StatusCode::CREATED,
+        [
+            (header::CONTENT_TYPE, SDP_CONTENT_TYPE.to_string()),
+            (header::LOCATION, session_location(&allocation.session_id)),
+            (header::ETAG, format!("\"{}\"", allocation.etag)),
+        ],
+        allocation.answer,
+    )
+        .into_response();
+
+    // ICE servers for the client, https://www.rfc-editor.org/rfc/rfc9725.html#section-4.6
+    if let Some(turn) = turn {
+        for link in ice_server_links(&turn.issue()) {
+            if let Ok(value) = HeaderValue::from_str(&link) {
+                response.headers_mut().append(header::LINK, value);
+            }
+        }
+    }
+    response
+}
+
+fn ice_server_links(server: &IceServer) -> Vec<String> {
+    server
+        .urls
+        .iter()
+        .map(|url| {
+            format!(
+                "<{url}>; rel=\"ice-server\"; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+                server.username, server.credential
+            )
+        })
+        .collect()
+}
+
+fn session_location(session_id: &EntityId) -> String {
+    format!("/resources/{session_id}")
+}
+
+fn ensure_content_type(
+    content_type: &ContentType,
+    expected: &'static str,
+) -> Result<(), SignalingError> {
+    // Ignore parameters, e.g. "application/sdp; charset=utf-8"
+    let content_type = content_type.to_string();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if essence.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(SignalingError::UnsupportedMediaType(expected))
+    }
+}
+
+fn ensure_accepting(drain: &Drain) -> Result<(), SignalingError> {
+    if drain.is_draining() {
+        Err(SignalingError::ServiceUnavailable)
+    } else {
+        Ok(())
+    }
 }
 
-pub fn router(controller: ControllerHandle) -> Router {
+pub fn router(
+    controller: ControllerHandle,
+    auth: Arc<Authenticator>,
+    turn: Option<Arc<TurnCredentials>>,
+    drain: Drain,
+) -> Router {
     Router::new()
         .route("/", post(spawn_participant))
-        .with_state(controller)
+        .route("/whip", post(whip))
+        .route("/whep", post(whep))
+        .route(
+            "/resources/{session_id}",
+            delete(delete_session).patch(patch_session),
+        )
+        .with_state(SignalingState {
+            controller,
+            auth,
+            turn,
+            drain,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{ApiKey, ApiKeyMaterial, issue_token},
+        controller::testing,
+        entity,
+    };
+    use axum::{body::Body, http::Request};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tower::ServiceExt;
+
+    const KEY_ID: &str = "kid_3mJr7AoUXx2Wqd";
+    const SECRET: &str = "sk_2NEpo7TZRRrLZSi2U";
+    const PROJECT_ID: &str = "p_6aBfUS7iYUcWJ";
+
+    async fn app() -> Router {
+        let auth = Authenticator::new(vec![ApiKey {
+            id: KEY_ID.to_string(),
+            project_id: PROJECT_ID.to_string(),
+            material: ApiKeyMaterial::Secret(SECRET.to_string()),
+        }])
+        .unwrap();
+        router(
+            testing::spawn_controller().await,
+            Arc::new(auth),
+            None,
+            Drain::default(),
+        )
+    }
+
+    fn token(room: &str, participant: &str) -> String {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let claims = Claims {
+            iss: KEY_ID.to_string(),
+            sub: ExternalParticipantId::new(participant.to_string()).unwrap(),
+            exp,
+            project: PROJECT_ID.to_string(),
+            room: Some(ExternalRoomId::new(room.to_string()).unwrap()),
+            grants: Grants::all(),
+            metadata: None,
+        };
+        issue_token(KEY_ID, SECRET, &claims).unwrap()
+    }
+
+    fn request(
+        method: &str,
+        uri: &str,
+        content_type: Option<&str>,
+        token: Option<&str>,
+        body: String,
+    ) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(content_type) = content_type {
+            builder = builder.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    fn whip_request(token: Option<&str>) -> Request<Body> {
+        request(
+            "POST",
+            "/whip?room=room&participant=alice",
+            Some(SDP_CONTENT_TYPE),
+            token,
+            testing::offer(),
+        )
+    }
+
+    /// Creates a WHIP session as alice and returns its resource path.
+    async fn create_session(app: &Router) -> String {
+        let token = token("room", "alice");
+        let response = app
+            .clone()
+            .oneshot(whip_request(Some(&token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_viewers_are_unique() {
+        let controller = testing::spawn_controller().await;
+        let app = router(
+            controller.clone(),
+            Arc::new(Authenticator::new(vec![]).unwrap()),
+            None,
+            Drain::default(),
+        );
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(request(
+                    "POST",
+                    "/whep?room=room",
+                    Some(SDP_CONTENT_TYPE),
+                    None,
+                    testing::offer(),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        let room = controller
+            .room_info(ExternalRoomId::new("room".to_string()).unwrap())
+            .await
+            .unwrap();
+        let viewers: Vec<_> = room
+            .participants
+            .iter()
+            .map(|participant| participant.participant_id.as_str())
+            .collect();
+        assert_eq!(viewers.len(), 2);
+        assert!(viewers.iter().all(|id| id.starts_with("viewer-")));
+        assert_ne!(viewers[0], viewers[1]);
+    }
+
+    #[test]
+    fn test_room_full_is_retryable() {
+        let response = SignalingError::JoinError(ControllerError::RoomFull).into_response();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    }
+
+    #[tokio::test]
+    async fn test_whip_creates_session() {
+        let app = app().await;
+        let token = token("room", "alice");
+        let response = app.oneshot(whip_request(Some(&token))).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], SDP_CONTENT_TYPE);
+        assert!(
+            headers[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .starts_with("/resources/")
+        );
+        assert!(headers.contains_key(header::ETAG));
+    }
+
+    #[tokio::test]
+    async fn test_session_ids_are_random() {
+        let app = app().await;
+        let first = create_session(&app).await;
+        let second = create_session(&app).await;
+
+        let session_id = first.strip_prefix("/resources/").unwrap();
+        assert_eq!(
+            entity::get_prefix(session_id),
+            Some(entity::prefix::SESSION_ID)
+        );
+        assert_eq!(entity::decode_id(session_id).unwrap().len(), 16);
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_whip_rejects_invalid_requests() {
+        let app = app().await;
+
+        let response = app.clone().oneshot(whip_request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let bob = token("room", "bob");
+        let response = app.clone().oneshot(whip_request(Some(&bob))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let alice = token("room", "alice");
+        let response = app
+            .clone()
+            .oneshot(request(
+                "POST",
+                "/whip?room=room&participant=alice",
+                Some("application/json"),
+                Some(&alice),
+                testing::offer(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let response = app
+            .oneshot(request(
+                "POST",
+                "/whip?room=room&participant=alice",
+                Some(SDP_CONTENT_TYPE),
+                Some(&alice),
+                "not an offer".to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_delete_session_requires_owner() {
+        let app = app().await;
+        let location = create_session(&app).await;
+
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", &location, None, None, String::new()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for other in [token("room", "bob"), token("other", "alice")] {
+            let response = app
+                .clone()
+                .oneshot(request(
+                    "DELETE",
+                    &location,
+                    None,
+                    Some(&other),
+                    String::new(),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+
+        let alice = token("room", "alice");
+        let response = app
+            .clone()
+            .oneshot(request(
+                "DELETE",
+                &location,
+                None,
+                Some(&alice),
+                String::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(request(
+                "DELETE",
+                &location,
+                None,
+                Some(&alice),
+                String::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_patch_session_requires_owner() {
+        let app = app().await;
+        let location = create_session(&app).await;
+        let patch = |token: Option<String>| {
+            request(
+                "PATCH",
+                &location,
+                Some(TRICKLE_ICE_CONTENT_TYPE),
+                token.as_deref(),
+                "a=end-of-candidates\r\n".to_string(),
+            )
+        };
+
+        let response = app.clone().oneshot(patch(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(patch(Some(token("room", "bob"))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app
+            .oneshot(patch(Some(token("room", "alice"))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
 }
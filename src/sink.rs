@@ -1,9 +1,13 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use metrics::Counter;
 use tokio::sync::mpsc::{self, error::SendError};
 
 use crate::{
     actor::{Actor, ActorError},
-    message,
-    net::PacketSocket,
+    message::{self, EgressUDPPacket},
+    net::{self, PacketSocket, Transmit},
+    telemetry,
 };
 
 #[derive(Debug)]
@@ -12,60 +16,219 @@ pub enum UdpSinkMessage {
 }
 
 pub struct UdpSinkActor<S> {
+    // Bound address and shard index, shards share the address
+    id: String,
     socket: S,
     receiver: mpsc::Receiver<UdpSinkMessage>,
+    packets: Counter,
+    bytes: Counter,
 }
 
 impl<S: PacketSocket> Actor for UdpSinkActor<S> {
-    type ID = usize;
+    type ID = String;
 
     fn kind(&self) -> &'static str {
-        "udp_source"
+        "udp_sink"
     }
 
     fn id(&self) -> Self::ID {
-        0
+        self.id.clone()
     }
 
     async fn run(&mut self) -> Result<(), ActorError> {
-        // TODO: this is far from ideal. sendmmsg can be used to reduce the syscalls.
         // In the future, we'll rewrite the source and sink with a dedicated thread of io-uring.
-        //
-        // tokio/mio doesn't support batching: https://github.com/tokio-rs/mio/issues/185
-        // TODO: use quinn-udp optimizations, https://github.com/quinn-rs/quinn/blob/4f8a0f13cf7931ef9be573af5089c7a4a49387ae//quinn/src/runtime/tokio.rs#L1-L102
-        while let Some(msg) = self.receiver.recv().await {
-            match msg {
-                UdpSinkMessage::UdpPacket(packet) => {
-                    let res = self.socket.send_to(&packet.raw, packet.dst).await;
-                    if let Err(err) = res {
-                        tracing::warn!("failed to send udp packet to {:?}: {:?}", packet.dst, err);
+        let max_segments = self.socket.max_gso_segments().max(1);
+        let mut msgs = Vec::with_capacity(net::BATCH_SIZE);
+        let mut packets = Vec::with_capacity(net::BATCH_SIZE);
+        let mut buf = Vec::new();
+
+        while self.receiver.recv_many(&mut msgs, net::BATCH_SIZE).await > 0 {
+            packets.extend(msgs.drain(..).map(|msg| match msg {
+                UdpSinkMessage::UdpPacket(packet) => packet,
+            }));
+
+            let mut start = 0;
+            while start < packets.len() {
+                let count = gso_segments(&packets[start..], max_segments);
+                let batch = &packets[start..start + count];
+                start += count;
+
+                let transmit = if let [packet] = batch {
+                    Transmit {
+                        dst: packet.dst,
+                        contents: &packet.raw,
+                        segment_size: None,
+                    }
+                } else {
+                    buf.clear();
+                    for packet in batch {
+                        buf.extend_from_slice(&packet.raw);
+                    }
+                    Transmit {
+                        dst: batch[0].dst,
+                        contents: &buf,
+                        segment_size: Some(batch[0].raw.len()),
+                    }
+                };
+
+                match self.socket.send_batch(&transmit).await {
+                    Ok(()) => {
+                        self.packets.increment(count as u64);
+                        self.bytes.increment(transmit.contents.len() as u64);
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "failed to send udp packet to {:?}: {:?}",
+                            transmit.dst,
+                            err
+                        );
                     }
                 }
             }
+            packets.clear();
         }
         Ok(())
     }
 }
 
+/// Returns how many leading packets can be sent as a single GSO batch. GSO needs
+/// the same destination and the same segment size, only the last segment can be
+/// shorter.
+fn gso_segments(packets: &[EgressUDPPacket], max_segments: usize) -> usize {
+    let Some(first) = packets.first() else {
+        return 0;
+    };
+
+    let segment_size = first.raw.len();
+    let mut count = 1;
+    for packet in &packets[1..] {
+        if count >= max_segments || packet.dst != first.dst || packet.raw.len() > segment_size {
+            break;
+        }
+
+        count += 1;
+        if packet.raw.len() < segment_size {
+            break;
+        }
+    }
+    count
+}
+
+/// With SO_REUSEPORT shards, any socket can send on behalf of the shared local address.
+/// Packets are spread across the shards of the destination's address family by
+/// destination, which keeps a flow in order.
 #[derive(Clone, Debug)]
 pub struct UdpSinkHandle {
-    sender: mpsc::Sender<UdpSinkMessage>,
+    v4: Vec<mpsc::Sender<UdpSinkMessage>>,
+    v6: Vec<mpsc::Sender<UdpSinkMessage>>,
 }
 
 impl UdpSinkHandle {
-    pub fn new<S: PacketSocket>(socket: S) -> (Self, UdpSinkActor<S>) {
-        let (sender, receiver) = mpsc::channel(2048);
-        let handle = Self { sender };
-        let actor = UdpSinkActor { socket, receiver };
+    /// `shard` tells apart the sockets sharing a local address.
+    pub fn new<S: PacketSocket>(
+        socket: S,
+        capacity: usize,
+        shard: usize,
+    ) -> (Self, UdpSinkActor<S>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let is_ipv6 = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        let handle = if is_ipv6 {
+            Self {
+                v4: Vec::new(),
+                v6: vec![sender],
+            }
+        } else {
+            Self {
+                v4: vec![sender],
+                v6: Vec::new(),
+            }
+        };
+        let local_addr = socket
+            .local_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let actor = UdpSinkActor {
+            id: format!("{local_addr}/{shard}"),
+            socket,
+            receiver,
+            packets: metrics::counter!(telemetry::UDP_PACKETS, "local_addr" => local_addr.clone(), "direction" => "out"),
+            bytes: metrics::counter!(telemetry::UDP_BYTES, "local_addr" => local_addr, "direction" => "out"),
+        };
         (handle, actor)
     }
 
+    /// Combines the handles of sockets sharing the same local address.
+    pub fn sharded(shards: impl IntoIterator<Item = UdpSinkHandle>) -> Self {
+        let mut handle = Self {
+            v4: Vec::new(),
+            v6: Vec::new(),
+        };
+        for shard in shards {
+            handle.v4.extend(shard.v4);
+            handle.v6.extend(shard.v6);
+        }
+        handle
+    }
+
     pub async fn send(
         &self,
         msg: message::EgressUDPPacket,
     ) -> Result<(), SendError<UdpSinkMessage>> {
+        let senders = if msg.dst.is_ipv6() {
+            &self.v6
+        } else {
+            &self.v4
+        };
+        let sender = match senders.len() {
+            0 => return Err(SendError(UdpSinkMessage::UdpPacket(msg))),
+            1 => &senders[0],
+            len => {
+                let mut hasher = DefaultHasher::new();
+                msg.dst.hash(&mut hasher);
+                &senders[hasher.finish() as usize % len]
+            }
+        };
+
         // TODO: monitor backpressure and packet dropping
         // Await because we want the producer to slow down when a backpressure occurs
-        self.sender.send(UdpSinkMessage::UdpPacket(msg)).await
+        sender.send(UdpSinkMessage::UdpPacket(msg)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+
+    fn packet(dst: &str, len: usize) -> EgressUDPPacket {
+        EgressUDPPacket {
+            raw: Bytes::from(vec![0; len]),
+            dst: dst.parse::<SocketAddr>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_gso_same_destination() {
+        let packets = vec![
+            packet("10.0.0.1:5000", 1200),
+            packet("10.0.0.1:5000", 1200),
+            packet("10.0.0.1:5000", 800),
+            packet("10.0.0.1:5000", 1200),
+        ];
+        // a shorter segment ends the batch
+        assert_eq!(gso_segments(&packets, 64), 3);
+        assert_eq!(gso_segments(&packets, 2), 2);
+        assert_eq!(gso_segments(&packets[3..], 64), 1);
+    }
+
+    #[test]
+    fn test_gso_split_by_destination_and_size() {
+        let packets = vec![packet("10.0.0.1:5000", 1000), packet("10.0.0.2:5000", 1000)];
+        assert_eq!(gso_segments(&packets, 64), 1);
+
+        let packets = vec![packet("10.0.0.1:5000", 1000), packet("10.0.0.1:5000", 1200)];
+        assert_eq!(gso_segments(&packets, 64), 1);
+        assert_eq!(gso_segments(&[], 64), 0);
     }
 }
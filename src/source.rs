@@ -1,49 +1,88 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, SocketAddr},
+};
 
 use crate::{
     actor::{Actor, ActorError},
     ice,
     message::UDPPacket,
-    net::PacketSocket,
+    net::{self, PacketSocket, RecvMeta},
     participant::ParticipantHandle,
+    telemetry,
 };
 use bytes::Bytes;
+use metrics::Counter;
+use str0m::net::Protocol;
 use tokio::sync::mpsc::{self, error::SendError};
 
+/// Larger than any datagram we expect, ICE and SRTP packets fit in a typical MTU.
+const MAX_DATAGRAM_SIZE: usize = 2000;
+
+#[derive(Clone)]
 pub enum UdpSourceMessage {
     AddParticipant(String, ParticipantHandle),
     RemoveParticipant(String),
+    /// A packet from a TURN relayed address to a local address.
+    Relay(SocketAddr, SocketAddr, Bytes),
 }
 
 pub struct UdpSourceActor<S> {
+    // Bound address and shard index, shards share the address
+    id: String,
     receiver: mpsc::Receiver<UdpSourceMessage>,
-    local_addr: SocketAddr,
+    // Advertised addresses of the socket, the first one is used when the destination
+    // is unknown, e.g. behind a 1:1 NAT
+    local_addrs: Vec<SocketAddr>,
     socket: S,
     conns: HashMap<String, ParticipantHandle>,
     mapping: HashMap<SocketAddr, ParticipantHandle>,
     reverse: HashMap<String, Vec<SocketAddr>>,
+    packets: Counter,
+    bytes: Counter,
 }
 
 impl<S: PacketSocket> Actor for UdpSourceActor<S> {
-    type ID = usize;
+    type ID = String;
 
     fn kind(&self) -> &'static str {
         "udp_source"
     }
 
     fn id(&self) -> Self::ID {
-        0
+        self.id.clone()
     }
 
     async fn run(&mut self) -> Result<(), ActorError> {
-        // let mut buf = BytesMut::with_capacity(128 * 1024);
-        let mut buf = vec![0; 2000];
+        // With GRO, every buffer can hold multiple datagrams from the same source
+        let buf_size = (MAX_DATAGRAM_SIZE * self.socket.max_gro_segments()).min(u16::MAX as usize);
+        let mut bufs = vec![vec![0; buf_size]; net::BATCH_SIZE];
+        let mut metas = vec![
+            RecvMeta {
+                addr: self.local_addrs[0],
+                len: 0,
+                stride: 0,
+                dst_ip: None,
+            };
+            net::BATCH_SIZE
+        ];
 
         loop {
             tokio::select! {
-                res = self.socket.recv_from(&mut buf) => {
+                res = self.socket.recv_batch(&mut bufs, &mut metas) => {
                     match res {
-                        Ok((size, source)) => self.handle_packet(source, &buf[..size]),
+                        Ok(count) => {
+                            for (meta, buf) in metas[..count].iter().zip(&bufs) {
+                                let destination = self.local_addr(meta.dst_ip);
+                                let stride = meta.stride.max(1);
+                                self.bytes.increment(meta.len as u64);
+                                for datagram in buf[..meta.len].chunks(stride) {
+                                    self.packets.increment(1);
+                                    self.handle_packet(meta.addr, destination, datagram, false);
+                                }
+                            }
+                        }
                         Err(err) => {
                             tracing::error!("udp socket is failing: {err}");
                             break;
@@ -69,7 +108,20 @@ impl<S: PacketSocket> Actor for UdpSourceActor<S> {
 }
 
 impl<S: PacketSocket> UdpSourceActor<S> {
-    pub fn handle_packet(&mut self, source: SocketAddr, packet: &[u8]) {
+    fn local_addr(&self, dst_ip: Option<IpAddr>) -> SocketAddr {
+        dst_ip
+            .and_then(|ip| self.local_addrs.iter().find(|addr| addr.ip() == ip))
+            .copied()
+            .unwrap_or(self.local_addrs[0])
+    }
+
+    pub fn handle_packet(
+        &mut self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        packet: &[u8],
+        relayed: bool,
+    ) {
         let participant_handle = if let Some(participant_handle) = self.mapping.get(&source) {
             tracing::trace!("found connection from mapping: {source} -> {participant_handle}");
             participant_handle.clone()
@@ -100,7 +152,9 @@ impl<S: PacketSocket> UdpSourceActor<S> {
         let _ = participant_handle.forward(UDPPacket {
             raw: Bytes::copy_from_slice(packet),
             src: source,
-            dst: self.local_addr,
+            dst: destination,
+            proto: Protocol::Udp,
+            relayed,
         });
     }
 
@@ -119,22 +173,44 @@ impl<S: PacketSocket> UdpSourceActor<S> {
                     }
                 }
             }
+            UdpSourceMessage::Relay(source, destination, packet) => {
+                self.handle_packet(source, destination, &packet, true);
+            }
         };
     }
 }
 
+/// With SO_REUSEPORT shards, the first STUN binding of a participant can land on any
+/// shard. Registrations are sent to every shard to make them visible everywhere.
 #[derive(Clone, Debug)]
 pub struct UdpSourceHandle {
-    sender: mpsc::Sender<UdpSourceMessage>,
+    senders: Vec<mpsc::Sender<UdpSourceMessage>>,
+    hasher: RandomState,
 }
 
 impl UdpSourceHandle {
-    pub fn new<S: PacketSocket>(local_addr: SocketAddr, socket: S) -> (Self, UdpSourceActor<S>) {
-        let (sender, receiver) = mpsc::channel(1);
-        let handle = Self { sender };
+    /// `local_addrs` are the addresses announced in the host candidates of `socket`.
+    /// `shard` tells apart the sockets sharing a local address.
+    pub fn new<S: PacketSocket>(
+        local_addrs: Vec<SocketAddr>,
+        socket: S,
+        capacity: usize,
+        shard: usize,
+    ) -> (Self, UdpSourceActor<S>) {
+        assert!(!local_addrs.is_empty(), "a socket needs a local address");
+        let (sender, receiver) = mpsc::channel(capacity);
+        let handle = Self {
+            senders: vec![sender],
+            hasher: RandomState::new(),
+        };
+        let local_addr = local_addrs[0].to_string();
+        let bound_addr = socket.local_addr().unwrap_or(local_addrs[0]);
         let actor = UdpSourceActor {
+            id: format!("{bound_addr}/{shard}"),
             receiver,
-            local_addr,
+            packets: metrics::counter!(telemetry::UDP_PACKETS, "local_addr" => local_addr.clone(), "direction" => "in"),
+            bytes: metrics::counter!(telemetry::UDP_BYTES, "local_addr" => local_addr, "direction" => "in"),
+            local_addrs,
             socket,
             conns: HashMap::new(),
             mapping: HashMap::new(),
@@ -143,13 +219,20 @@ impl UdpSourceHandle {
         (handle, actor)
     }
 
+    /// Combines the handles of sockets sharing the same local address.
+    pub fn sharded(shards: impl IntoIterator<Item = UdpSourceHandle>) -> Self {
+        Self {
+            senders: shards.into_iter().flat_map(|s| s.senders).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
     pub async fn add_participant(
         &self,
         ufrag: String,
         participant: ParticipantHandle,
     ) -> Result<(), SendError<UdpSourceMessage>> {
-        self.sender
-            .send(UdpSourceMessage::AddParticipant(ufrag, participant))
+        self.broadcast(UdpSourceMessage::AddParticipant(ufrag, participant))
             .await
     }
 
@@ -157,8 +240,28 @@ impl UdpSourceHandle {
         &self,
         ufrag: String,
     ) -> Result<(), SendError<UdpSourceMessage>> {
-        self.sender
-            .send(UdpSourceMessage::RemoveParticipant(ufrag))
+        self.broadcast(UdpSourceMessage::RemoveParticipant(ufrag))
             .await
     }
+
+    /// Injects a packet that the TURN server received for `destination` on behalf of the
+    /// relayed address `source`. A relayed address always lands on the same shard.
+    pub async fn relay(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        packet: Bytes,
+    ) -> Result<(), SendError<UdpSourceMessage>> {
+        let shard = self.hasher.hash_one(source) as usize % self.senders.len();
+        self.senders[shard]
+            .send(UdpSourceMessage::Relay(source, destination, packet))
+            .await
+    }
+
+    async fn broadcast(&self, msg: UdpSourceMessage) -> Result<(), SendError<UdpSourceMessage>> {
+        for sender in &self.senders {
+            sender.send(msg.clone()).await?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,275 @@
+use std::{collections::HashMap, hash::Hash, time::Duration};
+
+use str0m::media::MediaData;
+use tokio::time::Instant;
+
+/// Audio levels are aggregated over this window before the room samples them.
+pub const AUDIO_LEVEL_WINDOW: Duration = Duration::from_millis(200);
+/// The room runs the detection on every tick.
+pub const SPEAKER_TICK: Duration = Duration::from_millis(200);
+/// Clients aren't told about changes more often than this.
+const MIN_EMIT_INTERVAL: Duration = Duration::from_millis(500);
+/// A new dominant speaker has to keep talking over the current one for this long.
+const MIN_DOMINANT_DURATION: Duration = Duration::from_secs(1);
+/// A level that hasn't been refreshed, e.g. a muted publisher, counts as silence.
+const STALE_LEVEL: Duration = Duration::from_millis(600);
+
+/// Levels at or below -60 dBov are background noise.
+const SILENCE_DBOV: u8 = 60;
+/// Weight of the newest sample in the smoothed score.
+const SMOOTHING: f64 = 0.3;
+/// Scores to join and to leave the active speakers, the gap keeps the set from flickering.
+const ACTIVE_ENTER: f64 = 0.25;
+const ACTIVE_LEAVE: f64 = 0.1;
+/// A challenger has to be this much louder than the dominant speaker to take over.
+const DOMINANT_MARGIN: f64 = 1.5;
+const MAX_ACTIVE_SPEAKERS: usize = 3;
+
+/// Loudest audio level of a track over the last window, in -dBov (0 is the loudest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioLevel {
+    pub dbov: u8,
+    pub measured_at: Instant,
+}
+
+impl AudioLevel {
+    pub fn is_fresh(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.measured_at) <= STALE_LEVEL
+    }
+}
+
+/// Level of a frame from its `ssrc-audio-level` header extension (RFC 6464), `None`
+/// when the publisher doesn't send it.
+pub fn frame_audio_level(data: &MediaData) -> Option<u8> {
+    let level = data.ext_vals.audio_level?;
+    if data.ext_vals.voice_activity == Some(false) {
+        return Some(127);
+    }
+    Some(level.unsigned_abs().min(127))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveSpeakers<K> {
+    /// Stays with the last dominant speaker through silence.
+    pub dominant: Option<K>,
+    /// Loudest first.
+    pub active: Vec<K>,
+}
+
+/// Dominant speaker detection of a room. Every tick, each speaker's loudest level is
+/// folded into a smoothed score. Entering and leaving the active speakers use separate
+/// thresholds, and the dominant speaker only changes to someone clearly louder after
+/// a hold time, so short noises and crosstalk don't make the layout jump around.
+#[derive(Debug)]
+pub struct SpeakerDetector<K> {
+    scores: HashMap<K, f64>,
+    dominant: Option<K>,
+    dominant_since: Option<Instant>,
+    active: Vec<K>,
+    changed: bool,
+    last_emit: Option<Instant>,
+}
+
+impl<K> Default for SpeakerDetector<K> {
+    fn default() -> Self {
+        Self {
+            scores: HashMap::new(),
+            dominant: None,
+            dominant_since: None,
+            active: Vec::new(),
+            changed: false,
+            last_emit: None,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> SpeakerDetector<K> {
+    /// Folds the loudest level of a speaker during the last tick into its score, `None`
+    /// is silence.
+    pub fn observe(&mut self, speaker: K, dbov: Option<u8>) {
+        let activity = match dbov {
+            Some(dbov) if dbov < SILENCE_DBOV => (SILENCE_DBOV - dbov) as f64 / SILENCE_DBOV as f64,
+            _ => 0.0,
+        };
+        let score = self.scores.entry(speaker).or_default();
+        *score = *score * (1.0 - SMOOTHING) + activity * SMOOTHING;
+    }
+
+    pub fn remove(&mut self, speaker: &K) {
+        if self.scores.remove(speaker).is_none() {
+            return;
+        }
+
+        if self.dominant.as_ref() == Some(speaker) {
+            self.dominant = None;
+            self.dominant_since = None;
+            self.changed = true;
+        }
+        let len = self.active.len();
+        self.active.retain(|active| active != speaker);
+        self.changed |= self.active.len() != len;
+    }
+
+    /// Updates the speakers after a tick of observations. Returns them when they changed
+    /// and the last change was sent long enough ago, otherwise the change is held back
+    /// for a later tick.
+    pub fn update(&mut self, now: Instant) -> Option<ActiveSpeakers<K>> {
+        self.update_active();
+        self.update_dominant(now);
+
+        if !self.changed
+            || self
+                .last_emit
+                .is_some_and(|last| now.saturating_duration_since(last) < MIN_EMIT_INTERVAL)
+        {
+            return None;
+        }
+
+        self.changed = false;
+        self.last_emit = Some(now);
+        Some(ActiveSpeakers {
+            dominant: self.dominant.clone(),
+            active: self.active.clone(),
+        })
+    }
+
+    fn score(&self, speaker: &K) -> f64 {
+        self.scores.get(speaker).copied().unwrap_or_default()
+    }
+
+    fn update_active(&mut self) {
+        let mut candidates: Vec<(&K, f64)> = self
+            .scores
+            .iter()
+            .filter(|(speaker, score)| {
+                let threshold = if self.active.contains(*speaker) {
+                    ACTIVE_LEAVE
+                } else {
+                    ACTIVE_ENTER
+                };
+                **score >= threshold
+            })
+            .map(|(speaker, score)| (speaker, *score))
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        candidates.truncate(MAX_ACTIVE_SPEAKERS);
+
+        // Only joins and leaves are changes, the order is refreshed along with them
+        let same = candidates.len() == self.active.len()
+            && candidates
+                .iter()
+                .all(|(speaker, _)| self.active.contains(speaker));
+        if !same {
+            self.active = candidates
+                .into_iter()
+                .map(|(speaker, _)| speaker.clone())
+                .collect();
+            self.changed = true;
+        }
+    }
+
+    fn update_dominant(&mut self, now: Instant) {
+        let loudest = self
+            .scores
+            .iter()
+            .filter(|(_, score)| **score >= ACTIVE_ENTER)
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(speaker, score)| (speaker.clone(), *score));
+        let Some((loudest, loudest_score)) = loudest else {
+            return;
+        };
+
+        let take_over = match &self.dominant {
+            None => true,
+            Some(dominant) if *dominant == loudest => false,
+            Some(dominant) => {
+                let held = self.dominant_since.is_none_or(|since| {
+                    now.saturating_duration_since(since) >= MIN_DOMINANT_DURATION
+                });
+                held && loudest_score >= self.score(dominant) * DOMINANT_MARGIN
+            }
+        };
+        if take_over {
+            self.dominant = Some(loudest);
+            self.dominant_since = Some(now);
+            self.changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(detector: &mut SpeakerDetector<&'static str>, levels: &[(&'static str, Option<u8>)]) {
+        for (speaker, dbov) in levels {
+            detector.observe(*speaker, *dbov);
+        }
+    }
+
+    #[test]
+    fn test_speaker_becomes_dominant() {
+        let mut detector = SpeakerDetector::default();
+        let mut now = Instant::now();
+        let mut speakers = None;
+        for _ in 0..5 {
+            tick(&mut detector, &[("alice", Some(20)), ("bob", None)]);
+            speakers = speakers.or(detector.update(now));
+            now += SPEAKER_TICK;
+        }
+
+        let speakers = speakers.unwrap();
+        assert_eq!(speakers.dominant, Some("alice"));
+        assert_eq!(speakers.active, vec!["alice"]);
+    }
+
+    #[test]
+    fn test_dominant_speaker_hysteresis() {
+        let mut detector = SpeakerDetector::default();
+        let mut now = Instant::now();
+        for _ in 0..10 {
+            tick(&mut detector, &[("alice", Some(20)), ("bob", None)]);
+            detector.update(now);
+            now += SPEAKER_TICK;
+        }
+
+        // A short noise from bob doesn't take over
+        tick(&mut detector, &[("alice", Some(20)), ("bob", Some(0))]);
+        detector.update(now);
+        now += SPEAKER_TICK;
+        assert_eq!(detector.dominant, Some("alice"));
+
+        // alice stops, bob keeps talking
+        for _ in 0..10 {
+            tick(&mut detector, &[("alice", None), ("bob", Some(20))]);
+            detector.update(now);
+            now += SPEAKER_TICK;
+        }
+        assert_eq!(detector.dominant, Some("bob"));
+
+        // Silence keeps the last dominant speaker
+        for _ in 0..20 {
+            tick(&mut detector, &[("alice", None), ("bob", None)]);
+            detector.update(now);
+            now += SPEAKER_TICK;
+        }
+        assert_eq!(detector.dominant, Some("bob"));
+        assert!(detector.active.is_empty());
+    }
+
+    #[test]
+    fn test_changes_are_rate_limited() {
+        let mut detector = SpeakerDetector::default();
+        let now = Instant::now();
+        for _ in 0..3 {
+            tick(&mut detector, &[("alice", Some(10))]);
+        }
+        assert!(detector.update(now).is_some());
+
+        detector.remove(&"alice");
+        assert_eq!(detector.update(now + SPEAKER_TICK), None);
+        let speakers = detector.update(now + MIN_EMIT_INTERVAL).unwrap();
+        assert_eq!(speakers.dominant, None);
+        assert!(speakers.active.is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+//! STUN message codec (RFC 8489) with the attributes needed by TURN (RFC 8656).
+//! `ice` only peeks at the USERNAME of incoming bindings, this module parses and builds
+//! full messages.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+pub const HEADER_SIZE: usize = 20;
+pub const MAGIC_COOKIE: u32 = 0x2112A442;
+const INTEGRITY_SIZE: usize = 20;
+
+pub const METHOD_BINDING: u16 = 0x001;
+pub const METHOD_ALLOCATE: u16 = 0x003;
+pub const METHOD_REFRESH: u16 = 0x004;
+pub const METHOD_SEND: u16 = 0x006;
+pub const METHOD_DATA: u16 = 0x007;
+pub const METHOD_CREATE_PERMISSION: u16 = 0x008;
+pub const METHOD_CHANNEL_BIND: u16 = 0x009;
+
+pub const ATTR_USERNAME: u16 = 0x0006;
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+pub const ATTR_CHANNEL_NUMBER: u16 = 0x000C;
+pub const ATTR_LIFETIME: u16 = 0x000D;
+pub const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+pub const ATTR_DATA: u16 = 0x0013;
+pub const ATTR_REALM: u16 = 0x0014;
+pub const ATTR_NONCE: u16 = 0x0015;
+pub const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+pub const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const ATTR_SOFTWARE: u16 = 0x8022;
+
+/// IANA protocol number of UDP in REQUESTED-TRANSPORT.
+pub const TRANSPORT_UDP: u8 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+impl Class {
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0b00,
+            Class::Indication => 0b01,
+            Class::Success => 0b10,
+            Class::Error => 0b11,
+        }
+    }
+
+    fn from_bits(bits: u16) -> Self {
+        match bits {
+            0b00 => Class::Request,
+            0b01 => Class::Indication,
+            0b10 => Class::Success,
+            _ => Class::Error,
+        }
+    }
+}
+
+fn message_type(method: u16, class: Class) -> u16 {
+    let class = class.bits();
+    (method & 0x000F)
+        | ((method & 0x0070) << 1)
+        | ((method & 0x0F80) << 2)
+        | ((class & 0b01) << 4)
+        | ((class & 0b10) << 7)
+}
+
+/// Returns true for a STUN message, as opposed to TURN ChannelData or media.
+pub fn is_stun(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE
+        && data[0] & 0b1100_0000 == 0
+        && data[4..8] == MAGIC_COOKIE.to_be_bytes()
+}
+
+/// A parsed STUN message borrowing from the received buffer.
+#[derive(Debug)]
+pub struct Message<'a> {
+    pub method: u16,
+    pub class: Class,
+    pub transaction_id: [u8; 12],
+    attributes: Vec<(u16, &'a [u8])>,
+    // Start of the MESSAGE-INTEGRITY attribute, the HMAC covers everything before it
+    integrity_offset: Option<usize>,
+    raw: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    pub fn decode(raw: &'a [u8]) -> Option<Self> {
+        if !is_stun(raw) {
+            return None;
+        }
+
+        let typ = u16::from_be_bytes([raw[0], raw[1]]);
+        let len = u16::from_be_bytes([raw[2], raw[3]]) as usize;
+        if len % 4 != 0 || raw.len() < HEADER_SIZE + len {
+            return None;
+        }
+        let raw = &raw[..HEADER_SIZE + len];
+
+        let mut attributes = Vec::new();
+        let mut integrity_offset = None;
+        let mut offset = HEADER_SIZE;
+        while offset + 4 <= raw.len() {
+            let attr = u16::from_be_bytes([raw[offset], raw[offset + 1]]);
+            let attr_len = u16::from_be_bytes([raw[offset + 2], raw[offset + 3]]) as usize;
+            let start = offset + 4;
+            let end = start.checked_add(attr_len)?;
+            if end > raw.len() {
+                return None;
+            }
+
+            // Only FINGERPRINT may follow MESSAGE-INTEGRITY, it isn't covered by the HMAC
+            if integrity_offset.is_none() {
+                if attr == ATTR_MESSAGE_INTEGRITY {
+                    if attr_len != INTEGRITY_SIZE {
+                        return None;
+                    }
+                    integrity_offset = Some(offset);
+                }
+                attributes.push((attr, &raw[start..end]));
+            }
+            offset = end.next_multiple_of(4);
+        }
+
+        let method = (typ & 0x000F) | ((typ & 0x00E0) >> 1) | ((typ & 0x3E00) >> 2);
+        let class = Class::from_bits(((typ >> 4) & 0b01) | ((typ >> 7) & 0b10));
+        let mut transaction_id = [0; 12];
+        transaction_id.copy_from_slice(&raw[8..20]);
+
+        Some(Self {
+            method,
+            class,
+            transaction_id,
+            attributes,
+            integrity_offset,
+            raw,
+        })
+    }
+
+    pub fn get(&self, attr: u16) -> Option<&'a [u8]> {
+        self.attributes
+            .iter()
+            .find(|(a, _)| *a == attr)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn get_str(&self, attr: u16) -> Option<&'a str> {
+        self.get(attr)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub fn get_u32(&self, attr: u16) -> Option<u32> {
+        let value = self.get(attr)?;
+        Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+    }
+
+    /// CHANNEL-NUMBER, the trailing RFFU bytes are ignored.
+    pub fn channel_number(&self) -> Option<u16> {
+        let value = self.get(ATTR_CHANNEL_NUMBER)?;
+        Some(u16::from_be_bytes(value.get(..2)?.try_into().ok()?))
+    }
+
+    /// REQUESTED-TRANSPORT protocol number.
+    pub fn requested_transport(&self) -> Option<u8> {
+        self.get(ATTR_REQUESTED_TRANSPORT)?.first().copied()
+    }
+
+    pub fn xor_address(&self, attr: u16) -> Option<SocketAddr> {
+        decode_xor_address(self.get(attr)?, &self.transaction_id)
+    }
+
+    /// Every instance of `attr`, CreatePermission may carry several peers.
+    pub fn xor_addresses(&self, attr: u16) -> Vec<SocketAddr> {
+        self.attributes
+            .iter()
+            .filter(|(a, _)| *a == attr)
+            .filter_map(|(_, value)| decode_xor_address(value, &self.transaction_id))
+            .collect()
+    }
+
+    pub fn has_integrity(&self) -> bool {
+        self.integrity_offset.is_some()
+    }
+
+    /// Checks MESSAGE-INTEGRITY with the long-term credential `key`.
+    pub fn verify_integrity(&self, key: &[u8]) -> bool {
+        let Some(offset) = self.integrity_offset else {
+            return false;
+        };
+
+        // The length in the header has to end right after MESSAGE-INTEGRITY
+        let mut covered = self.raw[..offset].to_vec();
+        let len = (offset + 4 + INTEGRITY_SIZE - HEADER_SIZE) as u16;
+        covered[2..4].copy_from_slice(&len.to_be_bytes());
+
+        let expected = &self.raw[offset + 4..offset + 4 + INTEGRITY_SIZE];
+        let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(key) else {
+            return false;
+        };
+        mac.update(&covered);
+        mac.verify_slice(expected).is_ok()
+    }
+}
+
+fn decode_xor_address(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match value[1] {
+        0x01 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            for (octet, mask) in octets.iter_mut().zip(cookie) {
+                *octet ^= mask;
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            let mask = cookie.iter().chain(transaction_id.iter());
+            for (octet, mask) in octets.iter_mut().zip(mask) {
+                *octet ^= mask;
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Builds a message attribute by attribute, MESSAGE-INTEGRITY goes last.
+pub struct MessageBuilder {
+    buf: Vec<u8>,
+    transaction_id: [u8; 12],
+}
+
+impl MessageBuilder {
+    pub fn new(method: u16, class: Class, transaction_id: [u8; 12]) -> Self {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&message_type(method, class).to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&transaction_id);
+        Self {
+            buf,
+            transaction_id,
+        }
+    }
+
+    pub fn add(mut self, attr: u16, value: &[u8]) -> Self {
+        self.buf.extend_from_slice(&attr.to_be_bytes());
+        self.buf
+            .extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(value);
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+        self.set_len(self.buf.len() - HEADER_SIZE);
+        self
+    }
+
+    pub fn add_u32(self, attr: u16, value: u32) -> Self {
+        self.add(attr, &value.to_be_bytes())
+    }
+
+    pub fn add_xor_address(self, attr: u16, addr: SocketAddr) -> Self {
+        let cookie = MAGIC_COOKIE.to_be_bytes();
+        let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+        let mut value = Vec::with_capacity(20);
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                value.extend_from_slice(&[0, 0x01]);
+                value.extend_from_slice(&port.to_be_bytes());
+                value.extend(ip.octets().iter().zip(cookie).map(|(o, m)| o ^ m));
+            }
+            IpAddr::V6(ip) => {
+                value.extend_from_slice(&[0, 0x02]);
+                value.extend_from_slice(&port.to_be_bytes());
+                let mask = cookie.iter().chain(self.transaction_id.iter());
+                value.extend(ip.octets().iter().zip(mask).map(|(o, m)| o ^ m));
+            }
+        }
+        self.add(attr, &value)
+    }
+
+    pub fn add_error(self, code: u16, reason: &str) -> Self {
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        self.add(ATTR_ERROR_CODE, &value)
+    }
+
+    /// Appends MESSAGE-INTEGRITY computed with the long-term credential `key`.
+    pub fn build_with_integrity(mut self, key: &[u8]) -> Vec<u8> {
+        self.set_len(self.buf.len() + 4 + INTEGRITY_SIZE - HEADER_SIZE);
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key size");
+        mac.update(&self.buf);
+        let integrity = mac.finalize().into_bytes();
+        self.add(ATTR_MESSAGE_INTEGRITY, &integrity).buf
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    #[test]
+    fn test_message_type_roundtrip() {
+        let raw = MessageBuilder::new(METHOD_CHANNEL_BIND, Class::Error, TID).build();
+        let msg = Message::decode(&raw).unwrap();
+        assert_eq!(msg.method, METHOD_CHANNEL_BIND);
+        assert_eq!(msg.class, Class::Error);
+        assert_eq!(msg.transaction_id, TID);
+
+        // Allocate request and Data indication from RFC 8656
+        assert_eq!(message_type(METHOD_ALLOCATE, Class::Request), 0x0003);
+        assert_eq!(message_type(METHOD_DATA, Class::Indication), 0x0017);
+        assert_eq!(message_type(METHOD_BINDING, Class::Success), 0x0101);
+    }
+
+    #[test]
+    fn test_xor_address_roundtrip() {
+        let v4: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+        let raw = MessageBuilder::new(METHOD_CREATE_PERMISSION, Class::Request, TID)
+            .add_xor_address(ATTR_XOR_PEER_ADDRESS, v4)
+            .add_xor_address(ATTR_XOR_PEER_ADDRESS, v6)
+            .build();
+
+        let msg = Message::decode(&raw).unwrap();
+        assert_eq!(msg.xor_address(ATTR_XOR_PEER_ADDRESS), Some(v4));
+        assert_eq!(msg.xor_addresses(ATTR_XOR_PEER_ADDRESS), vec![v4, v6]);
+    }
+
+    #[test]
+    fn test_integrity() {
+        let raw = MessageBuilder::new(METHOD_ALLOCATE, Class::Request, TID)
+            .add(ATTR_USERNAME, b"user")
+            .add_u32(ATTR_LIFETIME, 600)
+            .build_with_integrity(b"key");
+
+        let msg = Message::decode(&raw).unwrap();
+        assert_eq!(msg.get_str(ATTR_USERNAME), Some("user"));
+        assert_eq!(msg.get_u32(ATTR_LIFETIME), Some(600));
+        assert!(msg.verify_integrity(b"key"));
+        assert!(!msg.verify_integrity(b"other"));
+
+        let mut tampered = raw.clone();
+        tampered[HEADER_SIZE + 4] ^= 1;
+        assert!(!Message::decode(&tampered).unwrap().verify_integrity(b"key"));
+    }
+
+    #[test]
+    fn test_reject_truncated() {
+        let raw = MessageBuilder::new(METHOD_BINDING, Class::Request, TID)
+            .add(ATTR_SOFTWARE, b"pulsebeam")
+            .build();
+        assert!(Message::decode(&raw[..raw.len() - 4]).is_none());
+        assert!(Message::decode(&[0x40, 0, 0, 4, 1, 2, 3, 4]).is_none());
+    }
+}
@@ -0,0 +1,549 @@
+//! Restarts long-lived actors after a failure, modeled after OTP supervisors.
+//!
+//! A restart runs the same actor value again through [`actor::run_until`], so its
+//! mailbox, sockets and handles held elsewhere stay valid. `pre_start` is called again
+//! and must redo whatever `post_stop` tore down.
+//!
+//! Short-lived actors like rooms, participants and tracks can't be recovered, their
+//! state lives in the client. They are spawned into a [`ChildSet`] that reports how each
+//! of them exited to the owner instead.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tokio::{
+    sync::oneshot,
+    task::{self, JoinSet},
+    time::Instant,
+};
+use tracing::Instrument;
+
+use crate::{
+    actor::{self, Actor, ActorError, ActorStatus},
+    telemetry,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Only the failed child is restarted.
+    OneForOne,
+    /// Every child is stopped and restarted when one fails, for children that depend on
+    /// each other.
+    OneForAll,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// Always restarted, the child is not expected to exit.
+    Permanent,
+    /// Restarted only after a failure, see [`ActorStatus::is_failure`].
+    Transient,
+    /// Never restarted.
+    Temporary,
+}
+
+impl Restart {
+    fn should_restart(&self, status: ActorStatus) -> bool {
+        match self {
+            Restart::Permanent => true,
+            Restart::Transient => status.is_failure(),
+            Restart::Temporary => false,
+        }
+    }
+}
+
+/// The supervisor gives up after more than `max_restarts` restarts within `period`, and
+/// fails itself so that its own supervisor can react.
+#[derive(Debug, Clone, Copy)]
+pub struct Intensity {
+    pub max_restarts: usize,
+    pub period: Duration,
+}
+
+impl Default for Intensity {
+    fn default() -> Self {
+        Self {
+            max_restarts: 3,
+            period: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Resolves when the supervisor stops a child, or when the supervisor is gone.
+pub struct StopSignal(oneshot::Receiver<()>);
+
+impl StopSignal {
+    pub async fn wait(self) {
+        let _ = self.0.await;
+    }
+}
+
+/// Keeps an actor between runs, so it can be restarted in place.
+pub struct Restartable<A> {
+    actor: Arc<Mutex<Option<A>>>,
+}
+
+impl<A> Clone for Restartable<A> {
+    fn clone(&self) -> Self {
+        Self {
+            actor: self.actor.clone(),
+        }
+    }
+}
+
+impl<A: Actor + Send + 'static> Restartable<A> {
+    pub fn new(actor: A) -> Self {
+        Self {
+            actor: Arc::new(Mutex::new(Some(actor))),
+        }
+    }
+
+    pub async fn run(self, stop: StopSignal) -> ActorStatus {
+        let taken = self.actor.lock().expect("restartable lock").take();
+        // Only missing when a previous run was aborted with its task
+        let Some(mut actor) = taken else {
+            tracing::error!("actor is lost and can't be restarted");
+            return ActorStatus::PreStartFailed;
+        };
+
+        let status = actor::run_until(&mut actor, stop.wait()).await;
+        *self.actor.lock().expect("restartable lock") = Some(actor);
+        status
+    }
+}
+
+type StartFn = Box<dyn FnMut(StopSignal) -> BoxFuture<'static, ActorStatus> + Send>;
+
+struct Child {
+    name: String,
+    restart: Restart,
+    start: StartFn,
+    // Set while the child is running
+    stop: Option<oneshot::Sender<()>>,
+}
+
+pub struct Supervisor {
+    name: &'static str,
+    strategy: Strategy,
+    intensity: Intensity,
+    children: Vec<Child>,
+    tasks: JoinSet<(usize, ActorStatus)>,
+    restarts: VecDeque<Instant>,
+}
+
+impl Actor for Supervisor {
+    type ID = &'static str;
+
+    fn kind(&self) -> &'static str {
+        "supervisor"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.name
+    }
+
+    async fn pre_start(&mut self) -> Result<(), ActorError> {
+        for index in 0..self.children.len() {
+            self.start_child(index);
+        }
+        Ok(())
+    }
+
+    async fn run(&mut self) -> Result<(), ActorError> {
+        while let Some(res) = self.tasks.join_next().await {
+            let Ok((index, status)) = res else {
+                // Aborted together with the runtime
+                continue;
+            };
+
+            let child = &mut self.children[index];
+            child.stop = None;
+            if !child.restart.should_restart(status) {
+                tracing::info!(child = %child.name, %status, "child has exited");
+                continue;
+            }
+
+            tracing::warn!(child = %child.name, %status, "child has exited, restarting");
+            if !self.allow_restart() {
+                let name = self.children[index].name.clone();
+                tracing::error!(child = %name, "restart intensity is exceeded, giving up");
+                return Err(ActorError::Custom(format!(
+                    "{name} exceeded the restart intensity"
+                )));
+            }
+
+            match self.strategy {
+                Strategy::OneForOne => self.start_child(index),
+                Strategy::OneForAll => {
+                    self.stop_children().await;
+                    for index in 0..self.children.len() {
+                        if self.children[index].restart != Restart::Temporary {
+                            self.start_child(index);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn post_stop(&mut self) -> Result<(), ActorError> {
+        self.stop_children().await;
+        Ok(())
+    }
+}
+
+impl Supervisor {
+    pub fn new(name: &'static str, strategy: Strategy, intensity: Intensity) -> Self {
+        Self {
+            name,
+            strategy,
+            intensity,
+            children: Vec::new(),
+            tasks: JoinSet::new(),
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Adds a child that is started with the supervisor, in the order they are added.
+    /// Children are stopped in reverse, so add dependencies first.
+    /// `start` is called for every (re)start, an actor is restarted in place with
+    /// `move |stop| restartable.clone().run(stop)`, see [`Restartable`].
+    pub fn add_child<F, Fut>(&mut self, name: impl Into<String>, restart: Restart, mut start: F)
+    where
+        F: FnMut(StopSignal) -> Fut + Send + 'static,
+        Fut: Future<Output = ActorStatus> + Send + 'static,
+    {
+        self.children.push(Child {
+            name: name.into(),
+            restart,
+            start: Box::new(move |stop| Box::pin(start(stop))),
+            stop: None,
+        });
+    }
+
+    fn start_child(&mut self, index: usize) {
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let child = &mut self.children[index];
+        child.stop = Some(stop_sender);
+        let task = (child.start)(StopSignal(stop_receiver));
+        self.tasks
+            .spawn(async move { (index, task.await) }.in_current_span());
+    }
+
+    /// Stops the children in the reverse order they were added and waits for each of
+    /// them, so a child outlives everything that was added after it.
+    async fn stop_children(&mut self) {
+        for index in (0..self.children.len()).rev() {
+            let Some(stop) = self.children[index].stop.take() else {
+                continue;
+            };
+            let _ = stop.send(());
+
+            while let Some(res) = self.tasks.join_next().await {
+                let Ok((exited, _)) = res else {
+                    continue;
+                };
+                self.children[exited].stop = None;
+                if exited == index {
+                    break;
+                }
+            }
+        }
+        while self.tasks.join_next().await.is_some() {}
+    }
+
+    fn allow_restart(&mut self) -> bool {
+        let now = Instant::now();
+        while self
+            .restarts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= self.intensity.period)
+        {
+            self.restarts.pop_front();
+        }
+
+        if self.restarts.len() >= self.intensity.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+/// Children that are never restarted. The owner observes how each of them exited,
+/// keyed by `K`, e.g. to clean up after a participant that panicked.
+pub struct ChildSet<K> {
+    tasks: JoinSet<(K, ActorStatus)>,
+    // Key and kind of every running child, a lost task is still reported to the owner
+    children: HashMap<task::Id, (K, &'static str)>,
+}
+
+impl<K> Default for ChildSet<K> {
+    fn default() -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Debug + Clone + Send + 'static> ChildSet<K> {
+    pub fn spawn<A>(&mut self, key: K, actor: A)
+    where
+        A: Actor + 'static,
+    {
+        let kind = actor.kind();
+        let child = key.clone();
+        let task = self
+            .tasks
+            .spawn(async move { (child, actor::run(actor).await) }.in_current_span());
+        self.children.insert(task.id(), (key, kind));
+    }
+
+    /// Waits for the next child to exit, `None` when there are no children. A child
+    /// whose task is lost, e.g. aborted, is reported as panicked.
+    pub async fn join_next(&mut self) -> Option<(K, ActorStatus)> {
+        loop {
+            let (key, status) = match self.tasks.join_next_with_id().await? {
+                Ok((id, exited)) => {
+                    self.children.remove(&id);
+                    exited
+                }
+                Err(err) => {
+                    let Some((key, kind)) = self.children.remove(&err.id()) else {
+                        continue;
+                    };
+                    tracing::warn!(child = ?key, "child task is lost: {err}");
+                    // The actor didn't get to count its own exit
+                    let status = ActorStatus::Panicked;
+                    metrics::counter!(
+                        telemetry::ACTOR_EXITS,
+                        "kind" => kind,
+                        "status" => status.to_string(),
+                    )
+                    .increment(1);
+                    (key, status)
+                }
+            };
+
+            if status.is_failure() {
+                tracing::warn!(child = ?key, %status, "child has failed");
+            } else {
+                tracing::debug!(child = ?key, %status, "child has exited");
+            }
+            return Some((key, status));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Panics on its first `failures` runs, then exits gracefully.
+    struct Flaky {
+        runs: Arc<AtomicUsize>,
+        failures: usize,
+    }
+
+    impl Actor for Flaky {
+        type ID = usize;
+
+        fn kind(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn id(&self) -> Self::ID {
+            0
+        }
+
+        async fn run(&mut self) -> Result<(), ActorError> {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            if run < self.failures {
+                panic!("run {run} failed");
+            }
+            Ok(())
+        }
+    }
+
+    /// Runs until stopped.
+    struct Idle {
+        runs: Arc<AtomicUsize>,
+    }
+
+    impl Actor for Idle {
+        type ID = usize;
+
+        fn kind(&self) -> &'static str {
+            "idle"
+        }
+
+        fn id(&self) -> Self::ID {
+            0
+        }
+
+        async fn run(&mut self) -> Result<(), ActorError> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            std::future::pending().await
+        }
+    }
+
+    /// Runs until stopped and records when it has stopped.
+    struct Ordered {
+        name: &'static str,
+        stopped: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Actor for Ordered {
+        type ID = &'static str;
+
+        fn kind(&self) -> &'static str {
+            "ordered"
+        }
+
+        fn id(&self) -> Self::ID {
+            self.name
+        }
+
+        async fn run(&mut self) -> Result<(), ActorError> {
+            std::future::pending().await
+        }
+
+        async fn post_stop(&mut self) -> Result<(), ActorError> {
+            self.stopped.lock().unwrap().push(self.name);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_for_one_restarts_until_success() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let flaky = Flaky {
+            runs: runs.clone(),
+            failures: 2,
+        };
+        let flaky = Restartable::new(flaky);
+        let mut supervisor = Supervisor::new("test", Strategy::OneForOne, Intensity::default());
+        supervisor.add_child("flaky", Restart::Transient, move |stop| {
+            flaky.clone().run(stop)
+        });
+
+        let status = actor::run(supervisor).await;
+        assert_eq!(status, ActorStatus::ShutDown);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_intensity_is_exceeded() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let flaky = Flaky {
+            runs: runs.clone(),
+            failures: usize::MAX,
+        };
+        let intensity = Intensity {
+            max_restarts: 2,
+            period: Duration::from_secs(60),
+        };
+        let flaky = Restartable::new(flaky);
+        let mut supervisor = Supervisor::new("test", Strategy::OneForOne, intensity);
+        supervisor.add_child("flaky", Restart::Permanent, move |stop| {
+            flaky.clone().run(stop)
+        });
+
+        let status = actor::run(supervisor).await;
+        assert_eq!(status, ActorStatus::ExitedWithError);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_children_stop_in_reverse_order() {
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+        let mut supervisor = Supervisor::new("test", Strategy::OneForOne, Intensity::default());
+        for name in ["source", "sink", "controller"] {
+            let child = Restartable::new(Ordered {
+                name,
+                stopped: stopped.clone(),
+            });
+            supervisor.add_child(name, Restart::Transient, move |stop| {
+                child.clone().run(stop)
+            });
+        }
+
+        let status = actor::run_until(&mut supervisor, tokio::task::yield_now()).await;
+        assert_eq!(status, ActorStatus::ShutDown);
+        assert_eq!(
+            *stopped.lock().unwrap(),
+            vec!["controller", "sink", "source"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_one_for_all_restarts_siblings() {
+        let flaky_runs = Arc::new(AtomicUsize::new(0));
+        let idle_runs = Arc::new(AtomicUsize::new(0));
+        let idle = Restartable::new(Idle {
+            runs: idle_runs.clone(),
+        });
+        let flaky = Restartable::new(Flaky {
+            runs: flaky_runs.clone(),
+            failures: 1,
+        });
+        let mut supervisor = Supervisor::new("test", Strategy::OneForAll, Intensity::default());
+        supervisor.add_child("idle", Restart::Permanent, move |stop| {
+            idle.clone().run(stop)
+        });
+        supervisor.add_child("flaky", Restart::Transient, move |stop| {
+            flaky.clone().run(stop)
+        });
+
+        let handle = tokio::spawn(actor::run(supervisor));
+        while flaky_runs.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        while idle_runs.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_child_set_reports_exits() {
+        let mut children = ChildSet::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        children.spawn(
+            "failing",
+            Flaky {
+                runs: runs.clone(),
+                failures: 1,
+            },
+        );
+        assert_eq!(
+            children.join_next().await,
+            Some(("failing", ActorStatus::Panicked))
+        );
+
+        children.spawn("done", Flaky { runs, failures: 0 });
+        assert_eq!(
+            children.join_next().await,
+            Some(("done", ActorStatus::ShutDown))
+        );
+        assert!(children.is_empty());
+        assert_eq!(children.join_next().await, None);
+    }
+}
@@ -0,0 +1,647 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use str0m::net::Protocol;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::SendError},
+        oneshot,
+    },
+    task::JoinSet,
+};
+use tracing::Instrument;
+
+use crate::{
+    actor::{Actor, ActorError},
+    ice,
+    message::{EgressUDPPacket, UDPPacket},
+    participant::ParticipantHandle,
+    telemetry,
+};
+
+/// RFC 4571 frames carry a 16-bit length.
+const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+
+/// A connection has to identify its participant with a STUN binding in the first frame,
+/// otherwise it's dropped after this long.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Enough for a STUN binding and most media packets, grows to the length of bigger frames.
+const INITIAL_READ_BUFFER: usize = 2048;
+
+/// Backs off when accept fails, e.g. when running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub enum TcpMessage {
+    AddParticipant(String, ParticipantHandle),
+    RemoveParticipant(String),
+    Send(EgressUDPPacket),
+}
+
+/// A new connection that presented `ufrag` in its first STUN binding.
+struct Connect {
+    ufrag: String,
+    peer: SocketAddr,
+    egress: mpsc::Sender<Bytes>,
+    resp: oneshot::Sender<Option<ParticipantHandle>>,
+}
+
+struct Connection {
+    ufrag: String,
+    egress: mpsc::Sender<Bytes>,
+}
+
+/// Accepts passive ICE-TCP connections (RFC 6544). Every connection is served by its own
+/// task, which forwards the frames to the participant that owns the ufrag of the first
+/// STUN binding. Egress packets are routed to the connection of their destination.
+pub struct TcpActor {
+    receiver: mpsc::Receiver<TcpMessage>,
+    listener: TcpListener,
+    // Advertised addresses of the listener, the first one is used when the local address
+    // of a connection isn't advertised, e.g. behind a 1:1 NAT
+    local_addrs: Vec<SocketAddr>,
+    connection_capacity: usize,
+    max_connections: usize,
+    connect_sender: mpsc::Sender<Connect>,
+    connect_receiver: mpsc::Receiver<Connect>,
+
+    conns: HashMap<String, ParticipantHandle>,
+    connections: HashMap<SocketAddr, Connection>,
+    reverse: HashMap<String, Vec<SocketAddr>>,
+    connection_tasks: JoinSet<SocketAddr>,
+}
+
+impl Actor for TcpActor {
+    type ID = SocketAddr;
+
+    fn kind(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.listener.local_addr().unwrap_or(self.local_addrs[0])
+    }
+
+    async fn run(&mut self) -> Result<(), ActorError> {
+        loop {
+            tokio::select! {
+                res = self.listener.accept() => {
+                    match res {
+                        Ok((stream, peer)) => self.handle_accept(stream, peer),
+                        Err(err) => {
+                            tracing::warn!("failed to accept a tcp connection: {err}");
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        }
+                    }
+                }
+
+                msg = self.receiver.recv() => {
+                    match msg {
+                        Some(msg) => self.handle_control(msg),
+                        None => {
+                            tracing::info!("all controllers have exited, will gracefully shutdown");
+                            break;
+                        }
+                    }
+                }
+
+                Some(connect) = self.connect_receiver.recv() => {
+                    self.handle_connect(connect);
+                }
+
+                Some(Ok(peer)) = self.connection_tasks.join_next() => {
+                    self.handle_disconnect(peer);
+                }
+            }
+        }
+
+        tracing::info!("tcp listener has exited");
+        Ok(())
+    }
+}
+
+impl TcpActor {
+    fn local_addr(&self, local: Option<IpAddr>) -> SocketAddr {
+        local
+            .and_then(|ip| self.local_addrs.iter().find(|addr| addr.ip() == ip))
+            .copied()
+            .unwrap_or(self.local_addrs[0])
+    }
+
+    fn handle_accept(&mut self, stream: TcpStream, peer: SocketAddr) {
+        if self.connection_tasks.len() >= self.max_connections {
+            telemetry::record_dropped("tcp_connection");
+            tracing::warn!("dropped a tcp connection from {peer}, too many connections");
+            return;
+        }
+
+        tracing::trace!("accepted a tcp connection from {peer}");
+        if let Err(err) = stream.set_nodelay(true) {
+            tracing::warn!("failed to disable nagle for {peer}: {err}");
+        }
+
+        let local = self.local_addr(stream.local_addr().ok().map(|addr| addr.ip()));
+        let connect = self.connect_sender.clone();
+        let capacity = self.connection_capacity;
+        self.connection_tasks.spawn(
+            async move {
+                if let Err(err) = serve_connection(stream, peer, local, connect, capacity).await {
+                    tracing::debug!("tcp connection from {peer} is closed: {err}");
+                }
+                peer
+            }
+            .in_current_span(),
+        );
+    }
+
+    fn handle_connect(&mut self, connect: Connect) {
+        let Some(participant) = self.conns.get(&connect.ufrag) else {
+            tracing::trace!(
+                "dropped a tcp connection from {} due to unregistered stun binding: {}",
+                connect.peer,
+                connect.ufrag
+            );
+            let _ = connect.resp.send(None);
+            return;
+        };
+
+        tracing::trace!(
+            "found connection from ufrag: {} -> {} -> {participant}",
+            connect.ufrag,
+            connect.peer
+        );
+        let _ = connect.resp.send(Some(participant.clone()));
+        self.reverse
+            .entry(connect.ufrag.clone())
+            .or_default()
+            .push(connect.peer);
+        self.connections.insert(
+            connect.peer,
+            Connection {
+                ufrag: connect.ufrag,
+                egress: connect.egress,
+            },
+        );
+    }
+
+    fn handle_disconnect(&mut self, peer: SocketAddr) {
+        let Some(connection) = self.connections.remove(&peer) else {
+            return;
+        };
+
+        if let Some(addrs) = self.reverse.get_mut(&connection.ufrag) {
+            addrs.retain(|addr| *addr != peer);
+        }
+    }
+
+    fn handle_control(&mut self, msg: TcpMessage) {
+        match msg {
+            TcpMessage::AddParticipant(ufrag, participant) => {
+                tracing::trace!("added {ufrag} to connection map");
+                self.conns.insert(ufrag, participant);
+            }
+            TcpMessage::RemoveParticipant(ufrag) => {
+                tracing::trace!("removed {ufrag} to connection map");
+                self.conns.remove(&ufrag);
+                // Dropping the egress sender closes the connection
+                if let Some(addrs) = self.reverse.remove(&ufrag) {
+                    for addr in addrs.iter() {
+                        self.connections.remove(addr);
+                    }
+                }
+            }
+            TcpMessage::Send(packet) => {
+                let Some(connection) = self.connections.get(&packet.dst) else {
+                    tracing::trace!("dropped a packet to {} without a connection", packet.dst);
+                    return;
+                };
+
+                // A slow connection shouldn't hold up the others
+                if let Err(err) = connection.egress.try_send(packet.raw) {
+                    telemetry::record_dropped("tcp_egress");
+                    tracing::trace!("dropped a packet to {}: {err}", packet.dst);
+                }
+            }
+        }
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    connect: mpsc::Sender<Connect>,
+    capacity: usize,
+) -> io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = BytesMut::with_capacity(INITIAL_READ_BUFFER);
+    let (egress, mut egress_receiver) = mpsc::channel(capacity);
+
+    let handshake = async {
+        let Some(first) = read_frame(&mut reader, &mut buf).await? else {
+            return Ok(None);
+        };
+        let Some(ufrag) = ice::parse_stun_remote_ufrag(&first) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "first frame is not a stun binding",
+            ));
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let request = Connect {
+            ufrag: ufrag.to_string(),
+            peer,
+            egress,
+            resp: tx,
+        };
+        let participant = match connect.send(request).await {
+            Ok(()) => rx.await.ok().flatten(),
+            Err(_) => None,
+        };
+        Ok(participant.map(|participant| (participant, first)))
+    };
+    let Some((participant, first)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no stun binding"))??
+    else {
+        return Ok(());
+    };
+
+    let forward = |raw: Bytes| {
+        let _ = participant.forward(UDPPacket {
+            raw,
+            src: peer,
+            dst: local,
+            proto: Protocol::Tcp,
+            relayed: false,
+        });
+    };
+    forward(first);
+
+    let ingress = async {
+        while let Some(frame) = read_frame(&mut reader, &mut buf).await? {
+            if !frame.is_empty() {
+                forward(frame);
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    let egress = async {
+        let mut out = BytesMut::new();
+        while let Some(raw) = egress_receiver.recv().await {
+            out.clear();
+            if !encode_frame(&raw, &mut out) {
+                tracing::warn!("dropped an oversized packet to {peer}");
+                continue;
+            }
+            writer.write_all(&out).await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    tokio::select! {
+        res = ingress => res,
+        res = egress => res,
+    }
+}
+
+/// Returns `None` once the peer closes the connection.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+) -> io::Result<Option<Bytes>> {
+    loop {
+        if let Some(frame) = decode_frame(buf) {
+            return Ok(Some(frame));
+        }
+
+        if reader.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// Splits the next RFC 4571 frame off `buf`, `None` until it's complete.
+fn decode_frame(buf: &mut BytesMut) -> Option<Bytes> {
+    if buf.len() < 2 {
+        return None;
+    }
+
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if buf.len() < 2 + len {
+        buf.reserve(2 + len - buf.len());
+        return None;
+    }
+
+    buf.advance(2);
+    Some(buf.split_to(len).freeze())
+}
+
+/// Returns false when `payload` doesn't fit in a frame.
+fn encode_frame(payload: &[u8], out: &mut BytesMut) -> bool {
+    if payload.len() > MAX_FRAME_SIZE {
+        return false;
+    }
+
+    out.reserve(2 + payload.len());
+    out.put_u16(payload.len() as u16);
+    out.put_slice(payload);
+    true
+}
+
+/// Every listener knows about every participant, egress goes to the listener of the
+/// destination's address family. Without any listener, ICE-TCP is disabled.
+#[derive(Clone, Debug, Default)]
+pub struct TcpHandle {
+    v4: Vec<mpsc::Sender<TcpMessage>>,
+    v6: Vec<mpsc::Sender<TcpMessage>>,
+}
+
+impl TcpHandle {
+    /// `local_addrs` are the addresses announced in the host candidates of `listener`.
+    /// `connection_capacity` is the egress queue of a single connection, and further
+    /// connections are refused while `max_connections` are open.
+    pub fn new(
+        local_addrs: Vec<SocketAddr>,
+        listener: TcpListener,
+        capacity: usize,
+        connection_capacity: usize,
+        max_connections: usize,
+    ) -> (Self, TcpActor) {
+        assert!(!local_addrs.is_empty(), "a listener needs a local address");
+        let (sender, receiver) = mpsc::channel(capacity);
+        let (connect_sender, connect_receiver) = mpsc::channel(capacity);
+        let is_ipv6 = listener.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        let handle = if is_ipv6 {
+            Self {
+                v4: Vec::new(),
+                v6: vec![sender],
+            }
+        } else {
+            Self {
+                v4: vec![sender],
+                v6: Vec::new(),
+            }
+        };
+        let actor = TcpActor {
+            receiver,
+            listener,
+            local_addrs,
+            connection_capacity,
+            max_connections,
+            connect_sender,
+            connect_receiver,
+            conns: HashMap::new(),
+            connections: HashMap::new(),
+            reverse: HashMap::new(),
+            connection_tasks: JoinSet::new(),
+        };
+        (handle, actor)
+    }
+
+    /// Combines the handles of the listeners of each address family.
+    pub fn combined(handles: impl IntoIterator<Item = TcpHandle>) -> Self {
+        let mut combined = Self::default();
+        for handle in handles {
+            combined.v4.extend(handle.v4);
+            combined.v6.extend(handle.v6);
+        }
+        combined
+    }
+
+    pub async fn add_participant(
+        &self,
+        ufrag: String,
+        participant: ParticipantHandle,
+    ) -> Result<(), SendError<TcpMessage>> {
+        self.broadcast(TcpMessage::AddParticipant(ufrag, participant))
+            .await
+    }
+
+    pub async fn remove_participant(&self, ufrag: String) -> Result<(), SendError<TcpMessage>> {
+        self.broadcast(TcpMessage::RemoveParticipant(ufrag)).await
+    }
+
+    pub async fn send(&self, packet: EgressUDPPacket) -> Result<(), SendError<TcpMessage>> {
+        let senders = if packet.dst.is_ipv6() {
+            &self.v6
+        } else {
+            &self.v4
+        };
+        let Some(sender) = senders.first() else {
+            return Err(SendError(TcpMessage::Send(packet)));
+        };
+
+        sender.send(TcpMessage::Send(packet)).await
+    }
+
+    async fn broadcast(&self, msg: TcpMessage) -> Result<(), SendError<TcpMessage>> {
+        for sender in self.v4.iter().chain(&self.v6) {
+            sender.send(msg.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{
+        actor,
+        entity::{ExternalParticipantId, ParticipantId},
+        participant::ParticipantDataMessage,
+        rng::Rng,
+    };
+
+    async fn listen(max_connections: usize) -> (TcpHandle, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (handle, actor) = TcpHandle::new(vec![addr], listener, 16, 16, max_connections);
+        tokio::spawn(actor::run(actor));
+        (handle, addr)
+    }
+
+    fn participant() -> (ParticipantHandle, mpsc::Receiver<ParticipantDataMessage>) {
+        let external = ExternalParticipantId::new("alice".to_string()).unwrap();
+        let participant_id = ParticipantId::new(&mut Rng::seed_from_u64(1), external);
+        let (data_sender, data_receiver) = mpsc::channel(16);
+        let (control_sender, _) = mpsc::channel(1);
+        let handle = ParticipantHandle {
+            data_sender,
+            control_sender,
+            participant_id: Arc::new(participant_id),
+        };
+        (handle, data_receiver)
+    }
+
+    /// A framed STUN binding request from the client to `ufrag`.
+    fn binding(ufrag: &str) -> (Vec<u8>, BytesMut) {
+        let username = format!("{ufrag}:client");
+        let padded = (username.len() + 3) & !3;
+        let mut msg = Vec::new();
+        msg.put_u16(0x0001);
+        msg.put_u16((4 + padded) as u16);
+        msg.put_u32(0x2112_A442);
+        msg.put_slice(&[7; 12]);
+        msg.put_u16(0x0006);
+        msg.put_u16(username.len() as u16);
+        msg.put_slice(username.as_bytes());
+        msg.resize(20 + 4 + padded, 0);
+
+        let mut frame = BytesMut::new();
+        assert!(encode_frame(&msg, &mut frame));
+        (msg, frame)
+    }
+
+    async fn udp_packet(receiver: &mut mpsc::Receiver<ParticipantDataMessage>) -> UDPPacket {
+        match receiver.recv().await {
+            Some(ParticipantDataMessage::UdpPacket(packet)) => packet,
+            _ => panic!("expected a packet"),
+        }
+    }
+
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0; 16];
+        matches!(
+            tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await,
+            Ok(Ok(0) | Err(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn test_forward_after_binding() {
+        let (handle, addr) = listen(16).await;
+        let (participant, mut receiver) = participant();
+        handle
+            .add_participant("alice".to_string(), participant)
+            .await
+            .unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let client = stream.local_addr().unwrap();
+        let (msg, frame) = binding("alice");
+        stream.write_all(&frame).await.unwrap();
+        let packet = udp_packet(&mut receiver).await;
+        assert_eq!(packet.raw, msg);
+        assert_eq!(packet.src, client);
+        assert_eq!(packet.dst, addr);
+        assert_eq!(packet.proto, Protocol::Tcp);
+
+        // A frame longer than the initial buffer grows it
+        let mut frame = BytesMut::new();
+        assert!(encode_frame(&[1; INITIAL_READ_BUFFER * 2], &mut frame));
+        stream.write_all(&frame).await.unwrap();
+        assert_eq!(
+            udp_packet(&mut receiver).await.raw.len(),
+            INITIAL_READ_BUFFER * 2
+        );
+
+        handle
+            .send(EgressUDPPacket {
+                raw: Bytes::from_static(b"hello"),
+                dst: client,
+            })
+            .await
+            .unwrap();
+        let mut buf = BytesMut::new();
+        let frame = read_frame(&mut stream, &mut buf).await.unwrap().unwrap();
+        assert_eq!(frame, Bytes::from_static(b"hello"));
+    }
+
+    #[tokio::test]
+    async fn test_drop_unknown_ufrag() {
+        let (_handle, addr) = listen(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&binding("bob").1).await.unwrap();
+        assert!(is_closed(&mut stream).await);
+    }
+
+    #[tokio::test]
+    async fn test_drop_first_frame_without_binding() {
+        let (_handle, addr) = listen(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut frame = BytesMut::new();
+        assert!(encode_frame(b"not stun", &mut frame));
+        stream.write_all(&frame).await.unwrap();
+        assert!(is_closed(&mut stream).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drop_idle_connection() {
+        let (_handle, addr) = listen(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 16];
+        let res = tokio::time::timeout(HANDSHAKE_TIMEOUT * 2, stream.read(&mut buf)).await;
+        assert!(matches!(res, Ok(Ok(0) | Err(_))));
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let (_handle, addr) = listen(1).await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(is_closed(&mut second).await);
+
+        let mut buf = [0; 16];
+        assert_eq!(
+            first.try_read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buf = BytesMut::new();
+        assert!(encode_frame(b"hello", &mut buf));
+        assert!(encode_frame(b"", &mut buf));
+        assert!(encode_frame(b"world", &mut buf));
+
+        assert_eq!(
+            decode_frame(&mut buf).unwrap(),
+            Bytes::from_static(b"hello")
+        );
+        assert_eq!(decode_frame(&mut buf).unwrap(), Bytes::new());
+        assert_eq!(
+            decode_frame(&mut buf).unwrap(),
+            Bytes::from_static(b"world")
+        );
+        assert!(decode_frame(&mut buf).is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_partial_frame() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(0);
+        assert!(decode_frame(&mut buf).is_none());
+
+        buf.put_u8(3);
+        buf.put_slice(b"ab");
+        assert!(decode_frame(&mut buf).is_none());
+        assert_eq!(buf.len(), 4);
+
+        buf.put_slice(b"c");
+        assert_eq!(decode_frame(&mut buf).unwrap(), Bytes::from_static(b"abc"));
+    }
+
+    #[test]
+    fn test_reject_oversized_frame() {
+        let mut buf = BytesMut::new();
+        assert!(!encode_frame(&vec![0; MAX_FRAME_SIZE + 1], &mut buf));
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+//! Metric names and the Prometheus exporter. Metrics are recorded through the `metrics`
+//! facade and are no-ops until [`install`] sets the global recorder.
+
+use axum::{Router, extract::State, routing::get};
+use metrics::{Unit, describe_counter, describe_gauge};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+
+pub const ROOMS: &str = "pulsebeam_rooms";
+pub const PARTICIPANTS: &str = "pulsebeam_participants";
+pub const TRACKS: &str = "pulsebeam_tracks";
+pub const UDP_PACKETS: &str = "pulsebeam_udp_packets_total";
+pub const UDP_BYTES: &str = "pulsebeam_udp_bytes_total";
+pub const DROPPED_PACKETS: &str = "pulsebeam_dropped_packets_total";
+pub const KEYFRAME_REQUESTS_THROTTLED: &str = "pulsebeam_keyframe_requests_throttled_total";
+pub const KEYFRAME_CACHE_HITS: &str = "pulsebeam_keyframe_cache_hits_total";
+pub const RTP_NACKS: &str = "pulsebeam_rtp_nacks_total";
+pub const ACTOR_EXITS: &str = "pulsebeam_actor_exits_total";
+
+/// Counts a message dropped by a full mailbox, `site` names the `try_send` call.
+pub fn record_dropped(site: &'static str) {
+    metrics::counter!(DROPPED_PACKETS, "site" => site).increment(1);
+}
+
+fn describe() {
+    describe_gauge!(ROOMS, "Rooms that are currently open");
+    describe_gauge!(PARTICIPANTS, "Participants that are currently connected");
+    describe_gauge!(TRACKS, "Published tracks that are currently forwarded");
+    describe_counter!(UDP_PACKETS, "UDP datagrams by socket and direction");
+    describe_counter!(
+        UDP_BYTES,
+        Unit::Bytes,
+        "UDP payload by socket and direction"
+    );
+    describe_counter!(
+        DROPPED_PACKETS,
+        "Packets and requests dropped because an actor mailbox is full"
+    );
+    describe_counter!(
+        KEYFRAME_REQUESTS_THROTTLED,
+        "Keyframe requests from subscribers that are not forwarded to the publisher"
+    );
+    describe_counter!(
+        KEYFRAME_CACHE_HITS,
+        "New subscribers started from a cached keyframe instead of requesting one"
+    );
+    describe_counter!(
+        RTP_NACKS,
+        "NACKs received from subscribers and sent to publishers, by direction"
+    );
+    describe_counter!(
+        ACTOR_EXITS,
+        "Actors that have stopped, by kind and final status"
+    );
+}
+
+/// Sets the global recorder. Must be called before any actor is created, the actors
+/// register their metrics on construction.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new().install_recorder()?;
+    describe();
+    Ok(handle)
+}
+
+/// Serves the metrics in the Prometheus text format on `/metrics`.
+pub fn router(handle: PrometheusHandle) -> Router {
+    Router::new()
+        .route("/metrics", get(render))
+        .with_state(handle)
+}
+
+async fn render(State(handle): State<PrometheusHandle>) -> String {
+    handle.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_text_format() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            record_dropped("participant_forward");
+            record_dropped("participant_forward");
+            metrics::gauge!(ROOMS).increment(1.0);
+        });
+
+        let rendered = handle.render();
+        assert!(
+            rendered.contains(r#"pulsebeam_dropped_packets_total{site="participant_forward"} 2"#)
+        );
+        assert!(rendered.contains("pulsebeam_rooms 1"));
+    }
+}
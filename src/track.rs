@@ -1,19 +1,37 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 
-use str0m::media::MediaData;
+use str0m::{
+    format::{Codec, CodecExtra},
+    media::{KeyframeRequestKind, MediaData, Rid},
+};
 use tokio::{
-    sync::mpsc::{self, error::SendError},
+    sync::{
+        mpsc::{
+            self,
+            error::{SendError, TrySendError},
+        },
+        watch,
+    },
     time::Instant,
 };
 
 use crate::{
-    actor::Actor,
+    actor::{Actor, ActorError},
+    bwe::LayerBitrates,
+    config::{ChannelCapacities, MediaConfig},
     entity::{ParticipantId, TrackId},
     message::{self, TrackIn},
     participant::ParticipantHandle,
+    speaker::{self, AudioLevel},
+    telemetry,
 };
 
 const KEYFRAME_REQUEST_THROTTLE: Duration = Duration::from_secs(1);
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
 
 #[derive(Debug, thiserror::Error)]
 pub enum TrackError {}
@@ -26,14 +44,147 @@ pub enum TrackDataMessage {
 
 #[derive(Debug)]
 pub enum TrackControlMessage {
-    Subscribe(ParticipantHandle),
+    Subscribe(ParticipantHandle, Option<Rid>),
     Unsubscribe(Arc<ParticipantId>),
+    SelectLayer(Arc<ParticipantId>, Option<Rid>),
+    Pause(Arc<ParticipantId>),
+}
+
+/// Simulcast layer forwarded to a single subscriber. `current` only follows `desired`
+/// on a keyframe of the desired layer, so the subscriber's decoder never receives
+/// a delta frame it can't decode. A paused subscriber doesn't receive anything, e.g.
+/// when its downlink can't even afford the lowest layer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LayerSelector {
+    pub desired: Option<Rid>,
+    pub current: Option<Rid>,
+    pub paused: bool,
+    /// Set for new and resumed subscribers, nothing is forwarded until a keyframe of the
+    /// desired layer. `current` can't express this for tracks without simulcast, their
+    /// only layer is `None`.
+    pub needs_keyframe: bool,
+}
+
+impl LayerSelector {
+    pub fn new(desired: Option<Rid>) -> Self {
+        Self {
+            desired,
+            current: None,
+            paused: false,
+            needs_keyframe: true,
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        // resuming has to wait for a keyframe again
+        self.needs_keyframe = true;
+    }
+
+    pub fn resume(&mut self, desired: Option<Rid>) {
+        self.paused = false;
+        self.desired = desired;
+    }
+
+    pub fn is_switching(&self) -> bool {
+        self.desired != self.current
+    }
+
+    /// Returns true when a frame from `rid` should be forwarded to the subscriber.
+    pub fn select(&mut self, rid: Option<Rid>, is_keyframe: bool) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        if !self.needs_keyframe && rid == self.current {
+            return true;
+        }
+
+        if rid == self.desired && is_keyframe {
+            self.current = rid;
+            self.needs_keyframe = false;
+            return true;
+        }
+
+        false
+    }
+}
+
+/// The latest keyframe and the frames following it for each layer. Replaying a group
+/// gets a new subscriber's decoder going without waiting for the publisher's next
+/// keyframe. A group that outgrows the limits is dropped until the next keyframe.
+#[derive(Debug)]
+pub struct KeyframeCache<T> {
+    max_frames: usize,
+    max_bytes: usize,
+    groups: HashMap<Option<Rid>, KeyframeGroup<T>>,
+}
+
+#[derive(Debug)]
+struct KeyframeGroup<T> {
+    frames: Vec<T>,
+    bytes: usize,
+}
+
+impl<T> KeyframeCache<T> {
+    pub fn new(max_frames: usize, max_bytes: usize) -> Self {
+        Self {
+            max_frames,
+            max_bytes,
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, rid: Option<Rid>, is_keyframe: bool, size: usize, frame: T) {
+        if is_keyframe {
+            self.groups.insert(
+                rid,
+                KeyframeGroup {
+                    frames: Vec::new(),
+                    bytes: 0,
+                },
+            );
+        }
+
+        let Some(group) = self.groups.get_mut(&rid) else {
+            return;
+        };
+        if group.frames.len() >= self.max_frames || group.bytes + size > self.max_bytes {
+            self.groups.remove(&rid);
+            return;
+        }
+        group.frames.push(frame);
+        group.bytes += size;
+    }
+
+    /// Frames of `rid` starting with a keyframe, empty when there's nothing cached.
+    pub fn frames(&self, rid: Option<Rid>) -> &[T] {
+        self.groups
+            .get(&rid)
+            .map(|group| group.frames.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Like [`Self::frames`], but also empty when the group doesn't fit into `capacity`.
+    /// Every frame depends on the ones before it, a partial replay can't be decoded.
+    pub fn replay(&self, rid: Option<Rid>, capacity: usize) -> &[T] {
+        let frames = self.frames(rid);
+        if frames.len() > capacity {
+            return &[];
+        }
+        frames
+    }
+}
+
+struct Subscription {
+    handle: ParticipantHandle,
+    layer: LayerSelector,
 }
 
 /// Responsibilities:
 /// * Represent a Single Published Track
 /// * Manage Track Subscribers
-/// * Store Subscriber Preferences: Keep track of the desired quality/layer (DesiredLayerInfo) requested by each subscriber.
+/// * Store Subscriber Preferences: Keep track of the desired quality/layer (LayerSelector) requested by each subscriber.
 /// * Receive Packet Notifications
 /// * Filter & Forward Packet Notifications
 /// * Route Publisher-Bound RTCP: Receive RTCP feedback (PLI, FIR, etc.) from subscriber and forward it to the publisher
@@ -42,8 +193,18 @@ pub struct TrackActor {
     data_receiver: mpsc::Receiver<TrackDataMessage>,
     control_receiver: mpsc::Receiver<TrackControlMessage>,
     origin: ParticipantHandle,
-    subscribers: BTreeMap<Arc<ParticipantId>, ParticipantHandle>,
-    last_keyframe_request: Instant,
+    subscribers: BTreeMap<Arc<ParticipantId>, Subscription>,
+    // Simulcast layers are separate streams, each layer is throttled on its own
+    last_keyframe_requests: HashMap<Option<Rid>, Instant>,
+    bitrates: watch::Sender<LayerBitrates>,
+    layer_bytes: HashMap<Option<Rid>, u64>,
+    bitrate_window_start: Instant,
+    keyframes: KeyframeCache<Arc<MediaData>>,
+    codec: watch::Sender<Option<Codec>>,
+    audio_level: watch::Sender<Option<AudioLevel>>,
+    // Loudest level in the current window, in -dBov
+    loudest: Option<u8>,
+    audio_level_window_start: Instant,
 }
 
 impl Actor for TrackActor {
@@ -57,7 +218,17 @@ impl Actor for TrackActor {
         self.meta.id.clone()
     }
 
-    async fn run(&mut self) -> Result<(), crate::actor::ActorError> {
+    async fn pre_start(&mut self) -> Result<(), ActorError> {
+        metrics::gauge!(telemetry::TRACKS).increment(1.0);
+        Ok(())
+    }
+
+    async fn post_stop(&mut self) -> Result<(), ActorError> {
+        metrics::gauge!(telemetry::TRACKS).decrement(1.0);
+        Ok(())
+    }
+
+    async fn run(&mut self) -> Result<(), ActorError> {
         loop {
             tokio::select! {
                 Some(msg) = self.data_receiver.recv() => {
@@ -80,30 +251,181 @@ impl TrackActor {
     fn handle_data_message(&mut self, msg: TrackDataMessage) {
         match msg {
             TrackDataMessage::ForwardMedia(data) => {
-                for (_, sub) in &self.subscribers {
-                    let _ = sub.forward_media(self.meta.clone(), data.clone());
+                self.measure_bitrate(&data);
+                self.measure_audio_level(&data);
+                let codec = data.params.spec().codec;
+                if *self.codec.borrow() != Some(codec) {
+                    self.codec.send_replace(Some(codec));
+                }
+                let is_keyframe = is_keyframe(&data);
+                self.keyframes
+                    .push(data.rid, is_keyframe, data.data.len(), data.clone());
+                // Every audio frame can be decoded on its own
+                let is_video = self.meta.kind.is_video();
+                let decodable = is_keyframe || !is_video;
+                let mut dropped = Vec::new();
+                for (_, sub) in &mut self.subscribers {
+                    if !sub.layer.select(data.rid, decodable) {
+                        continue;
+                    }
+                    let res = sub.handle.forward_media(self.meta.clone(), data.clone());
+                    // The following delta frames can't be decoded without the dropped one
+                    if is_video && matches!(res, Err(TrySendError::Full(_))) {
+                        sub.layer.needs_keyframe = true;
+                        dropped.push(sub.layer);
+                    }
+                }
+                for layer in dropped {
+                    self.request_layer_keyframe(layer);
                 }
             }
             TrackDataMessage::KeyframeRequest(req) => {
-                let now = Instant::now();
-                let elapsed = now.duration_since(self.last_keyframe_request);
-                if elapsed >= KEYFRAME_REQUEST_THROTTLE {
-                    self.origin.request_keyframe(self.meta.id.clone(), req);
-                    self.last_keyframe_request = now;
-                }
+                self.request_keyframe(req);
             }
         }
     }
 
+    fn request_keyframe(&mut self, req: message::KeyframeRequest) {
+        let now = Instant::now();
+        let last = self
+            .last_keyframe_requests
+            .get(&req.rid)
+            // allow keyframe request immediately
+            .map_or(KEYFRAME_REQUEST_THROTTLE, |last| now.duration_since(*last));
+        if last >= KEYFRAME_REQUEST_THROTTLE {
+            self.last_keyframe_requests.insert(req.rid, now);
+            self.origin.request_keyframe(self.meta.id.clone(), req);
+        } else {
+            metrics::counter!(telemetry::KEYFRAME_REQUESTS_THROTTLED).increment(1);
+        }
+    }
+
+    /// Measures the bitrate of each layer, subscribers use it to fit the layers into
+    /// their downlink.
+    fn measure_bitrate(&mut self, data: &MediaData) {
+        *self.layer_bytes.entry(data.rid).or_default() += data.data.len() as u64;
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.bitrate_window_start);
+        if elapsed < BITRATE_WINDOW {
+            return;
+        }
+
+        let mut rids: Vec<Option<Rid>> = self.meta.rids().map(Some).collect();
+        if rids.is_empty() {
+            rids.push(None);
+        }
+
+        let elapsed_ms = elapsed.as_millis().max(1) as u64;
+        let bitrates = rids
+            .into_iter()
+            .map(|rid| {
+                let bytes = self.layer_bytes.get(&rid).copied().unwrap_or_default();
+                (rid, bytes * 8 * 1000 / elapsed_ms)
+            })
+            .collect();
+        self.bitrates.send_replace(LayerBitrates(bitrates));
+        self.layer_bytes.clear();
+        self.bitrate_window_start = now;
+    }
+
+    /// Measures the loudest level of each window, the room picks the active speakers
+    /// from it.
+    fn measure_audio_level(&mut self, data: &MediaData) {
+        let Some(level) = speaker::frame_audio_level(data) else {
+            return;
+        };
+        self.loudest = Some(self.loudest.map_or(level, |loudest| loudest.min(level)));
+
+        let now = Instant::now();
+        if now.duration_since(self.audio_level_window_start) < speaker::AUDIO_LEVEL_WINDOW {
+            return;
+        }
+
+        if let Some(dbov) = self.loudest.take() {
+            self.audio_level.send_replace(Some(AudioLevel {
+                dbov,
+                measured_at: now,
+            }));
+        }
+        self.audio_level_window_start = now;
+    }
+
+    /// Requests a keyframe of the desired layer, the switch completes when it arrives.
+    fn request_layer_keyframe(&mut self, layer: LayerSelector) {
+        if !self.meta.kind.is_video()
+            || layer.paused
+            || !(layer.needs_keyframe || layer.is_switching())
+        {
+            return;
+        }
+
+        self.request_keyframe(message::KeyframeRequest {
+            rid: layer.desired,
+            kind: KeyframeRequestKind::Pli,
+        });
+    }
+
     fn handle_control_message(&mut self, msg: TrackControlMessage) {
         match msg {
-            TrackControlMessage::Subscribe(participant) => {
-                tracing::info!(participant_id=?participant.participant_id, "track subscribed");
+            TrackControlMessage::Subscribe(participant, rid) => {
+                tracing::info!(participant_id=?participant.participant_id, ?rid, "track subscribed");
+                let mut sub = Subscription {
+                    handle: participant,
+                    layer: LayerSelector::new(rid),
+                };
+                // Only what fits into the subscriber's mailbox, the frames it already holds
+                // aren't dropped for the replay
+                let cached = self
+                    .keyframes
+                    .replay(rid, sub.handle.data_sender.capacity());
+                let mut primed = !cached.is_empty();
+                for data in cached {
+                    if !sub.layer.select(data.rid, is_keyframe(data)) {
+                        continue;
+                    }
+                    if sub
+                        .handle
+                        .forward_media(self.meta.clone(), data.clone())
+                        .is_err()
+                    {
+                        // The rest of the group can't be decoded anymore
+                        sub.layer.needs_keyframe = true;
+                        primed = false;
+                        break;
+                    }
+                }
+                let layer = sub.layer;
                 self.subscribers
-                    .insert(participant.participant_id.clone(), participant);
+                    .insert(sub.handle.participant_id.clone(), sub);
+                // Without a cached keyframe, the subscriber can't decode anything until the
+                // next one
+                if primed {
+                    metrics::counter!(telemetry::KEYFRAME_CACHE_HITS).increment(1);
+                } else {
+                    self.request_layer_keyframe(layer);
+                }
             }
             TrackControlMessage::Unsubscribe(participant_id) => {
-                // TODO: handle unsubscribe
+                if self.subscribers.remove(&participant_id).is_some() {
+                    tracing::info!(?participant_id, "track unsubscribed");
+                }
+            }
+            TrackControlMessage::SelectLayer(participant_id, rid) => {
+                let Some(sub) = self.subscribers.get_mut(&participant_id) else {
+                    return;
+                };
+
+                tracing::debug!(?participant_id, ?rid, current = ?sub.layer.current, "layer selected");
+                sub.layer.resume(rid);
+                let layer = sub.layer;
+                self.request_layer_keyframe(layer);
+            }
+            TrackControlMessage::Pause(participant_id) => {
+                if let Some(sub) = self.subscribers.get_mut(&participant_id) {
+                    tracing::debug!(?participant_id, "layer paused");
+                    sub.layer.pause();
+                }
             }
         }
     }
@@ -114,16 +436,32 @@ pub struct TrackHandle {
     pub data_sender: mpsc::Sender<TrackDataMessage>,
     pub control_sender: mpsc::Sender<TrackControlMessage>,
     pub meta: Arc<TrackIn>,
+    pub bitrates: watch::Receiver<LayerBitrates>,
+    /// Codec of the latest frame, `None` until the publisher sends media.
+    pub codec: watch::Receiver<Option<Codec>>,
+    /// Only set for audio tracks with the `ssrc-audio-level` extension.
+    pub audio_level: watch::Receiver<Option<AudioLevel>>,
 }
 
 impl TrackHandle {
-    pub fn new(origin: ParticipantHandle, meta: Arc<TrackIn>) -> (Self, TrackActor) {
-        let (data_sender, data_receiver) = mpsc::channel(64);
-        let (control_sender, control_receiver) = mpsc::channel(8);
+    pub fn new(
+        origin: ParticipantHandle,
+        meta: Arc<TrackIn>,
+        channels: ChannelCapacities,
+        media: &MediaConfig,
+    ) -> (Self, TrackActor) {
+        let (data_sender, data_receiver) = mpsc::channel(channels.track_data);
+        let (control_sender, control_receiver) = mpsc::channel(channels.track_control);
+        let (bitrates, bitrates_receiver) = watch::channel(LayerBitrates::default());
+        let (codec, codec_receiver) = watch::channel(None);
+        let (audio_level, audio_level_receiver) = watch::channel(None);
         let handle = Self {
             data_sender,
             control_sender,
             meta: meta.clone(),
+            bitrates: bitrates_receiver,
+            codec: codec_receiver,
+            audio_level: audio_level_receiver,
         };
         let actor = TrackActor {
             meta,
@@ -131,8 +469,15 @@ impl TrackHandle {
             control_receiver,
             origin,
             subscribers: BTreeMap::new(),
-            // allow keyframe request immediately
-            last_keyframe_request: Instant::now() - KEYFRAME_REQUEST_THROTTLE,
+            last_keyframe_requests: HashMap::new(),
+            bitrates,
+            layer_bytes: HashMap::new(),
+            bitrate_window_start: Instant::now(),
+            keyframes: KeyframeCache::new(media.keyframe_cache_frames, media.keyframe_cache_bytes),
+            codec,
+            audio_level,
+            loudest: None,
+            audio_level_window_start: Instant::now(),
         };
         (handle, actor)
     }
@@ -150,9 +495,38 @@ impl TrackHandle {
     pub async fn subscribe(
         &self,
         participant: ParticipantHandle,
+        rid: Option<Rid>,
     ) -> Result<(), SendError<TrackControlMessage>> {
         self.control_sender
-            .send(TrackControlMessage::Subscribe(participant))
+            .send(TrackControlMessage::Subscribe(participant, rid))
+            .await
+    }
+
+    pub async fn unsubscribe(
+        &self,
+        participant_id: Arc<ParticipantId>,
+    ) -> Result<(), SendError<TrackControlMessage>> {
+        self.control_sender
+            .send(TrackControlMessage::Unsubscribe(participant_id))
+            .await
+    }
+
+    pub async fn select_layer(
+        &self,
+        participant_id: Arc<ParticipantId>,
+        rid: Option<Rid>,
+    ) -> Result<(), SendError<TrackControlMessage>> {
+        self.control_sender
+            .send(TrackControlMessage::SelectLayer(participant_id, rid))
+            .await
+    }
+
+    pub async fn pause(
+        &self,
+        participant_id: Arc<ParticipantId>,
+    ) -> Result<(), SendError<TrackControlMessage>> {
+        self.control_sender
+            .send(TrackControlMessage::Pause(participant_id))
             .await
     }
 
@@ -164,7 +538,137 @@ impl TrackHandle {
             .data_sender
             .try_send(TrackDataMessage::KeyframeRequest(req))
         {
+            telemetry::record_dropped("track_request_keyframe");
             tracing::warn!("keyframe request is dropped by the track actor: {err}");
         }
     }
 }
+
+pub(crate) fn is_keyframe(data: &MediaData) -> bool {
+    match &data.codec_extra {
+        CodecExtra::Vp8(extra) => extra.is_keyframe,
+        CodecExtra::Vp9(extra) => extra.is_keyframe,
+        CodecExtra::H264(extra) => extra.is_keyframe,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_waits_for_keyframe() {
+        let mut layer = LayerSelector::new(Some(Rid::from("q")));
+
+        assert!(!layer.select(Some(Rid::from("q")), false));
+        assert!(!layer.select(Some(Rid::from("f")), true));
+        assert!(layer.select(Some(Rid::from("q")), true));
+        assert!(layer.select(Some(Rid::from("q")), false));
+        assert!(!layer.is_switching());
+    }
+
+    #[test]
+    fn test_layer_switch_is_keyframe_aligned() {
+        let mut layer = LayerSelector::new(Some(Rid::from("q")));
+        assert!(layer.select(Some(Rid::from("q")), true));
+
+        layer.desired = Some(Rid::from("f"));
+        assert!(layer.is_switching());
+        // keep forwarding the current layer until the desired layer has a keyframe
+        assert!(layer.select(Some(Rid::from("q")), false));
+        assert!(!layer.select(Some(Rid::from("f")), false));
+        assert!(layer.select(Some(Rid::from("f")), true));
+        assert!(!layer.select(Some(Rid::from("q")), false));
+        assert_eq!(layer.current, Some(Rid::from("f")));
+    }
+
+    #[test]
+    fn test_layer_resume_after_pause() {
+        let mut layer = LayerSelector::new(Some(Rid::from("h")));
+        assert!(layer.select(Some(Rid::from("h")), true));
+
+        layer.pause();
+        assert!(!layer.select(Some(Rid::from("h")), true));
+
+        layer.resume(Some(Rid::from("q")));
+        assert!(!layer.select(Some(Rid::from("q")), false));
+        assert!(layer.select(Some(Rid::from("q")), true));
+    }
+
+    #[test]
+    fn test_layer_resume_after_pause_without_simulcast() {
+        let mut layer = LayerSelector::new(None);
+        assert!(!layer.select(None, false));
+        assert!(layer.select(None, true));
+        assert!(layer.select(None, false));
+
+        layer.pause();
+        assert!(!layer.select(None, true));
+
+        layer.resume(None);
+        assert!(layer.needs_keyframe);
+        assert!(!layer.select(None, false));
+        assert!(layer.select(None, true));
+        assert!(layer.select(None, false));
+        assert!(!layer.needs_keyframe);
+    }
+
+    #[test]
+    fn test_keyframe_cache_starts_on_keyframe() {
+        let q = Some(Rid::from("q"));
+        let mut cache = KeyframeCache::new(3, 1024);
+        cache.push(q, false, 10, 0);
+        assert!(cache.frames(q).is_empty());
+
+        cache.push(q, true, 10, 1);
+        cache.push(q, false, 10, 2);
+        cache.push(Some(Rid::from("h")), false, 10, 3);
+        assert_eq!(cache.frames(q), &[1, 2]);
+        assert!(cache.frames(Some(Rid::from("h"))).is_empty());
+
+        cache.push(q, true, 10, 4);
+        assert_eq!(cache.frames(q), &[4]);
+    }
+
+    #[test]
+    fn test_keyframe_cache_drops_large_groups() {
+        let mut cache = KeyframeCache::new(2, 1024);
+        cache.push(None, true, 10, 1);
+        cache.push(None, false, 10, 2);
+        cache.push(None, false, 10, 3);
+        assert!(cache.frames(None).is_empty());
+        // Stays empty until the next keyframe
+        cache.push(None, false, 10, 4);
+        assert!(cache.frames(None).is_empty());
+
+        cache.push(None, true, 1000, 5);
+        assert_eq!(cache.frames(None), &[5]);
+        cache.push(None, false, 25, 6);
+        assert!(cache.frames(None).is_empty());
+    }
+
+    #[test]
+    fn test_keyframe_cache_replay_fits_capacity() {
+        let mut cache = KeyframeCache::new(8, 1024);
+        cache.push(None, true, 10, 1);
+        cache.push(None, false, 10, 2);
+        cache.push(None, false, 10, 3);
+        assert_eq!(cache.replay(None, 3), &[1, 2, 3]);
+        assert!(cache.replay(None, 2).is_empty());
+
+        // Without a cache, nothing is replayed
+        let mut cache = KeyframeCache::new(0, 1024);
+        cache.push(None, true, 10, 1);
+        assert!(cache.replay(None, 8).is_empty());
+    }
+
+    #[test]
+    fn test_layer_without_simulcast() {
+        let mut layer = LayerSelector::new(None);
+        assert!(!layer.select(None, false));
+        assert!(layer.select(None, true));
+        assert!(layer.select(None, false));
+        assert!(!layer.is_switching());
+    }
+}
@@ -0,0 +1,1363 @@
+//! Embedded TURN server (RFC 8656) over UDP, TCP and TLS.
+//!
+//! The SFU is the only peer a client can reach through it. Relayed addresses are never
+//! bound to a socket, they only identify the allocation: data for the SFU is handed to
+//! the UDP source as if it arrived from the relayed address, and participants send
+//! their replies to such addresses through [`TurnHandle::send`].
+//!
+//! Credentials follow the TURN REST API convention: the username is
+//! `<unix expiry>:<api key id>` and the password is the base64 HMAC-SHA1 of the username
+//! with the API secret, see [`TurnCredentials`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader},
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use bytes::{Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha1::Sha1;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, error::SendError},
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_rustls::{TlsAcceptor, rustls};
+use tracing::Instrument;
+
+use crate::{
+    actor::{Actor, ActorError},
+    entity::EntityId,
+    message::EgressUDPPacket,
+    net::{PacketSocket, UdpSocket},
+    rng::Rng,
+    source::UdpSourceHandle,
+    stun::{self, Class, Message, MessageBuilder},
+    telemetry,
+};
+
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+const NONCE_LIFETIME: Duration = Duration::from_secs(3600);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Backs off when receiving fails, e.g. an ICMP error of an earlier send surfacing.
+const RECV_BACKOFF: Duration = Duration::from_millis(10);
+
+const CHANNEL_NUMBERS: RangeInclusive<u16> = 0x4000..=0x4FFF;
+const RELAY_PORTS: RangeInclusive<u16> = 49152..=65535;
+const MAX_DATAGRAM_SIZE: usize = 4096;
+const SOFTWARE: &[u8] = b"pulsebeam";
+
+/// An error code and reason phrase sent back to the client.
+type Rejection = (u16, &'static str);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// TURN REST API password of `username`.
+pub fn turn_password(secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("any key size");
+    mac.update(username.as_bytes());
+    BASE64_STANDARD.encode(mac.finalize().into_bytes())
+}
+
+fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    Md5::digest(format!("{username}:{realm}:{password}")).to_vec()
+}
+
+/// An ICE server entry handed to clients, e.g. in a WHIP Link header.
+#[derive(Debug, Clone)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+/// Issues short-lived credentials that the embedded TURN server accepts.
+#[derive(Debug, Clone)]
+pub struct TurnCredentials {
+    key_id: EntityId,
+    secret: String,
+    urls: Vec<String>,
+    ttl: Duration,
+}
+
+impl TurnCredentials {
+    pub fn new(key_id: EntityId, secret: String, urls: Vec<String>, ttl: Duration) -> Self {
+        Self {
+            key_id,
+            secret,
+            urls,
+            ttl,
+        }
+    }
+
+    pub fn issue(&self) -> IceServer {
+        let expiry = unix_now() + self.ttl.as_secs();
+        let username = format!("{expiry}:{}", self.key_id);
+        IceServer {
+            urls: self.urls.clone(),
+            credential: turn_password(&self.secret, &username),
+            username,
+        }
+    }
+}
+
+/// Loads a PEM certificate chain and private key for TURN over TLS.
+pub fn tls_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key is found"))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ClientTransport {
+    /// Index of the UDP socket
+    Udp(usize),
+    Tcp,
+    Tls,
+}
+
+/// The 5-tuple of an allocation, the server side is implied by the transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Client {
+    transport: ClientTransport,
+    addr: SocketAddr,
+}
+
+enum Ingress {
+    Packet(Client, Bytes),
+    Connected(Client, mpsc::Sender<Bytes>),
+    Disconnected(Client),
+}
+
+#[derive(Debug)]
+pub enum TurnMessage {
+    /// A packet from the SFU address to a relayed address.
+    Send(SocketAddr, EgressUDPPacket),
+}
+
+struct Channel {
+    peer: SocketAddr,
+    expires: Instant,
+}
+
+struct Allocation {
+    relay: SocketAddr,
+    username: String,
+    key: Vec<u8>,
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, Channel>,
+}
+
+impl Allocation {
+    fn is_permitted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions
+            .get(&ip)
+            .is_some_and(|expires| *expires > now)
+    }
+
+    fn channel_of(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, channel)| channel.peer == peer && channel.expires > now)
+            .map(|(number, _)| *number)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TurnSettings {
+    pub realm: String,
+    /// API key ID and secret pairs that credentials can be derived from.
+    pub keys: Vec<(EntityId, String)>,
+    /// Public IPs of the server, one per address family, used for relayed addresses.
+    pub relay_ips: Vec<IpAddr>,
+    /// Media addresses of the SFU, the only peers that clients may relay to.
+    pub peers: Vec<SocketAddr>,
+    pub max_allocations: usize,
+}
+
+#[derive(Default)]
+pub struct TurnListeners {
+    pub udp: Vec<UdpSocket>,
+    pub tcp: Vec<TcpListener>,
+    pub tls: Vec<TcpListener>,
+    pub tls_acceptor: Option<TlsAcceptor>,
+}
+
+pub struct TurnActor {
+    // Port of the UDP listeners
+    port: u16,
+    receiver: mpsc::Receiver<TurnMessage>,
+    ingress_sender: mpsc::Sender<Ingress>,
+    ingress: mpsc::Receiver<Ingress>,
+    listeners: TurnListeners,
+    connection_capacity: usize,
+    tasks: JoinSet<()>,
+    rng: Rng,
+    source: UdpSourceHandle,
+
+    realm: String,
+    keys: HashMap<EntityId, String>,
+    relay_ips: Vec<IpAddr>,
+    peers: HashSet<SocketAddr>,
+    max_allocations: usize,
+    nonce_key: [u8; 32],
+
+    udp: Vec<UdpSocket>,
+    streams: HashMap<Client, mpsc::Sender<Bytes>>,
+    allocations: HashMap<Client, Allocation>,
+    relays: HashMap<SocketAddr, Client>,
+    next_port: u16,
+}
+
+impl Actor for TurnActor {
+    type ID = u16;
+
+    fn kind(&self) -> &'static str {
+        "turn"
+    }
+
+    fn id(&self) -> Self::ID {
+        self.port
+    }
+
+    async fn pre_start(&mut self) -> Result<(), ActorError> {
+        let listeners = std::mem::take(&mut self.listeners);
+        for (index, socket) in listeners.udp.into_iter().enumerate() {
+            self.udp.push(socket.clone());
+            self.tasks
+                .spawn(read_udp(socket, index, self.ingress_sender.clone()).in_current_span());
+        }
+
+        for listener in listeners.tcp {
+            self.tasks.spawn(
+                accept_streams(
+                    listener,
+                    None,
+                    self.ingress_sender.clone(),
+                    self.connection_capacity,
+                )
+                .in_current_span(),
+            );
+        }
+
+        if let Some(acceptor) = listeners.tls_acceptor {
+            for listener in listeners.tls {
+                self.tasks.spawn(
+                    accept_streams(
+                        listener,
+                        Some(acceptor.clone()),
+                        self.ingress_sender.clone(),
+                        self.connection_capacity,
+                    )
+                    .in_current_span(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn run(&mut self) -> Result<(), ActorError> {
+        let mut expiry = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = self.receiver.recv() => {
+                    match msg {
+                        Some(TurnMessage::Send(source, packet)) => {
+                            self.handle_egress(source, packet).await;
+                        }
+                        None => {
+                            tracing::info!("all participants have exited, will gracefully shutdown");
+                            break;
+                        }
+                    }
+                }
+
+                Some(ingress) = self.ingress.recv() => {
+                    self.handle_ingress(ingress).await;
+                }
+
+                _ = expiry.tick() => {
+                    self.expire();
+                }
+            }
+        }
+
+        tracing::info!("turn server has exited");
+        Ok(())
+    }
+}
+
+impl TurnActor {
+    async fn handle_ingress(&mut self, ingress: Ingress) {
+        match ingress {
+            Ingress::Packet(client, data) => {
+                if let Some(msg) = Message::decode(&data) {
+                    self.handle_stun(client, msg).await;
+                } else if data.first().is_some_and(|b| b >> 6 == 0b01) {
+                    self.handle_channel_data(client, &data).await;
+                } else {
+                    tracing::trace!("dropped an unknown packet from {:?}", client);
+                }
+            }
+            Ingress::Connected(client, sender) => {
+                self.streams.insert(client, sender);
+            }
+            Ingress::Disconnected(client) => {
+                self.streams.remove(&client);
+                // A TCP allocation lives as long as its connection
+                if let Some(allocation) = self.allocations.remove(&client) {
+                    self.relays.remove(&allocation.relay);
+                }
+            }
+        }
+    }
+
+    async fn handle_stun(&mut self, client: Client, msg: Message<'_>) {
+        match (msg.class, msg.method) {
+            (Class::Request, stun::METHOD_BINDING) => {
+                let resp = MessageBuilder::new(msg.method, Class::Success, msg.transaction_id)
+                    .add_xor_address(stun::ATTR_XOR_MAPPED_ADDRESS, client.addr)
+                    .build();
+                self.send(client, resp).await;
+            }
+            (Class::Request, method) => {
+                let key = match self.authenticate(&client, &msg) {
+                    Ok(key) => key,
+                    Err(resp) => {
+                        self.send(client, resp).await;
+                        return;
+                    }
+                };
+
+                let res = match method {
+                    stun::METHOD_ALLOCATE => self.allocate(client, &msg, &key),
+                    stun::METHOD_REFRESH => self.refresh(client, &msg),
+                    stun::METHOD_CREATE_PERMISSION => self.create_permission(client, &msg),
+                    stun::METHOD_CHANNEL_BIND => self.channel_bind(client, &msg),
+                    _ => Err((400, "Bad Request")),
+                };
+                let resp = match res {
+                    Ok(resp) => resp,
+                    Err((code, reason)) => {
+                        tracing::debug!(?client, method, code, reason, "rejected turn request");
+                        MessageBuilder::new(method, Class::Error, msg.transaction_id)
+                            .add_error(code, reason)
+                    }
+                };
+                self.send(client, resp.build_with_integrity(&key)).await;
+            }
+            (Class::Indication, stun::METHOD_SEND) => {
+                self.handle_send_indication(client, &msg).await;
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the long-term credential key, or the error response to send.
+    fn authenticate(&self, client: &Client, msg: &Message) -> Result<Vec<u8>, Vec<u8>> {
+        let (Some(username), Some(nonce)) = (
+            msg.get_str(stun::ATTR_USERNAME),
+            msg.get_str(stun::ATTR_NONCE),
+        ) else {
+            return Err(self.challenge(msg, 401, "Unauthorized"));
+        };
+        if !msg.has_integrity() {
+            return Err(self.challenge(msg, 401, "Unauthorized"));
+        }
+        if !self.verify_nonce(nonce) {
+            return Err(self.challenge(msg, 438, "Stale Nonce"));
+        }
+
+        // Expiry is only checked when allocating, refreshes keep a long call going
+        if let Some(allocation) = self.allocations.get(client) {
+            if allocation.username != username {
+                return Err(self.challenge(msg, 441, "Wrong Credentials"));
+            }
+            if !msg.verify_integrity(&allocation.key) {
+                return Err(self.challenge(msg, 401, "Unauthorized"));
+            }
+            return Ok(allocation.key.clone());
+        }
+
+        match self.credential_key(username) {
+            Some(key) if msg.verify_integrity(&key) => Ok(key),
+            _ => Err(self.challenge(msg, 401, "Unauthorized")),
+        }
+    }
+
+    fn credential_key(&self, username: &str) -> Option<Vec<u8>> {
+        let (expiry, key_id) = username.split_once(':')?;
+        let expiry: u64 = expiry.parse().ok()?;
+        if expiry < unix_now() {
+            return None;
+        }
+
+        let secret = self.keys.get(key_id)?;
+        let password = turn_password(secret, username);
+        Some(long_term_key(username, &self.realm, &password))
+    }
+
+    /// An error response that asks the client to authenticate with a fresh nonce.
+    fn challenge(&self, msg: &Message, code: u16, reason: &str) -> Vec<u8> {
+        MessageBuilder::new(msg.method, Class::Error, msg.transaction_id)
+            .add_error(code, reason)
+            .add(stun::ATTR_REALM, self.realm.as_bytes())
+            .add(stun::ATTR_NONCE, self.nonce().as_bytes())
+            .add(stun::ATTR_SOFTWARE, SOFTWARE)
+            .build()
+    }
+
+    /// Stateless nonce, the expiry followed by a truncated HMAC of it.
+    fn nonce(&self) -> String {
+        let expiry = unix_now() + NONCE_LIFETIME.as_secs();
+        format!("{expiry:016x}{}", hex::encode(&self.nonce_mac(expiry)[..8]))
+    }
+
+    fn verify_nonce(&self, nonce: &str) -> bool {
+        if nonce.len() != 32 {
+            return false;
+        }
+        let (Ok(expiry), Ok(mac)) = (
+            u64::from_str_radix(&nonce[..16], 16),
+            hex::decode(&nonce[16..]),
+        ) else {
+            return false;
+        };
+
+        expiry >= unix_now() && self.nonce_mac(expiry)[..8] == mac[..]
+    }
+
+    fn nonce_mac(&self, expiry: u64) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.nonce_key).expect("any key size");
+        mac.update(&expiry.to_be_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn lifetime(msg: &Message) -> Duration {
+        let requested = msg
+            .get_u32(stun::ATTR_LIFETIME)
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(DEFAULT_LIFETIME);
+        requested.clamp(DEFAULT_LIFETIME, MAX_LIFETIME)
+    }
+
+    fn allocate(
+        &mut self,
+        client: Client,
+        msg: &Message,
+        key: &[u8],
+    ) -> Result<MessageBuilder, Rejection> {
+        if self.allocations.contains_key(&client) {
+            return Err((437, "Allocation Mismatch"));
+        }
+        if msg.requested_transport() != Some(stun::TRANSPORT_UDP) {
+            return Err((442, "Unsupported Transport Protocol"));
+        }
+        if self.allocations.len() >= self.max_allocations {
+            return Err((486, "Allocation Quota Reached"));
+        }
+        let Some(ip) = self
+            .relay_ips
+            .iter()
+            .find(|ip| ip.is_ipv6() == client.addr.is_ipv6())
+            .copied()
+        else {
+            return Err((440, "Address Family not Supported"));
+        };
+        let Some(relay) = self.next_relay(ip) else {
+            return Err((508, "Insufficient Capacity"));
+        };
+
+        let lifetime = Self::lifetime(msg);
+        let username = msg.get_str(stun::ATTR_USERNAME).unwrap_or_default();
+        tracing::debug!(?client, %relay, username, "allocated turn relay");
+        self.relays.insert(relay, client);
+        self.allocations.insert(
+            client,
+            Allocation {
+                relay,
+                username: username.to_string(),
+                key: key.to_vec(),
+                expires: Instant::now() + lifetime,
+                permissions: HashMap::new(),
+                channels: HashMap::new(),
+            },
+        );
+
+        Ok(
+            MessageBuilder::new(msg.method, Class::Success, msg.transaction_id)
+                .add_xor_address(stun::ATTR_XOR_RELAYED_ADDRESS, relay)
+                .add_u32(stun::ATTR_LIFETIME, lifetime.as_secs() as u32)
+                .add_xor_address(stun::ATTR_XOR_MAPPED_ADDRESS, client.addr)
+                .add(stun::ATTR_SOFTWARE, SOFTWARE),
+        )
+    }
+
+    fn next_relay(&mut self, ip: IpAddr) -> Option<SocketAddr> {
+        let ports = RELAY_PORTS.len();
+        for _ in 0..ports {
+            let port = self.next_port;
+            self.next_port = if port == *RELAY_PORTS.end() {
+                *RELAY_PORTS.start()
+            } else {
+                port + 1
+            };
+
+            let relay = SocketAddr::new(ip, port);
+            if !self.relays.contains_key(&relay) {
+                return Some(relay);
+            }
+        }
+        None
+    }
+
+    fn refresh(&mut self, client: Client, msg: &Message) -> Result<MessageBuilder, Rejection> {
+        if !self.allocations.contains_key(&client) {
+            return Err((437, "Allocation Mismatch"));
+        }
+
+        let lifetime = if msg.get_u32(stun::ATTR_LIFETIME) == Some(0) {
+            if let Some(allocation) = self.allocations.remove(&client) {
+                self.relays.remove(&allocation.relay);
+            }
+            Duration::ZERO
+        } else {
+            let lifetime = Self::lifetime(msg);
+            if let Some(allocation) = self.allocations.get_mut(&client) {
+                allocation.expires = Instant::now() + lifetime;
+            }
+            lifetime
+        };
+
+        Ok(
+            MessageBuilder::new(msg.method, Class::Success, msg.transaction_id)
+                .add_u32(stun::ATTR_LIFETIME, lifetime.as_secs() as u32),
+        )
+    }
+
+    fn create_permission(
+        &mut self,
+        client: Client,
+        msg: &Message,
+    ) -> Result<MessageBuilder, Rejection> {
+        let peers = msg.xor_addresses(stun::ATTR_XOR_PEER_ADDRESS);
+        if peers.is_empty() {
+            return Err((400, "Bad Request"));
+        }
+        if !peers.iter().all(|peer| self.is_peer_ip(peer.ip())) {
+            return Err((403, "Forbidden"));
+        }
+        let Some(allocation) = self.allocations.get_mut(&client) else {
+            return Err((437, "Allocation Mismatch"));
+        };
+
+        let expires = Instant::now() + PERMISSION_LIFETIME;
+        for peer in peers {
+            allocation.permissions.insert(peer.ip(), expires);
+        }
+        Ok(MessageBuilder::new(
+            msg.method,
+            Class::Success,
+            msg.transaction_id,
+        ))
+    }
+
+    fn channel_bind(&mut self, client: Client, msg: &Message) -> Result<MessageBuilder, Rejection> {
+        let (Some(number), Some(peer)) = (
+            msg.channel_number(),
+            msg.xor_address(stun::ATTR_XOR_PEER_ADDRESS),
+        ) else {
+            return Err((400, "Bad Request"));
+        };
+        if !CHANNEL_NUMBERS.contains(&number) {
+            return Err((400, "Bad Request"));
+        }
+        if !self.peers.contains(&peer) {
+            return Err((403, "Forbidden"));
+        }
+        let Some(allocation) = self.allocations.get_mut(&client) else {
+            return Err((437, "Allocation Mismatch"));
+        };
+
+        let now = Instant::now();
+        let bound_peer = allocation.channels.get(&number).map(|c| c.peer);
+        let bound_number = allocation.channel_of(peer, now);
+        if bound_peer.is_some_and(|p| p != peer) || bound_number.is_some_and(|n| n != number) {
+            return Err((400, "Bad Request"));
+        }
+
+        allocation.channels.insert(
+            number,
+            Channel {
+                peer,
+                expires: now + CHANNEL_LIFETIME,
+            },
+        );
+        allocation
+            .permissions
+            .insert(peer.ip(), now + PERMISSION_LIFETIME);
+        Ok(MessageBuilder::new(
+            msg.method,
+            Class::Success,
+            msg.transaction_id,
+        ))
+    }
+
+    fn is_peer_ip(&self, ip: IpAddr) -> bool {
+        self.peers.iter().any(|peer| peer.ip() == ip)
+    }
+
+    async fn handle_send_indication(&mut self, client: Client, msg: &Message<'_>) {
+        let (Some(peer), Some(data)) = (
+            msg.xor_address(stun::ATTR_XOR_PEER_ADDRESS),
+            msg.get(stun::ATTR_DATA),
+        ) else {
+            return;
+        };
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+        if !allocation.is_permitted(peer.ip(), Instant::now()) || !self.peers.contains(&peer) {
+            tracing::trace!(?client, %peer, "dropped a send indication without permission");
+            return;
+        }
+
+        let relay = allocation.relay;
+        self.relay(relay, peer, Bytes::copy_from_slice(data)).await;
+    }
+
+    async fn handle_channel_data(&mut self, client: Client, data: &Bytes) {
+        if data.len() < 4 {
+            return;
+        }
+        let number = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if data.len() < 4 + len {
+            return;
+        }
+
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+        let now = Instant::now();
+        let Some(channel) = allocation
+            .channels
+            .get(&number)
+            .filter(|channel| channel.expires > now)
+        else {
+            tracing::trace!(
+                ?client,
+                number,
+                "dropped channel data of an unbound channel"
+            );
+            return;
+        };
+
+        let (relay, peer) = (allocation.relay, channel.peer);
+        self.relay(relay, peer, data.slice(4..4 + len)).await;
+    }
+
+    /// Hands a packet to the SFU directly, as if it arrived from the relayed address.
+    async fn relay(&self, relay: SocketAddr, peer: SocketAddr, data: Bytes) {
+        if self.source.relay(relay, peer, data).await.is_err() {
+            tracing::warn!("source is closed, dropped a relayed packet");
+        }
+    }
+
+    async fn handle_egress(&mut self, source: SocketAddr, packet: EgressUDPPacket) {
+        let Some(client) = self.relays.get(&packet.dst).copied() else {
+            tracing::trace!("dropped a packet to {} without an allocation", packet.dst);
+            return;
+        };
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+        let now = Instant::now();
+        if !allocation.is_permitted(source.ip(), now) {
+            return;
+        }
+
+        let data = if let Some(number) = allocation.channel_of(source, now) {
+            let is_stream = !matches!(client.transport, ClientTransport::Udp(_));
+            channel_data(number, &packet.raw, is_stream)
+        } else {
+            let mut transaction_id = [0; 12];
+            self.rng.fill_bytes(&mut transaction_id);
+            MessageBuilder::new(stun::METHOD_DATA, Class::Indication, transaction_id)
+                .add_xor_address(stun::ATTR_XOR_PEER_ADDRESS, source)
+                .add(stun::ATTR_DATA, &packet.raw)
+                .build()
+        };
+        self.send(client, data).await;
+    }
+
+    async fn send(&self, client: Client, data: Vec<u8>) {
+        match client.transport {
+            ClientTransport::Udp(index) => {
+                if let Err(err) = self.udp[index].send_to(&data, client.addr).await {
+                    tracing::debug!("failed to send a turn packet to {}: {err}", client.addr);
+                }
+            }
+            ClientTransport::Tcp | ClientTransport::Tls => {
+                let Some(stream) = self.streams.get(&client) else {
+                    return;
+                };
+                // A slow connection shouldn't hold up the others
+                if let Err(err) = stream.try_send(Bytes::from(data)) {
+                    telemetry::record_dropped("turn_egress");
+                    tracing::trace!("dropped a turn packet to {}: {err}", client.addr);
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let relays = &mut self.relays;
+        self.allocations.retain(|client, allocation| {
+            if allocation.expires <= now {
+                tracing::debug!(?client, relay = %allocation.relay, "turn allocation expired");
+                relays.remove(&allocation.relay);
+                return false;
+            }
+
+            allocation.permissions.retain(|_, expires| *expires > now);
+            allocation
+                .channels
+                .retain(|_, channel| channel.expires > now);
+            true
+        });
+    }
+}
+
+/// ChannelData message, padded to 4 bytes over TCP and TLS.
+fn channel_data(number: u16, data: &[u8], padded: bool) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + data.len() + 3);
+    buf.extend_from_slice(&number.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    if padded {
+        buf.resize(buf.len().next_multiple_of(4), 0);
+    }
+    buf
+}
+
+/// Length of the next STUN or ChannelData message on a stream, `None` until the header
+/// is complete.
+fn frame_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    match buf[0] >> 6 {
+        0b00 => Ok(Some(stun::HEADER_SIZE + len)),
+        0b01 => Ok(Some(4 + len.next_multiple_of(4))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "neither stun nor channel data",
+        )),
+    }
+}
+
+async fn read_udp(socket: UdpSocket, index: usize, ingress: mpsc::Sender<Ingress>) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, addr)) => {
+                let client = Client {
+                    transport: ClientTransport::Udp(index),
+                    addr,
+                };
+                let packet = Bytes::copy_from_slice(&buf[..len]);
+                if ingress.send(Ingress::Packet(client, packet)).await.is_err() {
+                    break;
+                }
+            }
+            // A single bad datagram or ICMP error mustn't stop the listener for everyone
+            Err(err) => {
+                tracing::warn!("failed to receive a turn packet: {err}");
+                tokio::time::sleep(RECV_BACKOFF).await;
+            }
+        }
+    }
+}
+
+async fn accept_streams(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    ingress: mpsc::Sender<Ingress>,
+    capacity: usize,
+) {
+    let transport = if tls.is_some() {
+        ClientTransport::Tls
+    } else {
+        ClientTransport::Tcp
+    };
+    // Dropped with the listener when the actor exits, which closes the connections
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                let (stream, addr) = match res {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::warn!("failed to accept a turn connection: {err}");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+
+                let client = Client { transport, addr };
+                let tls = tls.clone();
+                let ingress = ingress.clone();
+                connections.spawn(
+                    async move {
+                        if let Err(err) = serve_connection(stream, tls, client, ingress, capacity).await {
+                            tracing::debug!("turn connection from {addr} is closed: {err}");
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    client: Client,
+    ingress: mpsc::Sender<Ingress>,
+    capacity: usize,
+) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let Some(acceptor) = tls else {
+        return serve_stream(stream, client, ingress, capacity).await;
+    };
+
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tls handshake"))??;
+    serve_stream(stream, client, ingress, capacity).await
+}
+
+async fn serve_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    client: Client,
+    ingress: mpsc::Sender<Ingress>,
+    capacity: usize,
+) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (sender, mut receiver) = mpsc::channel::<Bytes>(capacity);
+    if ingress
+        .send(Ingress::Connected(client, sender))
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+
+    let read = async {
+        let mut buf = BytesMut::with_capacity(MAX_DATAGRAM_SIZE);
+        loop {
+            while let Some(len) = frame_len(&buf)? {
+                if buf.len() < len {
+                    break;
+                }
+
+                let frame = buf.split_to(len).freeze();
+                if ingress.send(Ingress::Packet(client, frame)).await.is_err() {
+                    return Ok(());
+                }
+            }
+
+            if reader.read_buf(&mut buf).await? == 0 {
+                return Ok(());
+            }
+        }
+    };
+
+    let write = async {
+        while let Some(data) = receiver.recv().await {
+            writer.write_all(&data).await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    let res = tokio::select! {
+        res = read => res,
+        res = write => res,
+    };
+    let _ = ingress.send(Ingress::Disconnected(client)).await;
+    res
+}
+
+/// Disabled without an actor, participants never see relayed addresses then.
+#[derive(Clone, Debug, Default)]
+pub struct TurnHandle {
+    sender: Option<mpsc::Sender<TurnMessage>>,
+}
+
+impl TurnHandle {
+    /// `connection_capacity` is the egress queue of a single TCP or TLS connection.
+    pub fn new(
+        settings: TurnSettings,
+        listeners: TurnListeners,
+        source: UdpSourceHandle,
+        mut rng: Rng,
+        capacity: usize,
+        connection_capacity: usize,
+    ) -> (Self, TurnActor) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let (ingress_sender, ingress) = mpsc::channel(capacity);
+        let mut nonce_key = [0; 32];
+        rng.fill_bytes(&mut nonce_key);
+
+        let handle = Self {
+            sender: Some(sender),
+        };
+        let port = listeners
+            .udp
+            .first()
+            .and_then(|socket| socket.local_addr().ok())
+            .map_or(0, |addr| addr.port());
+        let actor = TurnActor {
+            port,
+            receiver,
+            ingress_sender,
+            ingress,
+            listeners,
+            connection_capacity,
+            tasks: JoinSet::new(),
+            rng,
+            source,
+            realm: settings.realm,
+            keys: settings.keys.into_iter().collect(),
+            relay_ips: settings.relay_ips,
+            peers: settings.peers.into_iter().collect(),
+            max_allocations: settings.max_allocations,
+            nonce_key,
+            udp: Vec::new(),
+            streams: HashMap::new(),
+            allocations: HashMap::new(),
+            relays: HashMap::new(),
+            next_port: *RELAY_PORTS.start(),
+        };
+        (handle, actor)
+    }
+
+    /// Sends `packet` from the SFU address `source` to a relayed address.
+    pub async fn send(
+        &self,
+        source: SocketAddr,
+        packet: EgressUDPPacket,
+    ) -> Result<(), SendError<TurnMessage>> {
+        let msg = TurnMessage::Send(source, packet);
+        match &self.sender {
+            Some(sender) => sender.send(msg).await,
+            None => Err(SendError(msg)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{
+        actor,
+        controller::testing,
+        entity::{ExternalParticipantId, ParticipantId},
+        message::UDPPacket,
+        participant::{ParticipantDataMessage, ParticipantHandle},
+    };
+
+    const REALM: &str = "pulsebeam";
+    const KEY_ID: &str = "kid_3mJr7AoUXx2Wqd";
+    const SECRET: &str = "sk_2NEpo7TZRRrLZSi2U";
+
+    struct Harness {
+        client: tokio::net::UdpSocket,
+        server: SocketAddr,
+        turn: TurnHandle,
+        /// Media address of the SFU, the only permitted peer.
+        peer: SocketAddr,
+        participant: mpsc::Receiver<ParticipantDataMessage>,
+        username: String,
+        key: Vec<u8>,
+    }
+
+    /// A TURN server on loopback that relays to a UDP source, STUN bindings for the
+    /// ufrag "alice" reach the returned participant.
+    async fn harness() -> Harness {
+        let (transport, peer) = testing::spawn_transport().await;
+        let external = ExternalParticipantId::new("alice".to_string()).unwrap();
+        let participant_id = ParticipantId::new(&mut Rng::seed_from_u64(1), external);
+        let (data_sender, participant) = mpsc::channel(16);
+        let (control_sender, _) = mpsc::channel(1);
+        let handle = ParticipantHandle {
+            data_sender,
+            control_sender,
+            participant_id: Arc::new(participant_id),
+        };
+        transport
+            .source
+            .add_participant("alice".to_string(), handle)
+            .await
+            .unwrap();
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        let listeners = TurnListeners {
+            udp: vec![UdpSocket::new(socket).unwrap()],
+            ..Default::default()
+        };
+        let settings = TurnSettings {
+            realm: REALM.to_string(),
+            keys: vec![(KEY_ID.to_string(), SECRET.to_string())],
+            relay_ips: vec![server.ip()],
+            peers: vec![peer],
+            max_allocations: 16,
+        };
+        let (turn, actor) = TurnHandle::new(
+            settings,
+            listeners,
+            transport.source,
+            Rng::seed_from_u64(2),
+            16,
+            16,
+        );
+        tokio::spawn(actor::run(actor));
+
+        let username = format!("{}:{KEY_ID}", unix_now() + 60);
+        let key = long_term_key(&username, REALM, &turn_password(SECRET, &username));
+        Harness {
+            client: tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            server,
+            turn,
+            peer,
+            participant,
+            username,
+            key,
+        }
+    }
+
+    impl Harness {
+        async fn send(&self, data: &[u8]) {
+            self.client.send_to(data, self.server).await.unwrap();
+        }
+
+        async fn recv(&self) -> Vec<u8> {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let len = tokio::time::timeout(Duration::from_secs(1), self.client.recv(&mut buf))
+                .await
+                .expect("a response")
+                .unwrap();
+            buf.truncate(len);
+            buf
+        }
+
+        async fn request(&self, data: Vec<u8>) -> Vec<u8> {
+            self.send(&data).await;
+            self.recv().await
+        }
+
+        /// A nonce from the challenge of an unauthenticated request.
+        async fn nonce(&self) -> String {
+            let resp = self.request(allocate([0; 12]).build()).await;
+            let resp = Message::decode(&resp).unwrap();
+            assert_eq!(error_code(&resp), Some(401));
+            assert_eq!(resp.get_str(stun::ATTR_REALM), Some(REALM));
+            resp.get_str(stun::ATTR_NONCE).unwrap().to_string()
+        }
+
+        fn authenticated(&self, builder: MessageBuilder, nonce: &str) -> Vec<u8> {
+            builder
+                .add(stun::ATTR_USERNAME, self.username.as_bytes())
+                .add(stun::ATTR_REALM, REALM.as_bytes())
+                .add(stun::ATTR_NONCE, nonce.as_bytes())
+                .build_with_integrity(&self.key)
+        }
+
+        /// Allocates a relayed address with fresh credentials.
+        async fn allocate(&self) -> (SocketAddr, String) {
+            let nonce = self.nonce().await;
+            let resp = self
+                .request(self.authenticated(allocate([1; 12]), &nonce))
+                .await;
+            let resp = Message::decode(&resp).unwrap();
+            assert_eq!(resp.class, Class::Success);
+            assert!(resp.verify_integrity(&self.key));
+            let relay = resp.xor_address(stun::ATTR_XOR_RELAYED_ADDRESS).unwrap();
+            (relay, nonce)
+        }
+
+        async fn packet(&mut self) -> UDPPacket {
+            let msg = tokio::time::timeout(Duration::from_secs(1), self.participant.recv())
+                .await
+                .expect("a relayed packet");
+            match msg {
+                Some(ParticipantDataMessage::UdpPacket(packet)) => packet,
+                _ => panic!("expected a packet"),
+            }
+        }
+    }
+
+    fn allocate(transaction_id: [u8; 12]) -> MessageBuilder {
+        MessageBuilder::new(stun::METHOD_ALLOCATE, Class::Request, transaction_id).add(
+            stun::ATTR_REQUESTED_TRANSPORT,
+            &[stun::TRANSPORT_UDP, 0, 0, 0],
+        )
+    }
+
+    fn send_indication(peer: SocketAddr, data: &[u8]) -> Vec<u8> {
+        MessageBuilder::new(stun::METHOD_SEND, Class::Indication, [3; 12])
+            .add_xor_address(stun::ATTR_XOR_PEER_ADDRESS, peer)
+            .add(stun::ATTR_DATA, data)
+            .build()
+    }
+
+    /// A STUN binding from the client to the SFU, routed by its ufrag "alice".
+    fn binding(transaction_id: [u8; 12]) -> Vec<u8> {
+        MessageBuilder::new(stun::METHOD_BINDING, Class::Request, transaction_id)
+            .add(stun::ATTR_USERNAME, b"alice:client")
+            .build()
+    }
+
+    fn error_code(msg: &Message) -> Option<u16> {
+        let value = msg.get(stun::ATTR_ERROR_CODE)?;
+        Some(value[2] as u16 * 100 + value[3] as u16)
+    }
+
+    #[test]
+    fn test_credentials_roundtrip() {
+        let credentials = TurnCredentials::new(
+            "kid_3mJr7AoUXx2Wqd".to_string(),
+            "sk_2NEpo7TZRRrLZSi2U".to_string(),
+            vec!["turn:192.0.2.1:3479?transport=udp".to_string()],
+            Duration::from_secs(60),
+        );
+        let server = credentials.issue();
+
+        let (expiry, key_id) = server.username.split_once(':').unwrap();
+        assert!(expiry.parse::<u64>().unwrap() > unix_now());
+        assert_eq!(key_id, "kid_3mJr7AoUXx2Wqd");
+        assert_eq!(
+            server.credential,
+            turn_password("sk_2NEpo7TZRRrLZSi2U", &server.username)
+        );
+        assert_ne!(
+            server.credential,
+            turn_password("sk_other", &server.username)
+        );
+    }
+
+    #[test]
+    fn test_stream_frame_len() {
+        let binding = MessageBuilder::new(stun::METHOD_BINDING, Class::Request, [0; 12])
+            .add(stun::ATTR_SOFTWARE, SOFTWARE)
+            .build();
+        assert_eq!(frame_len(&binding).unwrap(), Some(binding.len()));
+
+        let data = channel_data(0x4000, b"hello", true);
+        assert_eq!(data.len(), 12);
+        assert_eq!(frame_len(&data).unwrap(), Some(12));
+        assert_eq!(frame_len(&data[..3]).unwrap(), None);
+        assert!(frame_len(&[0xC0, 0, 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_stale_nonce() {
+        let harness = harness().await;
+        let nonce = format!("{:016x}{}", unix_now() + 60, "0".repeat(16));
+        let resp = harness
+            .request(harness.authenticated(allocate([1; 12]), &nonce))
+            .await;
+        let resp = Message::decode(&resp).unwrap();
+        assert_eq!(error_code(&resp), Some(438));
+        assert!(resp.get_str(stun::ATTR_NONCE).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reject_bad_integrity() {
+        let mut harness = harness().await;
+        let nonce = harness.nonce().await;
+        let password = turn_password("sk_other", &harness.username);
+        harness.key = long_term_key(&harness.username, REALM, &password);
+        let resp = harness
+            .request(harness.authenticated(allocate([1; 12]), &nonce))
+            .await;
+        assert_eq!(error_code(&Message::decode(&resp).unwrap()), Some(401));
+
+        // Expired credentials are refused even with the right secret
+        harness.username = format!("{}:{KEY_ID}", unix_now() - 1);
+        let password = turn_password(SECRET, &harness.username);
+        harness.key = long_term_key(&harness.username, REALM, &password);
+        let resp = harness
+            .request(harness.authenticated(allocate([2; 12]), &nonce))
+            .await;
+        assert_eq!(error_code(&Message::decode(&resp).unwrap()), Some(401));
+    }
+
+    #[tokio::test]
+    async fn test_allocate_twice() {
+        let harness = harness().await;
+        let (relay, nonce) = harness.allocate().await;
+        assert_eq!(relay.ip(), harness.server.ip());
+        assert!(RELAY_PORTS.contains(&relay.port()));
+
+        let resp = harness
+            .request(harness.authenticated(allocate([2; 12]), &nonce))
+            .await;
+        assert_eq!(error_code(&Message::decode(&resp).unwrap()), Some(437));
+    }
+
+    #[tokio::test]
+    async fn test_relay_with_permission() {
+        let mut harness = harness().await;
+        let (relay, nonce) = harness.allocate().await;
+
+        // Without a permission nothing reaches the SFU
+        harness
+            .send(&send_indication(harness.peer, &binding([9; 12])))
+            .await;
+
+        let other = SocketAddr::from(([192, 0, 2, 1], 5000));
+        let permission = |peer| {
+            MessageBuilder::new(stun::METHOD_CREATE_PERMISSION, Class::Request, [5; 12])
+                .add_xor_address(stun::ATTR_XOR_PEER_ADDRESS, peer)
+        };
+        let resp = harness
+            .request(harness.authenticated(permission(other), &nonce))
+            .await;
+        assert_eq!(error_code(&Message::decode(&resp).unwrap()), Some(403));
+        let resp = harness
+            .request(harness.authenticated(permission(harness.peer), &nonce))
+            .await;
+        assert_eq!(Message::decode(&resp).unwrap().class, Class::Success);
+
+        harness
+            .send(&send_indication(harness.peer, &binding([4; 12])))
+            .await;
+        let packet = harness.packet().await;
+        assert_eq!(packet.raw, binding([4; 12]));
+        assert_eq!(packet.src, relay);
+        assert_eq!(packet.dst, harness.peer);
+        assert!(packet.relayed);
+
+        // Replies without a channel come back as data indications
+        let reply = EgressUDPPacket {
+            raw: Bytes::from_static(b"hello"),
+            dst: relay,
+        };
+        harness.turn.send(harness.peer, reply).await.unwrap();
+        let data = harness.recv().await;
+        let data = Message::decode(&data).unwrap();
+        assert_eq!(
+            (data.class, data.method),
+            (Class::Indication, stun::METHOD_DATA)
+        );
+        assert_eq!(
+            data.xor_address(stun::ATTR_XOR_PEER_ADDRESS),
+            Some(harness.peer)
+        );
+        assert_eq!(data.get(stun::ATTR_DATA), Some(&b"hello"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_relay_over_channel() {
+        let mut harness = harness().await;
+        let (relay, nonce) = harness.allocate().await;
+
+        let bind = |number: u16, peer| {
+            MessageBuilder::new(stun::METHOD_CHANNEL_BIND, Class::Request, [6; 12])
+                .add(
+                    stun::ATTR_CHANNEL_NUMBER,
+                    &[(number >> 8) as u8, number as u8, 0, 0],
+                )
+                .add_xor_address(stun::ATTR_XOR_PEER_ADDRESS, peer)
+        };
+        let other = SocketAddr::from(([192, 0, 2, 1], 5000));
+        let resp = harness
+            .request(harness.authenticated(bind(0x4000, other), &nonce))
+            .await;
+        assert_eq!(error_code(&Message::decode(&resp).unwrap()), Some(403));
+        let resp = harness
+            .request(harness.authenticated(bind(0x3FFF, harness.peer), &nonce))
+            .await;
+        assert_eq!(error_code(&Message::decode(&resp).unwrap()), Some(400));
+        let resp = harness
+            .request(harness.authenticated(bind(0x4000, harness.peer), &nonce))
+            .await;
+        assert_eq!(Message::decode(&resp).unwrap().class, Class::Success);
+
+        // Data on an unbound channel is dropped
+        harness
+            .send(&channel_data(0x4001, &binding([9; 12]), false))
+            .await;
+        harness
+            .send(&channel_data(0x4000, &binding([4; 12]), false))
+            .await;
+        let packet = harness.packet().await;
+        assert_eq!(packet.raw, binding([4; 12]));
+        assert_eq!(packet.src, relay);
+        assert!(packet.relayed);
+
+        let reply = EgressUDPPacket {
+            raw: Bytes::from_static(b"hello"),
+            dst: relay,
+        };
+        harness.turn.send(harness.peer, reply).await.unwrap();
+        assert_eq!(harness.recv().await, channel_data(0x4000, b"hello", false));
+
+        // The SFU can't reach the client from an address without a permission
+        let reply = EgressUDPPacket {
+            raw: Bytes::from_static(b"dropped"),
+            dst: relay,
+        };
+        harness.turn.send(other, reply).await.unwrap();
+        let reply = EgressUDPPacket {
+            raw: Bytes::from_static(b"world"),
+            dst: relay,
+        };
+        harness.turn.send(harness.peer, reply).await.unwrap();
+        assert_eq!(harness.recv().await, channel_data(0x4000, b"world", false));
+    }
+}
@@ -3,16 +3,23 @@ use std::{io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
 mod net;
 
 use console_subscriber::ConsoleLayer;
+use futures::FutureExt;
 use net::{VirtualTcpListener, VirtualUdpSocket};
 use pulsebeam::{
     actor,
-    controller::ControllerHandle,
+    auth::Authenticator,
+    config::ChannelCapacities,
+    controller::{ControllerConfig, ControllerHandle},
     entity::{ExternalParticipantId, ExternalRoomId},
     net::PacketSocket,
+    participant::Transport,
     rng::Rng,
+    shutdown::Drain,
     signaling,
     sink::UdpSinkHandle,
     source::UdpSourceHandle,
+    tcp::TcpHandle,
+    turn::TurnHandle,
 };
 use rand::SeedableRng;
 use str0m::{Candidate, Event, IceConnectionState, Input, Output, change::SdpAnswer, net::Receive};
@@ -49,26 +56,40 @@ pub fn setup_sim(seed: u64) {
             let socket = VirtualUdpSocket(Arc::new(socket));
 
             let rng = Rng::seed_from_u64(seed);
-            let (source_handle, source_actor) = UdpSourceHandle::new(server_addr, socket.clone());
-            let (sink_handle, sink_actor) = UdpSinkHandle::new(socket.clone());
-
+            let channels = ChannelCapacities::default();
+            let (source_handle, source_actor) =
+                UdpSourceHandle::new(vec![server_addr], socket.clone(), channels.udp_source, 0);
+            let (sink_handle, sink_actor) =
+                UdpSinkHandle::new(socket.clone(), channels.udp_sink, 0);
+
+            let transport = Transport {
+                source: source_handle,
+                sink: sink_handle,
+                tcp: TcpHandle::default(),
+                turn: TurnHandle::default(),
+            };
             let (controller_handle, controller_actor) = ControllerHandle::new(
                 rng,
-                source_handle,
-                sink_handle,
-                vec![server_addr],
+                transport,
+                vec![Candidate::host(server_addr, "udp").unwrap()],
+                ControllerConfig::default(),
                 Arc::new("root".to_string()),
             );
-            let router = signaling::router(controller_handle);
+            let router = signaling::router(
+                controller_handle,
+                Arc::new(Authenticator::default()),
+                None,
+                Drain::default(),
+            );
             let listener = TcpListener::bind("0.0.0.0:3000").await?;
             let signaling = async move {
                 let _ = axum::serve(VirtualTcpListener(listener), router).await;
             };
 
             let mut join_set = JoinSet::new();
-            join_set.spawn(actor::run(source_actor));
-            join_set.spawn(actor::run(sink_actor));
-            join_set.spawn(actor::run(controller_actor));
+            join_set.spawn(actor::run(source_actor).map(drop));
+            join_set.spawn(actor::run(sink_actor).map(drop));
+            join_set.spawn(actor::run(controller_actor).map(drop));
             join_set.spawn(signaling);
 
             while let Some(_) = join_set.join_next().await {}